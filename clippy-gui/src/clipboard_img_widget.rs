@@ -1,12 +1,10 @@
+use crate::custom_egui_widget::device_label;
 use crate::ipc::ipc::send_process;
 use clippy::{Data, EditData, UserSettings, log_error};
 use clippy_gui::set_lock;
 use egui::{self, *};
 use log::error;
-use std::{
-    path::PathBuf,
-    sync::{Arc, Mutex},
-};
+use std::sync::{Arc, Mutex};
 
 pub fn item_card_image(
     data: &mut Data,
@@ -15,7 +13,7 @@ pub fn item_card_image(
     pinned: &mut bool,
     settings: &UserSettings,
     changed: Arc<Mutex<bool>>,
-    path: &PathBuf,
+    id: i64,
     ctx: &Context,
     sync: &bool,
 ) -> Response {
@@ -53,30 +51,17 @@ pub fn item_card_image(
                             if pin_response.clicked() {
                                 if pin_response.clicked() {
                                     data.change_pined();
-                                    if let Some(file_name) =
-                                        path.file_name().and_then(|f| f.to_str())
-                                    {
-                                        let msg = clippy::MessageIPC::Edit(EditData::new(
-                                            data.clone(),
-                                            file_name.to_string(),
-                                            path.to_path_buf(),
-                                        ));
-                                        log_error!(send_process(msg));
-                                    }
+                                    let msg =
+                                        clippy::MessageIPC::Edit(EditData::new(data.clone(), id));
+                                    log_error!(send_process(msg));
                                     set_lock!(changed, true);
                                 }
                             }
 
                             let delete_response = ui.selectable_label(false, "🗑");
                             if delete_response.clicked() {
-                                if let Some(file_name) = path.file_name().and_then(|f| f.to_str()) {
-                                    let msg = clippy::MessageIPC::Delete(
-                                        path.to_path_buf(),
-                                        file_name.to_string(),
-                                    );
-                                    log_error!(send_process(msg));
-                                    set_lock!(changed, true);
-                                }
+                                log_error!(send_process(clippy::MessageIPC::Delete(id)));
+                                set_lock!(changed, true);
                             }
                         }
 
@@ -84,6 +69,8 @@ pub fn item_card_image(
                             let sync = ui.selectable_label(false, "🔄");
                             sync.on_hover_text("update in progress");
                         }
+
+                        device_label(ui, data);
                     });
                 });
             });
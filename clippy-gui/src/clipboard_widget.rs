@@ -1,13 +1,11 @@
-use std::{
-    path::PathBuf,
-    sync::{Arc, Mutex},
-};
+use std::sync::{Arc, Mutex};
 
 use clippy::{Data, EditData, UserSettings, log_error};
 use clippy_gui::set_lock;
 use egui::{self, *};
 use log::error;
 
+use crate::custom_egui_widget::device_label;
 use crate::ipc::ipc::send_process;
 
 pub fn item_card(
@@ -16,9 +14,9 @@ pub fn item_card(
     text_label: &str,
     pinned: &mut bool,
     settings: &UserSettings,
-    show_data_popup: &mut (bool, String, Option<PathBuf>, bool),
+    show_data_popup: &mut (bool, String, Option<i64>, bool),
     changed: Arc<Mutex<bool>>,
-    path: &PathBuf,
+    id: i64,
     ctx: &Context,
     sync: &bool,
 ) -> Response {
@@ -60,27 +58,15 @@ pub fn item_card(
                             let pin_response = ui.selectable_label(*pinned, "📌");
                             if pin_response.clicked() {
                                 data.change_pined();
-                                if let Some(file_name) = path.file_name().and_then(|f| f.to_str()) {
-                                    let msg = clippy::MessageIPC::Edit(EditData::new(
-                                        data.clone(),
-                                        file_name.to_string(),
-                                        path.to_path_buf(),
-                                    ));
-                                    log_error!(send_process(msg));
-                                }
+                                let msg = clippy::MessageIPC::Edit(EditData::new(data.clone(), id));
+                                log_error!(send_process(msg));
                                 set_lock!(changed, true);
                             }
 
                             let delete_response = ui.selectable_label(false, "🗑");
                             if delete_response.clicked() {
-                                if let Some(file_name) = path.file_name().and_then(|f| f.to_str()) {
-                                    let msg = clippy::MessageIPC::Delete(
-                                        path.to_path_buf(),
-                                        file_name.to_string(),
-                                    );
-                                    log_error!(send_process(msg));
-                                    set_lock!(changed, true);
-                                }
+                                log_error!(send_process(clippy::MessageIPC::Delete(id)));
+                                set_lock!(changed, true);
                             }
 
                             let view_all = ui.selectable_label(false, "💬");
@@ -89,7 +75,7 @@ pub fn item_card(
                                 *show_data_popup = (
                                     true,
                                     data.get_data().unwrap().to_string(),
-                                    Some(path.clone()),
+                                    Some(id),
                                     *pinned,
                                 );
                             }
@@ -99,6 +85,8 @@ pub fn item_card(
                             let sync = ui.selectable_label(false, "🔄");
                             sync.on_hover_text("update in progress");
                         }
+
+                        device_label(ui, data);
                     });
                 });
             });
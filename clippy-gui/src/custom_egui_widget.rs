@@ -62,3 +62,14 @@ pub fn toggle_ui(ui: &mut egui::Ui, on: &mut bool) -> egui::Response {
 pub fn toggle(on: &mut bool) -> impl egui::Widget + '_ {
     move |ui: &mut egui::Ui| toggle_ui(ui, on)
 }
+
+/// The device the entry was copied on, entries of older versions do not know it.
+pub fn device_label(ui: &mut egui::Ui, data: &clippy::Data) {
+    if data.device() == "os" {
+        return;
+    }
+    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+        ui.label(egui::RichText::new(data.device()).small().weak())
+            .on_hover_text("copied on this device");
+    });
+}
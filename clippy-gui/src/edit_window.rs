@@ -1,6 +1,4 @@
-use std::fs;
-
-use clippy::{Data, EditData, log_error};
+use clippy::{Data, EditData, log_error, storage::storage};
 use clippy_gui::set_lock;
 use egui::ScrollArea;
 use egui::{
@@ -47,22 +45,13 @@ impl Clipboard {
                             .stroke(Stroke::new(1.0, ui.visuals().widgets.inactive.bg_fill));
 
                         if ui.add(button).on_hover_text("Save").clicked() {
-                            if let Some(path) = &self.show_data_popup.2 {
-                                if let Ok(val) = fs::read_to_string(path) {
-                                    if let Ok(mut data) = serde_json::from_str::<Data>(&val) {
-                                        data.change_data(&self.show_data_popup.1);
-                                        data.pined = self.show_data_popup.3;
-                                        if let Some(file_name) =
-                                            path.file_name().and_then(|f| f.to_str())
-                                        {
-                                            let msg = clippy::MessageIPC::Edit(EditData::new(
-                                                data,
-                                                file_name.to_string(),
-                                                path.to_path_buf(),
-                                            ));
-                                            log_error!(send_process(msg));
-                                        }
-                                    }
+                            if let Some(id) = self.show_data_popup.2 {
+                                if let Ok(Some(entry)) = storage().get(id) {
+                                    let mut data = entry.data;
+                                    data.change_data(&self.show_data_popup.1);
+                                    data.pined = self.show_data_popup.3;
+                                    let msg = clippy::MessageIPC::Edit(EditData::new(data, id));
+                                    log_error!(send_process(msg));
                                 }
                             } else {
                                 log_error!(send_process(clippy::MessageIPC::New(Data::new(
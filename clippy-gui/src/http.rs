@@ -1,13 +1,35 @@
-use clippy::{LoginUserCred, NewUser, NewUserOtp, UserCred, http::SERVER};
+use clippy::{LoginUserCred, NewUser, NewUserOtp, PasswordReset, UserCred};
 use log::debug;
 use reqwest::Client;
+use std::time::Duration;
 
-pub async fn check_user(user: String) -> Result<bool, String> {
+/// Checks that `server` is a reachable clippy server.
+pub async fn check_server(server: String) -> Result<(), String> {
+    let connection = Client::new();
+    let response = connection
+        .get(format!("{}/health", server))
+        .timeout(Duration::from_secs(5))
+        .send();
+
+    match response.await {
+        Ok(resp) if resp.status().is_success() => match resp.text().await {
+            Ok(text) if text == "SERVER_ACTIVE" => Ok(()),
+            _ => Err("Not a clippy server".to_string()),
+        },
+        Ok(resp) => Err(format!("Server responded with {}", resp.status())),
+        Err(e) => {
+            debug!("{e}");
+            Err("Unable to communicate with server".to_string())
+        }
+    }
+}
+
+pub async fn check_user(server: String, user: String) -> Result<bool, String> {
     let connection = Client::new();
     let data = NewUser::new(user);
 
     let response = connection
-        .get(format!("{}/usercheck", SERVER))
+        .get(format!("{}/usercheck", server))
         .json(&data)
         .send();
 
@@ -29,10 +51,10 @@ pub async fn check_user(user: String) -> Result<bool, String> {
     }
 }
 
-pub async fn signin(data: NewUser) -> Result<(), String> {
+pub async fn signin(server: String, data: NewUser) -> Result<(), String> {
     let connection = Client::new();
     let response = connection
-        .post(format!("{}/signin", SERVER))
+        .post(format!("{}/signin", server))
         .json(&data)
         .send();
 
@@ -55,10 +77,10 @@ pub async fn signin(data: NewUser) -> Result<(), String> {
     }
 }
 
-pub async fn signin_otp_auth(data: NewUserOtp) -> Result<UserCred, String> {
+pub async fn signin_otp_auth(server: String, data: NewUserOtp) -> Result<UserCred, String> {
     let connection = Client::new();
     let response = connection
-        .post(format!("{}/authotp", SERVER))
+        .post(format!("{}/authotp", server))
         .json(&data)
         .send()
         .await;
@@ -83,10 +105,10 @@ pub async fn signin_otp_auth(data: NewUserOtp) -> Result<UserCred, String> {
     }
 }
 
-pub async fn login(user: &LoginUserCred) -> Result<UserCred, String> {
+pub async fn login(server: String, user: &LoginUserCred) -> Result<UserCred, String> {
     let connection = Client::new();
     let response = connection
-        .get(format!("{}/login", SERVER))
+        .get(format!("{}/login", server))
         .json(&user)
         .send()
         .await
@@ -100,3 +122,48 @@ pub async fn login(user: &LoginUserCred) -> Result<UserCred, String> {
         Err(format!("Login failed: {}", err_msg).into())
     }
 }
+
+/// Mails a one-time password to the address of `user`.
+pub async fn request_password_reset(server: String, user: String) -> Result<(), String> {
+    let connection = Client::new();
+    let response = connection
+        .post(format!("{}/password/reset/request", server))
+        .json(&NewUser::new(user))
+        .send()
+        .await;
+
+    match response {
+        Ok(res) if res.status().is_success() => Ok(()),
+        Ok(res) => Err(res.text().await.unwrap_or("Server error".to_string())),
+        Err(e) => {
+            debug!("{e}");
+            Err("Unable to connect to server".to_string())
+        }
+    }
+}
+
+/// Sets a new password, every other device of the account is logged out.
+pub async fn reset_password(server: String, data: PasswordReset) -> Result<UserCred, String> {
+    let connection = Client::new();
+    let response = connection
+        .post(format!("{}/password/reset/confirm", server))
+        .json(&data)
+        .send()
+        .await;
+
+    match response {
+        Ok(res) => {
+            if res.status().is_success() {
+                res.json::<UserCred>()
+                    .await
+                    .map_err(|_| "Invalid server response while parsing user data".to_string())
+            } else {
+                Err(res.text().await.unwrap_or_default())
+            }
+        }
+        Err(e) => {
+            debug!("{e}");
+            Err("Unable to connect to server".to_string())
+        }
+    }
+}
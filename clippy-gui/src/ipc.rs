@@ -1,37 +1,38 @@
 #[cfg(target_family = "unix")]
 pub mod ipc {
     use clippy::MessageIPC;
+    use clippy::protocol::{Client, Reply};
     use std::error::Error;
     use std::os::fd::FromRawFd;
-    use std::{io::Write, sync::Mutex};
+    use std::os::unix::net::UnixStream;
+    use std::sync::Mutex;
 
-    static STREAM: std::sync::OnceLock<Mutex<std::os::unix::net::UnixStream>> =
-        std::sync::OnceLock::new();
+    static STREAM: std::sync::OnceLock<Mutex<Client<UnixStream>>> = std::sync::OnceLock::new();
 
     pub fn init_stream() -> Result<(), Box<dyn Error>> {
         let fd = std::env::var("IPC")?.parse::<i32>()?;
-        let stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) };
+        let stream = unsafe { UnixStream::from_raw_fd(fd) };
+        let client = Client::connect(stream)?;
         STREAM
-            .set(Mutex::new(stream))
+            .set(Mutex::new(client))
             .ok()
             .expect("STREAM already initialized");
         Ok(())
     }
 
-    pub fn send_process(message: MessageIPC) -> Result<(), Box<dyn std::error::Error>> {
-        let stream = STREAM.get().expect("STREAM not initialized");
-        let stream = stream.lock().unwrap();
-        let mut cloned = stream.try_clone()?;
-        cloned.write_all(&serde_json::to_vec(&message)?)?;
-        Ok(())
+    pub fn send_process(message: MessageIPC) -> Result<Reply, Box<dyn std::error::Error>> {
+        let stream = STREAM.get().ok_or("STREAM not initialized")?;
+        let mut client = stream.lock().map_err(|e| e.to_string())?;
+        client.request(message)
     }
 }
 
 #[cfg(not(target_family = "unix"))]
 pub mod ipc {
     use clippy::MessageIPC;
+    use clippy::protocol::{Client, Reply};
     use interprocess::os::windows::named_pipe::DuplexPipeStream;
-    use std::{io::Write, sync::Mutex};
+    use std::sync::Mutex;
 
     static STREAM: std::sync::OnceLock<Mutex<String>> = std::sync::OnceLock::new();
 
@@ -44,10 +45,9 @@ pub mod ipc {
         Ok(())
     }
 
-    pub fn send_process(message: MessageIPC) -> Result<(), Box<dyn std::error::Error>> {
-        let path = STREAM.get().unwrap().lock()?;
-        let mut stream = DuplexPipeStream::connect_by_path(path.as_str())?;
-        stream.write(&serde_json::to_vec(&message)?)?;
-        Ok(())
+    pub fn send_process(message: MessageIPC) -> Result<Reply, Box<dyn std::error::Error>> {
+        let path = STREAM.get().ok_or("STREAM not initialized")?.lock()?;
+        let stream = DuplexPipeStream::connect_by_path(path.as_str())?;
+        Client::connect(stream)?.request(message)
     }
 }
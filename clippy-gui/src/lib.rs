@@ -1,4 +1,5 @@
-use clippy::UserCred;
+use clippy::{SessionInfo, UserCred};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug)]
 pub enum Thumbnail {
@@ -11,6 +12,11 @@ pub enum Waiting {
     SigninOTP(Result<(), String>),
     Login(Result<UserCred, String>),
     Signin(Result<UserCred, String>),
+    Health(Result<(), String>),
+    Sessions(Result<Vec<SessionInfo>, String>),
+    ResetOTP(Result<(), String>),
+    EmailOTP(Result<(), String>),
+    EmailChanged(Result<String, String>),
     None,
 }
 
@@ -38,6 +44,25 @@ pub fn str_formate(text: &str) -> String {
     result
 }
 
+/// How long ago the unix time `timestamp` was, "5 minutes ago".
+pub fn time_ago(timestamp: i64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|val| val.as_secs() as i64)
+        .unwrap_or_default();
+    let (count, unit) = match (now - timestamp).max(0) {
+        secs if secs < 60 => return String::from("just now"),
+        secs if secs < 3600 => (secs / 60, "minute"),
+        secs if secs < 86400 => (secs / 3600, "hour"),
+        secs => (secs / 86400, "day"),
+    };
+    if count == 1 {
+        format!("1 {} ago", unit)
+    } else {
+        format!("{} {}s ago", count, unit)
+    }
+}
+
 #[macro_export]
 macro_rules! set_lock {
     ($lock:expr, $value:expr) => {
@@ -6,11 +6,15 @@
 use clipboard_img_widget::item_card_image;
 use clipboard_widget::item_card;
 use clippy::{
-    APP_ID, Data, LoginUserCred, NewUser, NewUserOtp, SystemTheam, UserSettings,
-    get_global_update_bool, get_path, get_path_pending, get_path_pined, is_valid_email,
+    APP_ID, AccountRequest, Data, EmailChange, LoginUserCred, MessageIPC, SessionInfo, NewUser, NewUserOtp, PasswordReset,
+    SystemTheam, UserSettings,
+    search::{SearchMode, SearchQuery, SearchResult},
+    get_global_update_bool, get_sync_error, is_valid_email,
     is_valid_otp, is_valid_password, is_valid_username, log_error, set_global_update_bool,
+    storage::{Location, storage},
 };
-use clippy_gui::{Thumbnail, Waiting, set_lock};
+use clippy::protocol::Reply;
+use clippy_gui::{Thumbnail, Waiting, set_lock, time_ago};
 use custom_egui_widget::toggle;
 use eframe::{
     App, NativeOptions,
@@ -22,12 +26,12 @@ use egui::{
     TopBottomPanel, Vec2,
 };
 use env_logger::{Builder, Env};
-use http::{check_user, login, signin, signin_otp_auth};
+use http::{
+    check_server, check_user, login, request_password_reset, reset_password, signin, signin_otp_auth,
+};
 use log::{debug, error};
 use std::{
-    fs::{self},
     io::Error,
-    path::PathBuf,
     process,
     sync::{Arc, Mutex},
     thread::{self, JoinHandle},
@@ -53,15 +57,27 @@ struct Clipboard {
     newuser: NewUser,
     key: String,
     otp: String,
+    passphrase: String,
+    server: String,
+    server_status: Option<Result<(), String>>,
+    sessions: Option<Result<Vec<SessionInfo>, String>>,
     thread: Option<JoinHandle<()>>,
     waiting: Arc<Mutex<Waiting>>,
     show_login_window: bool,
     show_createuser_window: bool,
     show_createuser_auth_window: bool,
+    show_reset_window: bool,
+    // the address the account moves to, `None` while the email is not being changed
+    new_email: Option<String>,
+    email_otp_sent: bool,
     show_error: (bool, String),
     warn: Option<String>,
-    show_data_popup: (bool, String, Option<PathBuf>, bool),
+    show_data_popup: (bool, String, Option<i64>, bool),
     scrool_to_top: bool,
+    search: SearchQuery,
+    // the last answer of the daemon, it also lists the values of the filters
+    search_result: Option<SearchResult>,
+    search_error: Option<String>,
 }
 
 #[derive(PartialEq)]
@@ -79,59 +95,40 @@ pub enum GETPAGE {
 
 pub struct PatgeData {
     page_no: u32,
-    page_data: Option<Vec<(Thumbnail, PathBuf, Data, bool)>>,
+    page_data: Option<Vec<(Thumbnail, i64, Data, bool)>>,
     current_pos: Vec<u32>,
     current_patge: Page,
-    data: Vec<(PathBuf, bool)>,
+    data: Vec<(i64, bool)>,
 }
 
 impl PatgeData {
-    pub fn get_data() -> Vec<(PathBuf, bool)> {
-        let mut temp = Vec::new();
-
-        if let Ok(entries) = fs::read_dir(get_path()) {
-            for entry in entries.flatten() {
-                temp.push((entry.path(), false));
-            }
-        }
-
-        if let Ok(entries) = fs::read_dir(get_path_pending()) {
-            for entry in entries.flatten() {
-                temp.push((entry.path(), true));
+    pub fn get_data() -> Vec<(i64, bool)> {
+        match storage().list() {
+            Ok(val) => val
+                .into_iter()
+                .map(|(id, location)| (id, location == Location::Pending))
+                .collect(),
+            Err(e) => {
+                error!("Unable to read clipboard history: {}", e);
+                Vec::new()
             }
         }
-
-        if let Ok(entries) = fs::read_dir(get_path_pined()) {
-            for entry in entries.flatten() {
-                temp.push((entry.path(), false));
-            }
-        }
-
-        fn priority(path: &PathBuf) -> u8 {
-            let path_str = path.to_string_lossy();
-            if path_str.contains("clippy/local_data/") {
-                3
-            } else if path_str.contains("clippy/data/") {
-                2
-            } else if path_str.contains("clippy/pined/") {
-                1
-            } else {
-                0
-            }
-        }
-
-        temp.sort_by(|(a, _), (b, _)| {
-            let pa = priority(a);
-            let pb = priority(b);
-
-            pb.cmp(&pa).then_with(|| b.cmp(a))
-        });
-        temp
     }
 }
 
 impl Clipboard {
     fn new() -> Self {
+        let settings = match UserSettings::build_user() {
+            // without the secrets the stored ones are left alone when settings change
+            Ok(val) => val.clone().with_secrets().unwrap_or_else(|err| {
+                eprintln!("Unable to read the stored secrets: {}", err);
+                val
+            }),
+            Err(err) => {
+                eprintln!("{}", err);
+                UserSettings::new()
+            }
+        };
         let mut new = Self {
             page: PatgeData {
                 page_no: 1,
@@ -142,18 +139,19 @@ impl Clipboard {
             },
             changed: Arc::new(Mutex::new(false)),
             first_run: true,
-            settings: match UserSettings::build_user() {
-                Ok(val) => val,
-                Err(err) => {
-                    eprintln!("{}", err);
-                    UserSettings::new()
-                }
-            },
+            passphrase: settings.get_encrept().unwrap_or_default().to_string(),
+            server: settings.server().to_string(),
+            server_status: None,
+            sessions: None,
+            settings,
             show_settings: false,
             show_signin_window: false,
             show_login_window: false,
             show_createuser_window: false,
             show_createuser_auth_window: false,
+            show_reset_window: false,
+            new_email: None,
+            email_otp_sent: false,
             show_error: (false, String::from("")),
             warn: None,
             newuser: NewUser::new_signin(String::new(), String::new()),
@@ -163,16 +161,97 @@ impl Clipboard {
             waiting: Arc::new(Mutex::new(Waiting::None)),
             show_data_popup: (false, String::new(), None, true),
             scrool_to_top: false,
+            search: SearchQuery::default(),
+            search_result: None,
+            search_error: None,
         };
         new.get_current_page(GETPAGE::REFRESH);
         new
     }
 
     fn refresh(&mut self) {
-        self.page.data = PatgeData::get_data();
+        self.run_search();
         self.get_current_page(GETPAGE::REFRESH);
     }
 
+    // the daemon holds the tokens of the account, it asks the server
+    fn load_sessions(&mut self, ctx: &egui::Context, revoke: Option<String>) {
+        let wait = self.waiting.clone();
+        let ctx = ctx.clone();
+        self.sessions = None;
+        thread::spawn(move || {
+            let result = match revoke {
+                Some(id) => send_process(MessageIPC::Account(AccountRequest::Revoke(id))),
+                None => Ok(Reply::Done),
+            }
+            .and_then(|_| send_process(MessageIPC::Account(AccountRequest::Sessions)));
+            let sessions = match result {
+                Ok(Reply::Sessions(val)) => Ok(val),
+                Ok(_) => Err(String::from("Unexpected reply from clippy")),
+                Err(e) => Err(e.to_string()),
+            };
+            set_lock!(wait, Waiting::Sessions(sessions));
+            ctx.request_repaint();
+        });
+    }
+
+    // the new address is confirmed through the daemon, it holds the tokens of the account
+    fn change_email(&mut self, ctx: &egui::Context, email: String, otp: Option<String>) {
+        let wait = self.waiting.clone();
+        let ctx = ctx.clone();
+        self.warn = None;
+        self.thread = Some(thread::spawn(move || {
+            let status = match otp {
+                Some(otp) => {
+                    let change = EmailChange {
+                        email: email.clone(),
+                        otp,
+                    };
+                    Waiting::EmailChanged(
+                        send_process(MessageIPC::Account(AccountRequest::ConfirmEmail(change)))
+                            .map(|_| email)
+                            .map_err(|e| e.to_string()),
+                    )
+                }
+                None => Waiting::EmailOTP(
+                    send_process(MessageIPC::Account(AccountRequest::ChangeEmail(email)))
+                        .map(|_| ())
+                        .map_err(|e| e.to_string()),
+                ),
+            };
+            set_lock!(wait, status);
+            ctx.request_repaint();
+        }));
+    }
+
+    fn run_search(&mut self) {
+        self.search_error = None;
+        if self.search.is_empty() {
+            self.search_result = None;
+            self.page.data = PatgeData::get_data();
+            return;
+        }
+
+        match daemon_search(&self.search) {
+            Ok(val) => {
+                self.page.data = val.ids.clone();
+                self.search_result = Some(val);
+            }
+            Err(e) => {
+                self.search_error = Some(e);
+                self.page.data = Vec::new();
+            }
+        }
+    }
+
+    fn search(&mut self) {
+        self.run_search();
+        self.page.current_pos = vec![0];
+        self.page.page_no = 1;
+        self.get_current_page(GETPAGE::REFRESH);
+        self.scrool_to_top = true;
+    }
+
     fn get_current_page(&mut self, get_page: GETPAGE) {
         let mut page_data = Vec::new();
         let mut count = 0;
@@ -196,76 +275,32 @@ impl Clipboard {
             .enumerate()
             .skip(*skip.unwrap_or(&0) as usize)
         {
-            if let Ok(content) = fs::read_to_string(&path.0) {
-                match serde_json::from_str::<Data>(&content) {
-                    Ok(file) => match self.page.current_patge {
-                        Page::Clipboard => {
-                            if file.typ.starts_with("image/") {
-                                if let Some(val) = file.get_image_thumbnail(&path.0) {
-                                    page_data.push((
-                                        Thumbnail::Image(val),
-                                        path.0.clone(),
-                                        file,
-                                        path.1,
-                                    ));
-                                    count += 1;
-                                }
-                            } else {
-                                if let Some(val) = file.get_meta_data() {
-                                    page_data.push((
-                                        Thumbnail::Text(val),
-                                        path.0.clone(),
-                                        file,
-                                        path.1,
-                                    ));
-                                    count += 1;
-                                }
-                            }
+            match storage().get(path.0) {
+                Ok(Some(entry)) => {
+                    let file = entry.data;
+                    let show = match self.page.current_patge {
+                        Page::Clipboard => true,
+                        Page::Notification => file.typ.starts_with("notification/"),
+                        Page::Pined => file.pined,
+                    };
+                    if show {
+                        let thumbnail = if file.typ.starts_with("image/") {
+                            let thumbnail = storage().thumbnail(path.0).ok().flatten();
+                            file.get_image_thumbnail(thumbnail.as_deref())
+                                .map(Thumbnail::Image)
+                        } else {
+                            file.get_meta_data().map(Thumbnail::Text)
+                        };
+                        if let Some(val) = thumbnail {
+                            page_data.push((val, path.0, file, path.1));
+                            count += 1;
                         }
-                        Page::Notification => {
-                            if file.typ.starts_with("notification/") {
-                                if let Some(val) = file.get_meta_data() {
-                                    page_data.push((
-                                        Thumbnail::Text(val),
-                                        path.0.clone(),
-                                        file,
-                                        path.1,
-                                    ));
-                                    count += 1;
-                                }
-                            }
-                        }
-                        Page::Pined => {
-                            if file.pined {
-                                if file.typ.starts_with("image/") {
-                                    if let Some(val) = file.get_image_thumbnail(&path.0) {
-                                        page_data.push((
-                                            Thumbnail::Image(val),
-                                            path.0.clone(),
-                                            file,
-                                            path.1,
-                                        ));
-                                        count += 1;
-                                    }
-                                } else {
-                                    if let Some(val) = file.get_meta_data() {
-                                        page_data.push((
-                                            Thumbnail::Text(val),
-                                            path.0.clone(),
-                                            file,
-                                            path.1,
-                                        ));
-                                        count += 1;
-                                    }
-                                }
-                            }
-                        }
-                    },
-                    Err(err) => {
-                        eprintln!("json: {:?}", content);
-                        eprintln!("{:?}", err)
                     }
                 }
+                Ok(None) => {}
+                Err(err) => {
+                    eprintln!("{:?}", err)
+                }
             }
             if i == 0 {
                 self.page.page_no = 1;
@@ -377,6 +412,107 @@ impl App for Clipboard {
                             }
                         });
                     });
+
+                    ui.horizontal(|ui| {
+                        ui.add_space(10.0);
+                        let response = ui.add(
+                            TextEdit::singleline(&mut self.search.text)
+                                .hint_text("🔍 Search")
+                                .desired_width((ui.available_width() - 310.0).max(100.0)),
+                        );
+                        let mut changed = response.changed();
+
+                        egui::ComboBox::new("search_mode", "")
+                            .selected_text(match self.search.mode {
+                                SearchMode::Substring => "Match case",
+                                SearchMode::CaseInsensitive => "Ignore case",
+                                SearchMode::Regex => "Regex",
+                            })
+                            .show_ui(ui, |ui| {
+                                for (mode, label) in [
+                                    (SearchMode::Substring, "Match case"),
+                                    (SearchMode::CaseInsensitive, "Ignore case"),
+                                    (SearchMode::Regex, "Regex"),
+                                ] {
+                                    changed |= ui
+                                        .selectable_value(&mut self.search.mode, mode, label)
+                                        .changed();
+                                }
+                            });
+
+                        egui::ComboBox::new("search_type", "")
+                            .selected_text(self.search.typ.as_deref().unwrap_or("All types"))
+                            .show_ui(ui, |ui| {
+                                let filters = self.search_result.get_or_insert_with(search_filters);
+                                changed |= ui
+                                    .selectable_value(&mut self.search.typ, None, "All types")
+                                    .changed();
+                                for typ in &filters.types {
+                                    changed |= ui
+                                        .selectable_value(
+                                            &mut self.search.typ,
+                                            Some(typ.clone()),
+                                            typ,
+                                        )
+                                        .changed();
+                                }
+                            });
+
+                        egui::ComboBox::new("search_device", "")
+                            .selected_text(self.search.device.as_deref().unwrap_or("All devices"))
+                            .show_ui(ui, |ui| {
+                                let filters = self.search_result.get_or_insert_with(search_filters);
+                                changed |= ui
+                                    .selectable_value(&mut self.search.device, None, "All devices")
+                                    .changed();
+                                for device in &filters.devices {
+                                    changed |= ui
+                                        .selectable_value(
+                                            &mut self.search.device,
+                                            Some(device.clone()),
+                                            device,
+                                        )
+                                        .changed();
+                                }
+                            });
+
+                        egui::ComboBox::new("search_app", "")
+                            .selected_text(self.search.app.as_deref().unwrap_or("All apps"))
+                            .show_ui(ui, |ui| {
+                                let filters = self.search_result.get_or_insert_with(search_filters);
+                                changed |= ui
+                                    .selectable_value(&mut self.search.app, None, "All apps")
+                                    .changed();
+                                for app in &filters.apps {
+                                    changed |= ui
+                                        .selectable_value(
+                                            &mut self.search.app,
+                                            Some(app.clone()),
+                                            app,
+                                        )
+                                        .changed();
+                                }
+                            });
+
+                        let pin = ui
+                            .selectable_label(self.search.pined.is_some(), "📌")
+                            .on_hover_text("Only pinned");
+                        if pin.clicked() {
+                            self.search.pined = match self.search.pined {
+                                Some(_) => None,
+                                None => Some(true),
+                            };
+                            changed = true;
+                        }
+
+                        if changed {
+                            self.search();
+                        }
+                    });
+
+                    if let Some(err) = &self.search_error {
+                        ui.colored_label(egui::Color32::RED, err);
+                    }
                 });
 
                 if self.show_settings {
@@ -400,6 +536,119 @@ impl App for Clipboard {
                                             );
                                             ui.label(RichText::new(user_data.username).size(15.0));
 
+                                            ui.add_space(5.0);
+                                            ui.label(RichText::new("email:").size(12.3).strong());
+                                            ui.label(RichText::new(user_data.email).size(15.0));
+                                            if let Some(va) = &self.thread
+                                                && va.is_finished()
+                                            {
+                                                self.thread = None
+                                            }
+                                            let mut submit = None;
+                                            if let Some(new_email) = &mut self.new_email {
+                                                ui.add_enabled_ui(self.thread.is_none(), |ui| {
+                                                    ui.add(
+                                                        TextEdit::singleline(new_email)
+                                                            .hint_text("new email")
+                                                            .interactive(!self.email_otp_sent),
+                                                    );
+                                                    if self.email_otp_sent {
+                                                        ui.label("Enter the OTP sent to the new email.");
+                                                        ui.add(
+                                                            TextEdit::singleline(&mut self.otp)
+                                                                .hint_text("enter the OTP"),
+                                                        );
+                                                    }
+                                                    if let Some(val) = &self.warn {
+                                                        ui.colored_label(egui::Color32::RED, val);
+                                                    }
+                                                    ui.horizontal(|ui| {
+                                                        let label = if self.email_otp_sent {
+                                                            "Confirm"
+                                                        } else {
+                                                            "Send OTP"
+                                                        };
+                                                        if ui.button(label).clicked() {
+                                                            submit = Some(new_email.trim().to_string());
+                                                        }
+                                                        if ui.button("Cancel").clicked() {
+                                                            submit = Some(String::new());
+                                                        }
+                                                    });
+                                                });
+                                            } else if ui.small_button("Change email").clicked() {
+                                                self.new_email = Some(String::new());
+                                                self.email_otp_sent = false;
+                                                self.otp.clear();
+                                                self.warn = None;
+                                            }
+                                            match submit {
+                                                Some(email) if email.is_empty() => {
+                                                    self.new_email = None;
+                                                    self.warn = None;
+                                                }
+                                                Some(email) if !is_valid_email(&email) => {
+                                                    self.warn = Some(String::from("Invalid email"));
+                                                }
+                                                Some(email) if self.email_otp_sent => {
+                                                    if is_valid_otp(&self.otp) {
+                                                        self.change_email(ctx, email, Some(self.otp.clone()));
+                                                    } else {
+                                                        self.warn = Some(String::from("Invalid otp"));
+                                                    }
+                                                }
+                                                Some(email) => self.change_email(ctx, email, None),
+                                                None => (),
+                                            }
+
+                                            if let Some(err) = get_sync_error() {
+                                                ui.add_space(5.0);
+                                                ui.colored_label(egui::Color32::RED, err);
+                                            }
+
+                                            ui.add_space(10.0);
+                                            ui.label(RichText::new("devices:").size(12.3).strong());
+                                            let mut load = None;
+                                            match &self.sessions {
+                                                None => {
+                                                    if ui.button("Show devices").clicked() {
+                                                        load = Some(None);
+                                                    }
+                                                }
+                                                Some(Err(e)) => {
+                                                    ui.colored_label(egui::Color32::RED, e);
+                                                    if ui.button("Retry").clicked() {
+                                                        load = Some(None);
+                                                    }
+                                                }
+                                                Some(Ok(sessions)) => {
+                                                    ScrollArea::vertical().max_height(120.0).show(ui, |ui| {
+                                                        for session in sessions {
+                                                            ui.horizontal(|ui| {
+                                                                let name = if session.current {
+                                                                    format!("{} (this device)", session.name)
+                                                                } else {
+                                                                    session.name.clone()
+                                                                };
+                                                                ui.label(name).on_hover_text(format!(
+                                                                    "logged in {}, last used {}",
+                                                                    time_ago(session.created_at),
+                                                                    time_ago(session.last_used)
+                                                                ));
+                                                                if !session.current
+                                                                    && ui.small_button("Log out").clicked()
+                                                                {
+                                                                    load = Some(Some(session.id.clone()));
+                                                                }
+                                                            });
+                                                        }
+                                                    });
+                                                }
+                                            }
+                                            if let Some(revoke) = load {
+                                                self.load_sessions(ctx, revoke);
+                                            }
+
                                             ui.add_space(10.0);
 
                                             let button = ui.add(
@@ -410,8 +659,9 @@ impl App for Clipboard {
                                             );
                                             if button.clicked() {
                                                 self.settings.remove_user();
-                                                log_error!(send_process(clippy::MessageIPC::UpdateSettings(
-                                                    self.settings.clone(),
+                                                self.sessions = None;
+                                                log_error!(send_process(MessageIPC::Account(
+                                                    AccountRequest::Logout
                                                 )));
                                             }
 
@@ -516,13 +766,14 @@ impl App for Clipboard {
                                                     if is_valid_username(&username) {
                                                         self.warn = None;
                                                         let wait = self.waiting.clone();
+                                                        let server = self.settings.server().to_string();
                                                         let ctx = ctx.clone();
                                                         let thread = thread::spawn(move || {
                                                             let async_runtime =
                                                                 Runtime::new().unwrap();
                                                             let status =
                                                                 async_runtime.block_on(async {
-                                                                    check_user(username).await
+                                                                    check_user(server, username).await
                                                                 });
                                                             let mut wait_lock =
                                                                 wait.lock().unwrap();
@@ -619,12 +870,13 @@ impl App for Clipboard {
                                                     &self.newuser.email.as_ref().unwrap(),
                                                 ) {
                                                     let wait = self.waiting.clone();
+                                                    let server = self.settings.server().to_string();
                                                     let ctx = ctx.clone();
                                                     let thread = thread::spawn(move || {
                                                         let async_runtime = Runtime::new().unwrap();
 
                                                         let signin = async_runtime
-                                                            .block_on(async { signin(user).await });
+                                                            .block_on(async { signin(server, user).await });
                                                         let mut wait_lock = wait.lock().unwrap();
                                                         *wait_lock = Waiting::SigninOTP(signin);
                                                         ctx.request_repaint();
@@ -743,6 +995,7 @@ impl App for Clipboard {
                                                             otp,
                                                             key,
                                                         );
+                                                        let server = self.settings.server().to_string();
                                                         let ctx = ctx.clone();
                                                         let thread = thread::spawn(move || {
                                                             let async_runtime =
@@ -750,7 +1003,7 @@ impl App for Clipboard {
 
                                                             let signin =
                                                                 async_runtime.block_on(async {
-                                                                    signin_otp_auth(user).await
+                                                                    signin_otp_auth(server, user).await
                                                                 });
                                                             let mut wait_lock =
                                                                 wait.lock().unwrap();
@@ -784,6 +1037,129 @@ impl App for Clipboard {
                                             ui.add_space(20.0);
                                         });
                                         ui.add_space(10.0);
+                                    } else if self.show_reset_window {
+                                        ui.label(RichText::new("😃").size(150.0).strong());
+
+                                        ui.label(
+                                            RichText::new("Reset your password").size(20.0).strong(),
+                                        );
+
+                                        ui.add_space(8.0);
+
+                                        ui.label(RichText::new(
+                                            "Enter the OTP sent to your email and a new password. \
+                                                All your devices will be logged out.",
+                                        ));
+
+                                        ui.add_space(8.0);
+
+                                        if let Some(va) = &self.thread {
+                                            if va.is_finished() {
+                                                self.thread = None
+                                            } else {
+                                                ui.disable();
+                                            }
+                                        }
+
+                                        ui.style_mut().override_text_style =
+                                            Some(TextStyle::Heading);
+
+                                        let response = ui.add(
+                                            TextEdit::singleline(&mut self.otp)
+                                                .vertical_align(Align::Center)
+                                                .hint_text("enter the OTP")
+                                                .min_size(button_size),
+                                        );
+
+                                        let enter_pressed = response.lost_focus()
+                                            && ui.input(|i| i.key_pressed(egui::Key::Enter));
+
+                                        ui.add_space(8.0);
+
+                                        let response = ui.add(
+                                            TextEdit::singleline(&mut self.key)
+                                                .vertical_align(Align::Center)
+                                                .hint_text("enter the new Password")
+                                                .password(true)
+                                                .min_size(button_size),
+                                        );
+                                        if enter_pressed {
+                                            response.request_focus();
+                                        }
+                                        if let Some(val) = &self.warn {
+                                            ui.colored_label(egui::Color32::RED, val);
+                                        }
+
+                                        ui.style_mut().override_text_style = None;
+
+                                        ui.add_space(10.0);
+
+                                        ui.horizontal(|ui| {
+                                            let total_button_width =
+                                                button_size.x * 2.0 + 20.0 + 2.0 * 35.0;
+                                            let available_width = ui.available_width();
+                                            let horizontal_padding =
+                                                (available_width - total_button_width).max(0.0)
+                                                    / 2.0;
+
+                                            ui.add_space(horizontal_padding);
+                                            ui.add_space(35.0);
+
+                                            if ui
+                                                .add(
+                                                    egui::Button::new(
+                                                        egui::RichText::new("Reset")
+                                                            .size(16.0)
+                                                            .strong(),
+                                                    )
+                                                    .min_size(button_size),
+                                                )
+                                                .clicked()
+                                            {
+                                                let key = self.key.clone();
+                                                let otp = self.otp.clone();
+                                                if !is_valid_otp(&otp) {
+                                                    self.warn = Some(String::from("Invalid otp"));
+                                                } else if !is_valid_password(&key) {
+                                                    self.warn = Some(String::from("Invalid password"));
+                                                } else {
+                                                    let wait = self.waiting.clone();
+                                                    let data = PasswordReset::new(
+                                                        self.newuser.user.clone(),
+                                                        otp,
+                                                        key,
+                                                    );
+                                                    let server = self.settings.server().to_string();
+                                                    let ctx = ctx.clone();
+                                                    let thread = thread::spawn(move || {
+                                                        let async_runtime = Runtime::new().unwrap();
+
+                                                        let status = async_runtime
+                                                            .block_on(reset_password(server, data));
+                                                        set_lock!(wait, Waiting::Login(status));
+                                                        ctx.request_repaint();
+                                                    });
+                                                    self.thread = Some(thread)
+                                                }
+                                            }
+                                            ui.add_space(20.0);
+                                            if ui
+                                                .add(
+                                                    egui::Button::new(
+                                                        egui::RichText::new("Cancel")
+                                                            .size(16.0)
+                                                            .strong(),
+                                                    )
+                                                    .min_size(button_size),
+                                                )
+                                                .clicked()
+                                            {
+                                                self.warn = None;
+                                                self.show_reset_window = false;
+                                            }
+                                            ui.add_space(20.0);
+                                        });
+                                        ui.add_space(10.0);
                                     } else if self.show_login_window {
                                         ui.vertical_centered(|ui| {
                                             ui.label(RichText::new("😃").size(150.0).strong());
@@ -856,6 +1232,7 @@ impl App for Clipboard {
                                                             key,
                                                         );
                                                         let wait = self.waiting.clone();
+                                                        let server = self.settings.server().to_string();
                                                         let ctx = ctx.clone();
                                                         let thread = thread::spawn(move || {
                                                             let async_runtime =
@@ -863,7 +1240,7 @@ impl App for Clipboard {
 
                                                             let status =
                                                                 async_runtime.block_on(async {
-                                                                    login(&user).await
+                                                                    login(server, &user).await
                                                                 });
 
                                                             let mut wait_lock =
@@ -898,6 +1275,23 @@ impl App for Clipboard {
                                                 ui.add_space(35.0);
                                             });
                                             ui.add_space(10.0);
+
+                                            if ui.link("Forgot password?").clicked() {
+                                                let wait = self.waiting.clone();
+                                                let user = self.newuser.user.clone();
+                                                let server = self.settings.server().to_string();
+                                                let ctx = ctx.clone();
+                                                let thread = thread::spawn(move || {
+                                                    let async_runtime = Runtime::new().unwrap();
+
+                                                    let status = async_runtime
+                                                        .block_on(request_password_reset(server, user));
+                                                    set_lock!(wait, Waiting::ResetOTP(status));
+                                                    ctx.request_repaint();
+                                                });
+                                                self.thread = Some(thread)
+                                            }
+                                            ui.add_space(10.0);
                                         });
                                     } else {
                                         ui.label(RichText::new("😃").size(150.0).strong());
@@ -1019,6 +1413,50 @@ impl App for Clipboard {
                                     });
                                 });
                             }
+                            let label = "Removes clipboards older than the given number of days \
+                            on every device. Per type limits and a size limit can be set in the settings file.";
+                            let mut expire = self.settings.retention.max_age.is_some();
+                            ui.horizontal(|ui| {
+                                ui.label("Remove old clipboards").on_hover_text(label);
+                                ui.with_layout(Layout::bottom_up(Align::RIGHT), |ui| {
+                                    if ui.add(toggle(&mut expire)).changed() {
+                                        self.settings.retention.max_age = expire.then_some(30 * 86400);
+                                        log_error!(send_process(clippy::MessageIPC::UpdateSettings(
+                                            self.settings.clone(),
+                                        )));
+                                    }
+                                });
+                            });
+                            if let Some(age) = self.settings.retention.max_age {
+                                let mut days = age / 86400;
+                                ui.horizontal(|ui| {
+                                    ui.label("Days to keep");
+                                    ui.with_layout(Layout::bottom_up(Align::RIGHT), |ui| {
+                                        if ui
+                                            .add(egui::Slider::new(&mut days, 1..=365).text(""))
+                                            .changed()
+                                        {
+                                            self.settings.retention.max_age = Some(days * 86400);
+                                            log_error!(send_process(clippy::MessageIPC::UpdateSettings(
+                                                self.settings.clone(),
+                                            )));
+                                        }
+                                    });
+                                });
+                                ui.horizontal(|ui| {
+                                    ui.label("Keep pinned clipboards");
+                                    ui.with_layout(Layout::bottom_up(Align::RIGHT), |ui| {
+                                        if ui
+                                            .add(toggle(&mut self.settings.retention.keep_pined))
+                                            .changed()
+                                        {
+                                            log_error!(send_process(clippy::MessageIPC::UpdateSettings(
+                                                self.settings.clone(),
+                                            )));
+                                        }
+                                    });
+                                });
+                            }
                             let note = "If enabled, copied images are also stored as thumbnails. \
                             If disabled, image previews won’t be shown in the app.";
                             ui.horizontal(|ui| {
@@ -1075,6 +1513,72 @@ impl App for Clipboard {
                                 });
                             }
 
+                            let note = "Address of the sync server, change it to use a \
+                            self-hosted server. Every server keeps its own login.";
+                            ui.horizontal(|ui| {
+                                ui.label("Server").on_hover_text(note);
+                                ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                                    let check = ui.button("Check").on_hover_text("Test connection");
+                                    let button = ui.button("💾").on_hover_text("Save");
+                                    let response = ui.add(
+                                        TextEdit::singleline(&mut self.server)
+                                            .hint_text("https://")
+                                            .desired_width(160.0),
+                                    );
+                                    if button.clicked()
+                                        || response.lost_focus()
+                                            && ui.input(|i| i.key_pressed(egui::Key::Enter))
+                                    {
+                                        match self.settings.set_server(&self.server) {
+                                            Ok(()) => {
+                                                self.server = self.settings.server().to_string();
+                                                self.server_status = None;
+                                                log_error!(send_process(clippy::MessageIPC::UpdateSettings(
+                                                    self.settings.clone(),
+                                                )));
+                                            }
+                                            Err(e) => self.server_status = Some(Err(e)),
+                                        }
+                                    }
+                                    if check.clicked() {
+                                        let server = self.server.trim().trim_end_matches('/').to_string();
+                                        let wait = self.waiting.clone();
+                                        let ctx = ctx.clone();
+                                        self.server_status = None;
+                                        thread::spawn(move || {
+                                            let async_runtime = Runtime::new().unwrap();
+                                            let status = async_runtime
+                                                .block_on(async { check_server(server).await });
+                                            let mut wait_lock = wait.lock().unwrap();
+                                            *wait_lock = Waiting::Health(status);
+                                            ctx.request_repaint();
+                                        });
+                                    }
+                                });
+                            });
+                            match &self.server_status {
+                                Some(Ok(())) => {
+                                    ui.colored_label(egui::Color32::GREEN, "Server is reachable");
+                                }
+                                Some(Err(e)) => {
+                                    ui.colored_label(egui::Color32::RED, e);
+                                }
+                                None => (),
+                            }
+
+                            let note = "Syncs the clipboard with devices on the same network \
+                            instead of the server. Pair a device with `clippy pair` first.";
+                            ui.horizontal(|ui| {
+                                ui.label("Sync on local network").on_hover_text(note);
+                                ui.with_layout(Layout::bottom_up(Align::RIGHT), |ui| {
+                                    if ui.add(toggle(&mut self.settings.lan_sync)).changed() {
+                                        log_error!(send_process(clippy::MessageIPC::UpdateSettings(
+                                            self.settings.clone(),
+                                        )));
+                                    }
+                                });
+                            });
+
                             if self.settings.is_login() {
                                 let note = "Prevents your clipboard from \
                                 syncing to your cloud account.";
@@ -1090,6 +1594,32 @@ impl App for Clipboard {
                                     });
                                 });
 
+                                let note = "Clipboard entries are encrypted with this \
+                                passphrase before they are synced. Use the same passphrase \
+                                on all your devices.";
+                                ui.horizontal(|ui| {
+                                    ui.label("Encryption passphrase").on_hover_text(note);
+                                    ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                                        let button = ui.button("💾").on_hover_text("Save");
+                                        let response = ui.add(
+                                            TextEdit::singleline(&mut self.passphrase)
+                                                .password(true)
+                                                .hint_text("passphrase")
+                                                .desired_width(120.0),
+                                        );
+                                        if button.clicked()
+                                            || response.lost_focus()
+                                                && ui.input(|i| i.key_pressed(egui::Key::Enter))
+                                        {
+                                            self.settings
+                                                .set_encrept(Some(self.passphrase.clone()));
+                                                log_error!(send_process(clippy::MessageIPC::UpdateSettings(
+                                                    self.settings.clone(),
+                                                )));
+                                        }
+                                    });
+                                });
+
                                 ui.horizontal(|ui| {
                                     ui.label("Placeholder");
                                     ui.with_layout(Layout::bottom_up(Align::RIGHT), |ui| {
@@ -1132,6 +1662,7 @@ impl App for Clipboard {
                             Waiting::Login(Ok(usercred)) => {
                                 self.settings.set_user(usercred.clone());
                                 self.show_login_window = false;
+                                self.show_reset_window = false;
                                 log_error!(send_process(clippy::MessageIPC::UpdateSettings(
                                     self.settings.clone(),
                                 )));
@@ -1167,14 +1698,54 @@ impl App for Clipboard {
                                 self.show_error = (true, e.to_string());
                                 *val = Waiting::None;
                             }
+                            Waiting::Health(status) => {
+                                self.server_status = Some(status.clone());
+                                *val = Waiting::None;
+                            }
+                            Waiting::Sessions(sessions) => {
+                                self.sessions = Some(sessions.clone());
+                                *val = Waiting::None;
+                            }
+                            Waiting::ResetOTP(Ok(_)) => {
+                                self.show_login_window = false;
+                                self.show_reset_window = true;
+                                self.warn = None;
+                                self.otp.clear();
+                                self.key.clear();
+                                *val = Waiting::None;
+                            }
+                            Waiting::ResetOTP(Err(e)) => {
+                                self.show_error = (true, e.to_string());
+                                *val = Waiting::None;
+                            }
+                            Waiting::EmailOTP(Ok(_)) => {
+                                self.email_otp_sent = true;
+                                self.otp.clear();
+                                *val = Waiting::None;
+                            }
+                            Waiting::EmailChanged(Ok(email)) => {
+                                if let Some(mut user) = self.settings.get_sync().clone() {
+                                    user.email = email.clone();
+                                    self.settings.set_user(user);
+                                }
+                                self.new_email = None;
+                                self.email_otp_sent = false;
+                                *val = Waiting::None;
+                            }
+                            Waiting::EmailOTP(Err(e)) | Waiting::EmailChanged(Err(e)) => {
+                                self.warn = Some(e.to_string());
+                                *val = Waiting::None;
+                            }
                         }
                     }
 
                     if !open {
                         self.show_settings = false;
                         self.show_login_window = false;
+                        self.show_reset_window = false;
                         self.show_createuser_window = false;
                         self.show_error = (false, "".to_string());
+                        self.new_email = None;
                         self.show_signin_window = false;
                     }
                 }
@@ -1199,7 +1770,7 @@ impl App for Clipboard {
                         }
                         let data = &mut self.page.page_data;
                         if let Some(data) = data {
-                            for (thumbnail, path, i, sync) in data.iter_mut() {
+                            for (thumbnail, id, i, sync) in data.iter_mut() {
                                 if let Thumbnail::Text(thumbnail) = thumbnail {
                                     ui.add_enabled_ui(true, |ui| {
                                         item_card(
@@ -1210,7 +1781,7 @@ impl App for Clipboard {
                                             &self.settings,
                                             &mut self.show_data_popup,
                                             self.changed.clone(),
-                                            path,
+                                            *id,
                                             ctx,
                                             sync,
                                         )
@@ -1236,7 +1807,7 @@ impl App for Clipboard {
                                             &mut i.get_pined(),
                                             &self.settings,
                                             self.changed.clone(),
-                                            path,
+                                            *id,
                                             ctx,
                                             sync,
                                         )
@@ -1365,8 +1936,25 @@ impl App for Clipboard {
     }
 }
 
+// the daemon keeps the search index of the history
+fn daemon_search(query: &SearchQuery) -> Result<SearchResult, String> {
+    match send_process(MessageIPC::Search(query.clone())) {
+        Ok(Reply::Search(val)) => Ok(val),
+        Ok(_) => Err(String::from("Unexpected reply from clippy")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// the values the search filters can take
+fn search_filters() -> SearchResult {
+    daemon_search(&SearchQuery::default()).unwrap_or_else(|e| {
+        error!("Unable to read the search filters: {}", e);
+        SearchResult::default()
+    })
+}
+
 fn setup() -> Result<(), Error> {
-    Builder::from_env(Env::default().filter_or("LOG", "info")).init();
+    Builder::from_env(Env::default().filter_or("LOG", clippy::DEFAULT_LOG)).init();
     if let Err(e) = init_stream() {
         error!(
             "clippy-gui cannot run without the Clippy backend. Please start `clippy`, not `clippy-gui`."
@@ -1405,8 +1993,13 @@ fn main() -> Result<(), eframe::Error> {
         ..Default::default()
     };
 
+    let title = match clippy::paths::profile() {
+        Some(profile) => format!("clippy ({})", profile),
+        None => "clippy".to_string(),
+    };
+
     run_native(
-        "clippy",
+        &title,
         options,
         Box::new(|cc| {
             match ui.settings.theme {
@@ -0,0 +1,4 @@
+// new migrations are embedded in the binary, see `MIGRATOR`
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
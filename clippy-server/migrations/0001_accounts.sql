@@ -0,0 +1,16 @@
+-- accounts and the one-time passwords mailed to them, servers set up before
+-- migrations already have these tables
+CREATE TABLE IF NOT EXISTS usercred (
+    username TEXT PRIMARY KEY,
+    email TEXT NOT NULL UNIQUE,
+    -- Argon2id in PHC format, or Sha256(password || username) until the next login
+    key TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS otp_state (
+    email TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    otp TEXT NOT NULL,
+    attempt INTEGER NOT NULL DEFAULT 0,
+    time_created TIMESTAMP NOT NULL
+);
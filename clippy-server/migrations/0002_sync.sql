@@ -0,0 +1,29 @@
+-- the change log of every user
+CREATE TABLE IF NOT EXISTS sync_state (
+    username TEXT PRIMARY KEY,
+    epoch TEXT NOT NULL,
+    seq BIGINT NOT NULL
+);
+
+-- removed rows are the tombstones
+CREATE TABLE IF NOT EXISTS sync_entries (
+    username TEXT NOT NULL,
+    id TEXT NOT NULL,
+    seq BIGINT NOT NULL,
+    removed BOOLEAN NOT NULL,
+    PRIMARY KEY (username, id)
+);
+
+CREATE INDEX IF NOT EXISTS sync_entries_seq ON sync_entries (username, seq);
+
+-- bytes of the entry, entries stored before sizes were recorded count as empty
+ALTER TABLE sync_entries ADD COLUMN IF NOT EXISTS size BIGINT NOT NULL DEFAULT 0;
+
+-- overrides of the limits of the config for some users, a null keeps the default
+CREATE TABLE IF NOT EXISTS user_limits (
+    username TEXT PRIMARY KEY,
+    max_entries BIGINT,
+    max_bytes BIGINT,
+    max_item_size BIGINT,
+    max_tombstones BIGINT
+);
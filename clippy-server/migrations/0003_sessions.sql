@@ -0,0 +1,15 @@
+-- `token_hash` is the hash of the refresh token the client holds, `previous_hash`
+-- of the one it replaced
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    previous_hash TEXT,
+    created_at BIGINT NOT NULL,
+    last_used BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS sessions_username ON sessions (username);
+CREATE INDEX IF NOT EXISTS sessions_previous ON sessions (previous_hash);
@@ -0,0 +1,11 @@
+-- `session` is the login the device last connected with
+CREATE TABLE IF NOT EXISTS devices (
+    username TEXT NOT NULL,
+    id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    session TEXT,
+    first_seen BIGINT NOT NULL,
+    last_seen BIGINT NOT NULL,
+    PRIMARY KEY (username, id)
+);
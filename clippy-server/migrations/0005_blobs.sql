@@ -0,0 +1,7 @@
+-- the entries of the users when `BLOB_STORE` is `postgres`
+CREATE TABLE IF NOT EXISTS blobs (
+    username TEXT NOT NULL,
+    name TEXT NOT NULL,
+    data BYTEA NOT NULL,
+    PRIMARY KEY (username, name)
+);
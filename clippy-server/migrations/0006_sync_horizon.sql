@@ -0,0 +1,15 @@
+-- the newest removal whose tombstone was trimmed, clients with an older cursor resync
+ALTER TABLE sync_state ADD COLUMN IF NOT EXISTS removed_horizon BIGINT NOT NULL DEFAULT 0;
+-- the time in the id of the newest entry dropped to make room, clients keep those
+ALTER TABLE sync_state ADD COLUMN IF NOT EXISTS dropped_before BIGINT NOT NULL DEFAULT 0;
+
+-- older servers did not record either, assume everything before what is left was trimmed
+UPDATE sync_state SET
+    removed_horizon = COALESCE((
+        SELECT min(seq) - 1 FROM sync_entries
+        WHERE sync_entries.username = sync_state.username AND removed
+    ), 0),
+    dropped_before = COALESCE((
+        SELECT min(split_part(id, '-', 1)::BIGINT) FROM sync_entries
+        WHERE sync_entries.username = sync_state.username AND NOT removed AND id ~ '^[0-9]+-'
+    ), extract(epoch FROM now())::BIGINT);
@@ -0,0 +1,3 @@
+-- the refresh token that replaced `previous_hash`, masked with the token it replaced so
+-- only a client holding that one can read it again
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS successor TEXT;
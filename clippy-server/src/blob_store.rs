@@ -0,0 +1,308 @@
+use crate::DATABASE_PATH;
+use async_trait::async_trait;
+use sqlx::{Pool, Postgres, Row, query};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+/// Where the clipboard entries of the users are stored, shared by every server replica
+/// unless the filesystem backend is used. Names starting with a dot are records of the
+/// server (like the key check), not entries.
+///
+/// Picked with `BLOB_STORE`:
+/// - `fs` (default): files under `BLOB_ROOT`, `data-base/users` if unset
+/// - `postgres`: a `bytea` table in the database of the server
+/// - `s3`: an S3 compatible bucket configured by the usual `AWS_*` variables
+///   (`AWS_BUCKET_NAME`, `AWS_REGION`, `AWS_ENDPOINT`, ...), needs the `s3` feature
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn get(&self, user: &str, name: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Stores the blob only if `name` is free, returns false if it is taken.
+    async fn put_new(&self, user: &str, name: &str, data: &[u8]) -> io::Result<bool>;
+
+    /// Removing a missing blob is not an error.
+    async fn delete(&self, user: &str, name: &str) -> io::Result<()>;
+
+    /// Names of the entries of a user, without the server records.
+    async fn list(&self, user: &str) -> io::Result<Vec<String>>;
+}
+
+pub async fn blob_store(pool: &Pool<Postgres>) -> io::Result<Arc<dyn BlobStore>> {
+    match env::var("BLOB_STORE").as_deref() {
+        Err(_) | Ok("fs") => {
+            let root = env::var("BLOB_ROOT").unwrap_or_else(|_| DATABASE_PATH.to_string());
+            Ok(Arc::new(FsStore::new(root)))
+        }
+        Ok("postgres") => Ok(Arc::new(PgStore::new(pool.clone()))),
+        #[cfg(feature = "s3")]
+        Ok("s3") => Ok(Arc::new(S3Store::from_env()?)),
+        #[cfg(not(feature = "s3"))]
+        Ok("s3") => Err(io::Error::other(
+            "clippy-server was built without the s3 feature",
+        )),
+        Ok(val) => Err(io::Error::other(format!("unknown BLOB_STORE {}", val))),
+    }
+}
+
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, user: &str, name: &str) -> PathBuf {
+        let mut path = self.root.join(user);
+        path.push(name);
+        path
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsStore {
+    async fn get(&self, user: &str, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(user, name)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn put_new(&self, user: &str, name: &str, data: &[u8]) -> io::Result<bool> {
+        fs::create_dir_all(self.root.join(user))?;
+        let path = self.path(user, name);
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if let Err(e) = file.write_all(data) {
+            drop(file);
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    async fn delete(&self, user: &str, name: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(user, name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, user: &str) -> io::Result<Vec<String>> {
+        let dir = match fs::read_dir(self.root.join(user)) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut names: Vec<String> = dir
+            .flatten()
+            .filter_map(|val| val.file_name().into_string().ok())
+            .filter(|name| !name.starts_with('.'))
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+}
+
+pub struct PgStore {
+    pool: Pool<Postgres>,
+}
+
+impl PgStore {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl BlobStore for PgStore {
+    async fn get(&self, user: &str, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let row = query("SELECT data FROM blobs WHERE username = $1 AND name = $2")
+            .bind(user)
+            .bind(name)
+            .persistent(false)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+        Ok(row.map(|row| row.get("data")))
+    }
+
+    async fn put_new(&self, user: &str, name: &str, data: &[u8]) -> io::Result<bool> {
+        let result = query(
+            "INSERT INTO blobs (username, name, data) VALUES ($1, $2, $3)
+             ON CONFLICT (username, name) DO NOTHING",
+        )
+        .bind(user)
+        .bind(name)
+        .bind(data)
+        .persistent(false)
+        .execute(&self.pool)
+        .await
+        .map_err(io::Error::other)?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn delete(&self, user: &str, name: &str) -> io::Result<()> {
+        query("DELETE FROM blobs WHERE username = $1 AND name = $2")
+            .bind(user)
+            .bind(name)
+            .persistent(false)
+            .execute(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    async fn list(&self, user: &str) -> io::Result<Vec<String>> {
+        let rows = query(
+            "SELECT name FROM blobs WHERE username = $1 AND name NOT LIKE '.%' ORDER BY name",
+        )
+        .bind(user)
+        .persistent(false)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(io::Error::other)?;
+        Ok(rows.iter().map(|row| row.get("name")).collect())
+    }
+}
+
+#[cfg(feature = "s3")]
+pub use s3::S3Store;
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use super::BlobStore;
+    use async_trait::async_trait;
+    use futures_util::StreamExt;
+    use object_store::{
+        ObjectStore, PutMode, PutPayload,
+        aws::{AmazonS3, AmazonS3Builder},
+        path::Path,
+    };
+    use std::io;
+
+    pub struct S3Store {
+        store: AmazonS3,
+    }
+
+    impl S3Store {
+        pub fn from_env() -> io::Result<Self> {
+            let store = AmazonS3Builder::from_env()
+                .build()
+                .map_err(io::Error::other)?;
+            Ok(Self { store })
+        }
+    }
+
+    // usernames are validated, they never contain a `/`
+    fn path(user: &str, name: &str) -> Path {
+        Path::from(format!("{}/{}", user, name))
+    }
+
+    #[async_trait]
+    impl BlobStore for S3Store {
+        async fn get(&self, user: &str, name: &str) -> io::Result<Option<Vec<u8>>> {
+            match self.store.get(&path(user, name)).await {
+                Ok(val) => Ok(Some(val.bytes().await.map_err(io::Error::other)?.to_vec())),
+                Err(object_store::Error::NotFound { .. }) => Ok(None),
+                Err(e) => Err(io::Error::other(e)),
+            }
+        }
+
+        async fn put_new(&self, user: &str, name: &str, data: &[u8]) -> io::Result<bool> {
+            let payload = PutPayload::from(data.to_vec());
+            match self
+                .store
+                .put_opts(&path(user, name), payload, PutMode::Create.into())
+                .await
+            {
+                Ok(_) => Ok(true),
+                Err(object_store::Error::AlreadyExists { .. }) => Ok(false),
+                Err(e) => Err(io::Error::other(e)),
+            }
+        }
+
+        async fn delete(&self, user: &str, name: &str) -> io::Result<()> {
+            match self.store.delete(&path(user, name)).await {
+                Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+                Err(e) => Err(io::Error::other(e)),
+            }
+        }
+
+        async fn list(&self, user: &str) -> io::Result<Vec<String>> {
+            let prefix = Path::from(user);
+            let mut list = self.store.list(Some(&prefix));
+            let mut names = Vec::new();
+            while let Some(meta) = list.next().await {
+                let meta = meta.map_err(io::Error::other)?;
+                if let Some(name) = meta.location.filename()
+                    && !name.starts_with('.')
+                {
+                    names.push(name.to_string());
+                }
+            }
+            names.sort();
+            Ok(names)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    async fn round_trip(store: &dyn BlobStore) {
+        let user = format!("test-{}", Uuid::new_v4());
+        assert!(store.list(&user).await.unwrap().is_empty());
+        assert!(store.get(&user, "1-10").await.unwrap().is_none());
+
+        assert!(store.put_new(&user, "1-10", b"first").await.unwrap());
+        assert!(store.put_new(&user, "2-10", b"second").await.unwrap());
+        assert!(store.put_new(&user, ".key", b"record").await.unwrap());
+        // a taken name is never overwritten
+        assert!(!store.put_new(&user, "1-10", b"other").await.unwrap());
+
+        assert_eq!(store.get(&user, "1-10").await.unwrap().unwrap(), b"first");
+        assert_eq!(store.get(&user, ".key").await.unwrap().unwrap(), b"record");
+        assert_eq!(store.list(&user).await.unwrap(), vec!["1-10", "2-10"]);
+
+        store.delete(&user, "1-10").await.unwrap();
+        store.delete(&user, "1-10").await.unwrap();
+        assert!(store.get(&user, "1-10").await.unwrap().is_none());
+        assert_eq!(store.list(&user).await.unwrap(), vec!["2-10"]);
+
+        store.delete(&user, "2-10").await.unwrap();
+        store.delete(&user, ".key").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fs_round_trip() {
+        let root = env::temp_dir().join(format!("clippy-blobs-{}", Uuid::new_v4()));
+        round_trip(&FsStore::new(&root)).await;
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    // needs a database with the migrations of the server, e.g.
+    // TEST_DB_CONF=postgres://postgres@localhost:5432/postgres
+    #[tokio::test]
+    async fn postgres_round_trip() {
+        let Ok(url) = env::var("TEST_DB_CONF") else {
+            eprintln!("TEST_DB_CONF not set, skipping");
+            return;
+        };
+        let pool = Pool::<Postgres>::connect(&url).await.unwrap();
+        crate::MIGRATOR.run(&pool).await.unwrap();
+        round_trip(&PgStore::new(pool)).await;
+    }
+}
@@ -0,0 +1,166 @@
+use clippy::LimitError;
+use serde::Deserialize;
+use std::{env, fs, io, str::FromStr};
+
+const CONFIG_FILE: &str = "clippy-server.toml";
+
+/// Settings of the server, read from the file in `CONFIG_FILE` (`clippy-server.toml` if
+/// unset, a missing file uses the defaults). Every value can be overridden by the
+/// environment variable of the same name in upper case.
+///
+/// ```toml
+/// [limits]
+/// max_entries = 30
+/// max_bytes = 104857600
+/// max_item_size = 10485760
+/// max_tombstones = 1000
+/// legacy_tombstones = 100
+///
+/// [rate_limit]
+/// ip_burst = 30
+/// ip_per_minute = 30
+/// account_burst = 10
+/// account_per_minute = 5
+/// lockout_after = 5
+/// lockout_secs = 30
+/// max_lockout_secs = 3600
+/// behind_proxy = false
+/// ```
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub limits: Limits,
+    pub rate_limit: RateLimits,
+}
+
+/// Limits applied to every user, the `user_limits` table overrides them per user.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Limits {
+    // entries kept per user, the oldest are dropped from the server, clients keep them
+    pub max_entries: i64,
+    // bytes of every entry of a user together, the oldest are dropped to make room
+    pub max_bytes: Option<i64>,
+    // bytes of one entry as the client sends it
+    pub max_item_size: i64,
+    // removals kept so clients that were offline catch up
+    pub max_tombstones: i64,
+    // removals sent to clients that do not use the change log
+    pub legacy_tombstones: i64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_entries: 30,
+            max_bytes: None,
+            max_item_size: 10 * 1024 * 1024,
+            max_tombstones: 1000,
+            legacy_tombstones: 100,
+        }
+    }
+}
+
+/// Throttling of the auth endpoints, see `RateLimiter`.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RateLimits {
+    // requests an address can make at once, and how fast they come back
+    pub ip_burst: u32,
+    pub ip_per_minute: u32,
+    // the same for the requests of an address naming one account
+    pub account_burst: u32,
+    pub account_per_minute: u32,
+    // failed attempts before a lockout, it doubles with every further failure
+    pub lockout_after: u32,
+    pub lockout_secs: u64,
+    pub max_lockout_secs: u64,
+    // takes the address of the client from `Forwarded` or `X-Forwarded-For`
+    pub behind_proxy: bool,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            ip_burst: 30,
+            ip_per_minute: 30,
+            account_burst: 10,
+            account_per_minute: 5,
+            lockout_after: 5,
+            lockout_secs: 30,
+            max_lockout_secs: 3600,
+            behind_proxy: false,
+        }
+    }
+}
+
+impl Limits {
+    /// Whether an entry of `size` bytes can be stored, older entries make room for it.
+    pub fn check(&self, size: usize) -> Result<(), LimitError> {
+        let size = size as u64;
+        if size > self.max_item_size as u64 {
+            return Err(LimitError::TooLarge {
+                size,
+                max: self.max_item_size as u64,
+            });
+        }
+        if let Some(max) = self.max_bytes
+            && size > max as u64
+        {
+            return Err(LimitError::QuotaExceeded {
+                size,
+                max: max as u64,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn load() -> io::Result<Self> {
+        let path = env::var("CONFIG_FILE").unwrap_or_else(|_| CONFIG_FILE.to_string());
+        let mut config: Config = match fs::read_to_string(&path) {
+            Ok(val) => toml::from_str(&val)
+                .map_err(|e| io::Error::other(format!("invalid config {}: {}", path, e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(e),
+        };
+
+        let limits = &mut config.limits;
+        env_override("MAX_ENTRIES", &mut limits.max_entries)?;
+        if let Some(val) = env_value("MAX_BYTES")? {
+            limits.max_bytes = Some(val);
+        }
+        env_override("MAX_ITEM_SIZE", &mut limits.max_item_size)?;
+        env_override("MAX_TOMBSTONES", &mut limits.max_tombstones)?;
+        env_override("LEGACY_TOMBSTONES", &mut limits.legacy_tombstones)?;
+
+        let rate_limit = &mut config.rate_limit;
+        env_override("IP_BURST", &mut rate_limit.ip_burst)?;
+        env_override("IP_PER_MINUTE", &mut rate_limit.ip_per_minute)?;
+        env_override("ACCOUNT_BURST", &mut rate_limit.account_burst)?;
+        env_override("ACCOUNT_PER_MINUTE", &mut rate_limit.account_per_minute)?;
+        env_override("LOCKOUT_AFTER", &mut rate_limit.lockout_after)?;
+        env_override("LOCKOUT_SECS", &mut rate_limit.lockout_secs)?;
+        env_override("MAX_LOCKOUT_SECS", &mut rate_limit.max_lockout_secs)?;
+        env_override("BEHIND_PROXY", &mut rate_limit.behind_proxy)?;
+        Ok(config)
+    }
+}
+
+fn env_value<T: FromStr>(name: &str) -> io::Result<Option<T>> {
+    match env::var(name) {
+        Ok(val) => val
+            .parse()
+            .map(Some)
+            .map_err(|_| io::Error::other(format!("invalid {}: {}", name, val))),
+        Err(_) => Ok(None),
+    }
+}
+
+fn env_override<T: FromStr>(name: &str, field: &mut T) -> io::Result<()> {
+    if let Some(val) = env_value(name)? {
+        *field = val;
+    }
+    Ok(())
+}
@@ -128,3 +128,35 @@ pub async fn write(user: &UserCred, pool: &Pool<Postgres>) -> Result<(), Error>
 
     Ok(())
 }
+
+pub async fn remove_otp(pool: &Pool<Postgres>, email: &str) -> Result<(), Error> {
+    query("DELETE FROM otp_state WHERE email = $1")
+        .bind(email)
+        .persistent(false)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn update_key(pool: &Pool<Postgres>, username: &str, key: &str) -> Result<(), Error> {
+    query("UPDATE usercred SET key = $2 WHERE username = $1")
+        .bind(username)
+        .bind(key)
+        .persistent(false)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn update_email(pool: &Pool<Postgres>, username: &str, email: &str) -> Result<(), Error> {
+    query("UPDATE usercred SET email = $2 WHERE username = $1")
+        .bind(username)
+        .bind(email)
+        .persistent(false)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
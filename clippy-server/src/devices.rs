@@ -0,0 +1,137 @@
+use chrono::Utc;
+use clippy::{Device, DeviceHello};
+use sqlx::{Pool, Postgres, Row, query};
+
+const MAX_ID: usize = 64;
+const MAX_NAME: usize = 64;
+const MAX_VERSION: usize = 32;
+
+/// The devices that connected to the accounts, stored in Postgres.
+#[derive(Clone)]
+pub struct Devices {
+    pool: Pool<Postgres>,
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn clean_name(name: &str) -> Option<String> {
+    let name: String = name
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME)
+        .collect();
+    (!name.is_empty()).then_some(name)
+}
+
+impl Devices {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        Self { pool }
+    }
+
+    /// Records a connection of the device, returns its id or `None` if the hello is
+    /// not valid. The name is kept once the device is known, the user may have renamed it.
+    pub async fn register(
+        &self,
+        username: &str,
+        hello: &DeviceHello,
+        session: Option<&str>,
+    ) -> Result<Option<String>, sqlx::Error> {
+        if !valid_id(&hello.device) {
+            return Ok(None);
+        }
+        let name = clean_name(&hello.name).unwrap_or_else(|| String::from("unknown device"));
+        let version: String = hello.version.chars().take(MAX_VERSION).collect();
+        query(
+            "INSERT INTO devices (username, id, name, version, session, first_seen, last_seen)
+             VALUES ($1, $2, $3, $4, $5, $6, $6)
+             ON CONFLICT (username, id) DO UPDATE
+             SET version = EXCLUDED.version, session = EXCLUDED.session, last_seen = EXCLUDED.last_seen",
+        )
+        .bind(username)
+        .bind(&hello.device)
+        .bind(name)
+        .bind(version)
+        .bind(session)
+        .bind(Utc::now().timestamp())
+        .execute(&self.pool)
+        .await?;
+        Ok(Some(hello.device.clone()))
+    }
+
+    /// Moves the last seen time of the device to now.
+    pub async fn seen(&self, username: &str, id: &str) -> Result<(), sqlx::Error> {
+        query("UPDATE devices SET last_seen = $3 WHERE username = $1 AND id = $2")
+            .bind(username)
+            .bind(id)
+            .bind(Utc::now().timestamp())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Devices of `username`, the one seen most recently first. `online` are the ids of
+    /// the connected ones.
+    pub async fn list(
+        &self,
+        username: &str,
+        online: &[String],
+    ) -> Result<Vec<Device>, sqlx::Error> {
+        let rows = query(
+            "SELECT id, name, version, first_seen, last_seen FROM devices
+             WHERE username = $1 ORDER BY last_seen DESC",
+        )
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let id: String = row.get("id");
+                Device {
+                    online: online.contains(&id),
+                    id,
+                    name: row.get("name"),
+                    version: row.get("version"),
+                    first_seen: row.get("first_seen"),
+                    last_seen: row.get("last_seen"),
+                }
+            })
+            .collect())
+    }
+
+    /// Renames the device `id`, returns false if there is none or the name is empty.
+    pub async fn rename(&self, username: &str, id: &str, name: &str) -> Result<bool, sqlx::Error> {
+        let Some(name) = clean_name(name) else {
+            return Ok(false);
+        };
+        let result = query("UPDATE devices SET name = $3 WHERE username = $1 AND id = $2")
+            .bind(username)
+            .bind(id)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Forgets the device `id`, returns the session it last used or `None` if there was
+    /// no such device.
+    pub async fn remove(
+        &self,
+        username: &str,
+        id: &str,
+    ) -> Result<Option<Option<String>>, sqlx::Error> {
+        let row = query("DELETE FROM devices WHERE username = $1 AND id = $2 RETURNING session")
+            .bind(username)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| row.get("session")))
+    }
+}
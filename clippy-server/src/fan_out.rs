@@ -0,0 +1,349 @@
+use crate::{Logout, RoomManager, SyncEvent};
+use actix_web::rt;
+use async_trait::async_trait;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, PgConnection, Pool, Postgres, postgres::PgListener, query};
+use std::{env, io, sync::Arc, time::Duration};
+
+const CHANNEL: &str = "clippy_sync";
+
+/// Carries the changes and logouts of a user to the connections of that user on every
+/// server instance.
+///
+/// Picked with `FAN_OUT`:
+/// - `local` (default): only the connections of this instance, for a single server
+/// - `postgres`: `LISTEN/NOTIFY` on the database of the server, for several replicas
+#[async_trait]
+pub trait FanOut: Send + Sync {
+    async fn publish(&self, user: &str, event: SyncEvent) -> io::Result<()>;
+
+    /// Sends `event` with the transaction that records it, so it goes out only once the
+    /// change is committed. Returns false if `publish` has to send it after the commit.
+    async fn publish_in(
+        &self,
+        _tx: &mut PgConnection,
+        _user: &str,
+        _event: &SyncEvent,
+    ) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Closes the connections of `user` that `logout` picks.
+    async fn logout(&self, user: &str, logout: Logout) -> io::Result<()>;
+}
+
+pub async fn fan_out(
+    pool: &Pool<Postgres>,
+    rooms: Arc<RoomManager>,
+) -> io::Result<Arc<dyn FanOut>> {
+    match env::var("FAN_OUT").as_deref() {
+        Err(_) | Ok("local") => Ok(Arc::new(LocalFanOut::new(rooms))),
+        Ok("postgres") => Ok(Arc::new(
+            PgFanOut::start(pool.clone(), rooms)
+                .await
+                .map_err(io::Error::other)?,
+        )),
+        Ok(val) => Err(io::Error::other(format!("unknown FAN_OUT {}", val))),
+    }
+}
+
+pub struct LocalFanOut {
+    rooms: Arc<RoomManager>,
+}
+
+impl LocalFanOut {
+    pub fn new(rooms: Arc<RoomManager>) -> Self {
+        Self { rooms }
+    }
+}
+
+#[async_trait]
+impl FanOut for LocalFanOut {
+    async fn publish(&self, user: &str, event: SyncEvent) -> io::Result<()> {
+        self.rooms.deliver(user, event).await;
+        Ok(())
+    }
+
+    async fn logout(&self, user: &str, logout: Logout) -> io::Result<()> {
+        self.rooms.disconnect(user, &logout).await;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Notification {
+    Change { user: String, event: SyncEvent },
+    Logout { user: String, logout: Logout },
+}
+
+/// Every instance listens on one channel and hands the events to its own connections,
+/// including the events it sent itself.
+pub struct PgFanOut {
+    pool: Pool<Postgres>,
+    rooms: Arc<RoomManager>,
+}
+
+impl PgFanOut {
+    pub async fn start(pool: Pool<Postgres>, rooms: Arc<RoomManager>) -> Result<Self, sqlx::Error> {
+        let mut listener = listen(&pool).await?;
+        let task_pool = pool.clone();
+        let task_rooms = rooms.clone();
+        rt::spawn(async move {
+            let rooms = task_rooms;
+            loop {
+                // events sent while the listener is down are lost, the connections are
+                // told to send their clients what they missed once it is back
+                match listener.try_recv().await {
+                    Ok(Some(val)) => match serde_json::from_str::<Notification>(val.payload()) {
+                        Ok(Notification::Change { user, event }) => {
+                            rooms.deliver(&user, event).await
+                        }
+                        Ok(Notification::Logout { user, logout }) => {
+                            rooms.disconnect(&user, &logout).await;
+                        }
+                        Err(e) => error!("malformed sync notification: {}", e),
+                    },
+                    // reported once the listener is connected and listening again
+                    Ok(None) => {
+                        error!("sync listener lost the connection to the database");
+                        rooms.resync().await;
+                    }
+                    Err(e) => {
+                        error!("sync listener failed: {}", e);
+                        rt::time::sleep(Duration::from_secs(1)).await;
+                        match listen(&task_pool).await {
+                            Ok(val) => {
+                                listener = val;
+                                rooms.resync().await;
+                            }
+                            Err(e) => error!("unable to restart the sync listener: {}", e),
+                        }
+                    }
+                }
+            }
+        });
+        debug!("listening for sync events on {}", CHANNEL);
+        Ok(Self { pool, rooms })
+    }
+}
+
+// a notification sent inside a transaction is delivered when it commits
+async fn notify<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    notification: &Notification,
+) -> io::Result<()> {
+    query("SELECT pg_notify($1, $2)")
+        .bind(CHANNEL)
+        .bind(serde_json::to_string(notification)?)
+        .persistent(false)
+        .execute(executor)
+        .await
+        .map_err(io::Error::other)?;
+    Ok(())
+}
+
+async fn listen(pool: &Pool<Postgres>) -> Result<PgListener, sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+    Ok(listener)
+}
+
+#[async_trait]
+impl FanOut for PgFanOut {
+    async fn publish(&self, user: &str, event: SyncEvent) -> io::Result<()> {
+        let notification = Notification::Change {
+            user: user.to_string(),
+            event,
+        };
+        notify(&self.pool, &notification).await
+    }
+
+    async fn publish_in(
+        &self,
+        tx: &mut PgConnection,
+        user: &str,
+        event: &SyncEvent,
+    ) -> io::Result<bool> {
+        let notification = Notification::Change {
+            user: user.to_string(),
+            event: event.clone(),
+        };
+        notify(tx, &notification).await?;
+        Ok(true)
+    }
+
+    // the connections of this instance are closed even if the others can not be told
+    async fn logout(&self, user: &str, logout: Logout) -> io::Result<()> {
+        let notification = Notification::Logout {
+            user: user.to_string(),
+            logout: logout.clone(),
+        };
+        let result = notify(&self.pool, &notification).await;
+        if result.is_err() {
+            self.rooms.disconnect(user, &logout).await;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Client, ClientInfo, MessageMPC, Room, RoomEvent};
+    use actix_web::{http::header, test::TestRequest, web};
+    use clippy::SyncCursor;
+    use std::collections::HashMap;
+    use tokio::task::LocalSet;
+    use uuid::Uuid;
+
+    // needs a database, e.g. TEST_DB_CONF=postgres://postgres@localhost:5432/postgres
+    async fn pool() -> Option<Pool<Postgres>> {
+        let Ok(url) = env::var("TEST_DB_CONF") else {
+            eprintln!("TEST_DB_CONF not set, skipping");
+            return None;
+        };
+        Some(Pool::<Postgres>::connect(&url).await.unwrap())
+    }
+
+    // an instance of the server, the database is shared by all of them
+    async fn instance(pool: &Pool<Postgres>) -> (Arc<RoomManager>, PgFanOut) {
+        let rooms = Arc::new(RoomManager::new());
+        let fan_out = PgFanOut::start(pool.clone(), rooms.clone()).await.unwrap();
+        (rooms, fan_out)
+    }
+
+    fn room<'a>(rooms: &'a mut HashMap<String, Room>, user: &str) -> &'a mut Room {
+        rooms.entry(user.to_string()).or_insert_with(Room::new)
+    }
+
+    // a connection of `user` to `rooms` that never says anything
+    async fn connect(rooms: &RoomManager, user: &str, device: &str, session: &str) {
+        let mut req = TestRequest::get()
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::CONNECTION, "upgrade"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_srv_request();
+        let payload = req.extract::<web::Payload>().await.unwrap();
+        let (_, session_ws, _) = actix_ws::handle(req.request(), payload).unwrap();
+        let client = Client {
+            info: ClientInfo {
+                device: Some(device.to_string()),
+                session: Some(session.to_string()),
+            },
+            session: session_ws,
+            task: rt::spawn(std::future::pending()),
+        };
+        let mut map = rooms.room.lock().await;
+        room(&mut map, user).clients.get_mut().push(client);
+    }
+
+    // the listener is spawned on the local set, like on the server
+    #[tokio::test]
+    async fn changes_reach_the_other_instances() {
+        LocalSet::new()
+            .run_until(async {
+                let Some(pool) = pool().await else {
+                    return;
+                };
+                let (_, sender) = instance(&pool).await;
+                let (rooms, _receiver) = instance(&pool).await;
+                let user = format!("test-{}", Uuid::new_v4());
+                let mut events = room(&mut *rooms.room.lock().await, &user).tx.subscribe();
+
+                let event = SyncEvent {
+                    cursor: SyncCursor {
+                        epoch: String::from("epoch"),
+                        seq: 1,
+                    },
+                    origin: Uuid::new_v4(),
+                    msg: MessageMPC::New(String::from("1-10")),
+                };
+                sender.publish(&user, event.clone()).await.unwrap();
+                let received = rt::time::timeout(Duration::from_secs(5), events.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert!(matches!(received, RoomEvent::Change(val) if val == event));
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn changes_go_out_with_their_transaction() {
+        LocalSet::new()
+            .run_until(async {
+                let Some(pool) = pool().await else {
+                    return;
+                };
+                let (_, sender) = instance(&pool).await;
+                let (rooms, _receiver) = instance(&pool).await;
+                let user = format!("test-{}", Uuid::new_v4());
+                let mut events = room(&mut *rooms.room.lock().await, &user).tx.subscribe();
+                let event = |id: &str| SyncEvent {
+                    cursor: SyncCursor {
+                        epoch: String::from("epoch"),
+                        seq: 1,
+                    },
+                    origin: Uuid::new_v4(),
+                    msg: MessageMPC::New(id.to_string()),
+                };
+
+                let mut tx = pool.begin().await.unwrap();
+                assert!(
+                    sender
+                        .publish_in(&mut tx, &user, &event("1-10"))
+                        .await
+                        .unwrap()
+                );
+                tx.rollback().await.unwrap();
+                let mut tx = pool.begin().await.unwrap();
+                let committed = event("1-11");
+                assert!(sender.publish_in(&mut tx, &user, &committed).await.unwrap());
+                tx.commit().await.unwrap();
+
+                // the change that was rolled back never arrives
+                let received = rt::time::timeout(Duration::from_secs(5), events.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert!(matches!(received, RoomEvent::Change(val) if val == committed));
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn logouts_close_connections_on_every_instance() {
+        LocalSet::new()
+            .run_until(async {
+                let Some(pool) = pool().await else {
+                    return;
+                };
+                let (_, sender) = instance(&pool).await;
+                let (rooms, _receiver) = instance(&pool).await;
+                let user = format!("test-{}", Uuid::new_v4());
+                connect(&rooms, &user, "laptop", "first").await;
+                connect(&rooms, &user, "phone", "second").await;
+                connect(&rooms, &user, "tablet", "third").await;
+
+                sender
+                    .logout(&user, Logout::Session(String::from("first")))
+                    .await
+                    .unwrap();
+                let device = Logout::Device {
+                    device: String::from("phone"),
+                    session: None,
+                };
+                sender.logout(&user, device).await.unwrap();
+                for _ in 0..50 {
+                    if rooms.online(&user).await.len() == 1 {
+                        break;
+                    }
+                    rt::time::sleep(Duration::from_millis(100)).await;
+                }
+                assert_eq!(rooms.online(&user).await, vec!["tablet"]);
+            })
+            .await
+    }
+}
@@ -1,211 +1,53 @@
+pub mod blob_store;
+pub mod config;
+pub mod devices;
+pub mod fan_out;
+pub mod mailer;
+pub mod rate_limit;
+pub mod sessions;
+mod user_state;
 mod ws_connection;
 use actix_multipart::Multipart;
 use actix_web::{HttpResponse, rt, web};
-use actix_ws::{AggregatedMessageStream, Session};
+use actix_ws::{AggregatedMessageStream, CloseCode, CloseReason, Session};
+use argon2::{
+    Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{self, SaltString},
+};
 use base64::{Engine, engine::general_purpose};
+use blob_store::BlobStore;
 use chrono::{Duration, Utc};
-use clippy::{LoginUserCred, NewUserOtp};
+use clippy::{KeyCheck, LoginUserCred, NewUserOtp, SyncCursor};
+use devices::Devices;
 use futures_util::StreamExt;
 use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
 use log::{debug, error};
-use rand::seq::IteratorRandom;
+use rand::{RngCore, seq::IteratorRandom};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::{Digest, Sha256};
-use sqlx::prelude::FromRow;
+use sqlx::{migrate::Migrator, prelude::FromRow};
 use std::{
-    collections::{BTreeSet, HashMap, VecDeque, hash_map::Entry},
-    error::Error,
-    fs::{self},
-    io::{self, Write},
-    path::PathBuf,
+    collections::{HashMap, hash_map::Entry},
+    io,
     sync::{Arc, Mutex, OnceLock},
 };
+use subtle::ConstantTimeEq;
 use tokio::sync::{self, broadcast::Sender};
+pub use user_state::{Changes, UserState};
+use uuid::Uuid;
 use ws_connection::ws_connection;
 
 pub const DATABASE_PATH: &str = "data-base/users";
-const MAX_SIZE: usize = 10 * 1024 * 1024;
-const MAX_COUNT: usize = 100;
-pub static SMTP_USERNAME: OnceLock<String> = OnceLock::new();
-pub static SMTP_PASSWORD: OnceLock<String> = OnceLock::new();
+// the key-verification record of users with end-to-end encrypted sync
+const KEY_CHECK_FILE: &str = ".keycheck";
 pub static SECRET_KEY: OnceLock<String> = OnceLock::new();
 pub static DB_CONF: OnceLock<String> = OnceLock::new();
 
-#[derive(Debug, Clone)]
-pub struct UserState {
-    data: Arc<Mutex<HashMap<String, User>>>,
-}
-
-#[derive(Debug, Clone)]
-pub struct User {
-    state: BTreeSet<String>,
-    remove: VecDeque<String>,
-}
-
-impl UserState {
-    pub fn new() -> Self {
-        Self {
-            data: Arc::new(Mutex::new(HashMap::new())),
-        }
-    }
-
-    pub fn remove_and_add_edit(&self, username: &str, remove: &str) -> Result<(), String> {
-        let mut map = self.data.lock().map_err(|_| "Mutex poisoned")?;
-        let user = map
-            .get_mut(username)
-            .ok_or("unable to identify user".to_string())?;
-
-        user.state.remove(remove);
-        println!("remove {}", remove);
-        match remove_db_file(username, remove) {
-            Ok(_) => (),
-            Err(err) => debug!("{}", err),
-        };
-
-        if user.remove.len() >= MAX_COUNT {
-            user.remove.pop_front();
-        }
-        user.remove.push_back(remove.to_string());
-
-        Ok(())
-    }
-
-    pub fn entry(&self, username: &str) -> Result<(), String> {
-        let mut map = self.data.lock().map_err(|_| "Mutex poisoned")?;
-
-        let dir_path = format!("{}/{}", DATABASE_PATH, username);
-        fs::create_dir_all(&dir_path)
-            .map_err(|e| format!("Failed to create dir {}: {}", dir_path, e))?;
-
-        if !map.contains_key(username) {
-            let user = User {
-                state: BTreeSet::new(),
-                remove: VecDeque::new(),
-            };
-            map.insert(username.to_string(), user);
-            debug!("{:?}", self);
-        }
-
-        Ok(())
-    }
-
-    pub fn verify(&self, username: &str) -> bool {
-        self.data.lock().unwrap().contains_key(username)
-    }
-
-    pub fn update(&self, username: &str, id: &str) {
-        let mut map = self.data.lock().unwrap();
-        if let Some(set) = map.get_mut(username) {
-            let len = set.state.len();
-            if len > 29 {
-                let remove_count = len - 29;
-                let to_remove: Vec<_> = set.state.iter().take(remove_count).cloned().collect();
-                for val in to_remove {
-                    debug!("removing {:?}", val);
-                    match remove_db_file(username, &val) {
-                        Ok(_) => (),
-                        Err(err) => error!("{}", err),
-                    };
-                    set.state.remove(&val);
-                }
-            }
-
-            set.state.insert(id.to_string());
-        } else {
-            error!("Unabe to update user state: {}", id);
-        }
-    }
-
-    pub fn is_updated(&self, username: &str, id: &str) -> bool {
-        let guard = match self.data.lock() {
-            Ok(g) => g,
-            Err(e) => {
-                error!("Mutex lock failed: {}", e);
-                panic!("Mutex lock failed")
-            }
-        };
-
-        let data = guard.get(username);
-        if let Some(val) = data {
-            match val.state.last() {
-                Some(last) => {
-                    debug!("{},{}", id, last);
-                    last == id
-                }
-                None => {
-                    error!("User '{}' has empty BTreeSet", username);
-                    false
-                }
-            }
-        } else {
-            true
-        }
-    }
-
-    pub fn next(&self, username: &str, id: &str) -> Result<Vec<String>, HttpResponse> {
-        let map = self.data.lock().unwrap();
-
-        let tree = map
-            .get(username)
-            .ok_or_else(|| HttpResponse::Unauthorized().body("Error: authentication failed"))?;
-
-        if let Some(pos) = tree.state.iter().position(|x| x == id) {
-            Ok(tree
-                .state
-                .iter()
-                .skip(pos + 1)
-                .map(|x| format!("{}/{}/{}", DATABASE_PATH, username, x))
-                .collect())
-        } else {
-            if !map.is_empty() {
-                Ok(tree
-                    .state
-                    .iter()
-                    .map(|x| format!("{}/{}/{}", DATABASE_PATH, username, x))
-                    .collect())
-            } else {
-                Err(HttpResponse::InternalServerError().body("Error: failed to get data position"))
-            }
-        }
-    }
-
-    pub fn get(&self, username: &str, id: &[String]) -> Option<Vec<(String, String)>> {
-        let map = self.data.lock().unwrap();
-        let tree = map.get(username)?;
-        let mut temp = Vec::new();
-        for i in tree.state.iter() {
-            if !id.contains(i) {
-                temp.push((
-                    format!("{}/{}/{}", DATABASE_PATH, username, i),
-                    i.to_string(),
-                ));
-            }
-        }
-
-        Some(temp)
-    }
-
-    pub fn get_remove(&self, username: &str) -> VecDeque<String> {
-        let map = self.data.lock().unwrap();
-        let tree = map.get(username).unwrap();
-        tree.remove.clone()
-    }
-
-    pub fn remove(&self, username: &str, id: &str) -> Result<(), Box<dyn Error>> {
-        let mut map = self.data.lock().or_else(|_| Err("Unable to lock cred"))?;
-        let tree = map
-            .get_mut(username)
-            .ok_or_else(|| "Unable to identify user")?;
-        if tree.state.remove(id) {
-            match remove_db_file(username, id) {
-                Ok(_) => (),
-                Err(err) => debug!("{}", err),
-            };
-        }
-        Ok(())
-    }
-}
+/// The schema of the database, the files of `migrations/` applied in order. The server
+/// applies them at startup unless `AUTO_MIGRATE` is `false`, `clippy-server migrate`
+/// applies them on their own.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
 
 #[derive(Debug, Clone)]
 pub struct EmailState {
@@ -254,8 +96,36 @@ impl UserCred {
     }
 
     pub fn verify(&self, logincred: &LoginUserCred) -> bool {
-        self.username == logincred.username
-            && self.key == hash_key(&logincred.key, &logincred.username)
+        if self.username != logincred.username {
+            return false;
+        }
+        match PasswordHash::new(&self.key) {
+            Ok(hash) => Argon2::default()
+                .verify_password(logincred.key.as_bytes(), &hash)
+                .is_ok(),
+            // compared in constant time, the timing does not tell how much of the hash matched
+            Err(_) => self
+                .key
+                .as_bytes()
+                .ct_eq(legacy_hash(&logincred.key, &logincred.username).as_bytes())
+                .into(),
+        }
+    }
+
+    /// Whether the stored hash is older than the current Argon2id parameters, it is
+    /// replaced on the next login.
+    pub fn needs_rehash(&self) -> bool {
+        match PasswordHash::new(&self.key) {
+            Ok(hash) => {
+                let current = Params::default();
+                hash.algorithm != argon2::ARGON2ID_IDENT
+                    || Params::try_from(&hash).map_or(true, |params| {
+                        (params.m_cost(), params.t_cost(), params.p_cost())
+                            != (current.m_cost(), current.t_cost(), current.p_cost())
+                    })
+            }
+            Err(_) => true,
+        }
     }
 }
 
@@ -304,67 +174,59 @@ pub fn gen_otp() -> String {
 
 pub async fn write_file(
     mut data: Multipart,
+    store: &dyn BlobStore,
     username: &str,
     id: i64,
+    max_size: usize,
 ) -> Result<String, actix_web::Error> {
-    let mut path: PathBuf = PathBuf::new().join(format!("{}/{}", DATABASE_PATH, username));
-
-    let file_name = get_filename(id, path.clone());
-    path.push(&file_name);
-
-    // Ensure parent directory exists
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent).map_err(actix_web::error::ErrorInternalServerError)?;
-    }
-
-    let mut file = fs::File::create(&path).map_err(actix_web::error::ErrorInternalServerError)?;
-
-    let mut total_size: usize = 0;
+    let mut buf = Vec::new();
     while let Some(field) = data.next().await {
         if let Ok(mut field) = field {
             while let Some(chunk) = field.next().await {
-                let data = chunk.unwrap();
-                total_size += data.len();
-
-                if total_size > MAX_SIZE {
-                    drop(file);
-                    let _ = fs::remove_file(&path);
-                    return Err(actix_web::error::ErrorBadRequest("File exceeds 10MB limit"));
+                let data = chunk?;
+                if buf.len() + data.len() > max_size {
+                    return Err(actix_web::error::ErrorBadRequest(format!(
+                        "File exceeds {} bytes limit",
+                        max_size
+                    )));
                 }
-                file.write_all(&data)?;
+                buf.extend_from_slice(&data);
             }
         }
     }
 
-    Ok(file_name)
+    store_entry(store, username, id, &buf)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)
 }
 
-pub fn get_filename(id: i64, mut path: PathBuf) -> String {
-    let mut file_name = id.to_string();
+/// Stores a new entry of the user under the first free `<id>-<n>` name and returns the name.
+pub async fn store_entry(
+    store: &dyn BlobStore,
+    username: &str,
+    id: i64,
+    data: &[u8],
+) -> Result<String, io::Error> {
     let mut cont = 10;
-    file_name.push('-');
-    file_name.push_str(&cont.to_string());
-
-    path.push(&file_name);
-
-    let len = file_name.len();
-
-    while path.exists() {
+    loop {
+        let file_name = format!("{}-{}", id, cont);
+        if store.put_new(username, &file_name, data).await? {
+            return Ok(file_name);
+        }
         cont += 1;
-        path.pop();
-        file_name.truncate(len - 2);
-        file_name.push_str(&cont.to_string());
-        path.push(&file_name);
     }
-    file_name
 }
 
 #[derive(Deserialize)]
 pub struct Claims {
     user: String,
+    // the session the token was minted for, tokens of older servers have none
+    #[serde(default)]
+    sid: Option<String>,
 }
 
-pub fn auth(key: &str) -> Result<String, jsonwebtoken::errors::Error> {
+/// The user of an access token and the session it belongs to.
+pub fn auth_session(key: &str) -> Result<(String, Option<String>), jsonwebtoken::errors::Error> {
     let mut validation = Validation::new(Algorithm::HS256);
     validation.set_audience(&["clippy"]);
     validation.set_issuer(&["https://clippy.dhanu.cloud"]);
@@ -375,7 +237,7 @@ pub fn auth(key: &str) -> Result<String, jsonwebtoken::errors::Error> {
         &validation,
     )?;
 
-    Ok(token.claims.user.to_string())
+    Ok((token.claims.user, token.claims.sid))
 }
 
 pub struct OTPState {
@@ -434,10 +296,47 @@ impl OTPState {
 pub struct RoomManager {
     room: sync::Mutex<HashMap<String, Room>>,
 }
-#[derive(Debug)]
 pub struct Room {
-    clients: sync::Mutex<Vec<rt::task::JoinHandle<()>>>,
-    tx: Sender<MessageMPC>,
+    clients: sync::Mutex<Vec<Client>>,
+    tx: Sender<RoomEvent>,
+}
+
+/// Who is on the other end of a connection, clients of older versions do not say.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub device: Option<String>,
+    pub session: Option<String>,
+}
+
+/// The connections a logout closes, sent to every instance by the `FanOut`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Logout {
+    All,
+    Session(String),
+    // a removed device, and the session it was logged in with
+    Device {
+        device: String,
+        session: Option<String>,
+    },
+}
+
+impl Logout {
+    pub fn matches(&self, info: &ClientInfo) -> bool {
+        match self {
+            Logout::All => true,
+            Logout::Session(id) => info.session.as_ref() == Some(id),
+            Logout::Device { device, session } => {
+                info.device.as_ref() == Some(device)
+                    || (session.is_some() && info.session == *session)
+            }
+        }
+    }
+}
+
+struct Client {
+    info: ClientInfo,
+    session: Session,
+    task: rt::task::JoinHandle<()>,
 }
 
 impl RoomManager {
@@ -450,26 +349,82 @@ impl RoomManager {
     pub async fn add_task(
         &self,
         user: String,
+        info: ClientInfo,
         session: Session,
         msg_stream: AggregatedMessageStream,
-        room: web::Data<RoomManager>,
         state: actix_web::web::Data<UserState>,
+        devices: web::Data<Devices>,
     ) {
         let mut rooms = self.room.lock().await;
-        match rooms.entry(user.clone()) {
-            Entry::Occupied(mut entry) => {
-                let a = entry.get_mut();
-                let tx = a.tx.clone();
-                a.add(session, msg_stream, tx, state, user, room).await;
-            }
-            Entry::Vacant(entry) => {
-                let mut new_room = Room::new();
-                let tx = new_room.tx.clone();
-                new_room
-                    .add(session, msg_stream, tx, state, user, room)
-                    .await;
-                entry.insert(new_room);
+        let room = match rooms.entry(user.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Room::new()),
+        };
+        room.add(user, info, session, msg_stream, state, devices)
+            .await;
+    }
+
+    /// Closes the connections of `user` on this instance that `logout` picks, returns
+    /// how many there were. `UserState::logout` reaches the other instances too.
+    pub async fn disconnect(&self, user: &str, logout: &Logout) -> usize {
+        let rooms = self.room.lock().await;
+        let Some(room) = rooms.get(user) else {
+            return 0;
+        };
+        let closing = {
+            let mut clients = room.clients.lock().await;
+            let (closing, keep): (Vec<Client>, Vec<Client>) = clients
+                .drain(..)
+                .filter(|client| !client.task.is_finished())
+                .partition(|client| logout.matches(&client.info));
+            *clients = keep;
+            closing
+        };
+        drop(rooms);
+
+        for client in &closing {
+            let reason = CloseReason {
+                code: CloseCode::Policy,
+                description: Some(String::from("Device was logged out")),
+            };
+            if let Err(e) = client.session.clone().close(Some(reason)).await {
+                debug!("connection already closed: {}", e);
             }
+            client.task.abort();
+        }
+        closing.len()
+    }
+
+    /// Ids of the devices of `user` connected to this instance.
+    pub async fn online(&self, user: &str) -> Vec<String> {
+        let rooms = self.room.lock().await;
+        let Some(room) = rooms.get(user) else {
+            return Vec::new();
+        };
+        room.clients
+            .lock()
+            .await
+            .iter()
+            .filter(|client| !client.task.is_finished())
+            .filter_map(|client| client.info.device.clone())
+            .collect()
+    }
+
+    /// Hands the event to the connections of the user on this instance.
+    pub async fn deliver(&self, user: &str, event: SyncEvent) {
+        let rooms = self.room.lock().await;
+        if let Some(room) = rooms.get(user)
+            && let Err(e) = room.tx.send(RoomEvent::Change(event))
+        {
+            debug!("no connection to notify: {}", e);
+        }
+    }
+
+    /// Tells every connection of this instance to send its client the changes it missed.
+    pub async fn resync(&self) {
+        let rooms = self.room.lock().await;
+        for room in rooms.values() {
+            let _ = room.tx.send(RoomEvent::Resync);
         }
     }
 
@@ -489,7 +444,7 @@ impl RoomManager {
             if client.is_empty() {
                 remove_room.push(k.clone());
             } else {
-                client.retain(|x| !x.is_finished());
+                client.retain(|x| !x.task.is_finished());
             }
         }
         for i in remove_room {
@@ -509,29 +464,69 @@ impl Room {
 
     async fn add(
         &mut self,
+        user: String,
+        info: ClientInfo,
         session: Session,
         msg_stream: AggregatedMessageStream,
-        tx: Sender<MessageMPC>,
         state: actix_web::web::Data<UserState>,
-        user: String,
-        room: web::Data<RoomManager>,
+        devices: web::Data<Devices>,
     ) {
+        let connection = ws_connection(
+            session.clone(),
+            msg_stream,
+            self.tx.clone(),
+            state,
+            user.clone(),
+        );
+        let device = info.device.clone();
+        let task = rt::spawn(async move {
+            connection.await;
+            if let Some(device) = device
+                && let Err(e) = devices.seen(&user, &device).await
+            {
+                error!("unable to update device {} of {}: {}", device, user, e);
+            }
+        });
         let val = self.clients.get_mut();
-        val.push(rt::spawn(ws_connection(
-            session, msg_stream, tx, state, user, room,
-        )));
+        val.push(Client {
+            info,
+            session,
+            task,
+        });
         debug!("total threads {}", val.len());
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum MessageMPC {
     New(String),
     Edit { old_id: String, new_id: String },
     Remove(String),
     None,
 }
-pub fn get_auth(username: &str, exp: i64) -> Result<String, jsonwebtoken::errors::Error> {
+
+/// What the connections of a user are told.
+#[derive(Debug, Clone)]
+pub enum RoomEvent {
+    Change(SyncEvent),
+    // changes may have been lost on the way
+    Resync,
+}
+
+/// A change broadcast to the connections of a user.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncEvent {
+    // position of the change in the log of the user
+    pub cursor: SyncCursor,
+    // connection that made the change, it already has the data
+    pub origin: Uuid,
+    pub msg: MessageMPC,
+}
+pub fn get_auth(
+    username: &str,
+    session: &str,
+    exp: i64,
+) -> Result<String, jsonwebtoken::errors::Error> {
     let now = Utc::now();
     let time = now.to_rfc3339();
     let expiry_time = now + Duration::hours(exp);
@@ -540,6 +535,7 @@ pub fn get_auth(username: &str, exp: i64) -> Result<String, jsonwebtoken::errors
         "iss": "https://clippy.dhanu.cloud",
         "aud": "clippy",
         "user": username,
+        "sid": session,
         "iat": now.timestamp(),
         "exp": expiry_time.timestamp(),
         "created": time
@@ -559,16 +555,39 @@ fn is_token_expired(expiry_timestamp: i64) -> bool {
     now_timestamp >= expiry_timestamp
 }
 
-fn remove_db_file(username: &str, id: &str) -> Result<(), io::Error> {
-    let mut path = PathBuf::from(DATABASE_PATH);
-    path.push(username);
-    path.push(id);
-    debug!("remove path {:?}", &path);
-    fs::remove_file(path)?;
-    Ok(())
+pub async fn read_key_check(
+    store: &dyn BlobStore,
+    username: &str,
+) -> Result<Option<KeyCheck>, io::Error> {
+    match store.get(username, KEY_CHECK_FILE).await? {
+        Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        None => Ok(None),
+    }
+}
+
+/// Stores the record only if the user has none, returns false if one already exists.
+pub async fn write_key_check(
+    store: &dyn BlobStore,
+    username: &str,
+    check: &KeyCheck,
+) -> Result<bool, io::Error> {
+    store
+        .put_new(username, KEY_CHECK_FILE, &serde_json::to_vec(check)?)
+        .await
+}
+
+/// Hashes the password with Argon2id and a random salt, in PHC format.
+pub fn hash_key(key: &str) -> Result<String, password_hash::Error> {
+    let mut salt = [0u8; 16];
+    rand::rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt)?;
+    Ok(Argon2::default()
+        .hash_password(key.as_bytes(), &salt)?
+        .to_string())
 }
 
-pub fn hash_key(key: &str, user: &str) -> String {
+// accounts created before Argon2 store `Sha256(password || username)`
+fn legacy_hash(key: &str, user: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(key);
     hasher.update(user);
@@ -579,3 +598,36 @@ pub fn hash_key(key: &str, user: &str) -> String {
 pub fn get_oncelock(key: &OnceLock<String>) -> &str {
     key.get().expect("not initialized")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(key: &str) -> LoginUserCred {
+        LoginUserCred {
+            username: String::from("alice"),
+            key: key.to_string(),
+            device: String::new(),
+        }
+    }
+
+    #[test]
+    fn legacy_and_argon2_hashes_verify() {
+        let legacy = UserCred::new(
+            String::from("alice"),
+            String::from("alice@example.com"),
+            legacy_hash("Secret1!", "alice"),
+        );
+        assert!(legacy.verify(&login("Secret1!")));
+        assert!(!legacy.verify(&login("Secret2!")));
+        assert!(legacy.needs_rehash());
+
+        let current = UserCred {
+            key: hash_key("Secret1!").unwrap(),
+            ..legacy
+        };
+        assert!(current.verify(&login("Secret1!")));
+        assert!(!current.verify(&login("Secret2!")));
+        assert!(!current.needs_rehash());
+    }
+}
@@ -0,0 +1,283 @@
+use async_trait::async_trait;
+use clippy::NewUser;
+use lettre::{
+    AsyncFileTransport, AsyncSendmailTransport, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+    message::{Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+};
+use log::info;
+use std::{env, error::Error, fs, io, path::PathBuf, sync::Arc};
+
+type MailError = Box<dyn Error + Send + Sync>;
+
+/// A mail of the server, in plain text and html.
+pub struct Mail {
+    pub to: Mailbox,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// How the server sends mail.
+///
+/// Picked with `MAILER`:
+/// - `smtp` (default): `SMTP_HOST` (`smtp.gmail.com` if unset), `SMTP_PORT`, `SMTP_TLS`
+///   (`tls` by default, `starttls` or `none`) and `SMTP_USERNAME`/`SMTP_PASSWORD`
+/// - `sendmail`: the `sendmail` command, or `SENDMAIL_COMMAND`
+/// - `file`: `.eml` files in `MAIL_DIR` (`mail` if unset), for development and tests
+/// - `log`: the plain text of every mail is logged, for development and tests
+/// - `none`: no mail at all, accounts are created with `clippy-server create-user`
+///
+/// Mails come from `MAIL_FROM`, the smtp user if unset. The templates in `templates/`
+/// are built in, `MAIL_TEMPLATES` names a directory whose files replace them.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> Result<(), MailError>;
+
+    /// False if the server sends no mail, the flows that need one are turned off.
+    fn enabled(&self) -> bool {
+        true
+    }
+}
+
+pub fn mailer() -> io::Result<Arc<dyn Mailer>> {
+    let from = |default: Option<String>| -> io::Result<Mailbox> {
+        env::var("MAIL_FROM")
+            .ok()
+            .or(default)
+            .unwrap_or_else(|| String::from("clippy@localhost"))
+            .parse()
+            .map(|address| Mailbox::new(Some(String::from("Clippy")), address))
+            .map_err(|e| io::Error::other(format!("invalid MAIL_FROM: {}", e)))
+    };
+
+    match env::var("MAILER").as_deref() {
+        Err(_) | Ok("smtp") => {
+            let username = env::var("SMTP_USERNAME").ok();
+            let transport = smtp_transport(username.clone())?;
+            Ok(Arc::new(Sender {
+                from: from(username)?,
+                transport,
+            }))
+        }
+        Ok("sendmail") => Ok(Arc::new(Sender {
+            from: from(None)?,
+            transport: match env::var("SENDMAIL_COMMAND") {
+                Ok(command) => AsyncSendmailTransport::<Tokio1Executor>::new_with_command(command),
+                Err(_) => AsyncSendmailTransport::<Tokio1Executor>::new(),
+            },
+        })),
+        Ok("file") => {
+            let dir = PathBuf::from(env::var("MAIL_DIR").unwrap_or_else(|_| String::from("mail")));
+            fs::create_dir_all(&dir)?;
+            Ok(Arc::new(Sender {
+                from: from(None)?,
+                transport: AsyncFileTransport::<Tokio1Executor>::new(dir),
+            }))
+        }
+        Ok("log") => Ok(Arc::new(LogMailer)),
+        Ok("none") => Ok(Arc::new(NoMailer)),
+        Ok(val) => Err(io::Error::other(format!("unknown MAILER {}", val))),
+    }
+}
+
+fn smtp_transport(username: Option<String>) -> io::Result<AsyncSmtpTransport<Tokio1Executor>> {
+    let host = env::var("SMTP_HOST").unwrap_or_else(|_| String::from("smtp.gmail.com"));
+    let error = |e: lettre::transport::smtp::Error| io::Error::other(format!("smtp: {}", e));
+    let mut builder = match env::var("SMTP_TLS").as_deref() {
+        Err(_) | Ok("tls") => AsyncSmtpTransport::<Tokio1Executor>::relay(&host).map_err(error)?,
+        Ok("starttls") => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host).map_err(error)?
+        }
+        Ok("none") => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+        Ok(val) => return Err(io::Error::other(format!("unknown SMTP_TLS {}", val))),
+    };
+    if let Ok(port) = env::var("SMTP_PORT") {
+        let port = port
+            .parse()
+            .map_err(|_| io::Error::other(format!("invalid SMTP_PORT: {}", port)))?;
+        builder = builder.port(port);
+    }
+    // a relay on the local network may take mail without a login
+    if let Some(username) = username {
+        let password =
+            env::var("SMTP_PASSWORD").map_err(|_| io::Error::other("SMTP_PASSWORD not set"))?;
+        builder = builder.credentials(Credentials::new(username, password));
+    }
+    Ok(builder.build())
+}
+
+// sends through a lettre transport
+struct Sender<T> {
+    from: Mailbox,
+    transport: T,
+}
+
+#[async_trait]
+impl<T> Mailer for Sender<T>
+where
+    T: AsyncTransport + Send + Sync,
+    T::Error: Error + Send + Sync + 'static,
+{
+    async fn send(&self, mail: Mail) -> Result<(), MailError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(mail.to)
+            .subject(mail.subject)
+            .multipart(MultiPart::alternative_plain_html(mail.text, mail.html))?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, mail: Mail) -> Result<(), MailError> {
+        info!("mail to {}: {}\n{}", mail.to, mail.subject, mail.text);
+        Ok(())
+    }
+}
+
+struct NoMailer;
+
+#[async_trait]
+impl Mailer for NoMailer {
+    async fn send(&self, _: Mail) -> Result<(), MailError> {
+        Err("this server sends no mail".into())
+    }
+
+    fn enabled(&self) -> bool {
+        false
+    }
+}
+
+/// What a one-time password sent by mail is for.
+pub enum OtpKind {
+    Signin,
+    PasswordReset,
+    EmailChange,
+}
+
+impl OtpKind {
+    fn subject(&self) -> &'static str {
+        match self {
+            OtpKind::Signin => "Welcome to Clippy Community – Here's Your OTP",
+            OtpKind::PasswordReset => "Reset your Clippy password",
+            OtpKind::EmailChange => "Confirm your new Clippy email",
+        }
+    }
+
+    // the name of the templates and the built in text and html
+    fn template(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            OtpKind::Signin => (
+                "signin",
+                include_str!("../templates/signin.txt"),
+                include_str!("../templates/signin.html"),
+            ),
+            OtpKind::PasswordReset => (
+                "password_reset",
+                include_str!("../templates/password_reset.txt"),
+                include_str!("../templates/password_reset.html"),
+            ),
+            OtpKind::EmailChange => (
+                "email_change",
+                include_str!("../templates/email_change.txt"),
+                include_str!("../templates/email_change.html"),
+            ),
+        }
+    }
+}
+
+fn render(template: &str, user: &str, otp: &str) -> String {
+    template.replace("{user}", user).replace("{otp}", otp)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// the file of `MAIL_TEMPLATES` if there is one, else the built in template
+fn load_template(name: &str, builtin: &'static str) -> String {
+    env::var("MAIL_TEMPLATES")
+        .ok()
+        .and_then(|dir| fs::read_to_string(PathBuf::from(dir).join(name)).ok())
+        .unwrap_or_else(|| builtin.to_string())
+}
+
+pub async fn send_otp(
+    mailer: &dyn Mailer,
+    user: &NewUser,
+    otp: &str,
+    kind: OtpKind,
+) -> Result<(), MailError> {
+    let email = user.email.as_deref().ok_or("no email address")?;
+    let (name, text, html) = kind.template();
+    let text = load_template(&format!("{}.txt", name), text);
+    let html = load_template(&format!("{}.html", name), html);
+    let mail = Mail {
+        to: Mailbox::new(Some(user.user.clone()), email.parse()?),
+        subject: kind.subject().to_string(),
+        text: render(&text, &user.user, otp),
+        html: render(&html, &escape_html(&user.user), otp),
+    };
+    mailer.send(mail).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn render_fills_in_the_user_and_otp() {
+        assert_eq!(render("{user}: {otp} {otp}", "bob", "42"), "bob: 42 42");
+        assert_eq!(
+            escape_html(r#"<a href="x">&</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+
+    #[tokio::test]
+    async fn file_mailer_renders_the_templates() {
+        let dir = env::temp_dir().join(format!("clippy-mail-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let mailer = Sender {
+            from: "Clippy <clippy@localhost>".parse().unwrap(),
+            transport: AsyncFileTransport::<Tokio1Executor>::new(&dir),
+        };
+        let user = NewUser {
+            user: String::from("<bob>"),
+            email: Some(String::from("bob@example.com")),
+        };
+        send_otp(&mailer, &user, "123456", OtpKind::Signin)
+            .await
+            .unwrap();
+
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().flatten().collect();
+        assert_eq!(files.len(), 1);
+        // the bodies are quoted-printable, joining the soft line breaks is enough here
+        let mail = fs::read_to_string(files[0].path())
+            .unwrap()
+            .replace("=\r\n", "")
+            .replace("=\n", "");
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(mail.contains("From: Clippy <clippy@localhost>"));
+        assert!(mail.contains("<bob@example.com>"));
+        assert!(mail.contains("Subject: Welcome to Clippy Community"));
+        assert!(mail.contains("Content-Type: text/plain"));
+        assert!(mail.contains("Hey <bob>!"));
+        assert!(mail.contains("(OTP): 123456"));
+        assert!(mail.contains("Content-Type: text/html"));
+        assert!(mail.contains("<p>Hey &lt;bob&gt;!</p>"));
+        assert!(mail.contains("4px;\">123456</p>"));
+        assert!(!mail.contains("{user}") && !mail.contains("{otp}"));
+    }
+}
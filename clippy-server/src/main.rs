@@ -1,30 +1,46 @@
 mod db;
-mod email;
 
-use std::env;
+use std::{env, io::IsTerminal};
 
-use crate::{
-    db::{add_otp, check_otp, get_user, is_email_exists, is_user_exists},
-    email::send_otp,
-};
+use crate::db::{add_otp, check_otp, get_user, is_email_exists, is_user_exists, remove_otp};
 use actix_web::{
     App, HttpRequest, HttpResponse, HttpServer, Responder,
+    middleware::from_fn,
     web::{self},
 };
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use clippy::{
-    LoginUserCred, NewUser, NewUserOtp, is_valid_email, is_valid_otp, is_valid_password,
-    is_valid_username,
+    DeviceHello, DeviceName, EmailChange, KeyCheck, LoginUserCred, NewUser, NewUserOtp,
+    PasswordReset, RefreshRequest, Tokens, UserCred as Account, is_valid_email, is_valid_otp,
+    is_valid_password, is_valid_username,
 };
 use clippy_server::{
-    CustomErr, DB_CONF, RoomManager, SECRET_KEY, SMTP_PASSWORD, SMTP_USERNAME, UserCred, UserState,
-    auth, gen_otp, get_auth, get_oncelock, hash_key,
+    ClientInfo, CustomErr, DB_CONF, Logout, MIGRATOR, RoomManager, SECRET_KEY, UserCred, UserState,
+    auth_session,
+    blob_store::blob_store,
+    config::Config,
+    devices::Devices,
+    fan_out::fan_out,
+    gen_otp, get_auth, get_oncelock, hash_key,
+    mailer::{Mailer, OtpKind, mailer, send_otp},
+    rate_limit::{RateLimiter, rate_limit},
+    read_key_check,
+    sessions::Sessions,
+    write_key_check,
 };
 use env_logger::{Builder, Env};
 use log::{debug, error};
-use sqlx::{PgPool, Pool, Postgres};
+use sqlx::{PgPool, Pool, Postgres, Row, query};
 
-async fn signin(new_user: web::Json<NewUser>, pool: web::Data<Pool<Postgres>>) -> impl Responder {
+async fn signin(
+    new_user: web::Json<NewUser>,
+    pool: web::Data<Pool<Postgres>>,
+    mailer: web::Data<dyn Mailer>,
+) -> impl Responder {
+    if !mailer.enabled() {
+        return HttpResponse::Forbidden()
+            .body("Failure: Sign up is turned off on this server, ask its admin for an account");
+    }
     if new_user
         .email
         .as_ref()
@@ -44,7 +60,7 @@ async fn signin(new_user: web::Json<NewUser>, pool: web::Data<Pool<Postgres>>) -
                     debug!("{:?}", e);
                     return HttpResponse::InternalServerError().body("Unable to retreve otp");
                 };
-                match send_otp(&new_user, otp).await {
+                match send_otp(mailer.as_ref(), &new_user, &otp, OtpKind::Signin).await {
                     Ok(_) => HttpResponse::Ok().body("SURCESS"),
                     Err(e) => {
                         debug!("{}", e);
@@ -67,6 +83,7 @@ async fn signin(new_user: web::Json<NewUser>, pool: web::Data<Pool<Postgres>>) -
 async fn signin_auth(
     data: web::Json<NewUserOtp>,
     pool: web::Data<Pool<Postgres>>,
+    sessions: web::Data<Sessions>,
 ) -> impl Responder {
     let username = &data.user;
     if !is_valid_username(&data.user)
@@ -79,7 +96,10 @@ async fn signin_auth(
 
     match check_otp(&data, pool.as_ref()).await {
         Ok(_) => {
-            let password = hash_key(&data.key, &data.user);
+            let Some(password) = hash_password(&data.key).await else {
+                return HttpResponse::InternalServerError()
+                    .body("Error: Failed to write credentials");
+            };
             let user = UserCred::new(username.clone(), data.email.clone(), password);
             if let Err(err) = db::write(&user, pool.as_ref()).await {
                 error!("Failure: failed to write credentials\n{}", err);
@@ -87,9 +107,7 @@ async fn signin_auth(
                     .body("Error: Failed to write credentials");
             }
 
-            HttpResponse::Ok()
-                .content_type("application/json")
-                .body(serde_json::to_string(&user).unwrap())
+            account(user, &data.device, &sessions).await
         }
         Err(err) => match err {
             CustomErr::DBError(err) => {
@@ -116,30 +134,34 @@ async fn check_user(data: web::Json<NewUser>, pool: web::Data<Pool<Postgres>>) -
     }
 }
 
-async fn get_key(cred: web::Json<UserCred>, pool: web::Data<Pool<Postgres>>) -> impl Responder {
-    if !is_valid_username(&cred.username) {
-        return HttpResponse::Unauthorized()
-            .body("Failure: Invalid credentials, Try updating the app");
-    }
-    let user_cred_db = match get_user(pool.as_ref(), &cred.username).await {
-        Ok(val) => val,
+// Argon2 is slow on purpose, it runs on the blocking pool
+async fn hash_password(key: &str) -> Option<String> {
+    let key = key.to_string();
+    match web::block(move || hash_key(&key)).await {
+        Ok(Ok(hash)) => Some(hash),
+        Ok(Err(err)) => {
+            error!("unable to hash password: {}", err);
+            None
+        }
         Err(err) => {
-            return HttpResponse::Unauthorized()
-                .body(format!("User not found: {}", err.to_string()));
+            error!("unable to hash password: {}", err);
+            None
         }
-    };
-    if user_cred_db == *cred {
-        let key = match get_auth(&cred.username, 1) {
-            Ok(val) => val,
-            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
-        };
-        HttpResponse::Ok().body(key)
-    } else {
-        HttpResponse::Unauthorized().body("User credentials do not match")
     }
 }
 
-async fn login(cred: web::Json<LoginUserCred>, pool: web::Data<Pool<Postgres>>) -> impl Responder {
+async fn check_password(user: &UserCred, cred: &LoginUserCred) -> bool {
+    let (user, cred) = (user.clone(), cred.clone());
+    web::block(move || user.verify(&cred))
+        .await
+        .unwrap_or(false)
+}
+
+async fn login(
+    cred: web::Json<LoginUserCred>,
+    pool: web::Data<Pool<Postgres>>,
+    sessions: web::Data<Sessions>,
+) -> impl Responder {
     if !is_valid_username(&cred.username) || !is_valid_password(&cred.key) {
         return HttpResponse::Unauthorized().body("Failure: Invalid credentials");
     }
@@ -148,10 +170,419 @@ async fn login(cred: web::Json<LoginUserCred>, pool: web::Data<Pool<Postgres>>)
         Err(_) => return HttpResponse::Unauthorized().body("Failure: Invalid credentials"),
     };
 
-    if user_cred_db.verify(&cred) {
-        HttpResponse::Ok().json(user_cred_db)
-    } else {
-        HttpResponse::Unauthorized().body("Failure: Invalid credentials")
+    if !check_password(&user_cred_db, &cred).await {
+        return HttpResponse::Unauthorized().body("Failure: Invalid credentials");
+    }
+    // hashes of older versions are replaced now that the password is known
+    if user_cred_db.needs_rehash()
+        && let Some(key) = hash_password(&cred.key).await
+        && let Err(err) = db::update_key(pool.as_ref(), &cred.username, &key).await
+    {
+        error!("unable to rehash password of {}: {}", cred.username, err);
+    }
+    account(user_cred_db, &cred.device, &sessions).await
+}
+
+// answers the same for unknown users, the mail goes to the address of the account
+async fn reset_request(
+    data: web::Json<NewUser>,
+    pool: web::Data<Pool<Postgres>>,
+    mailer: web::Data<dyn Mailer>,
+) -> impl Responder {
+    if !mailer.enabled() {
+        return HttpResponse::Forbidden()
+            .body("Failure: Password reset is turned off on this server, ask its admin");
+    }
+    if !is_valid_username(&data.user) {
+        return HttpResponse::Unauthorized().body("Failure: Invalid credentials");
+    }
+    let user = match get_user(pool.as_ref(), &data.user).await {
+        Ok(val) => val,
+        Err(sqlx::Error::RowNotFound) => return HttpResponse::Ok().body("SURCESS"),
+        Err(e) => {
+            debug!("{}", e);
+            return HttpResponse::InternalServerError().body("Unable to check status");
+        }
+    };
+
+    let request = NewUser::new_signin(user.username, user.email);
+    let otp = gen_otp();
+    if let Err(e) = add_otp(&request, otp.clone(), pool.as_ref()).await {
+        debug!("{:?}", e);
+        return HttpResponse::InternalServerError().body("Unable to retreve otp");
+    };
+    match send_otp(mailer.as_ref(), &request, &otp, OtpKind::PasswordReset).await {
+        Ok(_) => HttpResponse::Ok().body("SURCESS"),
+        Err(e) => {
+            debug!("{}", e);
+            HttpResponse::InternalServerError().body("Unable to send email")
+        }
+    }
+}
+
+// every session of the account ends, the device resetting gets a new one
+async fn reset_confirm(
+    data: web::Json<PasswordReset>,
+    pool: web::Data<Pool<Postgres>>,
+    sessions: web::Data<Sessions>,
+    state: web::Data<UserState>,
+) -> impl Responder {
+    if !is_valid_username(&data.user) || !is_valid_password(&data.key) || !is_valid_otp(&data.otp) {
+        return HttpResponse::Unauthorized().body("Failure: Invalid credentials");
+    }
+    let user = match get_user(pool.as_ref(), &data.user).await {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::Unauthorized().body("Failure: Invalid credentials"),
+    };
+
+    let otp = NewUserOtp::new(
+        data.user.clone(),
+        user.email.clone(),
+        data.otp.clone(),
+        String::new(),
+    );
+    if let Err(err) = check_otp(&otp, pool.as_ref()).await {
+        return match err {
+            CustomErr::DBError(err) => {
+                debug!("{}", err);
+                HttpResponse::Unauthorized().body("Unable to veriify otp")
+            }
+            CustomErr::Failed(er) => HttpResponse::Unauthorized().body(er),
+        };
+    }
+
+    let Some(key) = hash_password(&data.key).await else {
+        return HttpResponse::InternalServerError().body("Error: Failed to write credentials");
+    };
+    if let Err(err) = db::update_key(pool.as_ref(), &data.user, &key).await {
+        error!("Failure: failed to write credentials\n{}", err);
+        return HttpResponse::InternalServerError().body("Error: Failed to write credentials");
+    }
+    if let Err(err) = remove_otp(pool.as_ref(), &user.email).await {
+        error!("unable to remove otp: {}", err);
+    }
+    if let Err(err) = sessions.revoke_all(&data.user).await {
+        error!("unable to revoke sessions: {}", err);
+        return HttpResponse::InternalServerError().body("Unable to revoke sessions");
+    }
+    state.logout(&data.user, Logout::All).await;
+
+    account(
+        UserCred::new(user.username, user.email, key),
+        &data.device,
+        &sessions,
+    )
+    .await
+}
+
+// the client keeps the refresh token of a new session, never the password
+async fn account(user: UserCred, device: &str, sessions: &Sessions) -> HttpResponse {
+    match sessions.create(&user.username, device).await {
+        Ok(token) => HttpResponse::Ok().json(Account::new(user.username, user.email, token)),
+        Err(err) => {
+            error!("unable to start session: {}", err);
+            HttpResponse::InternalServerError().body("Unable to start session")
+        }
+    }
+}
+
+async fn token(req: web::Json<RefreshRequest>, sessions: web::Data<Sessions>) -> impl Responder {
+    let refreshed = match sessions.refresh(&req.refresh_token).await {
+        Ok(Some(val)) => val,
+        Ok(None) => return HttpResponse::Unauthorized().body("Failure: Invalid refresh token"),
+        Err(err) => {
+            error!("unable to refresh session: {}", err);
+            return HttpResponse::InternalServerError().body("Unable to refresh session");
+        }
+    };
+    match get_auth(&refreshed.username, &refreshed.session, 1) {
+        Ok(key) => HttpResponse::Ok().json(Tokens {
+            access_token: key,
+            refresh_token: refreshed.refresh_token,
+        }),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn logout(req: web::Json<RefreshRequest>, sessions: web::Data<Sessions>) -> impl Responder {
+    match sessions.logout(&req.refresh_token).await {
+        Ok(_) => HttpResponse::Ok().body("SURCESS"),
+        Err(err) => {
+            error!("unable to end session: {}", err);
+            HttpResponse::InternalServerError().body("Unable to end session")
+        }
+    }
+}
+
+// the user and session of an access token whose session was not revoked
+async fn active_session(
+    auth_key: &BearerAuth,
+    sessions: &Sessions,
+) -> Result<(String, String), HttpResponse> {
+    let (username, session) = auth_session(auth_key.token()).map_err(|err| {
+        debug!("{}", err);
+        HttpResponse::Unauthorized().body("Unable to authorize.")
+    })?;
+    // tokens of older servers belong to no session and can not be revoked
+    let Some(session) = session else {
+        return Err(HttpResponse::Unauthorized().body("Session expired, log in again."));
+    };
+    match sessions.is_active(&username, &session).await {
+        Ok(true) => Ok((username, session)),
+        Ok(false) => Err(HttpResponse::Unauthorized().body("Session was revoked.")),
+        Err(err) => {
+            error!("unable to check session: {}", err);
+            Err(HttpResponse::InternalServerError().body("Unable to check session"))
+        }
+    }
+}
+
+async fn list_sessions(auth_key: BearerAuth, sessions: web::Data<Sessions>) -> impl Responder {
+    let (username, current) = match active_session(&auth_key, &sessions).await {
+        Ok(val) => val,
+        Err(response) => return response,
+    };
+    match sessions.list(&username, Some(&current)).await {
+        Ok(list) => HttpResponse::Ok().json(list),
+        Err(err) => {
+            error!("unable to list sessions: {}", err);
+            HttpResponse::InternalServerError().body("Unable to list sessions")
+        }
+    }
+}
+
+async fn revoke_session(
+    auth_key: BearerAuth,
+    id: web::Path<String>,
+    sessions: web::Data<Sessions>,
+    state: web::Data<UserState>,
+) -> impl Responder {
+    let (username, _) = match active_session(&auth_key, &sessions).await {
+        Ok(val) => val,
+        Err(response) => return response,
+    };
+    match sessions.revoke(&username, &id).await {
+        Ok(true) => {
+            state
+                .logout(&username, Logout::Session(id.into_inner()))
+                .await;
+            HttpResponse::Ok().body("SURCESS")
+        }
+        Ok(false) => HttpResponse::NotFound().body("Failure: Session not found"),
+        Err(err) => {
+            error!("unable to revoke session: {}", err);
+            HttpResponse::InternalServerError().body("Unable to revoke session")
+        }
+    }
+}
+
+async fn email_request(
+    auth_key: BearerAuth,
+    data: web::Json<EmailChange>,
+    pool: web::Data<Pool<Postgres>>,
+    sessions: web::Data<Sessions>,
+    mailer: web::Data<dyn Mailer>,
+) -> impl Responder {
+    let (username, _) = match active_session(&auth_key, &sessions).await {
+        Ok(val) => val,
+        Err(response) => return response,
+    };
+    if !mailer.enabled() {
+        return HttpResponse::Forbidden()
+            .body("Failure: Email change is turned off on this server, ask its admin");
+    }
+    if !is_valid_email(&data.email) {
+        return HttpResponse::BadRequest().body("Failure: Invalid email");
+    }
+    match is_email_exists(pool.as_ref(), &data.email).await {
+        Ok(true) => return HttpResponse::Conflict().body("Failure: Email already exists"),
+        Ok(false) => (),
+        Err(e) => {
+            debug!("{}", e);
+            return HttpResponse::InternalServerError().body("Unable to check status");
+        }
+    }
+
+    let request = NewUser::new_signin(username, data.email.clone());
+    let otp = gen_otp();
+    if let Err(e) = add_otp(&request, otp.clone(), pool.as_ref()).await {
+        debug!("{:?}", e);
+        return HttpResponse::InternalServerError().body("Unable to retreve otp");
+    };
+    match send_otp(mailer.as_ref(), &request, &otp, OtpKind::EmailChange).await {
+        Ok(_) => HttpResponse::Ok().body("SURCESS"),
+        Err(e) => {
+            debug!("{}", e);
+            HttpResponse::InternalServerError().body("Unable to send email")
+        }
+    }
+}
+
+async fn email_confirm(
+    auth_key: BearerAuth,
+    data: web::Json<EmailChange>,
+    pool: web::Data<Pool<Postgres>>,
+    sessions: web::Data<Sessions>,
+) -> impl Responder {
+    let (username, _) = match active_session(&auth_key, &sessions).await {
+        Ok(val) => val,
+        Err(response) => return response,
+    };
+    if !is_valid_email(&data.email) || !is_valid_otp(&data.otp) {
+        return HttpResponse::BadRequest().body("Failure: Invalid email or otp");
+    }
+
+    let otp = NewUserOtp::new(
+        username.clone(),
+        data.email.clone(),
+        data.otp.clone(),
+        String::new(),
+    );
+    if let Err(err) = check_otp(&otp, pool.as_ref()).await {
+        return match err {
+            CustomErr::DBError(err) => {
+                debug!("{}", err);
+                HttpResponse::Unauthorized().body("Unable to veriify otp")
+            }
+            CustomErr::Failed(er) => HttpResponse::Unauthorized().body(er),
+        };
+    }
+
+    // the address may have been taken since the otp was sent
+    match is_email_exists(pool.as_ref(), &data.email).await {
+        Ok(true) => return HttpResponse::Conflict().body("Failure: Email already exists"),
+        Ok(false) => (),
+        Err(e) => {
+            debug!("{}", e);
+            return HttpResponse::InternalServerError().body("Unable to check status");
+        }
+    }
+    if let Err(err) = db::update_email(pool.as_ref(), &username, &data.email).await {
+        error!("Failure: failed to write credentials\n{}", err);
+        return HttpResponse::InternalServerError().body("Error: Failed to write credentials");
+    }
+    if let Err(err) = remove_otp(pool.as_ref(), &data.email).await {
+        error!("unable to remove otp: {}", err);
+    }
+    HttpResponse::Ok().body("SURCESS")
+}
+
+async fn list_devices(
+    auth_key: BearerAuth,
+    sessions: web::Data<Sessions>,
+    devices: web::Data<Devices>,
+    room: web::Data<RoomManager>,
+) -> impl Responder {
+    let (username, _) = match active_session(&auth_key, &sessions).await {
+        Ok(val) => val,
+        Err(response) => return response,
+    };
+    let online = room.online(&username).await;
+    match devices.list(&username, &online).await {
+        Ok(list) => HttpResponse::Ok().json(list),
+        Err(err) => {
+            error!("unable to list devices: {}", err);
+            HttpResponse::InternalServerError().body("Unable to list devices")
+        }
+    }
+}
+
+async fn rename_device(
+    auth_key: BearerAuth,
+    id: web::Path<String>,
+    name: web::Json<DeviceName>,
+    sessions: web::Data<Sessions>,
+    devices: web::Data<Devices>,
+) -> impl Responder {
+    let (username, _) = match active_session(&auth_key, &sessions).await {
+        Ok(val) => val,
+        Err(response) => return response,
+    };
+    if name.name.trim().is_empty() {
+        return HttpResponse::BadRequest().body("Failure: Device name is empty");
+    }
+    match devices.rename(&username, &id, &name.name).await {
+        Ok(true) => HttpResponse::Ok().body("SURCESS"),
+        Ok(false) => HttpResponse::NotFound().body("Failure: Device not found"),
+        Err(err) => {
+            error!("unable to rename device: {}", err);
+            HttpResponse::InternalServerError().body("Unable to rename device")
+        }
+    }
+}
+
+// the device is logged out and its connections are closed
+async fn remove_device(
+    auth_key: BearerAuth,
+    id: web::Path<String>,
+    sessions: web::Data<Sessions>,
+    devices: web::Data<Devices>,
+    state: web::Data<UserState>,
+) -> impl Responder {
+    let (username, _) = match active_session(&auth_key, &sessions).await {
+        Ok(val) => val,
+        Err(response) => return response,
+    };
+    let session = match devices.remove(&username, &id).await {
+        Ok(Some(val)) => val,
+        Ok(None) => return HttpResponse::NotFound().body("Failure: Device not found"),
+        Err(err) => {
+            error!("unable to remove device: {}", err);
+            return HttpResponse::InternalServerError().body("Unable to remove device");
+        }
+    };
+    if let Some(session) = &session
+        && let Err(err) = sessions.revoke(&username, session).await
+    {
+        error!("unable to revoke session of device: {}", err);
+        return HttpResponse::InternalServerError().body("Unable to remove device");
+    }
+    let logout = Logout::Device {
+        device: id.into_inner(),
+        session,
+    };
+    state.logout(&username, logout).await;
+    HttpResponse::Ok().body("SURCESS")
+}
+
+async fn key_check(
+    auth_key: BearerAuth,
+    state: web::Data<UserState>,
+    sessions: web::Data<Sessions>,
+) -> impl Responder {
+    let (username, _) = match active_session(&auth_key, &sessions).await {
+        Ok(val) => val,
+        Err(response) => return response,
+    };
+
+    match read_key_check(state.blobs(), &username).await {
+        Ok(Some(check)) => HttpResponse::Ok().json(check),
+        Ok(None) => HttpResponse::NotFound().body("Encryption is not enabled"),
+        Err(err) => {
+            error!("unable to read key check: {}", err);
+            HttpResponse::InternalServerError().body("Unable to read key check")
+        }
+    }
+}
+
+async fn add_key_check(
+    auth_key: BearerAuth,
+    check: web::Json<KeyCheck>,
+    state: web::Data<UserState>,
+    sessions: web::Data<Sessions>,
+) -> impl Responder {
+    let (username, _) = match active_session(&auth_key, &sessions).await {
+        Ok(val) => val,
+        Err(response) => return response,
+    };
+
+    match write_key_check(state.blobs(), &username, &check).await {
+        Ok(true) => HttpResponse::Ok().body("SURCESS"),
+        Ok(false) => HttpResponse::Conflict().body("Failure: Encryption is already enabled"),
+        Err(err) => {
+            error!("unable to write key check: {}", err);
+            HttpResponse::InternalServerError().body("Unable to write key check")
+        }
     }
 }
 
@@ -165,19 +596,25 @@ async fn handle_connection(
     stream: web::Payload,
     room: web::Data<RoomManager>,
     state: web::Data<UserState>,
+    sessions: web::Data<Sessions>,
+    devices: web::Data<Devices>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let key = auth_key.token();
-    let username = match auth(key) {
+    // the access token outlives a revoked session by up to an hour
+    let (username, sid) = match active_session(&auth_key, &sessions).await {
         Ok(val) => val,
-        Err(err) => {
-            return {
-                error!("unable to process jwt: {}", err);
-                Err(actix_web::error::ErrorUnauthorized("Unable to authorize."))
-            };
-        }
+        Err(response) => return Ok(response),
+    };
+    // clients of older versions do not say which device they are
+    let device = match web::Query::<DeviceHello>::from_query(req.query_string()) {
+        Ok(hello) => devices
+            .register(&username, &hello, Some(&sid))
+            .await
+            .map_err(actix_web::error::ErrorInternalServerError)?,
+        Err(_) => None,
     };
     state
         .entry(&username)
+        .await
         .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
 
     let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
@@ -186,42 +623,193 @@ async fn handle_connection(
         .aggregate_continuations()
         .max_continuation_size(30 * 1024 * 1024);
 
-    room.add_task(username, session, msg_stream, room.clone(), state.clone())
-        .await;
+    let info = ClientInfo {
+        device,
+        session: Some(sid),
+    };
+    room.add_task(
+        username,
+        info,
+        session,
+        msg_stream,
+        state.clone(),
+        devices.clone(),
+    )
+    .await;
     Ok(res)
 }
 
+// `clippy-server migrate` brings the schema of the database up to date
+async fn migrate(pool: &Pool<Postgres>) -> std::io::Result<()> {
+    MIGRATOR.run(pool).await.map_err(std::io::Error::other)?;
+    // what the database has applied, not what this build knows of
+    let latest = query(
+        "SELECT version, description FROM _sqlx_migrations
+         WHERE success ORDER BY version DESC LIMIT 1",
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(std::io::Error::other)?;
+    match latest {
+        Some(row) => println!(
+            "Database is at version {} ({})",
+            row.get::<i64, _>("version"),
+            row.get::<String, _>("description")
+        ),
+        None => println!("Database has no migrations applied"),
+    }
+    Ok(())
+}
+
+// `clippy-server create-user <username> <email>` adds an account without mailing an
+// otp, for servers that send no mail. The password is asked on the terminal.
+async fn create_user(pool: &Pool<Postgres>, args: &[String]) -> std::io::Result<()> {
+    let [username, email] = args else {
+        return Err(std::io::Error::other(
+            "usage: clippy-server create-user <username> <email>",
+        ));
+    };
+    if !is_valid_username(username) {
+        return Err(std::io::Error::other("Invalid username"));
+    }
+    if !is_valid_email(email) {
+        return Err(std::io::Error::other("Invalid email"));
+    }
+    let exists = is_user_exists(pool, username)
+        .await
+        .map_err(std::io::Error::other)?;
+    if exists {
+        return Err(std::io::Error::other("Username already exists"));
+    }
+    let exists = is_email_exists(pool, email)
+        .await
+        .map_err(std::io::Error::other)?;
+    if exists {
+        return Err(std::io::Error::other("Email already exists"));
+    }
+
+    // asked on the terminal without echo, scripts can pipe it instead
+    let key = if std::io::stdin().is_terminal() {
+        rpassword::prompt_password("Password: ")?
+    } else {
+        let mut key = String::new();
+        std::io::stdin().read_line(&mut key)?;
+        key
+    };
+    let key = key.trim_end_matches(['\r', '\n']);
+    if !is_valid_password(key) {
+        return Err(std::io::Error::other(
+            "Password must be 6–32 characters long, and include at least one number, one symbol and one uppercase letter",
+        ));
+    }
+
+    let key = hash_key(key).map_err(|e| std::io::Error::other(e.to_string()))?;
+    let user = UserCred::new(username.clone(), email.clone(), key);
+    db::write(&user, pool)
+        .await
+        .map_err(std::io::Error::other)?;
+    println!("Created account {}", username);
+    Ok(())
+}
+
 pub fn init_env() {
-    SMTP_USERNAME
-        .set(env::var("SMTP_USERNAME").expect("SMTP_USERNAME not set"))
-        .ok();
-    SMTP_PASSWORD
-        .set(env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD not set"))
-        .ok();
     SECRET_KEY.set(env::var("KEY").expect("KEY not set")).ok();
-    DB_CONF.set(env::var("DB_CONF").expect("KEY not set")).ok();
+    init_db_env();
+}
+
+fn init_db_env() {
+    DB_CONF
+        .set(env::var("DB_CONF").expect("DB_CONF not set"))
+        .ok();
+}
+
+// the admin commands only need the database
+async fn command(command: &str, args: &[String]) -> std::io::Result<()> {
+    if !matches!(command, "migrate" | "create-user") {
+        return Err(std::io::Error::other(format!(
+            "unknown command {}",
+            command
+        )));
+    }
+    init_db_env();
+    let pool = PgPool::connect(get_oncelock(&DB_CONF))
+        .await
+        .map_err(std::io::Error::other)?;
+    match command {
+        "migrate" => migrate(&pool).await,
+        _ => create_user(&pool, args).await,
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     Builder::from_env(Env::default().filter_or("LOG", "info")).init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(name) = args.first() {
+        if let Err(e) = command(name, &args[1..]).await {
+            eprintln!("clippy-server: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     init_env();
+    let config = Config::load()?;
 
-    let user_state = web::Data::new(UserState::new());
     let room = web::Data::new(RoomManager::new());
     let pool = web::Data::new(PgPool::connect(get_oncelock(&DB_CONF)).await.unwrap());
 
+    // deployments that migrate before rolling out turn this off
+    if env::var("AUTO_MIGRATE").as_deref() != Ok("false") {
+        MIGRATOR
+            .run(pool.get_ref())
+            .await
+            .map_err(std::io::Error::other)?;
+    }
+
+    let mailer = web::Data::from(mailer()?);
+    let blobs = blob_store(pool.get_ref()).await?;
+    let fan_out = fan_out(pool.get_ref(), room.clone().into_inner()).await?;
+    let user_state = web::Data::new(UserState::new(
+        pool.get_ref().clone(),
+        blobs,
+        fan_out,
+        config.limits,
+    ));
+    let sessions = web::Data::new(Sessions::new(pool.get_ref().clone()));
+    let devices = web::Data::new(Devices::new(pool.get_ref().clone()));
+
+    let limiter = web::Data::new(RateLimiter::new(config.rate_limit));
+
     HttpServer::new(move || {
         App::new()
+            .wrap(from_fn(rate_limit))
+            .app_data(limiter.clone())
             .app_data(user_state.clone())
+            .app_data(sessions.clone())
+            .app_data(devices.clone())
             .app_data(room.clone())
             .app_data(pool.clone())
+            .app_data(mailer.clone())
             .route("/connect", web::get().to(handle_connection))
             .route("/signin", web::post().to(signin))
             .route("/authotp", web::post().to(signin_auth))
             .route("/login", web::get().to(login))
-            .route("/getkey", web::get().to(get_key))
+            .route("/password/reset/request", web::post().to(reset_request))
+            .route("/password/reset/confirm", web::post().to(reset_confirm))
+            .route("/email/change/request", web::post().to(email_request))
+            .route("/email/change/confirm", web::post().to(email_confirm))
+            .route("/token", web::post().to(token))
+            .route("/logout", web::post().to(logout))
+            .route("/sessions", web::get().to(list_sessions))
+            .route("/sessions/{id}", web::delete().to(revoke_session))
+            .route("/devices", web::get().to(list_devices))
+            .route("/devices/{id}", web::put().to(rename_device))
+            .route("/devices/{id}", web::delete().to(remove_device))
             .route("/usercheck", web::get().to(check_user))
+            .route("/keycheck", web::get().to(key_check))
+            .route("/keycheck", web::post().to(add_key_check))
             .route("/health", web::get().to(health))
     })
     .bind(("0.0.0.0", 7777))?
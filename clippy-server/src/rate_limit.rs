@@ -0,0 +1,378 @@
+use crate::config::RateLimits;
+use actix_web::{
+    Error, HttpResponse,
+    body::{EitherBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::{StatusCode, header},
+    middleware::Next,
+    web,
+};
+use log::warn;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// the endpoints that are throttled, `true` marks the ones that check a secret, a
+// success there clears the failures of the address on the account
+const GUARDED: [(&str, bool); 7] = [
+    ("/login", true),
+    ("/authotp", true),
+    ("/password/reset/confirm", true),
+    ("/email/change/confirm", true),
+    ("/signin", false),
+    ("/usercheck", false),
+    ("/password/reset/request", false),
+];
+// past this many tracked addresses and accounts the idle ones are forgotten
+const PRUNE_AT: usize = 10_000;
+const IDLE: Duration = Duration::from_secs(600);
+
+struct Entry {
+    tokens: f64,
+    updated: Instant,
+    failures: u32,
+    last_failure: Option<Instant>,
+    locked_until: Option<Instant>,
+}
+
+/// Throttles the auth endpoints per address, and per address and account, with token
+/// buckets.
+///
+/// Failed attempts lock an address out of the account they were for once there are
+/// `lockout_after` of them, the lockout doubles with every further failure. The limits
+/// of an account are kept apart for every address, so requests naming it from one
+/// address never keep the owner out from another.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // takes a token of `key`, the time until the next one if there is none left
+    fn take(&self, key: &str, burst: u32, per_minute: u32, now: Instant) -> Result<(), Duration> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= PRUNE_AT {
+            entries.retain(|_, entry| {
+                now.duration_since(entry.updated) < IDLE
+                    || entry.locked_until.is_some_and(|until| until > now)
+            });
+        }
+        let burst = f64::from(burst.max(1));
+        let entry = entries.entry(key.to_string()).or_insert(Entry {
+            tokens: burst,
+            updated: now,
+            failures: 0,
+            last_failure: None,
+            locked_until: None,
+        });
+        if let Some(until) = entry.locked_until
+            && until > now
+        {
+            return Err(until - now);
+        }
+
+        let rate = f64::from(per_minute.max(1)) / 60.0;
+        let elapsed = now.duration_since(entry.updated).as_secs_f64();
+        entry.tokens = (entry.tokens + elapsed * rate).min(burst);
+        entry.updated = now;
+        if entry.tokens >= 1.0 {
+            entry.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - entry.tokens) / rate))
+        }
+    }
+
+    // the time left of the lockout of `key`
+    fn locked(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(key).and_then(|entry| entry.locked_until) {
+            Some(until) if until > now => Err(until - now),
+            _ => Ok(()),
+        }
+    }
+
+    fn failed(&self, key: &str, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key.to_string()).or_insert(Entry {
+            tokens: 0.0,
+            updated: now,
+            failures: 0,
+            last_failure: None,
+            locked_until: None,
+        });
+        // failures long ago are forgiven
+        let max_lockout = Duration::from_secs(self.limits.max_lockout_secs);
+        if entry
+            .last_failure
+            .is_some_and(|last| now.duration_since(last) > max_lockout)
+        {
+            entry.failures = 0;
+        }
+        entry.failures += 1;
+        entry.last_failure = Some(now);
+
+        let Some(over) = entry.failures.checked_sub(self.limits.lockout_after.max(1)) else {
+            return;
+        };
+        let lockout = Duration::from_secs(
+            self.limits
+                .lockout_secs
+                .saturating_mul(1 << over.min(32))
+                .min(self.limits.max_lockout_secs),
+        );
+        entry.locked_until = Some(now + lockout);
+        warn!(
+            "{} failed {} times, locked out for {}s",
+            key,
+            entry.failures,
+            lockout.as_secs()
+        );
+    }
+
+    fn succeeded(&self, key: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+            entry.failures = 0;
+            entry.last_failure = None;
+            entry.locked_until = None;
+        }
+    }
+
+    fn address(&self, req: &ServiceRequest) -> String {
+        let address = if self.limits.behind_proxy {
+            req.connection_info()
+                .realip_remote_addr()
+                .map(str::to_string)
+        } else {
+            req.peer_addr().map(|addr| addr.ip().to_string())
+        };
+        format!("ip:{}", address.unwrap_or_default())
+    }
+}
+
+// the account a request is for, named in its json body
+fn account(body: &[u8]) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+    ["username", "user"]
+        .iter()
+        .find_map(|field| value.get(field)?.as_str())
+        .map(|name| format!("user:{}", name))
+}
+
+fn too_many<B>(req: ServiceRequest, wait: Duration) -> ServiceResponse<EitherBody<B>> {
+    let secs = (wait.as_secs_f64().ceil() as u64).max(1);
+    let response = HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, secs.to_string()))
+        .body(format!(
+            "Failure: Too many attempts, try again in {} seconds",
+            secs
+        ));
+    req.into_response(response).map_into_right_body()
+}
+
+/// Middleware applying the `RateLimiter` of the app to the auth endpoints.
+pub async fn rate_limit(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let guarded = GUARDED.iter().find(|(path, _)| *path == req.path());
+    let (Some((_, checks_secret)), Some(limiter)) =
+        (guarded, req.app_data::<web::Data<RateLimiter>>().cloned())
+    else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let limits = &limiter.limits;
+    let now = Instant::now();
+
+    let address = limiter.address(&req);
+    if let Err(wait) = limiter.take(&address, limits.ip_burst, limits.ip_per_minute, now) {
+        return Ok(too_many(req, wait));
+    }
+
+    // the handler reads the body again
+    let body = req.extract::<web::Bytes>().await?;
+    let account = account(&body);
+    req.set_payload(Payload::from(body));
+
+    // failures lock the address out of the account, or out of everything when the
+    // request names none
+    let lockout = match &account {
+        Some(account) => format!("{} {}", address, account),
+        None => address,
+    };
+    if account.is_some()
+        && let Err(wait) = limiter.take(
+            &lockout,
+            limits.account_burst,
+            limits.account_per_minute,
+            now,
+        )
+    {
+        return Ok(too_many(req, wait));
+    }
+    if let Err(wait) = limiter.locked(&lockout, now) {
+        return Ok(too_many(req, wait));
+    }
+
+    let res = next.call(req).await?;
+    if res.status() == StatusCode::UNAUTHORIZED {
+        limiter.failed(&lockout, now);
+    } else if res.status().is_success() && *checks_secret && account.is_some() {
+        limiter.succeeded(&lockout);
+    }
+    Ok(res.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        App,
+        middleware::from_fn,
+        test::{TestRequest, call_service, init_service},
+    };
+    use serde_json::{Value, json};
+    use std::net::SocketAddr;
+
+    fn limits() -> RateLimits {
+        RateLimits {
+            ip_burst: 100,
+            ip_per_minute: 100,
+            account_burst: 100,
+            account_per_minute: 100,
+            lockout_after: 2,
+            lockout_secs: 30,
+            max_lockout_secs: 3600,
+            behind_proxy: false,
+        }
+    }
+
+    async fn login(body: web::Json<Value>) -> HttpResponse {
+        if body["password"] == "right" {
+            HttpResponse::Ok().finish()
+        } else {
+            HttpResponse::Unauthorized().finish()
+        }
+    }
+
+    // builds the app in place, the type of its requests is not exported by actix-web
+    macro_rules! app {
+        ($limits:expr) => {
+            init_service(
+                App::new()
+                    .app_data(web::Data::new(RateLimiter::new($limits)))
+                    .wrap(from_fn(rate_limit))
+                    .route("/login", web::post().to(login)),
+            )
+            .await
+        };
+    }
+
+    fn request(address: &str, username: &str, password: &str) -> TestRequest {
+        TestRequest::post()
+            .uri("/login")
+            .peer_addr(format!("{}:1234", address).parse::<SocketAddr>().unwrap())
+            .set_json(json!({ "username": username, "password": password }))
+    }
+
+    #[actix_web::test]
+    async fn burst_is_exhausted() {
+        let app = app!(RateLimits {
+            ip_burst: 2,
+            ip_per_minute: 1,
+            ..limits()
+        });
+        for _ in 0..2 {
+            let res = call_service(&app, request("10.0.0.1", "alice", "right").to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = call_service(&app, request("10.0.0.1", "alice", "right").to_request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        // a token comes back every minute
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "60");
+        let res = call_service(&app, request("10.0.0.2", "alice", "right").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn accounts_are_capped_per_address() {
+        let app = app!(RateLimits {
+            account_burst: 1,
+            ..limits()
+        });
+        let res = call_service(&app, request("10.0.0.1", "alice", "right").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = call_service(&app, request("10.0.0.1", "alice", "right").to_request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().contains_key(header::RETRY_AFTER));
+        let res = call_service(&app, request("10.0.0.1", "bob", "right").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // the owner is not kept out by the requests of another address
+        let res = call_service(&app, request("10.0.0.2", "alice", "right").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn failures_lock_the_address_out_of_the_account() {
+        let app = app!(limits());
+        for _ in 0..2 {
+            let res = call_service(&app, request("10.0.0.1", "alice", "wrong").to_request()).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+        let res = call_service(&app, request("10.0.0.1", "alice", "right").to_request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "30");
+
+        // the owner logs in from elsewhere and the address can still use other accounts
+        let res = call_service(&app, request("10.0.0.2", "alice", "right").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = call_service(&app, request("10.0.0.1", "bob", "right").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn success_resets_the_failures() {
+        let app = app!(limits());
+        let res = call_service(&app, request("10.0.0.1", "alice", "wrong").to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = call_service(&app, request("10.0.0.1", "alice", "right").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = call_service(&app, request("10.0.0.1", "alice", "wrong").to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = call_service(&app, request("10.0.0.1", "alice", "right").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn lockout_doubles() {
+        let limiter = RateLimiter::new(RateLimits {
+            lockout_after: 1,
+            lockout_secs: 10,
+            max_lockout_secs: 35,
+            ..limits()
+        });
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+        for (failure, lockout) in [(0, 10), (10, 20), (30, 35)] {
+            let at = now + secs(failure);
+            limiter.failed("key", at);
+            assert_eq!(limiter.locked("key", at), Err(secs(lockout)));
+        }
+
+        // failures older than the longest lockout are forgiven
+        let later = now + secs(30 + 36);
+        limiter.failed("key", later);
+        assert_eq!(limiter.locked("key", later), Err(secs(10)));
+    }
+}
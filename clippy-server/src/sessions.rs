@@ -0,0 +1,341 @@
+use base64::{Engine, engine::general_purpose};
+use chrono::{Duration, Utc};
+use clippy::SessionInfo;
+use log::warn;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres, Row, query};
+use uuid::Uuid;
+
+// days a session lasts without being used
+const REFRESH_DAYS: i64 = 90;
+// seconds a traded refresh token still gets its successor, for responses lost on the way
+const REPLAY_GRACE: i64 = 10 * 60;
+const MAX_NAME: usize = 64;
+
+/// Logins of the users, stored in Postgres.
+///
+/// A login gets a refresh token, the client trades it for a short lived access token
+/// and a new refresh token. Trading the previous token again shortly after gets the
+/// same new token, in case the client never got the answer. Any later trade of a token
+/// that was already traded is a stolen copy, the session is revoked so neither the
+/// thief nor the client can use it.
+#[derive(Clone)]
+pub struct Sessions {
+    pool: Pool<Postgres>,
+}
+
+/// A traded refresh token.
+pub struct Refreshed {
+    pub username: String,
+    pub session: String,
+    pub refresh_token: String,
+}
+
+fn new_token() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    let token = general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+    let hash = token_hash(&token);
+    (token, hash)
+}
+
+fn token_hash(token: &str) -> String {
+    general_purpose::STANDARD.encode(Sha256::digest(token.as_bytes()))
+}
+
+// hides `successor` from anyone without `token`, the same call reveals it again
+fn mask(successor: &[u8], token: &str) -> Vec<u8> {
+    let pad = Sha256::new()
+        .chain_update(b"clippy successor")
+        .chain_update(token.as_bytes())
+        .finalize();
+    successor.iter().zip(pad).map(|(a, b)| a ^ b).collect()
+}
+
+fn masked_successor(successor: &str, token: &str) -> Option<String> {
+    let bytes = general_purpose::URL_SAFE_NO_PAD.decode(successor).ok()?;
+    Some(general_purpose::STANDARD.encode(mask(&bytes, token)))
+}
+
+fn unmasked_successor(masked: &str, token: &str) -> Option<String> {
+    let bytes = general_purpose::STANDARD.decode(masked).ok()?;
+    Some(general_purpose::URL_SAFE_NO_PAD.encode(mask(&bytes, token)))
+}
+
+impl Sessions {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        Self { pool }
+    }
+
+    /// Starts a session for a login from the device `name`, returns its refresh token.
+    pub async fn create(&self, username: &str, name: &str) -> Result<String, sqlx::Error> {
+        let now = Utc::now().timestamp();
+        let name: String = match name.trim() {
+            "" => String::from("unknown device"),
+            val => val.chars().take(MAX_NAME).collect(),
+        };
+        query("DELETE FROM sessions WHERE username = $1 AND expires_at <= $2")
+            .bind(username)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        let (token, hash) = new_token();
+        query(
+            "INSERT INTO sessions (id, username, name, token_hash, created_at, last_used, expires_at)
+             VALUES ($1, $2, $3, $4, $5, $5, $6)",
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(username)
+        .bind(name)
+        .bind(hash)
+        .bind(now)
+        .bind(now + Duration::days(REFRESH_DAYS).num_seconds())
+        .execute(&self.pool)
+        .await?;
+        Ok(token)
+    }
+
+    /// Trades `token` for a new one, `None` if it is unknown, expired or reused.
+    pub async fn refresh(&self, token: &str) -> Result<Option<Refreshed>, sqlx::Error> {
+        let now = Utc::now().timestamp();
+        let hash = token_hash(token);
+        let mut tx = self.pool.begin().await?;
+
+        let row =
+            query("SELECT id, username, expires_at FROM sessions WHERE token_hash = $1 FOR UPDATE")
+                .bind(&hash)
+                .fetch_optional(&mut *tx)
+                .await?;
+        let Some(row) = row else {
+            let replayed = query(
+                "SELECT id, username, token_hash, successor, last_used FROM sessions
+                 WHERE previous_hash = $1 AND expires_at > $2 FOR UPDATE",
+            )
+            .bind(&hash)
+            .bind(now)
+            .fetch_optional(&mut *tx)
+            .await?;
+            let replayed = replayed.and_then(|row| {
+                let masked: Option<String> = row.get("successor");
+                let successor = unmasked_successor(&masked?, token)?;
+                (now - row.get::<i64, _>("last_used") <= REPLAY_GRACE
+                    && token_hash(&successor) == row.get::<String, _>("token_hash"))
+                .then(|| Refreshed {
+                    username: row.get("username"),
+                    session: row.get("id"),
+                    refresh_token: successor,
+                })
+            });
+            if replayed.is_some() {
+                tx.commit().await?;
+                return Ok(replayed);
+            }
+
+            let reused =
+                query("DELETE FROM sessions WHERE previous_hash = $1 RETURNING username, name")
+                    .bind(&hash)
+                    .fetch_optional(&mut *tx)
+                    .await?;
+            if let Some(row) = reused {
+                warn!(
+                    "refresh token of {} was used twice, revoked the session of {}",
+                    row.get::<String, _>("username"),
+                    row.get::<String, _>("name")
+                );
+            }
+            tx.commit().await?;
+            return Ok(None);
+        };
+
+        let session: String = row.get("id");
+        if row.get::<i64, _>("expires_at") <= now {
+            query("DELETE FROM sessions WHERE id = $1")
+                .bind(&session)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            return Ok(None);
+        }
+
+        let (refresh_token, new_hash) = new_token();
+        query(
+            "UPDATE sessions SET previous_hash = token_hash, token_hash = $2, successor = $3,
+                 last_used = $4, expires_at = $5
+             WHERE id = $1",
+        )
+        .bind(&session)
+        .bind(new_hash)
+        .bind(masked_successor(&refresh_token, token))
+        .bind(now)
+        .bind(now + Duration::days(REFRESH_DAYS).num_seconds())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(Some(Refreshed {
+            username: row.get("username"),
+            session,
+            refresh_token,
+        }))
+    }
+
+    /// Ends the session of `token`, returns false if there was none.
+    pub async fn logout(&self, token: &str) -> Result<bool, sqlx::Error> {
+        let result = query("DELETE FROM sessions WHERE token_hash = $1")
+            .bind(token_hash(token))
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Ends the session `id` of `username`, returns false if there was none.
+    pub async fn revoke(&self, username: &str, id: &str) -> Result<bool, sqlx::Error> {
+        let result = query("DELETE FROM sessions WHERE username = $1 AND id = $2")
+            .bind(username)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Ends every session of `username`, returns how many there were.
+    pub async fn revoke_all(&self, username: &str) -> Result<u64, sqlx::Error> {
+        let result = query("DELETE FROM sessions WHERE username = $1")
+            .bind(username)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Whether the session `id` of `username` is still active.
+    pub async fn is_active(&self, username: &str, id: &str) -> Result<bool, sqlx::Error> {
+        let row = query(
+            "SELECT EXISTS (SELECT 1 FROM sessions WHERE username = $1 AND id = $2 AND expires_at > $3)",
+        )
+        .bind(username)
+        .bind(id)
+        .bind(Utc::now().timestamp())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.get("exists"))
+    }
+
+    /// Active sessions of `username`, the one used most recently first.
+    pub async fn list(
+        &self,
+        username: &str,
+        current: Option<&str>,
+    ) -> Result<Vec<SessionInfo>, sqlx::Error> {
+        let rows = query(
+            "SELECT id, name, created_at, last_used FROM sessions
+             WHERE username = $1 AND expires_at > $2 ORDER BY last_used DESC",
+        )
+        .bind(username)
+        .bind(Utc::now().timestamp())
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let id: String = row.get("id");
+                SessionInfo {
+                    current: current == Some(id.as_str()),
+                    id,
+                    name: row.get("name"),
+                    created_at: row.get("created_at"),
+                    last_used: row.get("last_used"),
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // needs a database with the migrations of the server, e.g.
+    // TEST_DB_CONF=postgres://postgres@localhost:5432/postgres
+    async fn sessions() -> Option<(Sessions, String)> {
+        let Ok(url) = env::var("TEST_DB_CONF") else {
+            eprintln!("TEST_DB_CONF not set, skipping");
+            return None;
+        };
+        let pool = Pool::<Postgres>::connect(&url).await.unwrap();
+        crate::MIGRATOR.run(&pool).await.unwrap();
+        Some((Sessions::new(pool), format!("test-{}", Uuid::new_v4())))
+    }
+
+    #[test]
+    fn successor_is_masked() {
+        let (successor, _) = new_token();
+        let masked = masked_successor(&successor, "token").unwrap();
+        assert_ne!(masked, successor);
+        assert_eq!(unmasked_successor(&masked, "token").unwrap(), successor);
+        assert_ne!(unmasked_successor(&masked, "other").unwrap(), successor);
+    }
+
+    #[tokio::test]
+    async fn lost_refresh_can_be_retried() {
+        let Some((sessions, user)) = sessions().await else {
+            return;
+        };
+        let first = sessions.create(&user, "laptop").await.unwrap();
+        let second = sessions.refresh(&first).await.unwrap().unwrap();
+        let retried = sessions.refresh(&first).await.unwrap().unwrap();
+        assert_eq!(retried.refresh_token, second.refresh_token);
+        assert_eq!(retried.session, second.session);
+
+        // the retried token works like the first answer
+        let third = sessions
+            .refresh(&retried.refresh_token)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(third.refresh_token, second.refresh_token);
+        assert_eq!(sessions.list(&user, None).await.unwrap().len(), 1);
+        sessions.revoke_all(&user).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn old_tokens_are_refused() {
+        let Some((sessions, user)) = sessions().await else {
+            return;
+        };
+        let first = sessions.create(&user, "laptop").await.unwrap();
+        let second = sessions.refresh(&first).await.unwrap().unwrap();
+        sessions
+            .refresh(&second.refresh_token)
+            .await
+            .unwrap()
+            .unwrap();
+        // the first token is two trades old
+        assert!(sessions.refresh(&first).await.unwrap().is_none());
+        sessions.revoke_all(&user).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_after_the_grace_revokes_the_session() {
+        let Some((sessions, user)) = sessions().await else {
+            return;
+        };
+        let first = sessions.create(&user, "laptop").await.unwrap();
+        let second = sessions.refresh(&first).await.unwrap().unwrap();
+        query("UPDATE sessions SET last_used = last_used - $2 WHERE username = $1")
+            .bind(&user)
+            .bind(REPLAY_GRACE + 1)
+            .execute(&sessions.pool)
+            .await
+            .unwrap();
+        assert!(sessions.refresh(&first).await.unwrap().is_none());
+        assert!(
+            sessions
+                .refresh(&second.refresh_token)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,549 @@
+use crate::{
+    Logout, MessageMPC, SyncEvent, blob_store::BlobStore, config::Limits, fan_out::FanOut,
+};
+use chrono::Utc;
+use clippy::{SyncCursor, remote_time};
+use log::{debug, error};
+use serde::Deserialize;
+use sqlx::{Pool, Postgres, Row, Transaction, query};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use tokio::sync;
+use uuid::Uuid;
+
+// change logs written by older servers, imported the first time a user connects
+const SYNC_FILE: &str = ".sync";
+
+/// The change log of every user, stored in Postgres so it survives restarts.
+/// Every change gets the next `seq` of the user, so a client that knows the
+/// last `seq` it applied can ask for everything after it.
+#[derive(Clone)]
+pub struct UserState {
+    pool: Pool<Postgres>,
+    blobs: Arc<dyn BlobStore>,
+    fan_out: Arc<dyn FanOut>,
+    limits: Limits,
+    // users seen by this server, changes of one user are recorded one at a time
+    users: Arc<Mutex<HashMap<String, Arc<sync::Mutex<()>>>>>,
+}
+
+pub struct Changes {
+    // ids of the stored entries
+    pub data: Vec<String>,
+    pub removed: Vec<String>,
+    pub cursor: SyncCursor,
+    // set when `data` is the whole history because removals after the cursor were
+    // forgotten, entries stored after this time that are not in `data` were removed
+    pub resync: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct LegacyLog {
+    epoch: String,
+    seq: u64,
+    live: BTreeMap<String, u64>,
+    removed: BTreeMap<String, u64>,
+}
+
+impl LegacyLog {
+    async fn load(store: &dyn BlobStore, username: &str) -> Self {
+        match store.get(username, SYNC_FILE).await {
+            Ok(Some(data)) => match serde_json::from_slice(&data) {
+                Ok(log) => return log,
+                Err(e) => error!(
+                    "change log of {} is unreadable, starting a new one: {}",
+                    username, e
+                ),
+            },
+            Ok(None) => (),
+            Err(e) => error!("unable to read change log of {}: {}", username, e),
+        }
+
+        // entries stored before the change log existed, clients see a new epoch and sync from the start
+        let names = store.list(username).await.unwrap_or_else(|e| {
+            error!("unable to list entries of {}: {}", username, e);
+            Vec::new()
+        });
+        let mut log = LegacyLog {
+            epoch: Uuid::new_v4().to_string(),
+            seq: 0,
+            live: BTreeMap::new(),
+            removed: BTreeMap::new(),
+        };
+        for name in names {
+            log.seq += 1;
+            log.live.insert(name, log.seq);
+        }
+        log
+    }
+}
+
+fn cursor(row: &sqlx::postgres::PgRow) -> SyncCursor {
+    SyncCursor {
+        epoch: row.get("epoch"),
+        seq: row.get::<i64, _>("seq") as u64,
+    }
+}
+
+async fn put(
+    tx: &mut Transaction<'_, Postgres>,
+    username: &str,
+    id: &str,
+    seq: i64,
+    removed: bool,
+    size: i64,
+) -> Result<(), sqlx::Error> {
+    query(
+        "INSERT INTO sync_entries (username, id, seq, removed, size) VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (username, id) DO UPDATE
+         SET seq = excluded.seq, removed = excluded.removed, size = excluded.size",
+    )
+    .bind(username)
+    .bind(id)
+    .bind(seq)
+    .bind(removed)
+    .bind(size)
+    .persistent(false)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Drops the oldest entries beyond `keep`, returns their ids.
+async fn trim(
+    tx: &mut Transaction<'_, Postgres>,
+    username: &str,
+    keep: i64,
+) -> Result<Vec<String>, sqlx::Error> {
+    let rows = query(
+        "DELETE FROM sync_entries WHERE username = $1 AND NOT removed AND id IN (
+            SELECT id FROM sync_entries WHERE username = $1 AND NOT removed
+            ORDER BY seq DESC OFFSET $2
+         ) RETURNING id",
+    )
+    .bind(username)
+    .bind(keep)
+    .persistent(false)
+    .fetch_all(&mut **tx)
+    .await?;
+    Ok(rows.iter().map(|row| row.get("id")).collect())
+}
+
+/// Drops the oldest tombstones beyond `keep`, cursors before them can no longer catch up.
+async fn trim_tombstones(
+    tx: &mut Transaction<'_, Postgres>,
+    username: &str,
+    keep: i64,
+) -> Result<(), sqlx::Error> {
+    query(
+        "WITH dropped AS (
+            DELETE FROM sync_entries WHERE username = $1 AND removed AND id IN (
+                SELECT id FROM sync_entries WHERE username = $1 AND removed
+                ORDER BY seq DESC OFFSET $2
+            ) RETURNING seq
+         )
+         UPDATE sync_state SET removed_horizon = GREATEST(removed_horizon, (SELECT max(seq) FROM dropped))
+         WHERE username = $1",
+    )
+    .bind(username)
+    .bind(keep)
+    .persistent(false)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Drops the oldest entries until the others fit in `budget` bytes, returns their ids.
+async fn trim_bytes(
+    tx: &mut Transaction<'_, Postgres>,
+    username: &str,
+    budget: i64,
+) -> Result<Vec<String>, sqlx::Error> {
+    let rows = query(
+        "DELETE FROM sync_entries WHERE username = $1 AND NOT removed AND id IN (
+            SELECT id FROM (
+                SELECT id, sum(size) OVER (ORDER BY seq DESC) AS total
+                FROM sync_entries WHERE username = $1 AND NOT removed
+            ) AS newest WHERE total > $2
+         ) RETURNING id",
+    )
+    .bind(username)
+    .bind(budget)
+    .persistent(false)
+    .fetch_all(&mut **tx)
+    .await?;
+    Ok(rows.iter().map(|row| row.get("id")).collect())
+}
+
+// drops the oldest entries so one of `size` bytes fits in the limits
+async fn make_room(
+    tx: &mut Transaction<'_, Postgres>,
+    username: &str,
+    size: i64,
+    limits: &Limits,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut files = trim(tx, username, limits.max_entries - 1).await?;
+    if let Some(max) = limits.max_bytes {
+        files.extend(trim_bytes(tx, username, max - size).await?);
+    }
+    // a resync must not take these for removals
+    if let Some(newest) = files.iter().filter_map(|id| remote_time(id)).max() {
+        query(
+            "UPDATE sync_state SET dropped_before = GREATEST(dropped_before, $2)
+             WHERE username = $1",
+        )
+        .bind(username)
+        .bind(newest)
+        .persistent(false)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(files)
+}
+
+impl UserState {
+    pub fn new(
+        pool: Pool<Postgres>,
+        blobs: Arc<dyn BlobStore>,
+        fan_out: Arc<dyn FanOut>,
+        limits: Limits,
+    ) -> Self {
+        Self {
+            pool,
+            blobs,
+            fan_out,
+            limits,
+            users: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Where the entries of the users are stored.
+    pub fn blobs(&self) -> &dyn BlobStore {
+        self.blobs.as_ref()
+    }
+
+    /// The limits of the config with the overrides of the user.
+    pub async fn limits(&self, username: &str) -> Limits {
+        let mut limits = self.limits.clone();
+        let row = query(
+            "SELECT max_entries, max_bytes, max_item_size, max_tombstones
+             FROM user_limits WHERE username = $1",
+        )
+        .bind(username)
+        .persistent(false)
+        .fetch_optional(&self.pool)
+        .await;
+        match row {
+            Ok(Some(row)) => {
+                if let Some(val) = row.get("max_entries") {
+                    limits.max_entries = val;
+                }
+                if let Some(val) = row.get("max_bytes") {
+                    limits.max_bytes = Some(val);
+                }
+                if let Some(val) = row.get("max_item_size") {
+                    limits.max_item_size = val;
+                }
+                if let Some(val) = row.get("max_tombstones") {
+                    limits.max_tombstones = val;
+                }
+            }
+            Ok(None) => (),
+            Err(e) => error!("unable to read limits of {}: {}", username, e),
+        }
+        limits
+    }
+
+    fn lock(&self, username: &str) -> Result<Arc<sync::Mutex<()>>, String> {
+        let mut map = self.users.lock().map_err(|_| "Mutex poisoned")?;
+        Ok(map.entry(username.to_string()).or_default().clone())
+    }
+
+    pub async fn entry(&self, username: &str) -> Result<(), String> {
+        let lock = self.lock(username)?;
+        let _guard = lock.lock().await;
+        self.import(username)
+            .await
+            .map_err(|e| format!("Failed to load sync state of {}: {}", username, e))
+    }
+
+    async fn import(&self, username: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let exists = query("SELECT 1 FROM sync_state WHERE username = $1")
+            .bind(username)
+            .persistent(false)
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+        if exists {
+            return Ok(());
+        }
+
+        let log = LegacyLog::load(self.blobs(), username).await;
+        // the old log may have forgotten removals and dropped entries, like the migration assumes
+        let removed_horizon = log.removed.values().min().map_or(0, |seq| seq - 1);
+        let dropped_before = match log.live.keys().filter_map(|id| remote_time(id)).min() {
+            Some(val) => val,
+            None if log.seq == 0 => 0,
+            None => Utc::now().timestamp(),
+        };
+        let inserted = query(
+            "INSERT INTO sync_state (username, epoch, seq, removed_horizon, dropped_before)
+             VALUES ($1, $2, $3, $4, $5) ON CONFLICT (username) DO NOTHING",
+        )
+        .bind(username)
+        .bind(&log.epoch)
+        .bind(log.seq as i64)
+        .bind(removed_horizon as i64)
+        .bind(dropped_before)
+        .persistent(false)
+        .execute(&mut *tx)
+        .await?;
+        // another server created it first
+        if inserted.rows_affected() == 0 {
+            return Ok(());
+        }
+        for (id, seq) in &log.live {
+            put(&mut tx, username, id, *seq as i64, false, 0).await?;
+        }
+        for (id, seq) in &log.removed {
+            put(&mut tx, username, id, *seq as i64, true, 0).await?;
+        }
+        tx.commit().await?;
+        debug!("created change log of {} at seq {}", username, log.seq);
+        Ok(())
+    }
+
+    pub fn verify(&self, username: &str) -> bool {
+        self.users.lock().unwrap().contains_key(username)
+    }
+
+    /// Records a change in the log of the user and sends it to every connection of the user,
+    /// `size` is the bytes of the stored entry, 0 for a removal.
+    /// The event is sent under the lock of the user so connections of this instance see
+    /// changes in `seq` order. Other instances may see them reordered, clients never move
+    /// their cursor back.
+    pub async fn publish(
+        &self,
+        username: &str,
+        origin: Uuid,
+        msg: MessageMPC,
+        size: usize,
+    ) -> Result<u64, String> {
+        let limits = self.limits(username).await;
+        let lock = self.lock(username)?;
+        let _guard = lock.lock().await;
+
+        let (event, files, sent) = self
+            .record(username, origin, msg, size as i64, &limits)
+            .await
+            .map_err(|e| format!("Unable to record change: {}", e))?;
+        for id in files {
+            debug!("removing {:?}", id);
+            if let Err(err) = self.blobs.delete(username, &id).await {
+                debug!("{}", err);
+            }
+        }
+
+        let seq = event.cursor.seq;
+        if !sent && let Err(e) = self.fan_out.publish(username, event).await {
+            error!("unable to send change of {}: {}", username, e);
+        }
+        Ok(seq)
+    }
+
+    /// Closes the connections of `username` that `logout` picks on every instance.
+    pub async fn logout(&self, username: &str, logout: Logout) {
+        if let Err(e) = self.fan_out.logout(username, logout).await {
+            error!("unable to close the connections of {}: {}", username, e);
+        }
+    }
+
+    // returns the change, the entries whose files can be removed and whether the change
+    // was already sent to the other connections with the transaction
+    async fn record(
+        &self,
+        username: &str,
+        origin: Uuid,
+        msg: MessageMPC,
+        size: i64,
+        limits: &Limits,
+    ) -> Result<(SyncEvent, Vec<String>, bool), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let row =
+            query("UPDATE sync_state SET seq = seq + 1 WHERE username = $1 RETURNING epoch, seq")
+                .bind(username)
+                .persistent(false)
+                .fetch_one(&mut *tx)
+                .await?;
+        let cursor = cursor(&row);
+        let seq = cursor.seq as i64;
+
+        // dropping old entries is not a removal, clients keep their own history
+        let mut files = Vec::new();
+        match &msg {
+            MessageMPC::New(id) => {
+                files.extend(make_room(&mut tx, username, size, limits).await?);
+                put(&mut tx, username, id, seq, false, size).await?;
+            }
+            MessageMPC::Edit { old_id, new_id } => {
+                put(&mut tx, username, old_id, seq, true, 0).await?;
+                files.extend(make_room(&mut tx, username, size, limits).await?);
+                put(&mut tx, username, new_id, seq, false, size).await?;
+                files.push(old_id.clone());
+            }
+            MessageMPC::Remove(id) => {
+                put(&mut tx, username, id, seq, true, 0).await?;
+                files.push(id.clone());
+            }
+            MessageMPC::None => {}
+        }
+        trim_tombstones(&mut tx, username, limits.max_tombstones).await?;
+        let event = SyncEvent {
+            cursor,
+            origin,
+            msg,
+        };
+        let sent = self.fan_out.publish_in(&mut tx, username, &event).await?;
+        tx.commit().await?;
+        Ok((event, files, sent))
+    }
+
+    /// Entries stored and removed after `cursor`, oldest first, with the cursor they lead to.
+    /// A cursor from another epoch gets the whole history, a cursor from before the oldest
+    /// tombstone gets it as a resync.
+    pub async fn changes(&self, username: &str, cursor: Option<&SyncCursor>) -> Option<Changes> {
+        match self.read_changes(username, cursor).await {
+            Ok(val) => val,
+            Err(e) => {
+                error!("unable to read change log of {}: {}", username, e);
+                None
+            }
+        }
+    }
+
+    async fn read_changes(
+        &self,
+        username: &str,
+        since: Option<&SyncCursor>,
+    ) -> Result<Option<Changes>, sqlx::Error> {
+        let Some(row) = query(
+            "SELECT epoch, seq, removed_horizon, dropped_before FROM sync_state WHERE username = $1",
+        )
+        .bind(username)
+        .persistent(false)
+        .fetch_optional(&self.pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let current = cursor(&row);
+        let removed_horizon = row.get::<i64, _>("removed_horizon") as u64;
+        let mut resync = None;
+        let since = match since {
+            Some(val) if val.epoch == current.epoch && val.seq < removed_horizon => {
+                resync = Some(row.get("dropped_before"));
+                0
+            }
+            Some(val) if val.epoch == current.epoch && val.seq <= current.seq => val.seq,
+            _ => 0,
+        };
+
+        // later changes reach the connection through the broadcast
+        let rows = query(
+            "SELECT id, removed FROM sync_entries
+             WHERE username = $1 AND seq > $2 AND seq <= $3 ORDER BY seq",
+        )
+        .bind(username)
+        .bind(since as i64)
+        .bind(current.seq as i64)
+        .persistent(false)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut changes = Changes {
+            data: Vec::new(),
+            removed: Vec::new(),
+            cursor: current,
+            resync,
+        };
+        for row in rows {
+            let id: String = row.get("id");
+            if row.get("removed") {
+                changes.removed.push(id);
+            } else {
+                changes.data.push(id);
+            }
+        }
+        Ok(Some(changes))
+    }
+
+    async fn live(&self, username: &str) -> Result<Vec<String>, sqlx::Error> {
+        let rows =
+            query("SELECT id FROM sync_entries WHERE username = $1 AND NOT removed ORDER BY seq")
+                .bind(username)
+                .persistent(false)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows.iter().map(|row| row.get("id")).collect())
+    }
+
+    pub async fn is_updated(&self, username: &str, id: &str) -> Result<bool, String> {
+        let live = self
+            .live(username)
+            .await
+            .map_err(|e| format!("unable to read change log of {}: {}", username, e))?;
+        match live.last() {
+            Some(last) => {
+                debug!("{},{}", id, last);
+                Ok(last == id)
+            }
+            None => {
+                error!("User '{}' has no entries", username);
+                Ok(false)
+            }
+        }
+    }
+
+    pub async fn get(&self, username: &str, id: &[String]) -> Option<Vec<String>> {
+        let live = match self.live(username).await {
+            Ok(val) => val,
+            Err(e) => {
+                error!("unable to read change log of {}: {}", username, e);
+                return None;
+            }
+        };
+        let mut temp = Vec::new();
+        for i in live {
+            if !id.contains(&i) {
+                temp.push(i);
+            }
+        }
+
+        Some(temp)
+    }
+
+    pub async fn get_remove(&self, username: &str) -> VecDeque<String> {
+        let limits = self.limits(username).await;
+        let rows = query(
+            "SELECT id FROM (
+                SELECT id, seq FROM sync_entries WHERE username = $1 AND removed
+                ORDER BY seq DESC LIMIT $2
+             ) AS latest ORDER BY seq",
+        )
+        .bind(username)
+        .bind(limits.legacy_tombstones)
+        .persistent(false)
+        .fetch_all(&self.pool)
+        .await;
+        match rows {
+            Ok(rows) => rows.iter().map(|row| row.get("id")).collect(),
+            Err(e) => {
+                error!("unable to read change log of {}: {}", username, e);
+                VecDeque::new()
+            }
+        }
+    }
+}
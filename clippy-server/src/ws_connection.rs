@@ -1,15 +1,9 @@
-use std::{
-    collections::VecDeque,
-    fs::File,
-    io::{Read, Write},
-    path::PathBuf,
-    time::Duration,
-};
+use std::{collections::VecDeque, time::Duration};
 
-use actix_web::web::{self, Bytes};
+use actix_web::web::Bytes;
 use actix_ws::{AggregatedMessage, AggregatedMessageStream, Session};
 use chrono::Utc;
-use clippy::{ResopnseClientToServer, ResopnseServerToClient, ToByteString};
+use clippy::{ResopnseClientToServer, ResopnseServerToClient, SyncCursor, ToByteString};
 use futures_util::StreamExt;
 use log::{debug, error};
 use tokio::{
@@ -17,20 +11,26 @@ use tokio::{
     sync::broadcast::Sender,
     time::{Instant, sleep},
 };
+use uuid::Uuid;
 
-use crate::{DATABASE_PATH, MessageMPC, RoomManager, UserState, get_filename};
+use crate::{
+    Changes, MessageMPC, RoomEvent, SyncEvent, UserState, blob_store::BlobStore, store_entry,
+};
 
 pub async fn ws_connection(
     mut session: Session,
     mut msg_stream: AggregatedMessageStream,
-    tx: Sender<MessageMPC>,
+    tx: Sender<RoomEvent>,
     state: actix_web::web::Data<UserState>,
     user: String,
-    room: web::Data<RoomManager>,
 ) {
     let mut last_pong = Instant::now();
     let mut rx = tx.subscribe();
-    let mut old = true;
+    let conn = Uuid::new_v4();
+    // clients using the change log get a cursor after every change
+    let mut delta = false;
+    // the newest cursor sent to the client
+    let mut sent: Option<SyncCursor> = None;
 
     loop {
         select! {
@@ -47,20 +47,25 @@ pub async fn ws_connection(
                             if let Ok(parsed) = serde_json::from_str::<ResopnseClientToServer>(&txt) {
                                 match parsed {
                                     ResopnseClientToServer::CheckVersion(version) =>{
-                                        if state.is_updated(&user, &version){
-                                            let status = ResopnseServerToClient::Updated;
-                                            if let Err(e) =  session.text(serde_json::to_string(&status).unwrap()).await{
-                                                debug!("Unable to send response {}",e);
-                                            };
-                                        }else {
-                                            let status = ResopnseServerToClient::Outdated;
-                                            if let Err(e) =  session.text(serde_json::to_string(&status).unwrap()).await{
-                                                debug!("Unable to send response {}",e);
-                                            };
+                                        match state.is_updated(&user, &version).await {
+                                            Ok(true) => {
+                                                let status = ResopnseServerToClient::Updated;
+                                                if let Err(e) =  session.text(serde_json::to_string(&status).unwrap()).await{
+                                                    debug!("Unable to send response {}",e);
+                                                };
+                                            }
+                                            Ok(false) => {
+                                                let status = ResopnseServerToClient::Outdated;
+                                                if let Err(e) =  session.text(serde_json::to_string(&status).unwrap()).await{
+                                                    debug!("Unable to send response {}",e);
+                                                };
+                                            }
+                                            // the client asks again on its next check
+                                            Err(e) => error!("{}", e),
                                         }
                                     }
                                     ResopnseClientToServer::CheckVersionArr(version)  =>{
-                                         match state.get(&user, &version) {
+                                         match state.get(&user, &version).await {
                                             Some(data)=> {
                                                 if data.is_empty() {
                                                     let status = ResopnseServerToClient::Updated;
@@ -68,12 +73,12 @@ pub async fn ws_connection(
                                                         debug!("Unable to send response {}",e);
                                                     };
                                                 } else {
-                                                    if let Err(e) = send_to_client(data, &mut session).await {
+                                                    if let Err(e) = send_to_client(data, state.blobs(), &user, &mut session).await {
                                                         debug!("Unable to send response {}",e);
                                                         break;
                                                     };
                                                 }
-                                                let data = ResopnseServerToClient::Remove(state.get_remove(&user));
+                                                let data = ResopnseServerToClient::Remove(state.get_remove(&user).await);
                                                 if let Err(e) = session.text(serde_json::to_string(&data).unwrap()).await{
                                                     debug!("Unable to send response {}",e);
                                                 };
@@ -86,13 +91,27 @@ pub async fn ws_connection(
                                             },
                                         };
                                     }
-                                    ResopnseClientToServer::Data{data,id,last,is_it_edit} => {
-                                        handle_bin(&user, &state, &tx, &mut session, data, id, last, &mut old, is_it_edit).await;
+                                    ResopnseClientToServer::Sync(cursor) => {
+                                        delta = true;
+                                        match send_changes(&state, &user, &mut session, cursor.as_ref()).await {
+                                            Ok(Some(cursor)) => sent = Some(cursor),
+                                            Ok(None) => {
+                                                error!("Unable to identify user {}", user);
+                                                break;
+                                            }
+                                            Err(e) => {
+                                                debug!("Unable to send response {}",e);
+                                                break;
+                                            }
+                                        }
+                                    }
+                                    ResopnseClientToServer::Data{data,id,last: _,is_it_edit} => {
+                                        handle_bin(&user, &state, &mut session, data, id, conn, is_it_edit).await;
                                     },
                                     ResopnseClientToServer::Remove(id) => {
-                                        state.remove_and_add_edit(&user, &id).unwrap();
-                                        old = false;
-                                        tx.send(MessageMPC::Remove(id)).unwrap();
+                                        if let Err(e) = state.publish(&user, conn, MessageMPC::Remove(id), 0).await {
+                                            error!("Unable to remove entry: {}", e);
+                                        };
                                     },
                                     _ => {}
                                 }
@@ -120,8 +139,25 @@ pub async fn ws_connection(
 
             result = rx.recv() => {
                 match result {
-                    Ok(val) => {
-                        if old {
+                    // events may have been lost, the client gets what it misses
+                    Ok(RoomEvent::Resync) if delta => {
+                        match send_changes(&state, &user, &mut session, sent.as_ref()).await {
+                            Ok(Some(cursor)) => sent = Some(cursor),
+                            Ok(None) => error!("Unable to identify user {}", user),
+                            Err(e) => {
+                                debug!("Unable to send response {}",e);
+                                break;
+                            }
+                        }
+                    }
+                    Ok(RoomEvent::Resync) => {
+                        let status = ResopnseServerToClient::Outdated;
+                        if let Err(e) =  session.text(serde_json::to_string(&status).unwrap()).await {
+                            debug!("Unable to send response {}",e);
+                        };
+                    }
+                    Ok(RoomEvent::Change(SyncEvent { cursor, origin, msg: val })) => {
+                        if origin != conn {
                             match val {
                                 MessageMPC::Remove(id) => {
                                     let mut vec = VecDeque::new();
@@ -132,13 +168,7 @@ pub async fn ws_connection(
                                     };
                                 },
                                 MessageMPC::New(id) =>{
-                                    let path = format!("{}/{}/{}", DATABASE_PATH, user, id);
-                                    if let Ok(mut file) = File::open(path){
-                                        let mut buf = String::new();
-                                            if let Err(e) = file.read_to_string(&mut buf) {
-                                                error!("{}", e);
-                                                continue;
-                                            };
+                                    if let Some(buf) = read_entry(state.blobs(), &user, &id).await {
                                         let status = ResopnseServerToClient::Data { data: buf, is_it_last: true, new_id: id };
                                         if let Err(e) =  session.text(serde_json::to_string(&status).unwrap()).await {
                                             debug!("Unable to send response {}",e);
@@ -146,13 +176,7 @@ pub async fn ws_connection(
                                     }
                                 },
                                 MessageMPC::Edit{old_id, new_id} => {
-                                    let path = format!("{}/{}/{}", DATABASE_PATH, user, new_id);
-                                    if let Ok(mut file) = File::open(path){
-                                        let mut buf = String::new();
-                                            if let Err(e) = file.read_to_string(&mut buf) {
-                                                error!("{}", e);
-                                                continue;
-                                            };
+                                    if let Some(buf) = read_entry(state.blobs(), &user, &new_id).await {
                                         let status = ResopnseServerToClient::EditReplace { data: buf, is_it_last: true, old_id, new_id };
                                         if let Err(e) =  session.text(serde_json::to_string(&status).unwrap()).await {
                                             debug!("Unable to send response {}",e);
@@ -163,7 +187,15 @@ pub async fn ws_connection(
                             }
 
                         }
-                        old = true;
+                        if delta {
+                            if sent.as_ref().is_none_or(|sent| sent.epoch != cursor.epoch || sent.seq < cursor.seq) {
+                                sent = Some(cursor.clone());
+                            }
+                            let status = ResopnseServerToClient::Cursor(cursor);
+                            if let Err(e) =  session.text(serde_json::to_string(&status).unwrap()).await {
+                                debug!("Unable to send response {}",e);
+                            };
+                        }
                     }
                     Err(e) => {
                         error!("Broadcast receive error: {e}");
@@ -188,77 +220,150 @@ pub async fn ws_connection(
 async fn handle_bin(
     user: &str,
     state: &actix_web::web::Data<UserState>,
-    tx: &Sender<MessageMPC>,
     session: &mut Session,
     data: String,
     id: String,
-    last: bool,
-    old: &mut bool,
+    conn: Uuid,
     is_it_edit: Option<String>,
 ) {
-    let mut path: PathBuf = PathBuf::new().join(format!("{}/{}/", DATABASE_PATH, user));
-    match std::fs::create_dir_all(&path) {
-        Ok(_) => {}
-        Err(e) => error!("unable to create user dir {}", e),
-    };
-    let file_name = get_filename(Utc::now().timestamp(), path.clone());
-    path.push(&file_name);
-    let mut file = File::create(&path).unwrap();
-    file.write_all(data.as_bytes()).unwrap();
-    state.update(&user, &file_name);
+    if let Err(error) = state.limits(user).await.check(data.len()) {
+        debug!("refused entry {} of {}: {}", id, user, error);
+        let status = ResopnseServerToClient::Rejected { old: id, error };
+        if let Err(e) = session.text(serde_json::to_string(&status).unwrap()).await {
+            debug!("Unable to send response {}", e);
+        }
+        return;
+    }
+    let file_name =
+        match store_entry(state.blobs(), user, Utc::now().timestamp(), data.as_bytes()).await {
+            Ok(val) => val,
+            Err(e) => {
+                error!("unable to store entry of {}: {}", user, e);
+                send_failed(session, id).await;
+                return;
+            }
+        };
     debug!("Saved file: {id}");
-    if let Some(edit) = is_it_edit {
-        *old = false;
-        let message = MessageMPC::Edit {
+    // every change is published, a client that skipped one would move its cursor past it
+    let message = if is_it_edit.is_some() {
+        debug!("edit => old if: {}| new id: {}", id, file_name);
+        MessageMPC::Edit {
             old_id: id.clone(),
             new_id: file_name.clone(),
-        };
-        state.remove_and_add_edit(&user, &id).unwrap();
-        debug!("edit => old if: {}| new id: {}", id, file_name);
-        if let Err(e) = tx.send(message) {
-            error!("error sending state: {}", e);
-        };
-    } else if last {
-        *old = false;
-        if let Err(e) = tx.send(MessageMPC::New(file_name.clone())) {
-            error!("error sending state: {}", e);
-        };
-    }
+        }
+    } else {
+        MessageMPC::New(file_name.clone())
+    };
+    // an entry that is not in the change log never reaches the other devices, the
+    // client keeps it and sends it again
+    if let Err(e) = state.publish(user, conn, message, data.len()).await {
+        error!("error sending state: {}", e);
+        if let Err(e) = state.blobs().delete(user, &file_name).await {
+            error!("unable to remove entry {} of {}: {}", file_name, user, e);
+        }
+        send_failed(session, id).await;
+        return;
+    };
     let file: ResopnseServerToClient = ResopnseServerToClient::Success {
         old: id.to_string(),
         new: Some(file_name),
     };
-    let file_str = serde_json::to_string(&file).unwrap();
-    session.text(file_str).await.unwrap();
+    if let Err(e) = session.text(serde_json::to_string(&file).unwrap()).await {
+        debug!("Unable to send response {}", e);
+    }
+}
+
+// the details stay in the log of the server
+async fn send_failed(session: &mut Session, old: String) {
+    let status = ResopnseServerToClient::Failed {
+        old,
+        error: String::from("the server was unable to store the entry"),
+    };
+    if let Err(e) = session.text(serde_json::to_string(&status).unwrap()).await {
+        debug!("Unable to send response {}", e);
+    }
+}
+
+async fn read_entry(store: &dyn BlobStore, user: &str, id: &str) -> Option<String> {
+    match store.get(user, id).await {
+        Ok(Some(data)) => match String::from_utf8(data) {
+            Ok(val) => Some(val),
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        },
+        Ok(None) => {
+            error!("entry {} of {} is missing", id, user);
+            None
+        }
+        Err(e) => {
+            error!("{}", e);
+            None
+        }
+    }
+}
+
+// sends the changes after `since` and the cursor they lead to, `None` for an unknown user
+async fn send_changes(
+    state: &UserState,
+    user: &str,
+    session: &mut Session,
+    since: Option<&SyncCursor>,
+) -> Result<Option<SyncCursor>, actix_ws::Closed> {
+    let Some(Changes {
+        data,
+        removed,
+        cursor,
+        resync,
+    }) = state.changes(user, since).await
+    else {
+        return Ok(None);
+    };
+    if let Some(removed_after) = resync {
+        let status = ResopnseServerToClient::Resync {
+            live: data.clone(),
+            removed_after,
+        };
+        session
+            .text(serde_json::to_string(&status).unwrap())
+            .await?;
+    }
+    if !data.is_empty() {
+        send_to_client(data, state.blobs(), user, session).await?;
+    }
+    if !removed.is_empty() {
+        let status = ResopnseServerToClient::Remove(removed.into());
+        session
+            .text(serde_json::to_string(&status).unwrap())
+            .await?;
+    }
+    let status = ResopnseServerToClient::Cursor(cursor.clone());
+    session
+        .text(serde_json::to_string(&status).unwrap())
+        .await?;
+    Ok(Some(cursor))
 }
 
 async fn send_to_client(
-    data: Vec<(String, String)>,
+    data: Vec<String>,
+    store: &dyn BlobStore,
+    user: &str,
     session: &mut Session,
 ) -> Result<(), actix_ws::Closed> {
-    for (i, (path, new_id)) in data.iter().enumerate() {
-        match File::open(&path) {
-            Ok(mut va) => {
-                let mut buf = String::new();
-                if let Err(e) = va.read_to_string(&mut buf) {
-                    error!("{}", e);
-                    continue;
-                };
-                session
-                    .text(
-                        ResopnseServerToClient::Data {
-                            data: buf,
-                            is_it_last: (i == data.len() - 1),
-                            new_id: new_id.to_string(),
-                        }
-                        .to_bytestring()
-                        .unwrap(),
-                    )
-                    .await?;
-            }
-            Err(e) => {
-                error!("{}", e)
-            }
+    for (i, new_id) in data.iter().enumerate() {
+        if let Some(buf) = read_entry(store, user, new_id).await {
+            session
+                .text(
+                    ResopnseServerToClient::Data {
+                        data: buf,
+                        is_it_last: (i == data.len() - 1),
+                        new_id: new_id.to_string(),
+                    }
+                    .to_bytestring()
+                    .unwrap(),
+                )
+                .await?;
         }
     }
     Ok(())
@@ -0,0 +1,298 @@
+use crate::lan::LanState;
+use crate::protocol::{IpcError, Reply};
+use crate::storage::{Entry, Location, storage};
+use crate::{Data, MessageChannel, UserSettings, device_name, pairing, paths, remove_entry};
+use base64::{Engine, engine::general_purpose};
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::{self, Write};
+use tokio::sync::mpsc::Sender;
+
+#[derive(Parser)]
+#[command(name = "clippy", version, about = "Clipboard manager with sync")]
+pub struct Cli {
+    /// Use a separate profile with its own entries, settings and sync account
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Without a subcommand clippy starts the daemon, or opens the gui if it is already running.
+#[derive(Subcommand)]
+pub enum Command {
+    /// List the newest entries
+    List {
+        #[arg(short, long, default_value_t = 20)]
+        limit: usize,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print an entry, images are written as raw bytes
+    Get {
+        id: i64,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Copy an entry to the clipboard
+    Copy { id: i64 },
+    /// Pin an entry
+    Pin { id: i64 },
+    /// Unpin an entry
+    Unpin { id: i64 },
+    /// Delete an entry
+    Rm { id: i64 },
+    /// Add a text entry read from stdin
+    Add,
+    /// Print new entries as they are copied, one json object per line
+    Watch,
+    /// Pair with a device on the same network for network sync, run it without a code
+    /// on one device and with the code it shows on the other
+    Pair { code: Option<String> },
+    /// List the devices paired for network sync
+    Peers,
+    /// Forget a paired device
+    Unpair { device: String },
+    /// List the profiles
+    Profiles,
+}
+
+/// Requests sent by the command line to the running daemon.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum CliRequest {
+    List { limit: usize },
+    Get(i64),
+    Copy(i64),
+    Pin(i64, bool),
+    Remove(i64),
+    Add(String),
+    Watch,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EntryInfo {
+    pub id: i64,
+    pub typ: String,
+    pub device: String,
+    // the application the copy came from, when it is known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    pub pined: bool,
+    pub synced: bool,
+    // base64 for images
+    pub data: String,
+}
+
+impl From<Entry> for EntryInfo {
+    fn from(entry: Entry) -> Self {
+        Self {
+            id: entry.id,
+            synced: entry.location != Location::Pending,
+            app: entry.data.source().map(|val| val.name().to_string()),
+            typ: entry.data.typ,
+            device: entry.data.device,
+            pined: entry.data.pined,
+            data: entry.data.data,
+        }
+    }
+}
+
+fn get_entry(id: i64) -> Result<Entry, Box<dyn Error>> {
+    storage()
+        .get(id)?
+        .ok_or_else(|| format!("No entry with id {}", id).into())
+}
+
+/// Runs a request on the daemon side, `Watch` is handled by the ipc listener.
+pub fn handle_request(request: CliRequest, tx: &Sender<MessageChannel>) -> Result<Reply, IpcError> {
+    response(request, tx).map_err(|e| IpcError::Failed(e.to_string()))
+}
+
+fn response(request: CliRequest, tx: &Sender<MessageChannel>) -> Result<Reply, Box<dyn Error>> {
+    Ok(match request {
+        CliRequest::List { limit } => {
+            let mut entries = Vec::new();
+            for (id, _) in storage().list()?.into_iter().take(limit) {
+                if let Some(entry) = storage().get(id)? {
+                    entries.push(entry.into());
+                }
+            }
+            Reply::Entries(entries)
+        }
+        CliRequest::Get(id) => Reply::Entry(get_entry(id)?.into()),
+        CliRequest::Copy(id) => {
+            let data = get_entry(id)?.data;
+            #[cfg(target_family = "unix")]
+            crate::write_clipboard::copy_to_unix(data, false)?;
+            #[cfg(target_os = "windows")]
+            crate::write_clipboard::copy_to_clipboard(data, false).map_err(|e| e.to_string())?;
+            Reply::Done
+        }
+        CliRequest::Pin(id, pined) => {
+            let mut data = get_entry(id)?.data;
+            data.pined = pined;
+            Reply::Id(data.replace(tx, id)?)
+        }
+        CliRequest::Remove(id) => {
+            get_entry(id)?;
+            remove_entry(tx, id)?;
+            Reply::Done
+        }
+        CliRequest::Add(text) => {
+            let data = Data::new(
+                text,
+                "text/plain;charset=utf-8".to_string(),
+                device_name(),
+                false,
+            );
+            Reply::Id(data.write_pending(tx, None)?)
+        }
+        CliRequest::Watch => return Err("watch is not a single request".into()),
+    })
+}
+
+fn preview(entry: &EntryInfo) -> String {
+    if entry.typ.starts_with("image/") {
+        return format!("[{}]", entry.typ);
+    }
+    let line = entry.data.lines().next().unwrap_or_default().trim();
+    if line.chars().count() > 60 {
+        format!("{}..", line.chars().take(60).collect::<String>())
+    } else {
+        line.to_string()
+    }
+}
+
+// the daemon reloads the paired devices, it may not be running
+fn notify_daemon() {
+    #[cfg(target_family = "unix")]
+    if let Err(e) =
+        crate::ipc::ipc::connect().and_then(|mut client| client.request(crate::MessageIPC::Updated))
+    {
+        log::debug!("unable to notify the daemon: {}", e);
+    }
+}
+
+fn pair(code: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let mut state = LanState::load()?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    let peer = match code {
+        Some(code) => runtime.block_on(pairing::join(&state, code.trim()))?,
+        None => {
+            let code = pairing::new_code();
+            println!("Enter this code on the other device: clippy pair {}", code);
+            runtime.block_on(pairing::host(&state, &code))?
+        }
+    };
+    println!("Paired with {} ({})", peer.name, peer.device);
+    state.add_peer(peer);
+    state.save()?;
+    if !UserSettings::build_user().is_ok_and(|val| val.lan_sync) {
+        println!("Turn on network sync in the settings to start syncing");
+    }
+    notify_daemon();
+    Ok(())
+}
+
+fn peers() -> Result<(), Box<dyn Error>> {
+    let state = LanState::load()?;
+    let mut stdout = io::stdout();
+    writeln!(stdout, "this device\t{}\t{}", state.device, state.name)?;
+    for peer in state.peers {
+        writeln!(stdout, "paired\t{}\t{}", peer.device, peer.name)?;
+    }
+    Ok(())
+}
+
+fn unpair(device: &str) -> Result<(), Box<dyn Error>> {
+    let mut state = LanState::load()?;
+    if !state.remove_peer(device) {
+        return Err(format!("No paired device {}", device).into());
+    }
+    state.save()?;
+    notify_daemon();
+    Ok(())
+}
+
+fn profiles() -> Result<(), Box<dyn Error>> {
+    let current = paths::profile();
+    let mut stdout = io::stdout();
+    let mark = |selected: bool| if selected { "*" } else { " " };
+    writeln!(stdout, "{} default", mark(current.is_none()))?;
+    for name in paths::profiles() {
+        writeln!(stdout, "{} {}", mark(current == Some(name.as_str())), name)?;
+    }
+    Ok(())
+}
+
+/// Sends a subcommand to the running daemon and prints the result.
+pub fn run(command: Command) -> Result<(), Box<dyn Error>> {
+    let request = match &command {
+        Command::List { limit, .. } => CliRequest::List { limit: *limit },
+        Command::Get { id, .. } => CliRequest::Get(*id),
+        Command::Copy { id } => CliRequest::Copy(*id),
+        Command::Pin { id } => CliRequest::Pin(*id, true),
+        Command::Unpin { id } => CliRequest::Pin(*id, false),
+        Command::Rm { id } => CliRequest::Remove(*id),
+        Command::Add => {
+            let mut text = String::new();
+            io::Read::read_to_string(&mut io::stdin(), &mut text)?;
+            CliRequest::Add(text)
+        }
+        Command::Watch => CliRequest::Watch,
+        // paired devices are stored by the command itself
+        Command::Pair { code } => return pair(code.as_deref()),
+        Command::Peers => return peers(),
+        Command::Unpair { device } => return unpair(device),
+        Command::Profiles => return profiles(),
+    };
+
+    #[cfg(not(target_family = "unix"))]
+    {
+        let _ = request;
+        return Err("The command line interface is only supported on Unix".into());
+    }
+
+    #[cfg(target_family = "unix")]
+    {
+        let mut client = crate::ipc::ipc::connect()?;
+        let mut stdout = io::stdout();
+
+        if let Command::Watch = command {
+            let id = client.send(crate::MessageIPC::Cli(request))?;
+            loop {
+                if let Reply::Entry(entry) = client.recv(id)? {
+                    writeln!(stdout, "{}", serde_json::to_string(&entry)?)?;
+                    stdout.flush()?;
+                }
+            }
+        }
+
+        match (command, client.request(crate::MessageIPC::Cli(request))?) {
+            (Command::List { json: true, .. }, Reply::Entries(entries)) => {
+                writeln!(stdout, "{}", serde_json::to_string_pretty(&entries)?)?;
+            }
+            (Command::List { .. }, Reply::Entries(entries)) => {
+                for entry in entries {
+                    let pin = if entry.pined { "📌" } else { "" };
+                    writeln!(stdout, "{}\t{}{}", entry.id, pin, preview(&entry))?;
+                }
+            }
+            (Command::Get { json: true, .. }, Reply::Entry(entry)) => {
+                writeln!(stdout, "{}", serde_json::to_string_pretty(&entry)?)?;
+            }
+            (Command::Get { .. }, Reply::Entry(entry)) => {
+                if entry.typ.starts_with("image/") {
+                    stdout.write_all(&general_purpose::STANDARD.decode(&entry.data)?)?;
+                } else {
+                    stdout.write_all(entry.data.as_bytes())?;
+                }
+            }
+            (_, Reply::Id(id)) => writeln!(stdout, "{}", id)?,
+            (_, Reply::Done) => {}
+            _ => return Err("Unexpected response from clippy".into()),
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,12 @@
 use aes_gcm::aead::{Aead, KeyInit, OsRng, rand_core::RngCore};
 use aes_gcm::{Aes256Gcm, Error, Nonce};
+use argon2::Argon2;
+use base64::{Engine, engine::general_purpose};
+
+use crate::KeyCheck;
+
+// plaintext of the key-verification record, a wrong passphrase fails to decrypt it
+const KEY_CHECK: &[u8] = b"clippy-key-check";
 
 pub fn encrept_file(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
     let key = aes_gcm::Key::<Aes256Gcm>::from_slice(key);
@@ -20,8 +27,68 @@ pub fn decrypt_file(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
 
     let cipher = Aes256Gcm::new(key);
 
+    if data.len() < 12 {
+        return Err(Error);
+    }
     let (nonce_bytes, ciphertext) = data.split_at(12);
     let nonce = Nonce::from_slice(nonce_bytes);
 
     Ok(cipher.decrypt(nonce, ciphertext)?)
 }
+
+/// Key used to encrypt clipboard entries before they are sent to the server.
+/// It is derived from the user passphrase with Argon2id, so the server never sees it.
+pub struct SyncKey([u8; 32]);
+
+impl SyncKey {
+    fn derive(passphrase: &str, salt: &[u8]) -> Result<Self, argon2::Error> {
+        let mut key = [0u8; 32];
+        Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key)?;
+        Ok(Self(key))
+    }
+
+    /// Derives a key with a new random salt and builds the verification record
+    /// that other devices use to check their passphrase.
+    pub fn generate(passphrase: &str) -> Result<(Self, KeyCheck), String> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let key = Self::derive(passphrase, &salt).map_err(|e| e.to_string())?;
+        let check = encrept_file(&key.0, KEY_CHECK).map_err(|e| e.to_string())?;
+
+        let record = KeyCheck {
+            salt: general_purpose::STANDARD.encode(salt),
+            check: general_purpose::STANDARD.encode(check),
+        };
+        Ok((key, record))
+    }
+
+    /// Derives the key from the passphrase and the salt stored in the record,
+    /// fails if the passphrase does not match the one used to create it.
+    pub fn verify(passphrase: &str, record: &KeyCheck) -> Result<Self, String> {
+        let salt = general_purpose::STANDARD
+            .decode(&record.salt)
+            .map_err(|e| e.to_string())?;
+        let check = general_purpose::STANDARD
+            .decode(&record.check)
+            .map_err(|e| e.to_string())?;
+        let key = Self::derive(passphrase, &salt).map_err(|e| e.to_string())?;
+
+        match decrypt_file(&key.0, &check) {
+            Ok(val) if val == KEY_CHECK => Ok(key),
+            _ => Err("Wrong encryption passphrase".to_string()),
+        }
+    }
+
+    pub fn seal(&self, data: &str) -> Result<String, String> {
+        let data = encrept_file(&self.0, data.as_bytes()).map_err(|e| e.to_string())?;
+        Ok(general_purpose::STANDARD.encode(data))
+    }
+
+    pub fn open(&self, data: &str) -> Result<String, String> {
+        let data = general_purpose::STANDARD
+            .decode(data)
+            .map_err(|e| e.to_string())?;
+        let data = decrypt_file(&self.0, &data).map_err(|e| e.to_string())?;
+        String::from_utf8(data).map_err(|e| e.to_string())
+    }
+}
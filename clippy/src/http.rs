@@ -1,22 +1,38 @@
-use crate::{MessageChannel, UserCred, UserData, UserSettings};
+use crate::protocol::{IpcError, Reply};
+use crate::{
+    AccountRequest, EmailChange, KeyCheck, MessageChannel, RefreshRequest, SessionInfo, Tokens,
+    UserData, UserSettings, store_email, store_refresh_token, stored_account,
+};
 use core::time;
 use log::{debug, error, warn};
 use once_cell::sync::Lazy;
-use reqwest::{self, Client, multipart};
-use std::{error::Error, process, sync::Mutex, thread, time::Duration};
-use tokio::{fs::File, io::AsyncReadExt, sync::mpsc::Receiver};
+use reqwest::{self, Client, RequestBuilder, Response, StatusCode, multipart};
+use std::{error::Error, fmt, sync::Mutex, thread, time::Duration};
+use tokio::{
+    fs::File,
+    io::AsyncReadExt,
+    sync::mpsc::{Receiver, Sender},
+};
 
+// server used until the user picks another one in settings
 #[cfg(debug_assertions)]
-pub const SERVER: &str = "http://192.168.1.240:7777";
+pub const DEFAULT_SERVER: &str = "http://192.168.1.240:7777";
 
 #[cfg(not(debug_assertions))]
-pub const SERVER: &str = "https://clippy.dhanu.cloud";
+pub const DEFAULT_SERVER: &str = "https://clippy.dhanu.cloud";
 
-#[cfg(debug_assertions)]
-pub const SERVER_WS: &str = "ws://192.168.1.240:7777/connect";
-
-#[cfg(not(debug_assertions))]
-pub const SERVER_WS: &str = "wss://clippy.dhanu.cloud/connect";
+/// The websocket endpoint of the server at `server`.
+pub fn ws_url(server: &str) -> String {
+    let server = server.trim_end_matches('/');
+    let url = if let Some(host) = server.strip_prefix("https://") {
+        format!("wss://{}", host)
+    } else if let Some(host) = server.strip_prefix("http://") {
+        format!("ws://{}", host)
+    } else {
+        server.to_string()
+    };
+    format!("{}/connect", url)
+}
 
 static TOKEN: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::new()));
 
@@ -31,8 +47,8 @@ pub fn get_token() -> String {
 }
 
 pub async fn send(
+    server: &str,
     file_path: &str,
-    usercred: &UserCred,
     client: &Client,
 ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     let mut file = File::open(file_path).await?;
@@ -43,7 +59,7 @@ pub async fn send(
     let form = multipart::Form::new().part("file", part);
 
     let response = client
-        .post(&format!("{}/update", SERVER))
+        .post(format!("{}/update", server))
         .bearer_auth(get_token())
         .multipart(form)
         .send()
@@ -55,7 +71,7 @@ pub async fn send(
         if response.status() == reqwest::StatusCode::UNAUTHORIZED {
             warn!("Token expired");
 
-            match get_token_serv(usercred, client).await {
+            match get_token_serv(server, client).await {
                 Ok(_) => debug!("Fetched a new authentication token"),
                 Err(err) => {
                     warn!("Unable to fetch authentication token");
@@ -67,42 +83,98 @@ pub async fn send(
     }
 }
 
-pub async fn get_token_serv(user: &UserCred, client: &Client) -> Result<(), Box<dyn Error>> {
+/// The server refused the refresh token, the account has to log in again.
+#[derive(Debug)]
+pub struct LoggedOut;
+
+impl fmt::Display for LoggedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the session was ended, log in again")
+    }
+}
+
+impl Error for LoggedOut {}
+
+// a refresh token can be traded once, a second trade revokes the session
+static REFRESH: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+pub async fn get_token_serv(server: &str, client: &Client) -> Result<(), Box<dyn Error>> {
+    let _refresh = REFRESH.lock().await;
+    let user = stored_account(server)?.ok_or("Not logged in")?;
     let response = client
-        .get(format!("{}/getkey", SERVER))
-        .json(&user)
+        .post(format!("{}/token", server))
+        .json(&RefreshRequest {
+            refresh_token: user.refresh_token,
+        })
         .send()
         .await?;
 
     if response.status().is_success() {
-        let token = response.text().await?;
-        update_token(token);
+        let tokens: Tokens = response.json().await?;
+        update_token(tokens.access_token);
+        store_refresh_token(server, tokens.refresh_token)?;
         Ok(())
     } else {
+        // the sync loop logs the account out
         if response.status() == reqwest::StatusCode::UNAUTHORIZED {
-            let mut user = UserSettings::build_user().unwrap();
-            user.remove_user();
-            user.write_local().unwrap();
-            error!(
-                "Unable to verify credentials, logging out. {:?}",
-                response.text().await
-            );
-            process::exit(1);
+            debug!("refresh refused: {:?}", response.text().await);
+            return Err(LoggedOut.into());
         }
         let err_msg = response.text().await?;
         Err(format!("Login failed: {}", err_msg).into())
     }
 }
 
+pub async fn get_key_check(
+    server: &str,
+    client: &Client,
+) -> Result<Option<KeyCheck>, Box<dyn Error>> {
+    let response = client
+        .get(format!("{}/keycheck", server))
+        .bearer_auth(get_token())
+        .send()
+        .await?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        Ok(None)
+    } else if response.status().is_success() {
+        Ok(Some(response.json().await?))
+    } else {
+        Err(format!("Unable to get key check: {}", response.text().await?).into())
+    }
+}
+
+pub async fn set_key_check(
+    server: &str,
+    client: &Client,
+    check: &KeyCheck,
+) -> Result<(), Box<dyn Error>> {
+    let response = client
+        .post(format!("{}/keycheck", server))
+        .bearer_auth(get_token())
+        .json(check)
+        .send()
+        .await?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("Unable to store key check: {}", response.text().await?).into())
+    }
+}
+
+/// Waits for the server of `usersettings` to be reachable, returns true if the settings
+/// changed meanwhile, the server may then be another one.
 pub async fn health(
     client: &Client,
     rx: &mut Receiver<MessageChannel>,
     user_data: &UserData,
+    usersettings: &mut UserSettings,
 ) -> bool {
     let mut log = true;
     loop {
         let response = client
-            .get(format!("{}/health", SERVER))
+            .get(format!("{}/health", usersettings.server()))
             .timeout(Duration::from_secs(5))
             .send();
 
@@ -132,41 +204,197 @@ pub async fn health(
         }
         while let Ok(val) = rx.try_recv() {
             match val {
-                MessageChannel::New { path, time, typ } => {
-                    user_data
-                        .add_pending(
-                            time,
-                            crate::Edit::New {
-                                path: path.into(),
-                                typ,
-                            },
-                        )
-                        .await;
-                }
-                MessageChannel::Edit {
-                    old_id,
-                    new_id,
-                    typ,
-                    path,
-                } => {
-                    user_data
-                        .add_pending(
-                            old_id,
-                            crate::Edit::Edit {
-                                path: path.into(),
-                                typ,
-                                new_id,
-                            },
-                        )
-                        .await;
-                }
                 MessageChannel::SettingsChanged => {
-                    unimplemented!("to do")
-                }
-                MessageChannel::Remove(id) => {
-                    user_data.add_pending(id, crate::Edit::Remove).await;
+                    match UserSettings::build_user().and_then(|val| Ok(val.with_secrets()?)) {
+                        Ok(val) => {
+                            *usersettings = val;
+                            return true;
+                        }
+                        Err(e) => {
+                            error!("Unable to read settings");
+                            debug!("{}", e);
+                        }
+                    }
                 }
+                val => user_data.queue(val).await,
             }
         }
     }
 }
+
+// sends `request` with the access token, once more with a new one if it expired
+async fn authorized<F>(
+    server: &str,
+    client: &Client,
+    request: F,
+) -> Result<Response, Box<dyn Error>>
+where
+    F: Fn(String) -> RequestBuilder,
+{
+    let response = request(get_token()).send().await?;
+    if response.status() != StatusCode::UNAUTHORIZED {
+        return Ok(response);
+    }
+    get_token_serv(server, client).await?;
+    Ok(request(get_token()).send().await?)
+}
+
+/// Logins of the account on `server`.
+pub async fn sessions(server: &str, client: &Client) -> Result<Vec<SessionInfo>, Box<dyn Error>> {
+    let response = authorized(server, client, |token| {
+        client
+            .get(format!("{}/sessions", server))
+            .bearer_auth(token)
+    })
+    .await?;
+
+    if response.status().is_success() {
+        Ok(response.json().await?)
+    } else {
+        Err(response.text().await?.into())
+    }
+}
+
+/// Logs the account out on the device of the session `id`.
+pub async fn revoke_session(server: &str, id: &str, client: &Client) -> Result<(), Box<dyn Error>> {
+    let response = authorized(server, client, |token| {
+        client
+            .delete(format!("{}/sessions/{}", server, id))
+            .bearer_auth(token)
+    })
+    .await?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(response.text().await?.into())
+    }
+}
+
+/// Mails a one-time password to `email`, the account moves there once it is confirmed.
+pub async fn request_email_change(
+    server: &str,
+    email: &str,
+    client: &Client,
+) -> Result<(), Box<dyn Error>> {
+    let request = EmailChange {
+        email: email.to_string(),
+        otp: String::new(),
+    };
+    let response = authorized(server, client, |token| {
+        client
+            .post(format!("{}/email/change/request", server))
+            .bearer_auth(token)
+            .json(&request)
+    })
+    .await?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(response.text().await?.into())
+    }
+}
+
+/// Moves the account to the address of `change`.
+pub async fn confirm_email_change(
+    server: &str,
+    change: &EmailChange,
+    client: &Client,
+) -> Result<(), Box<dyn Error>> {
+    let response = authorized(server, client, |token| {
+        client
+            .post(format!("{}/email/change/confirm", server))
+            .bearer_auth(token)
+            .json(change)
+    })
+    .await?;
+
+    if response.status().is_success() {
+        store_email(server, change.email.clone())
+    } else {
+        Err(response.text().await?.into())
+    }
+}
+
+/// Ends the session of `refresh_token` on `server`.
+pub async fn end_session(
+    server: &str,
+    refresh_token: String,
+    client: &Client,
+) -> Result<(), Box<dyn Error>> {
+    let response = client
+        .post(format!("{}/logout", server))
+        .json(&RefreshRequest { refresh_token })
+        .send()
+        .await?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(response.text().await?.into())
+    }
+}
+
+// the account is removed right away, the server is told in the background
+fn logout(
+    mut settings: UserSettings,
+    tx: &Sender<MessageChannel>,
+) -> Result<Reply, Box<dyn Error>> {
+    let Some(user) = stored_account(settings.server())? else {
+        return Ok(Reply::Done);
+    };
+    settings.remove_user();
+    settings.write_local()?;
+    if let Err(e) = tx.try_send(MessageChannel::SettingsChanged) {
+        warn!("Unable to store Settings");
+        debug!("{}", e);
+    }
+
+    let server = settings.server().to_string();
+    thread::spawn(move || {
+        let result = tokio::runtime::Runtime::new()
+            .map_err(Box::from)
+            .and_then(|runtime| {
+                runtime.block_on(end_session(&server, user.refresh_token, &Client::new()))
+            });
+        if let Err(e) = result {
+            warn!("Unable to end the session on the server");
+            debug!("{}", e);
+        }
+    });
+    Ok(Reply::Done)
+}
+
+/// Serves an account request of the gui.
+pub fn account_request(
+    request: AccountRequest,
+    tx: &Sender<MessageChannel>,
+) -> Result<Reply, IpcError> {
+    let settings = UserSettings::build_user()
+        .map_err(|e| IpcError::Failed(e.to_string()))?
+        .with_secrets()?;
+    let server = settings.server().to_string();
+    let client = Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .map_err(|e| IpcError::Failed(e.to_string()))?;
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    match request {
+        AccountRequest::Sessions => runtime
+            .block_on(sessions(&server, &client))
+            .map(Reply::Sessions),
+        AccountRequest::Revoke(id) => runtime
+            .block_on(revoke_session(&server, &id, &client))
+            .map(|_| Reply::Done),
+        AccountRequest::Logout => logout(settings, tx),
+        AccountRequest::ChangeEmail(email) => runtime
+            .block_on(request_email_change(&server, &email, &client))
+            .map(|_| Reply::Done),
+        AccountRequest::ConfirmEmail(change) => runtime
+            .block_on(confirm_email_change(&server, &change, &client))
+            .map(|_| Reply::Done),
+    }
+    .map_err(|e| IpcError::Failed(e.to_string()))
+}
@@ -1,38 +1,47 @@
 #[cfg(target_family = "unix")]
 pub mod ipc {
+    use crate::cli::{CliRequest, EntryInfo, handle_request};
+    use crate::http::account_request;
+    use crate::protocol::{Client, IpcError, Reply, Server};
+    use crate::read_clipboard::reload_settings;
+    use crate::search::handle_search;
+    use crate::storage::storage;
     use crate::write_clipboard::copy_to_unix;
-    use crate::{
-        API_KEY, GUI_BIN, MessageChannel, MessageIPC, get_image_path, get_path_local, log_error,
-    };
+    use crate::{GUI_BIN, MessageChannel, MessageIPC, paths, remove_entry, subscribe_new_entries};
     use log::{debug, error, warn};
-    use serde_json::Deserializer;
+    use std::error::Error;
     use std::fs::File;
-    use std::io::{BufReader, Error, Read};
     use std::os::fd::{FromRawFd, IntoRawFd};
     use std::process::{Command, Stdio};
     use std::sync::{Arc, Mutex};
-    use std::thread;
-    use std::{env, fs, io::Write, process};
+    use std::thread::{self, JoinHandle};
+    use std::{env, fs, process};
     use std::{
         io,
         os::unix::net::{UnixListener, UnixStream},
     };
+    use tokio::sync::broadcast::error::RecvError;
     use tokio::sync::mpsc::Sender;
 
+    type GuiHandle = Arc<Mutex<Option<JoinHandle<()>>>>;
+
     pub fn startup() -> Result<UnixListener, std::io::Error> {
-        let mut path = get_path_local();
-        path.push(".LOCK");
+        let path = paths::lock_socket();
         if let Err(e) = File::create(&path) {
             debug!("{}", e);
         }
         match UnixStream::connect(&path) {
-            Ok(mut stream) => {
+            Ok(stream) => {
                 if env::var("CLIPPY_SERVICE").is_ok() {
                     eprintln!("Another Clippy service is already running. Please stop it first.");
                     process::exit(1);
                 } else {
-                    let msg = serde_json::to_vec(&MessageIPC::OpentGUI)?;
-                    stream.write_all(&msg)?;
+                    let result = Client::connect(stream)
+                        .and_then(|mut client| client.request(MessageIPC::OpentGUI));
+                    if let Err(e) = result {
+                        eprintln!("Unable to open the running Clippy: {}", e);
+                        process::exit(1);
+                    }
                     process::exit(0);
                 }
             }
@@ -43,108 +52,163 @@ pub mod ipc {
         UnixListener::bind(&path)
     }
 
+    fn gui_request(msg: MessageIPC, tx: &Sender<MessageChannel>) -> Result<Reply, IpcError> {
+        match msg {
+            MessageIPC::Paste(data, paste_on_click) => {
+                copy_to_unix(data, paste_on_click).map_err(IpcError::Failed)?;
+            }
+            MessageIPC::Updated => {
+                reload_settings();
+                if let Err(e) = tx.try_send(MessageChannel::SettingsChanged) {
+                    error!("Unable to send modification");
+                    debug!("{}", e);
+                };
+            }
+            MessageIPC::New(data) => return Ok(Reply::Id(data.write_pending(tx, None)?)),
+            MessageIPC::UpdateSettings(settings) => {
+                settings
+                    .write_local()
+                    .map_err(|e| IpcError::Failed(format!("Unable to store Settings: {}", e)))?;
+                reload_settings();
+                if let Err(e) = tx.try_send(MessageChannel::SettingsChanged) {
+                    warn!("Unable to store Settings");
+                    debug!("{}", e);
+                };
+            }
+            MessageIPC::Edit(data) => return Ok(Reply::Id(data.data.replace(tx, data.id)?)),
+            MessageIPC::Delete(id) => remove_entry(tx, id)?,
+            MessageIPC::Cli(CliRequest::Watch) => return Err(IpcError::Unsupported),
+            MessageIPC::Cli(request) => return handle_request(request, tx),
+            MessageIPC::Account(request) => return account_request(request, tx),
+            MessageIPC::Search(query) => return handle_search(&query),
+            MessageIPC::None | MessageIPC::OpentGUI | MessageIPC::Close => {
+                return Err(IpcError::Unsupported);
+            }
+        }
+        Ok(Reply::Done)
+    }
+
     fn start_gui(tx: &Sender<MessageChannel>) -> Result<(), io::Error> {
-        let (parent, child) = UnixStream::pair().unwrap();
+        let (parent, child) = UnixStream::pair()?;
         let child = child.into_raw_fd();
 
-        let mut process = Command::new(GUI_BIN)
+        let mut command = Command::new(GUI_BIN);
+        command
             .env("IPC", "0")
-            .env("KEY", API_KEY.unwrap())
             .stdin(unsafe { Stdio::from_raw_fd(child) })
             .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .spawn()?;
+            .stderr(Stdio::inherit());
+        if let Some(profile) = paths::profile() {
+            command.env(paths::PROFILE_ENV, profile);
+        }
+        let mut process = command.spawn()?;
 
-        let reader = BufReader::new(parent);
-        let stream = Deserializer::from_reader(reader).into_iter::<MessageIPC>();
+        if let Err(e) = serve_gui(parent, tx) {
+            warn!("Connection to clippy-gui lost");
+            debug!("{}", e);
+        }
 
-        for msg in stream {
-            if let Ok(val) = msg {
-                match val {
-                    MessageIPC::Paste(data, paste_on_click) => {
-                        if let Err(e) = copy_to_unix(data, paste_on_click) {
-                            error!("Unable to write clipboard");
-                            debug!("{}", e);
-                        };
-                    }
-                    MessageIPC::Updated => {
-                        if let Err(e) = tx.try_send(MessageChannel::SettingsChanged) {
-                            error!("Unable to send modification");
-                            debug!("{}", e);
-                        };
-                    }
-                    MessageIPC::New(data) => {
-                        let new_id = chrono::Utc::now().format("%Y-%m-%d_%H-%M-%S").to_string();
-                        data.write_to_json(tx, new_id).unwrap();
-                    }
-                    MessageIPC::UpdateSettings(settings) => {
-                        settings.write_local().unwrap();
-                        if let Err(e) = tx.try_send(MessageChannel::SettingsChanged) {
-                            warn!("Unable to store Settings");
-                            debug!("{}", e);
-                        };
-                    }
-                    MessageIPC::Edit(data) => {
-                        let new_id = chrono::Utc::now().format("%Y-%m-%d_%H-%M-%S").to_string();
-                        let old_id = data.id;
-                        let path = data.path;
-                        data.data.re_write_json(tx, new_id, old_id, path).unwrap();
-                    }
-                    MessageIPC::Delete(path, id) => {
-                        let img_path = get_image_path(&path);
-                        if let Some(path) = img_path {
-                            log_error!(fs::remove_file(path));
-                        }
-                        log_error!(fs::remove_file(path));
-                        log_error!(tx.try_send(MessageChannel::Remove(id)));
-                    }
-                    MessageIPC::Close => {
-                        break;
-                    }
-                    _ => {}
-                }
-            } else {
+        process.kill()
+    }
+
+    fn serve_gui(stream: UnixStream, tx: &Sender<MessageChannel>) -> Result<(), io::Error> {
+        let mut server = Server::accept(stream)?;
+        while let Some(request) = server.next_request()? {
+            if let MessageIPC::Close = request.body {
+                server.reply(request.id, Ok(Reply::Done))?;
                 break;
             }
+            server.reply(request.id, gui_request(request.body, tx))?;
         }
+        Ok(())
+    }
 
-        Ok(process.kill()?)
+    /// Connects to the running daemon for the command line.
+    pub fn connect() -> Result<Client<UnixStream>, Box<dyn Error>> {
+        let path = paths::lock_socket();
+        let stream = UnixStream::connect(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("clippy is not running: {}", e)))?;
+        Client::connect(stream)
     }
 
-    pub fn ipc_check(channel: UnixListener, rx: &Sender<MessageChannel>) -> Result<(), Error> {
-        let channel = channel;
-        let is_it_new = Arc::new(Mutex::new(None));
-        for i in channel.incoming() {
-            if let Ok(mut val) = i {
-                let mut buf = String::new();
-                val.read_to_string(&mut buf)?;
-                match serde_json::from_str(&buf)? {
-                    MessageIPC::OpentGUI => {
-                        let rx = rx.clone();
-                        if let Ok(mut guard) = is_it_new.lock() {
-                            if guard.is_none() {
-                                let is_it_new_clone = Arc::clone(&is_it_new);
-                                let rx_clone = rx.clone();
+    fn open_gui(tx: &Sender<MessageChannel>, is_it_new: &GuiHandle) {
+        if let Ok(mut guard) = is_it_new.lock()
+            && guard.is_none()
+        {
+            let is_it_new_clone = Arc::clone(is_it_new);
+            let rx_clone = tx.clone();
 
-                                let handle = thread::spawn(move || {
-                                    if let Err(e) = start_gui(&rx_clone) {
-                                        error!("Error opening clippy-gui: {}", e);
-                                    }
+            let handle = thread::spawn(move || {
+                if let Err(e) = start_gui(&rx_clone) {
+                    error!("Error opening clippy-gui: {}", e);
+                }
 
-                                    if let Ok(mut inner) = is_it_new_clone.lock() {
-                                        *inner = None;
-                                    }
-                                });
-                                *guard = Some(handle);
-                            }
-                        }
+                if let Ok(mut inner) = is_it_new_clone.lock() {
+                    *inner = None;
+                }
+            });
+            *guard = Some(handle);
+        }
+    }
+
+    fn handle_client(
+        stream: UnixStream,
+        tx: &Sender<MessageChannel>,
+        is_it_new: &GuiHandle,
+    ) -> Result<(), io::Error> {
+        let mut server = Server::accept(stream)?;
+        while let Some(request) = server.next_request()? {
+            let response = match request.body {
+                MessageIPC::OpentGUI => {
+                    open_gui(tx, is_it_new);
+                    Ok(Reply::Done)
+                }
+                MessageIPC::Cli(CliRequest::Watch) => return watch(server, request.id),
+                MessageIPC::Cli(request) => handle_request(request, tx),
+                _ => Err(IpcError::Unsupported),
+            };
+            server.reply(request.id, response)?;
+        }
+        Ok(())
+    }
+
+    fn watch(mut server: Server<UnixStream>, id: u64) -> Result<(), io::Error> {
+        let mut rx = subscribe_new_entries();
+        loop {
+            match rx.blocking_recv() {
+                Ok(entry_id) => {
+                    let Ok(Some(entry)) = storage().get(entry_id) else {
+                        continue;
+                    };
+                    if server
+                        .reply(id, Ok(Reply::Entry(EntryInfo::from(entry))))
+                        .is_err()
+                    {
+                        debug!("watch client disconnected");
+                        return Ok(());
                     }
-                    _ => {}
                 }
-            } else {
-                return Err(io::Error::other("Broken message"));
+                Err(RecvError::Lagged(count)) => warn!("watch client missed {} entries", count),
+                Err(RecvError::Closed) => return Ok(()),
             }
         }
+    }
+
+    pub fn ipc_check(channel: UnixListener, rx: &Sender<MessageChannel>) -> Result<(), io::Error> {
+        let is_it_new: GuiHandle = Arc::new(Mutex::new(None));
+        for i in channel.incoming() {
+            let Ok(val) = i else {
+                return Err(io::Error::other("Broken message"));
+            };
+            let rx = rx.clone();
+            let is_it_new = Arc::clone(&is_it_new);
+            thread::spawn(move || {
+                if let Err(e) = handle_client(val, &rx, &is_it_new) {
+                    warn!("Unable to handle ipc client");
+                    debug!("{}", e);
+                }
+            });
+        }
         Err(io::Error::other("channel ended"))
     }
 }
@@ -157,15 +221,22 @@ pub mod ipc {
     use log::{debug, error, warn};
     use rand::{Rng, distr::Alphanumeric};
     use std::{
-        env, fs,
-        io::{BufReader, Error, Read, Write},
+        env,
+        io::Error,
         process::{self, Stdio},
         thread,
     };
     use tokio::sync::mpsc::Sender;
 
     use crate::{
-        GUI_BIN, MessageChannel, MessageIPC, get_image_path, log_error,
+        GUI_BIN, MessageChannel, MessageIPC,
+        cli::{CliRequest, handle_request},
+        http::account_request,
+        paths,
+        protocol::{Client, IpcError, Reply, Server},
+        read_clipboard::reload_settings,
+        remove_entry,
+        search::handle_search,
         write_clipboard::copy_to_clipboard,
     };
     use std::{io, process::Command};
@@ -175,19 +246,21 @@ pub mod ipc {
     >;
 
     pub fn startup() -> Result<PipelistenerTyp, std::io::Error> {
-        let path = r"\\.\pipe\clippy";
-        match DuplexPipeStream::<pipe_mode::Bytes>::connect_by_path(path) {
+        let path = paths::lock_socket();
+        match DuplexPipeStream::<pipe_mode::Bytes>::connect_by_path(path.as_str()) {
             Ok(conn) => {
-                let mut stream = BufReader::new(conn);
                 if env::var("CLIPPY_SERVICE").is_ok() {
                     error!(
                         "Another Clippy service is already running. Please stop it before starting a new one."
                     );
                     process::exit(1)
                 } else {
-                    stream
-                        .get_mut()
-                        .write_all(&serde_json::to_vec(&MessageIPC::OpentGUI)?)?;
+                    let result = Client::connect(conn)
+                        .and_then(|mut client| client.request(MessageIPC::OpentGUI));
+                    if let Err(e) = result {
+                        error!("Unable to open the running Clippy: {}", e);
+                        process::exit(1)
+                    }
                     process::exit(0)
                 }
             }
@@ -201,6 +274,43 @@ pub mod ipc {
         }
     }
 
+    fn gui_request(msg: MessageIPC, tx: &Sender<MessageChannel>) -> Result<Reply, IpcError> {
+        match msg {
+            MessageIPC::Paste(data, paste_on_click) => {
+                copy_to_clipboard(data, paste_on_click)
+                    .map_err(|e| IpcError::Failed(e.to_string()))?;
+            }
+            MessageIPC::Updated => {
+                reload_settings();
+                if let Err(e) = tx.try_send(MessageChannel::SettingsChanged) {
+                    error!("Unable to send modification");
+                    debug!("{}", e);
+                };
+            }
+            MessageIPC::New(data) => return Ok(Reply::Id(data.write_pending(tx, None)?)),
+            MessageIPC::UpdateSettings(settings) => {
+                settings
+                    .write_local()
+                    .map_err(|e| IpcError::Failed(format!("Unable to store Settings: {}", e)))?;
+                reload_settings();
+                if let Err(e) = tx.try_send(MessageChannel::SettingsChanged) {
+                    error!("Unable to store Settings");
+                    debug!("{}", e);
+                };
+            }
+            MessageIPC::Edit(data) => return Ok(Reply::Id(data.data.replace(tx, data.id)?)),
+            MessageIPC::Delete(id) => remove_entry(tx, id)?,
+            MessageIPC::Cli(CliRequest::Watch) => return Err(IpcError::Unsupported),
+            MessageIPC::Cli(request) => return handle_request(request, tx),
+            MessageIPC::Account(request) => return account_request(request, tx),
+            MessageIPC::Search(query) => return handle_search(&query),
+            MessageIPC::None | MessageIPC::OpentGUI | MessageIPC::Close => {
+                return Err(IpcError::Unsupported);
+            }
+        }
+        Ok(Reply::Done)
+    }
+
     fn start_gui(tx: &Sender<MessageChannel>) -> Result<(), io::Error> {
         let random_str: String = rand::rng()
             .sample_iter(&Alphanumeric)
@@ -213,91 +323,71 @@ pub mod ipc {
             .path(path.clone())
             .create_duplex::<pipe_mode::Bytes>()?;
 
-        let mut process = Command::new(GUI_BIN)
+        let mut command = Command::new(GUI_BIN);
+        command
             .env("IPC", path)
             .stdout(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .spawn()?;
-        for i in listener.incoming() {
-            if let Ok(va) = i {
-                let mut reader = BufReader::new(va);
-                let mut buf = String::new();
-                reader.read_to_string(&mut buf)?;
-                let msg = serde_json::from_str(&buf);
-                if let Ok(val) = msg {
-                    match val {
-                        MessageIPC::Paste(data, paste_on_click) => {
-                            if let Err(e) = copy_to_clipboard(data, paste_on_click) {
-                                error!("Unable to write clipboard");
-                                debug!("{}", e);
-                            };
-                        }
-                        MessageIPC::Updated => {
-                            if let Err(e) = tx.try_send(MessageChannel::SettingsChanged) {
-                                error!("Unable to send modification");
-                                debug!("{}", e);
-                            };
-                        }
-                        MessageIPC::New(data) => {
-                            let time = chrono::Utc::now().format("%Y-%m-%d_%H-%M-%S").to_string();
-                            data.write_to_json(tx, time).unwrap();
-                        }
-                        MessageIPC::UpdateSettings(settings) => {
-                            settings.write_local().unwrap();
-                            if let Err(e) = tx.try_send(MessageChannel::SettingsChanged) {
-                                error!("Unable to store Settings");
-                                debug!("{}", e);
-                            };
-                        }
-                        MessageIPC::Edit(data) => {
-                            let time = chrono::Utc::now().format("%Y-%m-%d_%H-%M-%S").to_string();
-                            let id = data.id;
-                            let path = data.path;
-                            data.data.re_write_json(tx, time, id, path).unwrap();
-                        }
-                        MessageIPC::Delete(path, id) => {
-                            let img_path = get_image_path(&path);
-                            if let Some(path) = img_path {
-                                log_error!(fs::remove_file(path));
-                            }
-                            log_error!(fs::remove_file(path));
-                            log_error!(tx.try_send(MessageChannel::Remove(id)));
-                        }
-                        MessageIPC::Close => {
-                            break;
-                        }
-                        _ => {}
-                    }
-                } else {
+            .stderr(Stdio::inherit());
+        if let Some(profile) = paths::profile() {
+            command.env(paths::PROFILE_ENV, profile);
+        }
+        let mut process = command.spawn()?;
+        // the gui opens a new pipe connection for every message
+        'gui: for i in listener.incoming() {
+            let Ok(va) = i else {
+                break;
+            };
+            let mut server = match Server::accept(va) {
+                Ok(val) => val,
+                Err(e) => {
+                    warn!("Unable to read message from clippy-gui");
+                    debug!("{}", e);
+                    continue;
+                }
+            };
+            while let Ok(Some(request)) = server.next_request() {
+                if let MessageIPC::Close = request.body {
+                    let _ = server.reply(request.id, Ok(Reply::Done));
+                    break 'gui;
+                }
+                if let Err(e) = server.reply(request.id, gui_request(request.body, tx)) {
+                    debug!("{}", e);
                     break;
                 }
-            }else {
-                break;
             }
         }
-        Ok(process.kill()?)
+        process.kill()
     }
 
     pub fn ipc_check(channel: PipelistenerTyp, rx: &Sender<MessageChannel>) -> Result<(), Error> {
         loop {
             for conn in channel.incoming() {
-                if let Ok(val) = conn {
-                    let mut reader = BufReader::new(val);
-                    let mut buf = String::new();
-                    if let Err(e) = reader.read_to_string(&mut buf) {
+                let Ok(val) = conn else {
+                    continue;
+                };
+                let mut server = match Server::accept(val) {
+                    Ok(val) => val,
+                    Err(e) => {
                         error!("Problem reading pipe data try updating the app");
                         debug!("{e}");
-                    };
-                    let rx = rx.clone();
-                    match serde_json::from_str::<MessageIPC>(&buf) {
-                        Ok(MessageIPC::OpentGUI) => {
+                        continue;
+                    }
+                };
+                while let Ok(Some(request)) = server.next_request() {
+                    let response = match request.body {
+                        MessageIPC::OpentGUI => {
+                            let rx = rx.clone();
                             thread::spawn(move || {
                                 if let Err(e) = start_gui(&rx) {
                                     error!("Unable to start up gui app: {}", e);
                                 };
                             });
+                            Ok(Reply::Done)
                         }
-                        _ => {}
+                        _ => Err(IpcError::Unsupported),
+                    };
+                    if server.reply(request.id, response).is_err() {
+                        break;
                     }
                 }
             }
@@ -0,0 +1,708 @@
+//! Syncs the clipboard with paired devices on the same network, without a server.
+//!
+//! Daemons announce themselves with udp broadcasts, the device with the smaller id
+//! connects to the other one. Sessions are authenticated with the key agreed on when
+//! pairing (see `pairing`) and every frame is encrypted with it.
+
+use crate::encryption_decryption::{decrypt_file, encrept_file};
+use crate::local::start_local;
+use crate::secrets::install_key;
+use crate::storage::storage;
+use crate::{
+    Data, Edit, MessageChannel, UserData, UserSettings, device_id, device_name, get_path_local,
+    set_global_update_bool,
+};
+use aes_gcm::aead::{Aead, KeyInit, OsRng, rand_core::RngCore};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::{Engine, engine::general_purpose};
+use chrono::Utc;
+use hkdf::Hkdf;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::{fs, io};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::select;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::time::{Duration, Instant, interval, timeout};
+
+pub const DISCOVERY_PORT: u16 = 47625;
+const STATE_FILE: &str = "lan/.peers";
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HANDSHAKE: usize = 4 * 1024;
+const MAX_FRAME: usize = 64 * 1024 * 1024;
+// entries stored just before the last ping may not have been sent yet
+const CATCH_UP_MARGIN: i64 = 300;
+
+/// A device this one was paired with.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Peer {
+    pub device: String,
+    pub name: String,
+    // secret agreed on when pairing, base64
+    key: String,
+    // clock of the peer when it last told us it had sent everything
+    #[serde(default)]
+    seen: Option<i64>,
+}
+
+impl Peer {
+    pub fn new(device: String, name: String, key: &[u8]) -> Self {
+        Self {
+            device,
+            name,
+            key: general_purpose::STANDARD.encode(key),
+            seen: None,
+        }
+    }
+
+    fn key(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        general_purpose::STANDARD.decode(&self.key)
+    }
+}
+
+/// The identity of this device and the devices it is paired with, stored encrypted.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LanState {
+    pub device: String,
+    pub name: String,
+    pub peers: Vec<Peer>,
+}
+
+fn state_path() -> PathBuf {
+    get_path_local().join(STATE_FILE)
+}
+
+impl LanState {
+    /// Reads the state, a new identity is made on first use.
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        let path = state_path();
+        match fs::read(&path) {
+            Ok(file) => {
+                let data = decrypt_file(&install_key()?, &file)
+                    .map_err(|_| "Unable to decrypt paired devices")?;
+                Ok(serde_json::from_slice(&data)?)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let state = Self {
+                    device: device_id(),
+                    name: device_name(),
+                    peers: Vec::new(),
+                };
+                state.save()?;
+                Ok(state)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = state_path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let data =
+            encrept_file(&install_key()?, &serde_json::to_vec(self)?).map_err(|e| e.to_string())?;
+        fs::write(&path, data)?;
+        Ok(())
+    }
+
+    pub fn peer(&self, device: &str) -> Option<&Peer> {
+        self.peers.iter().find(|val| val.device == device)
+    }
+
+    /// Adds a peer, pairing the same device again replaces it.
+    pub fn add_peer(&mut self, peer: Peer) {
+        self.peers.retain(|val| val.device != peer.device);
+        self.peers.push(peer);
+    }
+
+    pub fn remove_peer(&mut self, device: &str) -> bool {
+        let len = self.peers.len();
+        self.peers.retain(|val| val.device != device);
+        len != self.peers.len()
+    }
+
+    // reloads before writing, `clippy pair` may have changed the file meanwhile
+    fn set_seen(seen: &HashMap<String, i64>) -> Result<(), Box<dyn Error>> {
+        let mut state = Self::load()?;
+        for peer in state.peers.iter_mut() {
+            if let Some(at) = seen.get(&peer.device) {
+                peer.seen = Some(*at);
+            }
+        }
+        state.save()
+    }
+}
+
+/// Udp broadcasts on `DISCOVERY_PORT`.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum Discovery {
+    // a daemon taking sync connections on `port`
+    Announce {
+        device: String,
+        port: u16,
+    },
+    // `clippy pair` waiting for the code on `port`
+    Pairing {
+        device: String,
+        name: String,
+        port: u16,
+    },
+}
+
+/// Binds the discovery port, shared with every clippy process on this machine.
+pub(crate) fn discovery_socket() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(target_family = "unix")]
+    socket.set_reuse_port(true)?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+pub(crate) async fn announce(socket: &UdpSocket, msg: &Discovery) -> io::Result<()> {
+    socket
+        .send_to(
+            &serde_json::to_vec(msg)?,
+            (Ipv4Addr::BROADCAST, DISCOVERY_PORT),
+        )
+        .await?;
+    Ok(())
+}
+
+pub(crate) async fn read_discovery(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(Discovery, IpAddr)> {
+    loop {
+        let (len, addr) = socket.recv_from(buf).await?;
+        match serde_json::from_slice(&buf[..len]) {
+            Ok(val) => return Ok((val, addr.ip())),
+            Err(e) => debug!("ignoring broadcast from {}: {}", addr, e),
+        }
+    }
+}
+
+pub(crate) async fn write_frame<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    data: &[u8],
+) -> io::Result<()> {
+    stream.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    stream.write_all(data).await?;
+    stream.flush().await
+}
+
+pub(crate) async fn read_frame<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    max: usize,
+) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Sent in the clear when a session starts, the nonces make the session keys unique.
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    device: String,
+    nonce: String,
+}
+
+/// Messages of a session.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+enum LanMessage {
+    // first message of both sides, `since` is the clock of the receiver when they last talked
+    Hello {
+        since: Option<i64>,
+    },
+    // `replaces` is the entry an edit replaces, `copy` puts it on the clipboard
+    Data {
+        id: String,
+        data: Data,
+        replaces: Option<String>,
+        copy: bool,
+    },
+    Remove(String),
+    // clock of the sender, everything it had at that time was sent
+    Ping(i64),
+}
+
+// one direction of a session, the nonce is a counter so a key never reuses one
+struct Cipher {
+    cipher: Aes256Gcm,
+    count: u64,
+}
+
+impl Cipher {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(aes_gcm::Key::<Aes256Gcm>::from_slice(key)),
+            count: 0,
+        }
+    }
+
+    fn nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.count.to_be_bytes());
+        self.count += 1;
+        nonce
+    }
+
+    fn seal(&mut self, msg: &LanMessage) -> Result<Vec<u8>, Box<dyn Error>> {
+        let nonce = self.nonce();
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), serde_json::to_vec(msg)?.as_ref())
+            .map_err(|_| "Unable to encrypt message".into())
+    }
+
+    fn open(&mut self, data: &[u8]) -> Result<LanMessage, Box<dyn Error>> {
+        let nonce = self.nonce();
+        let data = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| "Unable to decrypt message, the device may have been paired again")?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+}
+
+// keys of both directions, the first one is used by the side that connected
+fn session_keys(
+    key: &[u8],
+    initiator: &[u8],
+    responder: &[u8],
+) -> Result<([u8; 32], [u8; 32]), Box<dyn Error>> {
+    let hkdf = Hkdf::<Sha256>::new(Some(&[initiator, responder].concat()), key);
+    let mut send = [0u8; 32];
+    let mut recv = [0u8; 32];
+    hkdf.expand(b"clippy lan initiator", &mut send)
+        .map_err(|e| e.to_string())?;
+    hkdf.expand(b"clippy lan responder", &mut recv)
+        .map_err(|e| e.to_string())?;
+    Ok((send, recv))
+}
+
+fn peer_key(device: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let state = LanState::load()?;
+    let peer = state
+        .peer(device)
+        .ok_or_else(|| format!("device {} is not paired", device))?;
+    Ok(peer.key()?)
+}
+
+/// Agrees on the session keys, `expected` is the device we connected to.
+/// Returns the device on the other side with the sending and receiving cipher.
+async fn handshake(
+    stream: &mut TcpStream,
+    device: &str,
+    expected: Option<&str>,
+) -> Result<(String, Cipher, Cipher), Box<dyn Error>> {
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let hello = serde_json::to_vec(&Hello {
+        device: device.to_string(),
+        nonce: general_purpose::STANDARD.encode(nonce),
+    })?;
+
+    if let Some(expected) = expected {
+        write_frame(stream, &hello).await?;
+        let reply: Hello = serde_json::from_slice(&read_frame(stream, MAX_HANDSHAKE).await?)?;
+        if reply.device != expected {
+            return Err(
+                format!("expected device {} but {} answered", expected, reply.device).into(),
+            );
+        }
+        let theirs = general_purpose::STANDARD.decode(&reply.nonce)?;
+        let (send, recv) = session_keys(&peer_key(&reply.device)?, &nonce, &theirs)?;
+        Ok((reply.device, Cipher::new(&send), Cipher::new(&recv)))
+    } else {
+        let request: Hello = serde_json::from_slice(&read_frame(stream, MAX_HANDSHAKE).await?)?;
+        let key = peer_key(&request.device)?;
+        write_frame(stream, &hello).await?;
+        let theirs = general_purpose::STANDARD.decode(&request.nonce)?;
+        let (recv, send) = session_keys(&key, &theirs, &nonce)?;
+        Ok((request.device, Cipher::new(&send), Cipher::new(&recv)))
+    }
+}
+
+fn copy(data: &Data) -> Result<(), Box<dyn Error>> {
+    #[cfg(target_family = "unix")]
+    crate::write_clipboard::copy_to_unix(data.clone(), false)?;
+    #[cfg(target_os = "windows")]
+    crate::write_clipboard::copy_to_clipboard(data.clone(), false).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// a change and the peer it came from, the session of that peer skips it
+type Outgoing = (Option<String>, LanMessage);
+
+struct Lan {
+    device: String,
+    out: broadcast::Sender<Outgoing>,
+    connected: Mutex<HashSet<String>>,
+    // clock of every peer from its last ping, stored when a session ends
+    seen: Mutex<HashMap<String, i64>>,
+    user_data: UserData,
+    max_clipboard: Option<u32>,
+}
+
+impl Lan {
+    fn is_connected(&self, device: &str) -> bool {
+        self.connected.lock().unwrap().contains(device)
+    }
+
+    fn has_peers(&self) -> bool {
+        !self.connected.lock().unwrap().is_empty()
+    }
+
+    fn is_paired(&self, device: &str) -> bool {
+        match LanState::load() {
+            Ok(state) => state.peer(device).is_some(),
+            Err(e) => {
+                error!("Unable to read paired devices");
+                debug!("{}", e);
+                false
+            }
+        }
+    }
+
+    fn since(&self, peer: &str) -> Result<Option<i64>, Box<dyn Error>> {
+        if let Some(at) = self.seen.lock().unwrap().get(peer) {
+            return Ok(Some(*at));
+        }
+        Ok(LanState::load()?.peer(peer).and_then(|val| val.seen))
+    }
+
+    fn store_seen(&self) {
+        let seen = self.seen.lock().unwrap().clone();
+        if !seen.is_empty()
+            && let Err(e) = LanState::set_seen(&seen)
+        {
+            error!("Unable to store the sync position of paired devices");
+            debug!("{}", e);
+        }
+    }
+
+    /// Stores a change of a peer, returns false if it was already known.
+    fn apply(&self, msg: &LanMessage) -> Result<bool, Box<dyn Error>> {
+        match msg {
+            LanMessage::Data {
+                id,
+                data,
+                replaces,
+                copy: copy_it,
+            } => {
+                if storage().find_remote(id)?.is_some() || storage().is_removed(id)? {
+                    return Ok(false);
+                }
+                let new = data.just_write_paste(id, false, false)?;
+                self.user_data.add_data(new, self.max_clipboard);
+                if let Some(old) = replaces {
+                    storage().add_removed(old)?;
+                    self.user_data.remove_remote(old)?;
+                }
+                if *copy_it && let Err(e) = copy(data) {
+                    warn!("Unable to copy the synced entry");
+                    debug!("{}", e);
+                }
+                Ok(true)
+            }
+            LanMessage::Remove(id) => {
+                if storage().is_removed(id)? {
+                    return Ok(false);
+                }
+                storage().add_removed(id)?;
+                self.user_data.remove_remote(id)?;
+                set_global_update_bool(true);
+                Ok(true)
+            }
+            LanMessage::Hello { .. } | LanMessage::Ping(_) => Ok(false),
+        }
+    }
+
+    /// Sends a change made on this device to every connected peer.
+    fn send_change(&self, id: &str, edit: Edit) -> Result<(), Box<dyn Error>> {
+        match edit {
+            Edit::Remove => {
+                let _ = self.out.send((None, LanMessage::Remove(id.to_string())));
+            }
+            Edit::New { id: entry_id } | Edit::Edit { id: entry_id } => {
+                let Some(entry) = storage().get(entry_id)? else {
+                    debug!("entry {} was removed before sync", entry_id);
+                    return Ok(());
+                };
+                // starts with the time like the ids of the server, the history is trimmed in that order
+                let remote_id = format!("{}-{}-{}", Utc::now().timestamp(), self.device, entry_id);
+                let replaces = match edit {
+                    Edit::Edit { .. } => Some(id.to_string()),
+                    _ => None,
+                };
+                storage().mark_synced(entry_id, &remote_id)?;
+                self.user_data.add_data(entry_id, self.max_clipboard);
+                let msg = LanMessage::Data {
+                    id: remote_id,
+                    data: entry.data,
+                    replaces,
+                    copy: true,
+                };
+                let _ = self.out.send((None, msg));
+            }
+        }
+        set_global_update_bool(true);
+        Ok(())
+    }
+
+    async fn session(self: Arc<Self>, mut stream: TcpStream, expected: Option<String>) {
+        let handshake = timeout(
+            HANDSHAKE_TIMEOUT,
+            handshake(&mut stream, &self.device, expected.as_deref()),
+        )
+        .await;
+        let (peer, send, recv) = match handshake {
+            Ok(Ok(val)) => val,
+            Ok(Err(e)) => {
+                debug!("lan handshake failed: {}", e);
+                return;
+            }
+            Err(_) => {
+                debug!("lan handshake timed out");
+                return;
+            }
+        };
+        if !self.connected.lock().unwrap().insert(peer.clone()) {
+            debug!("already connected to {}", peer);
+            return;
+        }
+        info!("Connected to paired device {}", peer);
+
+        let (mut reader, writer) = stream.into_split();
+        let (tx, incoming) = mpsc::channel(16);
+        let mut recv = recv;
+        let reader = actix_rt::spawn(async move {
+            loop {
+                let msg = match read_frame(&mut reader, MAX_FRAME).await {
+                    Ok(data) => recv.open(&data),
+                    Err(e) => Err(e.into()),
+                };
+                match msg {
+                    Ok(msg) => {
+                        if tx.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        debug!("lan session closed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        if let Err(e) = self.exchange(&peer, writer, send, incoming).await {
+            warn!("Lost connection to paired device {}", peer);
+            debug!("{}", e);
+        } else {
+            info!("Disconnected from paired device {}", peer);
+        }
+        reader.abort();
+        self.connected.lock().unwrap().remove(&peer);
+        self.store_seen();
+    }
+
+    async fn exchange(
+        &self,
+        peer: &str,
+        mut writer: OwnedWriteHalf,
+        mut send: Cipher,
+        mut incoming: Receiver<LanMessage>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut out = self.out.subscribe();
+        let since = self.since(peer)?;
+        write_frame(&mut writer, &send.seal(&LanMessage::Hello { since })?).await?;
+
+        let mut ping = interval(PING_INTERVAL);
+        let mut last_seen = Instant::now();
+        loop {
+            select! {
+                msg = incoming.recv() => {
+                    let Some(msg) = msg else {
+                        return Ok(());
+                    };
+                    last_seen = Instant::now();
+                    match msg {
+                        LanMessage::Hello { since } => {
+                            let now = Utc::now().timestamp();
+                            let since = since.map_or(i64::MIN, |val| val - CATCH_UP_MARGIN);
+                            for entry in storage().synced_since(since)? {
+                                let Some(id) = entry.remote_id else {
+                                    continue;
+                                };
+                                let msg = LanMessage::Data { id, data: entry.data, replaces: None, copy: false };
+                                write_frame(&mut writer, &send.seal(&msg)?).await?;
+                            }
+                            for id in storage().removed_since(since)? {
+                                write_frame(&mut writer, &send.seal(&LanMessage::Remove(id))?).await?;
+                            }
+                            write_frame(&mut writer, &send.seal(&LanMessage::Ping(now))?).await?;
+                        }
+                        LanMessage::Ping(at) => {
+                            self.seen.lock().unwrap().insert(peer.to_string(), at);
+                        }
+                        msg => {
+                            // forwarded so peers that are not connected to the sender get it too
+                            if self.apply(&msg)? {
+                                let _ = self.out.send((Some(peer.to_string()), msg));
+                            }
+                        }
+                    }
+                }
+                msg = out.recv() => match msg {
+                    Ok((origin, msg)) => {
+                        if origin.as_deref() != Some(peer) {
+                            write_frame(&mut writer, &send.seal(&msg)?).await?;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => {
+                        return Err("missed changes, reconnecting to catch up".into());
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = ping.tick() => {
+                    if last_seen.elapsed() > PEER_TIMEOUT {
+                        return Err("the device stopped responding".into());
+                    }
+                    let msg = LanMessage::Ping(Utc::now().timestamp());
+                    write_frame(&mut writer, &send.seal(&msg)?).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Syncs with paired devices until the settings change.
+pub fn start_lan(rx: &mut Receiver<MessageChannel>, usersettings: UserSettings) {
+    let user_data = UserData::build();
+    let mut failed = false;
+
+    actix_rt::System::new().block_on(async {
+        if let Err(e) = run(rx, &user_data, &usersettings).await {
+            error!("Network sync stopped: {}", e);
+            failed = true;
+        }
+    });
+
+    if failed {
+        start_local(rx, usersettings);
+    }
+}
+
+async fn run(
+    rx: &mut Receiver<MessageChannel>,
+    user_data: &UserData,
+    usersettings: &UserSettings,
+) -> Result<(), Box<dyn Error>> {
+    let state = LanState::load()?;
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let discovery = discovery_socket()?;
+    let offer = Discovery::Announce {
+        device: state.device.clone(),
+        port: listener.local_addr()?.port(),
+    };
+    info!(
+        "Network sync started as {} with {} paired devices",
+        state.device,
+        state.peers.len()
+    );
+
+    let lan = Arc::new(Lan {
+        device: state.device,
+        out: broadcast::channel(100).0,
+        connected: Mutex::new(HashSet::new()),
+        seen: Mutex::new(HashMap::new()),
+        user_data: user_data.clone(),
+        max_clipboard: usersettings.max_clipboard,
+    });
+    let mut tick = interval(ANNOUNCE_INTERVAL);
+    let mut buf = vec![0u8; 2048];
+
+    loop {
+        select! {
+            _ = tick.tick() => {
+                if let Err(e) = announce(&discovery, &offer).await {
+                    debug!("unable to announce this device: {}", e);
+                }
+            }
+            msg = read_discovery(&discovery, &mut buf) => match msg {
+                // the device with the smaller id connects, so a pair makes one connection
+                Ok((Discovery::Announce { device, port }, addr))
+                    if *device > *lan.device
+                        && !lan.is_connected(&device)
+                        && lan.is_paired(&device) =>
+                {
+                    let lan = lan.clone();
+                    actix_rt::spawn(async move {
+                        match TcpStream::connect((addr, port)).await {
+                            Ok(stream) => lan.session(stream, Some(device)).await,
+                            Err(e) => debug!("unable to connect to {}: {}", device, e),
+                        }
+                    });
+                }
+                Ok(_) => (),
+                Err(e) => debug!("unable to read broadcasts: {}", e),
+            },
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    actix_rt::spawn(lan.clone().session(stream, None));
+                }
+                Err(e) => debug!("unable to accept connection: {}", e),
+            },
+            Some(msg) = rx.recv() => match msg {
+                MessageChannel::SettingsChanged => {
+                    lan.store_seen();
+                    return Ok(());
+                }
+                msg => {
+                    // the queue is not stored, peers that are offline learn removals from this
+                    if let MessageChannel::Remove { remote_id: Some(id), .. }
+                    | MessageChannel::Edit { remote_id: Some(id), .. } = &msg
+                        && let Err(e) = storage().add_removed(id)
+                    {
+                        error!("Unable to remember removed entry");
+                        debug!("{}", e);
+                    }
+                    user_data.queue(msg).await
+                }
+            },
+            Some((_, id, edit)) = user_data.next(), if lan.has_peers() => {
+                if let Err(e) = lan.send_change(&id, edit) {
+                    error!("Unable to sync change");
+                    debug!("{}", e);
+                }
+                user_data.pop_pending(&id);
+            }
+        }
+    }
+}
@@ -1,35 +1,50 @@
+pub mod cli;
 pub mod encryption_decryption;
 pub mod http;
 pub mod ipc;
+pub mod lan;
 pub mod local;
 pub mod macros;
+pub mod pairing;
+pub mod paths;
+pub mod protocol;
 pub mod read_clipboard;
+pub mod search;
+pub mod secrets;
+pub mod storage;
 pub mod user;
+#[cfg(target_os = "linux")]
+pub mod wayland;
 pub mod write_clipboard;
+#[cfg(target_os = "linux")]
+pub mod x11;
 
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
 use base64::Engine;
 use base64::engine::general_purpose;
 use bytestring::ByteString;
-use encryption_decryption::{decrypt_file, encrept_file};
-use image::{ImageReader, load_from_memory};
+use encryption_decryption::decrypt_file;
+use image::load_from_memory;
 use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use search::{SearchQuery, search_index};
+use storage::storage;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, VecDeque};
 use std::error::Error;
 use std::fs::create_dir;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::{
-    collections::BTreeSet,
     env,
     fs::File,
     fs::{self},
     io::{self},
     path::PathBuf,
 };
-use tokio::sync::Notify;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::{Notify, broadcast};
 
 #[cfg(target_os="windows")]
 use crate::write_clipboard::copy_to_clipboard;
@@ -37,7 +52,10 @@ use crate::write_clipboard::copy_to_clipboard;
 use crate::write_clipboard::copy_to_unix;
 
 pub const APP_ID: &str = "org.clippy.clippy";
+// only reads credential files of older versions, see `migrate_legacy`
 pub const API_KEY: Option<&str> = option_env!("KEY");
+// d-bus calls of the secret store are traced at info
+pub const DEFAULT_LOG: &str = "info,zbus=warn,tracing=warn";
 const IMAGE_DATA: &[u8] = include_bytes!("../../assets/gui_icons/image.png");
 #[cfg(debug_assertions)]
 const GUI_BIN: &str = "target/debug/clippy-gui";
@@ -48,6 +66,18 @@ const GUI_BIN: &str = "clippy-gui";
 
 static GLOBAL_BOOL: AtomicBool = AtomicBool::new(true);
 
+// ids of entries added to the history, used by `clippy watch`
+static NEW_ENTRY: Lazy<broadcast::Sender<i64>> = Lazy::new(|| broadcast::channel(64).0);
+
+pub fn subscribe_new_entries() -> broadcast::Receiver<i64> {
+    NEW_ENTRY.subscribe()
+}
+
+fn notify_new_entry(id: i64) {
+    // fails only when nobody is watching
+    let _ = NEW_ENTRY.send(id);
+}
+
 pub fn set_global_bool(value: bool) {
     GLOBAL_BOOL.store(value, Ordering::SeqCst);
 }
@@ -56,12 +86,91 @@ pub fn get_global_bool() -> bool {
     GLOBAL_BOOL.load(Ordering::SeqCst)
 }
 
+/// Another representation of the same copy (html, rtf, a file list...), restored
+/// next to the main one so the target application can pick the format it prefers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Flavor {
+    pub mime: String,
+    // base64, flavors can be binary
+    data: String,
+}
+
+impl Flavor {
+    pub fn new(mime: String, data: &[u8]) -> Self {
+        Flavor {
+            mime,
+            data: general_purpose::STANDARD.encode(data),
+        }
+    }
+
+    pub fn bytes(&self) -> Option<Vec<u8>> {
+        general_purpose::STANDARD.decode(&self.data).ok()
+    }
+}
+
+/// The application a copy came from, as far as the platform tells.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct SourceApp {
+    // WM_CLASS on X11, the app id on Wayland
+    pub class: Option<String>,
+    // name of the executable
+    pub process: Option<String>,
+    pub pid: Option<u32>,
+}
+
+impl SourceApp {
+    pub fn new(class: Option<String>, pid: Option<u32>) -> Option<Self> {
+        let class = class.filter(|val| !val.is_empty());
+        let process = pid.and_then(process_name);
+        if class.is_none() && process.is_none() {
+            return None;
+        }
+        Some(Self {
+            class,
+            process,
+            pid,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.class
+            .as_deref()
+            .or(self.process.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Whether `app` names this application, compared with its class and its process name.
+    pub fn is(&self, app: &str) -> bool {
+        [&self.class, &self.process]
+            .into_iter()
+            .flatten()
+            .any(|val| val.eq_ignore_ascii_case(app))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_name(pid: u32) -> Option<String> {
+    fs::read_to_string(format!("/proc/{}/comm", pid))
+        .ok()
+        .map(|val| val.trim_end().to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_name(_pid: u32) -> Option<String> {
+    None
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Data {
     data: String,
     pub typ: String,
     device: String,
     pub pined: bool,
+    // entries from older versions have none
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    flavors: Vec<Flavor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<SourceApp>,
 }
 
 impl Data {
@@ -71,19 +180,55 @@ impl Data {
             typ,
             device,
             pined,
+            flavors: Vec::new(),
+            source: None,
         }
     }
 
-    pub fn just_write_paste(&self, id: &str, copy: bool, paste: bool) -> Result<(), io::Error> {
-        let path = get_path();
-        fs::create_dir_all(&path)?;
-        let file_path = &path.join(id);
-        let mut file = File::create(file_path)?;
-        let json_data = serde_json::to_vec(self)?;
-        file.write_all(&json_data)?;
+    pub fn with_flavors(mut self, flavors: Vec<Flavor>) -> Self {
+        self.flavors = flavors;
+        self
+    }
+
+    pub fn flavors(&self) -> &[Flavor] {
+        &self.flavors
+    }
+
+    pub fn with_source(mut self, source: Option<SourceApp>) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn source(&self) -> Option<&SourceApp> {
+        self.source.as_ref()
+    }
+
+    /// Name of the device the entry was copied on, "os" for entries of older versions.
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    /// The main representation as the clipboard expects it, images are stored as base64.
+    pub fn bytes(&self) -> Option<Vec<u8>> {
         if self.typ.starts_with("image/") {
-            save_image(&id, &general_purpose::STANDARD.decode(&self.data).unwrap())?;
+            general_purpose::STANDARD.decode(&self.data).ok()
+        } else {
+            Some(self.data.clone().into_bytes())
         }
+    }
+
+    pub fn just_write_paste(
+        &self,
+        remote_id: &str,
+        copy: bool,
+        paste: bool,
+    ) -> Result<i64, io::Error> {
+        let thumbnail = match self.get_image() {
+            Some(val) => Some(make_thumbnail(&val)?),
+            None => None,
+        };
+        let id = storage().add_synced(remote_id, self, thumbnail.as_deref())?;
+        notify_new_entry(id);
         if copy {
             #[cfg(target_family = "unix")]
             copy_to_unix(self.clone(), paste)
@@ -93,74 +238,68 @@ impl Data {
             copy_to_clipboard(self.clone(), paste).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
         }
         set_global_update_bool(true);
-        Ok(())
+        Ok(id)
     }
 
-    pub fn write_to_json(
+    pub fn write_pending(
         &self,
         tx: &Sender<MessageChannel>,
-        time: String,
-    ) -> Result<(), io::Error> {
-        let path = get_path_pending();
-        fs::create_dir_all(&path)?;
-        let file_path = &path.join(&time);
-
-        let json_data = serde_json::to_vec(self)?;
-
-        let mut file = File::create(file_path)?;
-        file.write_all(&json_data)?;
-
-        match tx.try_send(MessageChannel::New {
-            path: file_path.to_str().unwrap().into(),
-            typ: self.typ.clone(),
-            time,
-        }) {
+        thumbnail: Option<Vec<u8>>,
+    ) -> Result<i64, io::Error> {
+        let id = storage().add_pending(self, thumbnail.as_deref())?;
+        notify_new_entry(id);
+
+        match tx.try_send(MessageChannel::New(id)) {
             Ok(_) => (),
-            Err(err) => warn!(
-                "Failed to send file '{}' to channel: {}",
-                file_path.display(),
-                err
-            ),
+            Err(err) => warn!("Failed to send entry '{}' to channel: {}", id, err),
         }
         set_global_update_bool(true);
-        Ok(())
+        Ok(id)
     }
 
-    pub fn re_write_json(
+    /// Stores an entry that is never synced, see `FilterAction::LocalOnly`.
+    pub fn write_local(
         &self,
         tx: &Sender<MessageChannel>,
-        new_id: String,
-        old_id: String,
-        path: PathBuf,
-    ) -> Result<(), io::Error> {
-        log_error!(fs::remove_file(path));
-        let path = get_path_image();
-        let old_path = path.join(&format!("{}.png", old_id));
-        if old_path.is_file() {
-            let new_path = path.join(&format!("{}.png", new_id));
-            fs::rename(&old_path, &new_path)?;
+        thumbnail: Option<Vec<u8>>,
+    ) -> Result<i64, io::Error> {
+        let id = storage().add_local(self, thumbnail.as_deref())?;
+        notify_new_entry(id);
+
+        if let Err(err) = tx.try_send(MessageChannel::NewLocal(id)) {
+            warn!("Failed to send entry '{}' to channel: {}", id, err);
         }
-        let path = get_path_pending();
-        fs::create_dir_all(&path)?;
-        let file_path = &path.join(&new_id);
-        let json_data = serde_json::to_string(self)?;
-        let mut file = File::create(file_path)?;
-        file.write_all(json_data.as_bytes())?;
-        match tx.try_send(MessageChannel::Edit {
-            new_id,
-            old_id,
-            path: file_path.to_str().unwrap().to_string(),
-            typ: self.typ.clone(),
-        }) {
-            Ok(_) => (),
-            Err(err) => warn!(
-                "Failed to send file '{}' to channel: {}",
-                file_path.display(),
-                err
-            ),
+        set_global_update_bool(true);
+        Ok(id)
+    }
+
+    pub fn replace(&self, tx: &Sender<MessageChannel>, old: i64) -> Result<i64, io::Error> {
+        let (new, remote_id) = storage().replace(old, self)?;
+        let local = storage()
+            .get(new)?
+            .is_some_and(|entry| entry.location == storage::Location::Local);
+        let messages = if local {
+            vec![
+                MessageChannel::Remove {
+                    id: old,
+                    remote_id: None,
+                },
+                MessageChannel::NewLocal(new),
+            ]
+        } else {
+            vec![MessageChannel::Edit {
+                old,
+                new,
+                remote_id,
+            }]
+        };
+        for msg in messages {
+            if let Err(err) = tx.try_send(msg) {
+                warn!("Failed to send entry '{}' to channel: {}", new, err);
+            }
         }
         set_global_update_bool(true);
-        Ok(())
+        Ok(new)
     }
 
     pub fn get_data(&self) -> Option<String> {
@@ -171,12 +310,10 @@ impl Data {
         }
     }
 
-    pub fn get_image_thumbnail(&self, id: &PathBuf) -> Option<(Vec<u8>, (u32, u32))> {
-        let path = get_image_path(id)?;
-        let image = if path.is_file() {
-            ImageReader::open(path).ok()?.decode().ok()?
-        } else {
-            load_from_memory(IMAGE_DATA).ok().unwrap()
+    pub fn get_image_thumbnail(&self, thumbnail: Option<&[u8]>) -> Option<(Vec<u8>, (u32, u32))> {
+        let image = match thumbnail {
+            Some(val) => load_from_memory(val).ok()?,
+            None => load_from_memory(IMAGE_DATA).ok().unwrap(),
         };
         let rgba = image.to_rgba8();
 
@@ -209,7 +346,9 @@ impl Data {
     }
 
     pub fn change_data(&mut self, data: &str) {
-        self.data = data.to_string()
+        self.data = data.to_string();
+        // the other representations would still hold the old content
+        self.flavors.clear();
     }
 
     pub fn get_meta_data(&self) -> Option<String> {
@@ -230,13 +369,6 @@ impl Data {
 
         Some(display_text)
     }
-
-    pub fn build(path: &PathBuf) -> Result<Self, io::Error> {
-        let mut buf = String::new();
-        let mut file = File::open(path)?;
-        file.read_to_string(&mut buf)?;
-        Ok(serde_json::from_str(&buf)?)
-    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -247,80 +379,37 @@ pub enum DataState {
 
 #[derive(Debug, Clone)]
 pub struct UserData {
-    data: Arc<Mutex<BTreeSet<String>>>,
     pending: Arc<Mutex<BTreeMap<String, (Edit, DataState)>>>,
     notify: Arc<Notify>,
 }
 
 impl UserData {
     fn build() -> Self {
-        let mut data = BTreeSet::new();
         let mut pending = BTreeMap::new();
 
         Self::build_pending(&mut pending);
-        Self::build_data(&mut data);
 
         let notify = Notify::new();
 
         Self {
-            data: Arc::new(Mutex::new(data)),
             pending: Arc::new(Mutex::new(pending)),
             notify: Arc::new(notify),
         }
     }
 
     fn build_pending(pending: &mut BTreeMap<String, (Edit, DataState)>) {
-        let data_path = get_path_pending();
-        if let Ok(entries) = fs::read_dir(data_path) {
-            for dir in entries {
-                if let Ok(entry) = dir {
-                    if let Ok(metadata) = File::open(entry.path()) {
-                        if let Ok(data) = serde_json::from_reader::<File, Data>(metadata) {
-                            if let Some(name) = entry.file_name().to_str() {
-                                pending.insert(
-                                    name.to_string(),
-                                    (
-                                        Edit::New {
-                                            path: entry.path(),
-                                            typ: data.typ,
-                                        },
-                                        DataState::WaitingToSend,
-                                    ),
-                                );
-                            }
-                        }
-                    }
+        match storage().pending() {
+            Ok(ids) => {
+                for id in ids {
+                    pending.insert(id.to_string(), (Edit::New { id }, DataState::WaitingToSend));
                 }
             }
-        }
-    }
-
-    fn build_data(data: &mut BTreeSet<String>) {
-        let data_path = get_path();
-        if let Ok(entries) = fs::read_dir(data_path) {
-            for dir in entries {
-                if let Ok(entry) = dir {
-                    if let Ok(metadata) = entry.metadata() {
-                        if metadata.is_file() {
-                            if let Some(name) = entry.file_name().to_str() {
-                                data.insert(name.to_string());
-                            }
-                        }
-                    }
-                }
+            Err(e) => {
+                error!("Unable to read pending entries");
+                debug!("{}", e);
             }
         }
     }
-    fn get_sync_30(&self) -> Vec<String> {
-        self.data
-            .lock()
-            .unwrap()
-            .iter()
-            .rev()
-            .take(30)
-            .cloned()
-            .collect()
-    }
 
     pub async fn next(&self) -> Option<(bool, String, Edit)> {
         loop {
@@ -348,6 +437,9 @@ impl UserData {
     }
 
     async fn add_pending(&self, id: String, act: Edit) {
+        if let Edit::New { id } | Edit::Edit { id } = &act {
+            self.reindex(*id);
+        }
         self.notify.notify_one();
         self.pending
             .lock()
@@ -355,6 +447,34 @@ impl UserData {
             .insert(id, (act, DataState::WaitingToSend));
     }
 
+    /// Queues a change made on this device to be sent to the server.
+    async fn queue(&self, msg: MessageChannel) {
+        match msg {
+            MessageChannel::New(id) => {
+                self.add_pending(id.to_string(), Edit::New { id }).await;
+            }
+            MessageChannel::NewLocal(id) => self.reindex(id),
+            MessageChannel::Edit {
+                old,
+                new,
+                remote_id,
+            } => {
+                self.unindex(old);
+                match remote_id {
+                    Some(remote_id) => self.add_pending(remote_id, Edit::Edit { id: new }).await,
+                    None => self.add_pending(new.to_string(), Edit::New { id: new }).await,
+                }
+            }
+            MessageChannel::Remove { id, remote_id } => {
+                self.unindex(id);
+                if let Some(remote_id) = remote_id {
+                    self.add_pending(remote_id, Edit::Remove).await;
+                }
+            }
+            MessageChannel::SettingsChanged => {}
+        }
+    }
+
     fn change_state(&self, id: &str) {
         let mut data = self.pending.lock().unwrap();
         if let Some(val) = data.get_mut(id) {
@@ -362,113 +482,342 @@ impl UserData {
         }
     }
 
+    // sends `id` again once the server had some time to recover
+    fn retry_later(&self, id: String) {
+        let user_data = self.clone();
+        actix_rt::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            if let Some(val) = user_data.pending.lock().unwrap().get_mut(&id) {
+                val.1 = DataState::WaitingToSend;
+            }
+            user_data.notify.notify_one();
+        });
+    }
+
     fn pop_pending(&self, id: &str) -> Option<(Edit, DataState)> {
         let mut data = self.pending.lock().unwrap();
         data.remove(id)
     }
 
-    pub fn get_30_data(&self) -> Vec<String> {
-        self.data
-            .lock()
-            .unwrap()
-            .iter()
-            .rev()
-            .take(30)
-            .cloned()
-            .collect()
+    fn reindex(&self, id: i64) {
+        search_index().insert(id);
     }
 
-    pub fn add_data(&self, id: String, total: Option<u32>) {
-        let mut data = self.data.lock().unwrap();
-        data.insert(id);
-        debug!("User clipboard count: {}", data.len());
+    fn unindex(&self, id: i64) {
+        search_index().remove(id);
+    }
+
+    pub fn add_data(&self, id: i64, total: Option<u32>) {
+        self.reindex(id);
 
         if let Some(val) = total {
-            let len = data.len() as u32;
-            if val < len {
-                let count = len - val;
-                let to_remove: Vec<String> = data.iter().take(count as usize).cloned().collect();
-                let mut path = get_path();
-                let mut pined_path = get_path_pined();
-                for i in to_remove {
-                    path.push(&i);
-                    if let Ok(file) = File::open(&path) {
-                        if let Ok(clipboard) = serde_json::from_reader::<_, Data>(file) {
-                            if clipboard.pined {
-                                pined_path.push(&i);
-                                fs::rename(&path, &pined_path).unwrap();
-                                pined_path.pop();
-                            } else {
-                                log_error!(fs::remove_file(&path));
-                            }
-                        } else {
-                            println!("{:?} : to do find the cause of the error", path);
-                            error!("file is correpted!")
-                        }
-                    } else {
-                        println!("{:?} : to do find the cause of the error", path);
-                        error!("file is correpted!")
-                    }
-                    path.pop();
-                    data.remove(&i);
+            match storage().trim(val) {
+                Ok(ids) => ids.into_iter().for_each(|id| self.reindex(id)),
+                Err(e) => {
+                    error!("Unable to trim clipboard history");
+                    debug!("{}", e);
                 }
-                debug!("User clipboard count: {}", data.len());
             }
         }
     }
 
-    pub fn remove_and_remove_file(&self, id: &str) -> Result<(), std::io::Error> {
-        if let Ok(mut va) = self.data.lock() {
-            va.remove(id);
-        }
-        let mut path = get_path();
-        path.push(id);
-        match fs::remove_file(&path) {
-            Ok(_) => Ok(()),
-            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
-                info!("File is already removed");
-                debug!("path of remove file {:?}", path);
-                Ok(())
-            }
-            Err(e) => {
-                return Err(e.into());
+    pub fn remove_remote(&self, remote_id: &str) -> Result<(), std::io::Error> {
+        match storage().remove_remote(remote_id)? {
+            Some(id) => self.unindex(id),
+            None => {
+                info!("Entry is already removed");
+                debug!("remote id of removed entry {:?}", remote_id);
             }
         }
+        Ok(())
     }
 }
 
+/// An account on a sync server, the server mints access tokens from `refresh_token`.
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct UserCred {
     pub username: String,
     pub email: String,
-    pub key: String,
+    pub refresh_token: String,
 }
 
 impl UserCred {
-    pub fn new(username: String, key: String, email: String) -> Self {
+    pub fn new(username: String, email: String, refresh_token: String) -> Self {
         Self {
             username,
-            key,
             email,
+            refresh_token,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// Tokens minted from a refresh token, the refresh token is replaced on every use.
+#[derive(Serialize, Deserialize)]
+pub struct Tokens {
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+/// A login of the account on some device.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionInfo {
+    pub id: String,
+    pub name: String,
+    pub created_at: i64,
+    pub last_used: i64,
+    // the session of the device asking
+    pub current: bool,
+}
+
+/// Sent by a device when it connects to the server.
+#[derive(Serialize, Deserialize)]
+pub struct DeviceHello {
+    pub device: String,
+    pub name: String,
+    pub version: String,
+}
+
+impl DeviceHello {
+    pub fn this_device() -> Self {
+        Self {
+            device: device_id(),
+            name: device_name(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
         }
     }
 }
 
+/// A device that synced with the account.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    // connected to the server right now
+    pub online: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeviceName {
+    pub name: String,
+}
+
+/// Key-verification record stored on the server for end-to-end encrypted sync.
+/// `check` is a known value encrypted with the passphrase-derived key.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyCheck {
+    pub salt: String,
+    pub check: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct LoginUserCred {
     pub username: String,
     pub key: String,
+    // names the session of the login
+    #[serde(default)]
+    pub device: String,
 }
 
 impl LoginUserCred {
     pub fn new(username: String, key: String) -> Self {
-        Self { username, key }
+        Self {
+            username,
+            key,
+            device: device_name(),
+        }
+    }
+}
+
+/// What happens to a copy caught by the sensitive-content filters.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub enum FilterAction {
+    Drop,
+    // stored on this device but never synced
+    LocalOnly,
+    // stored and synced, removed everywhere after the given seconds
+    Expire(u64),
+}
+
+impl FilterAction {
+    /// The action to take when a copy matches both rules.
+    pub fn strictest(self, other: Self) -> Self {
+        match (self, other) {
+            (FilterAction::Drop, _) | (_, FilterAction::Drop) => FilterAction::Drop,
+            (FilterAction::LocalOnly, _) | (_, FilterAction::LocalOnly) => FilterAction::LocalOnly,
+            (FilterAction::Expire(a), FilterAction::Expire(b)) => FilterAction::Expire(a.min(b)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FilterRule {
+    pub name: String,
+    // regex matched against the text of the copy
+    pub pattern: String,
+    pub action: FilterAction,
+}
+
+impl FilterRule {
+    pub fn new(name: &str, pattern: &str, action: FilterAction) -> Self {
+        Self {
+            name: name.to_string(),
+            pattern: pattern.to_string(),
+            action,
+        }
+    }
+}
+
+/// Applies `action` to every copy made in `app`, matched with `SourceApp::is`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AppRule {
+    pub app: String,
+    pub action: FilterAction,
+}
+
+impl AppRule {
+    pub fn new(app: &str, action: FilterAction) -> Self {
+        Self {
+            app: app.to_string(),
+            action,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ContentFilters {
+    // copies marked as secret by password managers, `None` keeps them like any other copy
+    pub password_hint: Option<FilterAction>,
+    pub rules: Vec<FilterRule>,
+    #[serde(default)]
+    pub apps: Vec<AppRule>,
+    // when not empty only copies from these applications are kept, copies from an
+    // unknown application are kept too
+    #[serde(default)]
+    pub allowed_apps: Vec<String>,
+}
+
+impl Default for ContentFilters {
+    fn default() -> Self {
+        Self {
+            password_hint: Some(FilterAction::Drop),
+            rules: vec![
+                FilterRule::new(
+                    "AWS access key",
+                    r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b",
+                    FilterAction::LocalOnly,
+                ),
+                FilterRule::new(
+                    "JSON web token",
+                    r"\beyJ[A-Za-z0-9_-]+\.eyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+",
+                    FilterAction::LocalOnly,
+                ),
+                FilterRule::new(
+                    "Private key",
+                    r"-----BEGIN [A-Z ]*PRIVATE KEY-----",
+                    FilterAction::Drop,
+                ),
+                FilterRule::new(
+                    "Credit card",
+                    r"\b(?:4\d{3}|5[1-5]\d{2}|6011|65\d{2})(?:[ -]?\d{4}){3}\b|\b3[47]\d{2}[ -]?\d{6}[ -]?\d{5}\b",
+                    FilterAction::Drop,
+                ),
+            ],
+            apps: vec![AppRule::new("KeePassXC", FilterAction::Drop)],
+            allowed_apps: Vec::new(),
+        }
+    }
+}
+
+/// Kind of entry a per-type retention applies to.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub enum EntryKind {
+    Text,
+    Image,
+}
+
+impl EntryKind {
+    fn of(typ: &str) -> Self {
+        if typ.starts_with("image/") {
+            EntryKind::Image
+        } else {
+            EntryKind::Text
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TypeRetention {
+    pub kind: EntryKind,
+    // seconds
+    pub max_age: u64,
+}
+
+/// Limits on the history enforced by the daemon next to `max_clipboard`, removals are
+/// synced so every device converges.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Retention {
+    // seconds, `None` keeps entries until they are trimmed
+    pub max_age: Option<u64>,
+    // bytes of every entry and thumbnail together, the oldest entries go first
+    pub max_bytes: Option<u64>,
+    // overrides `max_age` for one kind of entry
+    pub types: Vec<TypeRetention>,
+    pub keep_pined: bool,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_age: None,
+            max_bytes: None,
+            types: Vec::new(),
+            keep_pined: true,
+        }
+    }
+}
+
+impl Retention {
+    /// Entries the policy no longer keeps at unix time `now`, `entries` newest first.
+    pub fn expired(&self, entries: &[storage::EntryStats], now: i64) -> Vec<i64> {
+        let kept = |entry: &&storage::EntryStats| entry.pined && self.keep_pined;
+        // pined entries are never removed, they take their space first
+        let mut total: u64 = entries.iter().filter(kept).map(|entry| entry.size).sum();
+        let mut full = false;
+        let mut expired = Vec::new();
+        for entry in entries.iter().filter(|entry| !kept(entry)) {
+            let kind = EntryKind::of(&entry.typ);
+            let max_age = self
+                .types
+                .iter()
+                .find(|val| val.kind == kind)
+                .map(|val| val.max_age)
+                .or(self.max_age);
+            let too_old = max_age.is_some_and(|age| now - entry.created_at >= age as i64);
+            full = full || self.max_bytes.is_some_and(|max| total + entry.size > max);
+            if too_old || full {
+                expired.push(entry.id);
+            } else {
+                total += entry.size;
+            }
+        }
+        expired
     }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct UserSettings {
     sync: Option<UserCred>,
+    // base url of the sync server, the credentials in `sync` belong to it
+    #[serde(default = "default_server")]
+    server: String,
     pub store_image: bool,
     pub click_on_quit: bool,
     pub paste_on_click: bool,
@@ -478,6 +827,29 @@ pub struct UserSettings {
     pub intrevel: u32,
     pub max_clipboard: Option<u32>,
     pub theme: SystemTheam,
+    #[serde(default)]
+    pub filters: ContentFilters,
+    #[serde(default)]
+    pub retention: Retention,
+    // sync with paired devices on the same network instead of the server
+    #[serde(default)]
+    pub lan_sync: bool,
+    // which of `sync` and `encrept` were read from the secret store or set by the
+    // user, `write_local` only removes those
+    #[serde(default, skip_serializing_if = "Known::is_none")]
+    known: Known,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+struct Known {
+    sync: bool,
+    encrept: bool,
+}
+
+impl Known {
+    fn is_none(&self) -> bool {
+        !self.sync && !self.encrept
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
@@ -487,10 +859,90 @@ pub enum SystemTheam {
     Light,
 }
 
+fn default_server() -> String {
+    http::DEFAULT_SERVER.to_string()
+}
+
+// names of the secrets in the secret store
+const ACCOUNTS_SECRET: &str = "accounts";
+const PASSPHRASE_SECRET: &str = "passphrase";
+
+// credentials of every server the user logged in to, keyed by server url
+fn read_accounts() -> io::Result<BTreeMap<String, UserCred>> {
+    match secrets::get(ACCOUNTS_SECRET)? {
+        Some(data) => {
+            serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+fn write_accounts(accounts: &BTreeMap<String, UserCred>) -> Result<(), Box<dyn Error>> {
+    if accounts.is_empty() {
+        secrets::delete(ACCOUNTS_SECRET)?;
+    } else {
+        secrets::set(ACCOUNTS_SECRET, &serde_json::to_vec(accounts)?)?;
+    }
+    Ok(())
+}
+
+/// The account stored for `server`. Its refresh token is the latest one, copies held
+/// in memory may have been traded already.
+pub fn stored_account(server: &str) -> io::Result<Option<UserCred>> {
+    Ok(read_accounts()?.remove(server))
+}
+
+fn update_account(server: &str, update: impl FnOnce(&mut UserCred)) -> Result<(), Box<dyn Error>> {
+    let mut accounts = read_accounts()?;
+    let user = accounts.get_mut(server).ok_or("Account was logged out")?;
+    update(user);
+    write_accounts(&accounts)
+}
+
+/// Stores the refresh token the account of `server` was rotated to.
+pub fn store_refresh_token(server: &str, refresh_token: String) -> Result<(), Box<dyn Error>> {
+    update_account(server, |user| user.refresh_token = refresh_token)
+}
+
+/// Stores the address the account of `server` was moved to.
+pub fn store_email(server: &str, email: String) -> Result<(), Box<dyn Error>> {
+    update_account(server, |user| user.email = email)
+}
+
+/// Older versions kept the credentials in files encrypted with the key of the build,
+/// the passphrase is moved to the secret store and the accounts have to log in again.
+/// The daemon runs it once when it starts.
+pub fn migrate_legacy() {
+    let dir = get_path_local().join("user");
+    let accounts = dir.join(".user");
+    if accounts.is_file() {
+        warn!("Stored sync accounts are from an older version, log in again to sync");
+        log_error!(fs::remove_file(&accounts));
+    }
+
+    let passphrase = dir.join(".encrept");
+    let Ok(file) = fs::read(&passphrase) else {
+        return;
+    };
+    let key = API_KEY.map(str::to_string).or_else(|| env::var("KEY").ok());
+    match key.map(|key| decrypt_file(key.as_bytes(), &file)) {
+        Some(Ok(val)) => {
+            if let Err(e) = secrets::set(PASSPHRASE_SECRET, &val) {
+                error!("Unable to store encryption passphrase");
+                debug!("{}", e);
+                return;
+            }
+        }
+        _ => warn!("Unable to read the stored encryption passphrase, enter it again"),
+    }
+    log_error!(fs::remove_file(&passphrase));
+}
+
 impl UserSettings {
     pub fn new() -> Self {
         Self {
             sync: None,
+            server: default_server(),
             disable_sync: false,
             store_image: true,
             encrept: None,
@@ -500,31 +952,98 @@ impl UserSettings {
             intrevel: 3,
             max_clipboard: Some(100),
             theme: SystemTheam::System,
+            filters: ContentFilters::default(),
+            retention: Retention::default(),
+            lan_sync: false,
+            known: Known::default(),
         }
     }
 
     pub fn remove_user(&mut self) {
         self.sync = None;
+        self.known.sync = true;
     }
 
     pub fn get_sync(&self) -> &Option<UserCred> {
         &self.sync
     }
 
+    pub fn get_encrept(&self) -> Option<&str> {
+        self.encrept.as_deref()
+    }
+
+    pub fn set_encrept(&mut self, passphrase: Option<String>) {
+        self.encrept = passphrase.filter(|val| !val.is_empty());
+        self.known.encrept = true;
+    }
+
     pub fn set_user(&mut self, val: UserCred) {
-        self.sync = Some(val)
+        self.sync = Some(val);
+        self.known.sync = true;
     }
 
     pub fn is_login(&self) -> bool {
         !(self.sync == None)
     }
 
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    pub fn server_ws(&self) -> String {
+        http::ws_url(&self.server)
+    }
+
+    /// The account the sync position is stored under, accounts of the default server
+    /// keep the bare username they were stored under before servers were configurable.
+    pub fn sync_account(&self) -> Option<String> {
+        let user = self.sync.as_ref()?;
+        if self.server == http::DEFAULT_SERVER {
+            Some(user.username.clone())
+        } else {
+            Some(format!("{}@{}", user.username, self.server))
+        }
+    }
+
+    /// Switches to another sync server, the account last used on it is logged in again.
+    pub fn set_server(&mut self, url: &str) -> Result<(), String> {
+        let url = url.trim().trim_end_matches('/');
+        let parsed = reqwest::Url::parse(url).map_err(|e| format!("Invalid server url: {}", e))?;
+        if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+            return Err(String::from("Server url must start with http:// or https://"));
+        }
+        if url != self.server {
+            let mut accounts = read_accounts()
+                .map_err(|e| format!("Unable to read the stored accounts: {}", e))?;
+            self.sync = accounts.remove(url);
+            self.known.sync = true;
+            self.server = url.to_string();
+        }
+        Ok(())
+    }
+
     pub fn update(&mut self) -> Result<(), Box<dyn Error>> {
-        let new_self = Self::build_user()?;
+        let new_self = Self::build_user()?.with_secrets()?;
         *self = new_self;
         Ok(())
     }
 
+    /// Adds the sync account and the encryption passphrase from the secret store,
+    /// `build_user` only reads the settings file. A store that can not be read is an
+    /// error, not a logged out account.
+    pub fn with_secrets(mut self) -> io::Result<Self> {
+        self.sync = read_accounts()?.remove(&self.server);
+        self.encrept = secrets::get(PASSPHRASE_SECRET)?
+            .map(String::from_utf8)
+            .transpose()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.known = Known {
+            sync: true,
+            encrept: true,
+        };
+        Ok(self)
+    }
+
     pub fn build_user() -> Result<Self, Box<dyn Error>> {
         let mut user_config = get_path_local();
         user_config.push("user");
@@ -543,63 +1062,51 @@ impl UserSettings {
                 UserSettings::new()
             }
         };
-        user_config.pop();
-        user_config.push(".user");
-        let file = if let Ok(data) = fs::read(&user_config) {
-            Some(data)
-        } else {
-            None
-        };
-        let key_bytes: String = match API_KEY {
-            Some(va) => va.to_string(),
-            None => env::var("KEY")
-                .expect("Environment variable KEY is not set")
-                .to_string(),
-        };
-
-        if let Some(file) = file {
-            match decrypt_file(key_bytes.as_bytes(), &file) {
-                Ok(va) => {
-                    let data: UserCred =
-                        serde_json::from_str(&String::from_utf8(va).unwrap()).unwrap();
-                    settings.sync = Some(data);
-                }
-                Err(e) => {
-                    error!("Unable to get read user");
-                    debug!("{}", e);
-                    fs::remove_file(user_config)?;
-                }
-            };
-        }
+        // only `with_secrets` fills them in
+        settings.sync = None;
+        settings.encrept = None;
+        settings.known = Known::default();
 
         Ok(settings)
     }
 
     pub fn write_local(&self) -> Result<(), Box<dyn Error>> {
-        let mut user_config = get_path_local();
-        user_config.push("user");
-        user_config.push(".user");
-        let user = self.sync.clone();
-        if let Some(data) = user {
-            let data = serde_json::to_vec_pretty(&data)?;
-            let en_data = encrept_file(API_KEY.unwrap().as_bytes(), &data)
-                .map_err(|e| Box::<dyn std::error::Error>::from(e.to_string()))?;
-            let mut file = File::create(&user_config)?;
-            file.write_all(&en_data)?;
-        } else {
-            if user_config.is_file() {
-                if let Err(e) = fs::remove_file(&user_config) {
-                    error!("Unable to store settings");
-                    debug!("{}", e);
-                };
+        // accounts of the other servers are kept for when the user switches back,
+        // secrets that were never read are left alone
+        let mut accounts = read_accounts()?;
+        match self.sync.clone() {
+            // the stored refresh token of the same account is newer than this copy
+            Some(data)
+                if accounts
+                    .get(&self.server)
+                    .is_some_and(|user| user.username == data.username) => {}
+            Some(data) => {
+                accounts.insert(self.server.clone(), data);
+            }
+            None if self.known.sync => {
+                accounts.remove(&self.server);
             }
+            None => (),
+        };
+        write_accounts(&accounts)?;
+
+        match &self.encrept {
+            Some(passphrase) => secrets::set(PASSPHRASE_SECRET, passphrase.as_bytes())?,
+            None if self.known.encrept => secrets::delete(PASSPHRASE_SECRET)?,
+            None => (),
         }
 
-        user_config.pop();
+        let mut user_config = get_path_local();
+        user_config.push("user");
+        if !user_config.is_dir() {
+            create_dir(&user_config)?;
+        }
         user_config.push(".settings");
 
         let mut data = self.clone();
         data.sync = None;
+        data.encrept = None;
+        data.known = Known::default();
         let data = serde_json::to_vec_pretty(&data)?;
         let mut file = File::create(&user_config)?;
         file.write_all(&data)?;
@@ -611,14 +1118,11 @@ impl UserSettings {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Edit {
     New {
-        path: PathBuf,
-        typ: String,
+        id: i64,
     },
     // edit represent add new entry and remove the old one
     Edit {
-        path: PathBuf,
-        typ: String,
-        new_id: String,
+        id: i64,
     },
     Remove,
 }
@@ -648,6 +1152,8 @@ pub struct NewUserOtp {
     pub email: String,
     pub otp: String,
     pub key: String,
+    #[serde(default)]
+    pub device: String,
 }
 
 impl NewUserOtp {
@@ -657,16 +1163,48 @@ impl NewUserOtp {
             email,
             otp,
             key,
+            device: device_name(),
         }
     }
 }
 
+/// Sets a new password with the one-time password mailed to the account.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PasswordReset {
+    pub user: String,
+    pub otp: String,
+    pub key: String,
+    #[serde(default)]
+    pub device: String,
+}
+
+impl PasswordReset {
+    pub fn new(user: String, otp: String, key: String) -> Self {
+        Self {
+            user,
+            otp,
+            key,
+            device: device_name(),
+        }
+    }
+}
+
+/// A new address for the account, `otp` is the one mailed to it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EmailChange {
+    pub email: String,
+    #[serde(default)]
+    pub otp: String,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub enum ResopnseClientToServer {
     Updated,
     Outdated,
     CheckVersion(String),
     CheckVersionArr(Vec<String>),
+    // asks for every change after the cursor, `None` for a first sync
+    Sync(Option<SyncCursor>),
     Error(String),
     Data {
         data: String,
@@ -707,6 +1245,64 @@ pub enum ResopnseServerToClient {
     },
     Updated,
     Outdated,
+    // sent after the changes it covers were sent
+    Cursor(SyncCursor),
+    // sent before the whole history to a client that was offline for so long that
+    // removals it missed were forgotten, every entry stored after `removed_after`
+    // that is not in `live` was removed
+    Resync {
+        live: Vec<String>,
+        removed_after: i64,
+    },
+    // the entry sent as `old` was not stored
+    Rejected {
+        old: String,
+        error: LimitError,
+    },
+    // the entry sent as `old` could not be stored this time, it is sent again later
+    Failed {
+        old: String,
+        error: String,
+    },
+}
+
+/// A limit of the server an entry did not fit in.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum LimitError {
+    // bytes of the entry and the most the server accepts for one entry
+    TooLarge { size: u64, max: u64 },
+    // the entry alone is bigger than the storage of the user
+    QuotaExceeded { size: u64, max: u64 },
+}
+
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitError::TooLarge { size, max } => write!(
+                f,
+                "entry of {} bytes is over the server limit of {} bytes",
+                size, max
+            ),
+            LimitError::QuotaExceeded { size, max } => write!(
+                f,
+                "entry of {} bytes does not fit in the {} bytes of storage of the account",
+                size, max
+            ),
+        }
+    }
+}
+
+/// Position in the change log of a user on the server.
+/// The epoch changes when the server loses its log, clients then sync from the start.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SyncCursor {
+    pub epoch: String,
+    pub seq: u64,
+}
+
+/// Unix time the server stored an entry at, it names entries `<time>-<n>`.
+pub fn remote_time(remote_id: &str) -> Option<i64> {
+    remote_id.split_once('-')?.0.parse().ok()
 }
 
 pub enum MessageType {
@@ -722,256 +1318,95 @@ pub enum MessageIPC {
     New(Data),
     Edit(EditData),
     UpdateSettings(UserSettings),
-    Delete(PathBuf, String),
+    Delete(i64),
     Updated,
     Close,
+    Cli(cli::CliRequest),
+    Account(AccountRequest),
+    Search(SearchQuery),
+}
+
+/// Requests of the gui for the sync account, the daemon holds its tokens.
+#[derive(Serialize, Deserialize)]
+pub enum AccountRequest {
+    Sessions,
+    Revoke(String),
+    Logout,
+    // mails a one-time password to the new address
+    ChangeEmail(String),
+    ConfirmEmail(EmailChange),
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct EditData {
     data: Data,
-    id: String,
-    path: PathBuf,
+    id: i64,
 }
 
 impl EditData {
-    pub fn new(data: Data, id: String, path: PathBuf) -> Self {
-        Self { data, id, path }
+    pub fn new(data: Data, id: i64) -> Self {
+        Self { data, id }
     }
 }
 
 pub enum MessageChannel {
-    New {
-        path: String,
-        time: String,
-        typ: String,
-    },
+    New(i64),
+    // entries kept on this device only, they are indexed but never synced
+    NewLocal(i64),
+    // `remote_id` is the server id of the replaced entry, if it was synced
     Edit {
-        path: String,
-        old_id: String,
-        new_id: String,
-        typ: String,
+        old: i64,
+        new: i64,
+        remote_id: Option<String>,
+    },
+    Remove {
+        id: i64,
+        remote_id: Option<String>,
     },
-    Remove(String),
     SettingsChanged,
 }
 
-pub fn get_path_local() -> PathBuf {
-    #[cfg(target_os = "linux")]
-    {
-        let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-        let path: PathBuf = [home.as_str(), ".local/share/clippy/"].iter().collect();
-        fs::create_dir_all(&path).unwrap();
-        return path;
-    }
-
-    #[cfg(target_os = "macos")]
-    {
-        let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-        let path: PathBuf = [home.as_str(), ".local/share/clippy/"].iter().collect();
-        fs::create_dir_all(&path).unwrap();
-        return path;
-    }
-
-    #[cfg(target_os = "windows")]
-    {
-        let home = env::var("APPDATA").unwrap_or_else(|_| "C:\\Users\\Public".to_string());
-        let path: PathBuf = [home.as_str(), "clippy"].iter().collect();
-        fs::create_dir_all(&path).unwrap();
-        return path;
-    }
-
-    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
-    {
-        compile_error!("Unsupported operating system");
-    }
-}
-
-pub fn get_path() -> PathBuf {
-    #[cfg(target_os = "linux")]
-    {
-        let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-        let path: PathBuf = [home.as_str(), ".local/share/clippy/data"].iter().collect();
-        fs::create_dir_all(&path).unwrap();
-        return path;
-    }
-
-    #[cfg(target_os = "macos")]
-    {
-        let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-        let path: PathBuf = [home.as_str(), ".local/share/clippy/data"].iter().collect();
-        fs::create_dir_all(&path).unwrap();
-        return path;
-    }
-
-    #[cfg(target_os = "windows")]
-    {
-        let home = env::var("APPDATA").unwrap_or_else(|_| "C:\\Users\\Public\\AppData".to_string());
-        let path: PathBuf = [home.as_str(), "clippy\\data"].iter().collect();
-        fs::create_dir_all(&path).unwrap();
-        return path;
-    }
-
-    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
-    {
-        compile_error!("Unsupported operating system");
-    }
-}
-
-pub fn get_path_pending() -> PathBuf {
-    #[cfg(target_os = "linux")]
-    {
-        let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-        let path: PathBuf = [home.as_str(), ".local/share/clippy/local_data"]
-            .iter()
-            .collect();
-        fs::create_dir_all(&path).unwrap();
-        return path;
-    }
-
-    #[cfg(target_os = "macos")]
-    {
-        let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-        let path: PathBuf = [home.as_str(), ".local/share/clippy/local_data"]
-            .iter()
-            .collect();
-        fs::create_dir_all(&path).unwrap();
-        return path;
-    }
-
-    #[cfg(target_os = "windows")]
-    {
-        let home = env::var("APPDATA").unwrap_or_else(|_| "C:\\Users\\Public\\AppData".to_string());
-        let path: PathBuf = [home.as_str(), "clippy\\local_data"].iter().collect();
-        fs::create_dir_all(&path).unwrap();
-        return path;
-    }
-
-    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
-    {
-        compile_error!("Unsupported operating system");
-    }
+/// The name of this computer, shown to the user to tell devices apart.
+pub fn device_name() -> String {
+    env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|val| val.trim().to_string())
+        .filter(|val| !val.is_empty())
+        .unwrap_or_else(|| env::consts::OS.to_string())
 }
 
-pub fn get_path_image() -> PathBuf {
-    let path: PathBuf = {
-        #[cfg(target_os = "linux")]
+/// The id of this device, made on first use. Servers and paired devices know it by it.
+pub fn device_id() -> String {
+    static ID: OnceLock<String> = OnceLock::new();
+    ID.get_or_init(|| {
+        let path = get_path_local().join("user").join(".device");
+        if let Ok(id) = fs::read_to_string(&path)
+            && !id.trim().is_empty()
         {
-            let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-            [home.as_str(), ".local/share/clippy/image"]
-                .iter()
-                .collect()
+            return id.trim().to_string();
         }
-
-        #[cfg(target_os = "macos")]
+        let mut id = [0u8; 8];
+        OsRng.fill_bytes(&mut id);
+        let id: String = id.iter().map(|val| format!("{:02x}", val)).collect();
+        if let Err(e) = fs::create_dir_all(get_path_local().join("user"))
+            .and_then(|_| fs::write(&path, &id))
         {
-            let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-            [home.as_str(), ".local/share/clippy/image"]
-                .iter()
-                .collect()
+            error!("Unable to store the device id");
+            debug!("{}", e);
         }
-
-        #[cfg(target_os = "windows")]
-        {
-            let home =
-                env::var("APPDATA").unwrap_or_else(|_| "C:\\Users\\Public\\AppData".to_string());
-            [home.as_str(), "clippy\\image"].iter().collect()
-        }
-
-        #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
-        {
-            compile_error!("Unsupported operating system");
-        }
-    };
-    fs::create_dir_all(&path).unwrap();
-    path
+        id
+    })
+    .clone()
 }
 
-pub fn get_path_pined() -> PathBuf {
-    let path: PathBuf = {
-        #[cfg(target_os = "linux")]
-        {
-            let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-            [home.as_str(), ".local/share/clippy/pined"]
-                .iter()
-                .collect()
-        }
-
-        #[cfg(target_os = "macos")]
-        {
-            let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-            [home.as_str(), ".local/share/clippy/pined"]
-                .iter()
-                .collect()
-        }
-
-        #[cfg(target_os = "windows")]
-        {
-            let home =
-                env::var("APPDATA").unwrap_or_else(|_| "C:\\Users\\Public\\AppData".to_string());
-            [home.as_str(), "clippy\\pined"].iter().collect()
-        }
-
-        #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
-        {
-            compile_error!("Unsupported operating system");
-        }
-    };
-    fs::create_dir_all(&path).unwrap();
-    path
+pub fn get_path_local() -> PathBuf {
+    paths::data_dir()
 }
 
 pub fn cache_path() -> PathBuf {
-    let base: PathBuf = {
-        #[cfg(target_os = "linux")]
-        {
-            PathBuf::from(
-                env::var("XDG_CACHE_HOME")
-                    .unwrap_or_else(|_| format!("{}/.cache", env::var("HOME").unwrap())),
-            )
-        }
-
-        #[cfg(target_os = "windows")]
-        {
-            PathBuf::from(env::var("LOCALAPPDATA").expect("LOCALAPPDATA not set"))
-        }
-
-        #[cfg(target_os = "macos")]
-        {
-            PathBuf::from(format!("{}/Library/Caches", env::var("HOME").unwrap()))
-        }
-
-        #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
-        {
-            panic!("Unsupported platform");
-        }
-    };
-
-    let path = base.join("clippy");
-    fs::create_dir_all(&path).unwrap();
-    path
-}
-
-pub fn store_image(id: &[String], target_dir: PathBuf) -> Result<(), Box<dyn Error>> {
-    for i in id {
-        let mut path = target_dir.clone();
-        path.push(i);
-
-        let file = fs::read_to_string(path)?;
-        let data: Data = serde_json::from_str(&file)?;
-
-        if let Some(val) = data.get_image() {
-            save_image(i, &val)?;
-        }
-    }
-    Ok(())
-}
-
-pub fn get_image_path(id: &PathBuf) -> Option<PathBuf> {
-    let mut path = get_path_image();
-    let file_nema = format!("{}.png", id.file_name()?.to_str()?);
-    path.push(file_nema);
-    Some(path)
+    paths::cache_dir()
 }
 
 pub fn set_global_update_bool(value: bool) {
@@ -999,6 +1434,28 @@ pub fn get_global_update_bool() -> bool {
     path.exists()
 }
 
+// sync errors the user has to act on (like a wrong passphrase) are shown in the gui
+pub fn set_sync_error(err: Option<&str>) {
+    let mut path = get_path_local();
+    path.push("SYNC_ERROR");
+
+    if let Some(err) = err {
+        if let Err(e) = fs::write(&path, err) {
+            error!("Failed to write sync error state: {}", e);
+        }
+    } else if path.exists()
+        && let Err(e) = fs::remove_file(&path)
+    {
+        error!("Failed to remove sync error state: {}", e);
+    }
+}
+
+pub fn get_sync_error() -> Option<String> {
+    let mut path = get_path_local();
+    path.push("SYNC_ERROR");
+    fs::read_to_string(path).ok()
+}
+
 #[cfg(target_os = "linux")]
 pub fn copy_to_linux(data: Data, paste_on_click: bool) {
     use crate::write_clipboard::{copy_to_clipboard, copy_to_clipboard_wl};
@@ -1009,15 +1466,55 @@ pub fn copy_to_linux(data: Data, paste_on_click: bool) {
     }
 }
 
-pub fn read_data_by_id(id: &str) -> Result<Data, io::Error> {
-    let mut path = get_path();
-    path.push(id);
-
-    let data_str = fs::read_to_string(path)?;
+/// Removes entries whose expiry passed or that the retention policy no longer keeps,
+/// runs for the life of the daemon.
+pub fn expire_entries(tx: &Sender<MessageChannel>) {
+    for tick in 0u64.. {
+        let now = chrono::Utc::now().timestamp();
+        let mut ids = match storage().expired(now) {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!("Unable to read expired entries");
+                debug!("{}", e);
+                Vec::new()
+            }
+        };
+        // the policy reads the whole history, once a minute is enough
+        if tick % 60 == 0 {
+            ids.extend(retention_expired(now));
+            ids.sort_unstable();
+            ids.dedup();
+        }
+        for id in ids {
+            debug!("entry {} expired", id);
+            log_error!(remove_entry(tx, id));
+        }
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+}
 
-    let data: Data = serde_json::from_str(&data_str)?;
+fn retention_expired(now: i64) -> Vec<i64> {
+    let Ok(settings) = UserSettings::build_user() else {
+        return Vec::new();
+    };
+    match storage().stats() {
+        Ok(entries) => settings.retention.expired(&entries, now),
+        Err(e) => {
+            error!("Unable to apply the retention policy");
+            debug!("{}", e);
+            Vec::new()
+        }
+    }
+}
 
-    Ok(data)
+/// Removes an entry on behalf of the gui and queues the removal for sync.
+pub fn remove_entry(tx: &Sender<MessageChannel>, id: i64) -> Result<(), io::Error> {
+    let remote_id = storage().remove(id)?;
+    if let Err(err) = tx.try_send(MessageChannel::Remove { id, remote_id }) {
+        warn!("Failed to send removed entry '{}' to channel: {}", id, err);
+    }
+    set_global_update_bool(true);
+    Ok(())
 }
 
 pub fn is_valid_username(username: &str) -> bool {
@@ -1088,33 +1585,8 @@ pub fn is_valid_otp(otp: &str) -> bool {
     }
 }
 
-pub fn rewrite_pending_to_data(path: PathBuf, typ: String, time: &str, thumbnail: bool) {
-    if let Err(err) = fs::rename(&path, get_path().join(&time)) {
-        error!("unable to rewrite data: {:?}", err)
-    };
-
-    if thumbnail && typ.starts_with("image/") {
-        if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
-            let image_path = get_path_image();
-            let old_img_file_name = format!("{}.png", file_name);
-            let old_img = image_path.join(&old_img_file_name);
-            let new_image = image_path.join(format!("{}.png", time));
-
-            if let Err(e) = fs::rename(&old_img, &new_image) {
-                log::error!("Failed to rename file: {}", e);
-            }
-        }
-    }
-}
-
-pub fn save_image(time: &str, data: &[u8]) -> Result<(), io::Error> {
-    let path: PathBuf = crate::get_path_image();
-
-    fs::create_dir_all(&path)?;
-
-    let img_path = path.join(format!("{}.png", time));
-    let mut img_file = File::create(img_path)?;
-
+/// Builds the png thumbnail shown in the gui for an image entry.
+pub fn make_thumbnail(data: &[u8]) -> Result<Vec<u8>, io::Error> {
     let image = image::load_from_memory(&data).map_err(|e| {
         io::Error::new(
             io::ErrorKind::InvalidData,
@@ -1124,9 +1596,132 @@ pub fn save_image(time: &str, data: &[u8]) -> Result<(), io::Error> {
 
     let resized = image.thumbnail(128, 128);
 
+    let mut thumbnail = Vec::new();
     resized
-        .write_to(&mut img_file, image::ImageFormat::Png)
+        .write_to(&mut io::Cursor::new(&mut thumbnail), image::ImageFormat::Png)
         .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Image write error: {e}")))?;
 
-    Ok(())
+    Ok(thumbnail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(class: Option<&str>, process: Option<&str>) -> SourceApp {
+        SourceApp {
+            class: class.map(str::to_string),
+            process: process.map(str::to_string),
+            pid: None,
+        }
+    }
+
+    #[test]
+    fn source_app_matches_class_or_process() {
+        let keepass = app(Some("org.keepassxc.KeePassXC"), Some("keepassxc"));
+        assert!(keepass.is("KeePassXC"));
+        assert!(keepass.is("org.keepassxc.keepassxc"));
+        assert!(!keepass.is("keepass"));
+        assert_eq!(keepass.name(), "org.keepassxc.KeePassXC");
+
+        let unnamed = app(None, Some("firefox"));
+        assert!(unnamed.is("Firefox"));
+        assert_eq!(unnamed.name(), "firefox");
+        assert!(!app(None, None).is(""));
+    }
+
+    fn entry(id: i64, typ: &str, pined: bool, created_at: i64, size: u64) -> storage::EntryStats {
+        storage::EntryStats {
+            id,
+            typ: typ.to_string(),
+            pined,
+            created_at,
+            size,
+        }
+    }
+
+    #[test]
+    fn retention_keeps_everything_by_default() {
+        let entries = [
+            entry(2, "text/plain", false, 0, 10),
+            entry(1, "image/png", false, 0, 10),
+        ];
+        assert!(Retention::default().expired(&entries, 1_000_000).is_empty());
+    }
+
+    #[test]
+    fn retention_removes_old_entries() {
+        let retention = Retention {
+            max_age: Some(100),
+            ..Retention::default()
+        };
+        let entries = [
+            entry(3, "text/plain", false, 950, 1),
+            entry(2, "text/plain", false, 900, 1),
+            entry(1, "text/plain", true, 0, 1),
+        ];
+        assert_eq!(retention.expired(&entries, 1000), vec![2]);
+
+        let retention = Retention {
+            keep_pined: false,
+            ..retention
+        };
+        assert_eq!(retention.expired(&entries, 1000), vec![2, 1]);
+    }
+
+    #[test]
+    fn retention_per_type_overrides_max_age() {
+        let retention = Retention {
+            max_age: Some(1000),
+            types: vec![TypeRetention {
+                kind: EntryKind::Image,
+                max_age: 10,
+            }],
+            ..Retention::default()
+        };
+        let entries = [
+            entry(3, "image/png", false, 995, 1),
+            entry(2, "image/jpeg", false, 900, 1),
+            entry(1, "text/plain", false, 900, 1),
+        ];
+        assert_eq!(retention.expired(&entries, 1000), vec![2]);
+    }
+
+    #[test]
+    fn retention_max_bytes_drops_the_oldest() {
+        let retention = Retention {
+            max_bytes: Some(100),
+            ..Retention::default()
+        };
+        let entries = [
+            entry(5, "text/plain", false, 0, 40),
+            entry(4, "text/plain", true, 0, 30),
+            entry(3, "text/plain", false, 0, 20),
+            entry(2, "text/plain", false, 0, 20),
+            // would fit, but everything older than a removed entry goes too
+            entry(1, "text/plain", false, 0, 1),
+        ];
+        assert_eq!(retention.expired(&entries, 0), vec![2, 1]);
+    }
+
+    #[test]
+    fn source_app_needs_a_name() {
+        assert!(SourceApp::new(Some(String::new()), None).is_none());
+        let app = SourceApp::new(Some(String::from("kitty")), None).unwrap();
+        assert_eq!(app.name(), "kitty");
+    }
+
+    #[test]
+    fn settings_only_carry_the_secrets_they_know() {
+        let mut settings = UserSettings::new();
+        // what the gui sends without having read the secrets leaves them alone
+        let value = serde_json::to_value(&settings).unwrap();
+        assert!(value.get("known").is_none());
+
+        settings.set_encrept(None);
+        let settings: UserSettings =
+            serde_json::from_value(serde_json::to_value(&settings).unwrap()).unwrap();
+        assert!(settings.known.encrept);
+        assert!(!settings.known.sync);
+    }
 }
@@ -1,7 +1,6 @@
-use log::error;
 use tokio::sync::mpsc::Receiver;
 
-use crate::{MessageChannel, UserData, UserSettings, log_error};
+use crate::{MessageChannel, UserData, UserSettings};
 
 pub fn start_local(rx: &mut Receiver<MessageChannel>, mut usersettings: UserSettings) {
     let user_data = UserData::build();
@@ -9,27 +8,19 @@ pub fn start_local(rx: &mut Receiver<MessageChannel>, mut usersettings: UserSett
     actix_rt::System::new().block_on(async {
         while let Some(msg) = rx.recv().await {
             match msg {
-                MessageChannel::Edit {
-                    path: _,
-                    old_id: _,
-                    new_id,
-                    typ: _,
-                } => {
-                    user_data.add_data(new_id, usersettings.max_clipboard);
+                MessageChannel::Edit { old, new, .. } => {
+                    user_data.unindex(old);
+                    user_data.add_data(new, usersettings.max_clipboard);
                 }
-                MessageChannel::New {
-                    path: _,
-                    time,
-                    typ: _,
-                } => {
-                    user_data.add_data(time, usersettings.max_clipboard);
+                MessageChannel::New(id) | MessageChannel::NewLocal(id) => {
+                    user_data.add_data(id, usersettings.max_clipboard);
                 }
                 MessageChannel::SettingsChanged => {
                     usersettings = UserSettings::build_user().unwrap();
                     break;
                 }
-                MessageChannel::Remove(id) => {
-                    log_error!(user_data.remove_and_remove_file(&id));
+                MessageChannel::Remove { id, .. } => {
+                    user_data.unindex(id);
                 }
             }
         }
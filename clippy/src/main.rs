@@ -3,11 +3,17 @@
     windows_subsystem = "windows"
 )]
 
+use clap::Parser;
 use clipboard_rs::{ClipboardWatcher, ClipboardWatcherContext};
+use clippy::cli::{Cli, run as run_cli};
 use clippy::ipc::ipc::{ipc_check, startup};
+use clippy::lan::start_lan;
 use clippy::local::start_local;
 use clippy::user::start_cloud;
-use clippy::{MessageChannel, UserSettings, read_clipboard};
+use clippy::{
+    DEFAULT_LOG, MessageChannel, UserSettings, expire_entries, migrate_legacy, paths,
+    read_clipboard,
+};
 use env_logger::{Builder, Env};
 use log::error;
 use log::{debug, warn};
@@ -83,7 +89,21 @@ fn read_clipboard(tx: &Sender<MessageChannel>) -> Result<(), Box<dyn Error + Sen
 }
 
 fn main() {
-    Builder::from_env(Env::default().filter_or("LOG", "info")).init();
+    let cli = Cli::parse();
+    if let Err(e) = paths::init_profile(cli.profile.as_deref()) {
+        eprintln!("clippy: {}", e);
+        process::exit(1);
+    }
+    if let Some(command) = cli.command {
+        Builder::from_env(Env::default().filter_or("LOG", "warn")).init();
+        if let Err(e) = run_cli(command) {
+            eprintln!("clippy: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    Builder::from_env(Env::default().filter_or("LOG", DEFAULT_LOG)).init();
     let channel = match startup() {
         Ok(x) => {
             debug!("Process startup success");
@@ -95,15 +115,18 @@ fn main() {
             process::exit(1);
         }
     };
+    migrate_legacy();
 
     let (tx, mut rx) = tokio::sync::mpsc::channel::<MessageChannel>(30);
 
     thread::spawn(move || {
         loop {
-            match UserSettings::build_user() {
+            match UserSettings::build_user().and_then(|val| Ok(val.with_secrets()?)) {
                 Ok(usersettings) => {
                     if usersettings.disable_sync {
                         start_local(&mut rx, usersettings);
+                    } else if usersettings.lan_sync {
+                        start_lan(&mut rx, usersettings);
                     } else {
                         if usersettings.get_sync().is_some() {
                             start_cloud(&mut rx, usersettings);
@@ -121,6 +144,12 @@ fn main() {
         }
     });
 
+    // removes expired entries and enforces the retention policy
+    {
+        let tx_c = tx.clone();
+        thread::spawn(move || expire_entries(&tx_c));
+    }
+
     // this thread reads the gui clipboard entry && settings change
     {
         let tx_c = tx.clone();
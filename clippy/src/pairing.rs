@@ -0,0 +1,358 @@
+//! Pairs two devices for network sync with a short code.
+//!
+//! The devices run SPAKE2 with the code as password, so an attacker on the network
+//! gets a single guess per attempt and cannot try codes offline from what was sent.
+
+use crate::lan::{
+    Discovery, LanState, Peer, announce, discovery_socket, read_discovery, read_frame, write_frame,
+};
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use base64::{Engine, engine::general_purpose};
+use futures_util::StreamExt;
+use futures_util::stream::FuturesUnordered;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::collections::HashSet;
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::time::{Duration, interval, timeout};
+
+const PAIR_TIMEOUT: Duration = Duration::from_secs(120);
+// a device that connects has this long to finish the exchange
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(10);
+// wrong codes accepted before the offer is withdrawn
+const ATTEMPTS: usize = 3;
+const MAX_MESSAGE: usize = 4 * 1024;
+// identities of the two roles
+const HOST_ID: &[u8] = b"clippy pairing host";
+const JOIN_ID: &[u8] = b"clippy pairing join";
+
+#[derive(Serialize, Deserialize)]
+struct Start {
+    device: String,
+    name: String,
+    // the SPAKE2 message, base64
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Confirm {
+    mac: String,
+}
+
+/// A random six digit code.
+pub fn new_code() -> String {
+    format!("{:06}", OsRng.next_u32() % 1_000_000)
+}
+
+// keys of the confirmation of each side and the key of the pair
+struct Keys {
+    host: [u8; 32],
+    join: [u8; 32],
+    pair: [u8; 32],
+}
+
+impl Keys {
+    // the devices are not part of SPAKE2, they are bound to the keys here
+    fn derive(host: &Start, join: &Start, shared: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut transcript = Sha256::new();
+        for val in [host, join] {
+            for field in [&val.device, &val.name, &val.message] {
+                transcript.update((field.len() as u32).to_be_bytes());
+                transcript.update(field.as_bytes());
+            }
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&transcript.finalize()), shared);
+        let mut keys = Self {
+            host: [0u8; 32],
+            join: [0u8; 32],
+            pair: [0u8; 32],
+        };
+        for (info, key) in [
+            (&b"clippy pairing host"[..], &mut keys.host),
+            (b"clippy pairing join", &mut keys.join),
+            (b"clippy pairing key", &mut keys.pair),
+        ] {
+            hkdf.expand(info, key).map_err(|e| e.to_string())?;
+        }
+        Ok(keys)
+    }
+}
+
+fn confirm(key: &[u8]) -> Result<Hmac<Sha256>, Box<dyn Error>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).map_err(|e| e.to_string())?;
+    mac.update(b"clippy pairing confirm");
+    Ok(mac)
+}
+
+async fn send<T: Serialize>(stream: &mut TcpStream, msg: &T) -> Result<(), Box<dyn Error>> {
+    Ok(write_frame(stream, &serde_json::to_vec(msg)?).await?)
+}
+
+async fn recv<T: DeserializeOwned>(stream: &mut TcpStream) -> Result<T, Box<dyn Error>> {
+    Ok(serde_json::from_slice(
+        &read_frame(stream, MAX_MESSAGE).await?,
+    )?)
+}
+
+/// Runs the exchange on a connection, the joiner speaks first and proves the code
+/// before the host does.
+async fn exchange(
+    stream: &mut TcpStream,
+    state: &LanState,
+    code: &str,
+    host: bool,
+) -> Result<Peer, Box<dyn Error>> {
+    let password = Password::new(code);
+    let (host_id, join_id) = (Identity::new(HOST_ID), Identity::new(JOIN_ID));
+    let (spake, message) = if host {
+        Spake2::<Ed25519Group>::start_a(&password, &host_id, &join_id)
+    } else {
+        Spake2::<Ed25519Group>::start_b(&password, &host_id, &join_id)
+    };
+    let mine = Start {
+        device: state.device.clone(),
+        name: state.name.clone(),
+        message: general_purpose::STANDARD.encode(message),
+    };
+    let theirs: Start = if host {
+        let theirs = recv(stream).await?;
+        send(stream, &mine).await?;
+        theirs
+    } else {
+        send(stream, &mine).await?;
+        recv(stream).await?
+    };
+    if theirs.device == state.device {
+        return Err("Unable to pair a device with itself".into());
+    }
+
+    let shared = spake
+        .finish(&general_purpose::STANDARD.decode(&theirs.message)?)
+        .map_err(|e| format!("Invalid pairing message: {}", e))?;
+    let keys = if host {
+        Keys::derive(&mine, &theirs, &shared)?
+    } else {
+        Keys::derive(&theirs, &mine, &shared)?
+    };
+    let (own, other) = if host {
+        (keys.host, keys.join)
+    } else {
+        (keys.join, keys.host)
+    };
+    let mac = Confirm {
+        mac: general_purpose::STANDARD.encode(confirm(&own)?.finalize().into_bytes()),
+    };
+    let check = |reply: Confirm| -> Result<(), Box<dyn Error>> {
+        let bytes = general_purpose::STANDARD.decode(reply.mac)?;
+        confirm(&other)?
+            .verify_slice(&bytes)
+            .map_err(|_| "The code does not match".into())
+    };
+    if host {
+        check(recv(stream).await?)?;
+        send(stream, &mac).await?;
+    } else {
+        send(stream, &mac).await?;
+        check(recv(stream).await.map_err(|_| "The code does not match")?)?;
+    }
+
+    Ok(Peer::new(theirs.device, theirs.name, &keys.pair))
+}
+
+/// Offers pairing on the network until a device joins with `code`.
+pub async fn host(state: &LanState, code: &str) -> Result<Peer, Box<dyn Error>> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let socket = discovery_socket()?;
+    let offer = Discovery::Pairing {
+        device: state.device.clone(),
+        name: state.name.clone(),
+        port: listener.local_addr()?.port(),
+    };
+
+    let announcing = async {
+        let mut tick = interval(Duration::from_secs(1));
+        loop {
+            tick.tick().await;
+            if let Err(e) = announce(&socket, &offer).await {
+                debug!("unable to announce pairing: {}", e);
+            }
+        }
+    };
+    let pairing = async {
+        select! {
+            peer = serve(&listener, state, code) => peer,
+            _ = announcing => unreachable!(),
+        }
+    };
+    timeout(PAIR_TIMEOUT, pairing)
+        .await
+        .map_err(|_| "No device joined in time")?
+}
+
+// runs the exchanges concurrently, a device that stalls does not hold up the others
+async fn serve(
+    listener: &TcpListener,
+    state: &LanState,
+    code: &str,
+) -> Result<Peer, Box<dyn Error>> {
+    let mut running = FuturesUnordered::new();
+    let mut failed = 0;
+    loop {
+        select! {
+            // every running exchange may be a wrong guess
+            accepted = listener.accept(), if failed + running.len() < ATTEMPTS => match accepted {
+                Ok((stream, addr)) => running.push(attempt(stream, addr, state, code)),
+                Err(e) => debug!("unable to accept pairing connection: {}", e),
+            },
+            Some((addr, result)) = running.next() => match result {
+                Ok(peer) => return Ok(peer),
+                Err(e) => {
+                    warn!("Pairing with {} failed: {}", addr, e);
+                    failed += 1;
+                    if failed >= ATTEMPTS {
+                        return Err("Too many failed attempts, start pairing again".into());
+                    }
+                }
+            },
+        }
+    }
+}
+
+async fn attempt(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    state: &LanState,
+    code: &str,
+) -> (SocketAddr, Result<Peer, Box<dyn Error>>) {
+    let result = timeout(EXCHANGE_TIMEOUT, exchange(&mut stream, state, code, true))
+        .await
+        .unwrap_or_else(|_| Err("the device stopped responding".into()));
+    (addr, result)
+}
+
+/// Joins a device on the network that offers pairing with `code`, every device
+/// offering is tried until one accepts it.
+pub async fn join(state: &LanState, code: &str) -> Result<Peer, Box<dyn Error>> {
+    let socket = discovery_socket()?;
+    let mut tried = HashSet::new();
+    let pairing = async {
+        let mut buf = vec![0u8; 2048];
+        loop {
+            let (Discovery::Pairing { device, name, port }, addr) =
+                read_discovery(&socket, &mut buf).await?
+            else {
+                continue;
+            };
+            if device == state.device || !tried.insert(device) {
+                continue;
+            }
+            let pairing = async {
+                let mut stream = TcpStream::connect((addr, port)).await?;
+                exchange(&mut stream, state, code, false).await
+            };
+            match timeout(EXCHANGE_TIMEOUT, pairing).await {
+                Ok(Ok(peer)) => return Ok(peer),
+                Ok(Err(e)) => warn!("Pairing with {} failed: {}", name, e),
+                Err(_) => warn!(
+                    "Pairing with {} failed: the device stopped responding",
+                    name
+                ),
+            }
+        }
+    };
+    let result = timeout(PAIR_TIMEOUT, pairing).await;
+    result.map_err(|_| {
+        if tried.is_empty() {
+            "No device offering pairing was found"
+        } else {
+            "No device accepted the code"
+        }
+    })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: &str) -> LanState {
+        LanState {
+            device: format!("{}-id", name),
+            name: name.to_string(),
+            peers: Vec::new(),
+        }
+    }
+
+    fn key(peer: &Peer) -> serde_json::Value {
+        serde_json::to_value(peer).unwrap()["key"].clone()
+    }
+
+    async fn listener() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    async fn join_at(addr: SocketAddr, state: &LanState, code: &str) -> Result<Peer, String> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        exchange(&mut stream, state, code, false)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    #[tokio::test]
+    async fn devices_with_the_same_code_agree_on_a_key() {
+        let (listener, addr) = listener().await;
+        let (host, joiner) = (device("host"), device("joiner"));
+        let (hosted, joined) = tokio::join!(
+            serve(&listener, &host, "123456"),
+            join_at(addr, &joiner, "123456")
+        );
+        let (hosted, joined) = (hosted.unwrap(), joined.unwrap());
+        assert_eq!(hosted.device, "joiner-id");
+        assert_eq!(joined.device, "host-id");
+        assert_eq!(joined.name, "host");
+        assert_eq!(key(&hosted), key(&joined));
+    }
+
+    #[tokio::test]
+    async fn wrong_codes_are_refused() {
+        let (listener, addr) = listener().await;
+        let (host, joiner) = (device("host"), device("joiner"));
+        let guesses = async {
+            let mut errors = Vec::new();
+            for code in ["000000", "000001", "000002"] {
+                errors.push(join_at(addr, &joiner, code).await.unwrap_err());
+            }
+            errors
+        };
+        let (hosted, errors) = tokio::join!(serve(&listener, &host, "123456"), guesses);
+        assert!(hosted.is_err());
+        assert!(errors.iter().all(|e| e == "The code does not match"));
+    }
+
+    #[tokio::test]
+    async fn a_stalled_device_does_not_block_pairing() {
+        let (listener, addr) = listener().await;
+        let (host, joiner) = (device("host"), device("joiner"));
+        let joining = async {
+            // connects and never says anything
+            let _stalled = TcpStream::connect(addr).await.unwrap();
+            join_at(addr, &joiner, "123456").await
+        };
+        let (hosted, joined) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(serve(&listener, &host, "123456"), joining)
+        })
+        .await
+        .unwrap();
+        assert_eq!(key(&hosted.unwrap()), key(&joined.unwrap()));
+    }
+}
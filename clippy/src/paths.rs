@@ -0,0 +1,265 @@
+//! Where clippy keeps its files.
+//!
+//! Everything lives under one data directory, `CLIPPY_HOME` when it is set and the
+//! platform data directory otherwise (`XDG_DATA_HOME` on linux and macos, older
+//! versions always used `~/.local/share` and their directory is moved). A named profile gets its own directory below
+//! it with separate entries, settings, lock socket and sync account.
+
+use log::{info, warn};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Overrides the data directory, for isolated or portable instances.
+pub const HOME_ENV: &str = "CLIPPY_HOME";
+/// Selects the profile, `--profile` sets it and the gui inherits it from the daemon.
+pub const PROFILE_ENV: &str = "CLIPPY_PROFILE";
+const PROFILES_DIR: &str = "profiles";
+
+static PROFILE: OnceLock<Option<String>> = OnceLock::new();
+static BASE: OnceLock<PathBuf> = OnceLock::new();
+
+fn check_profile(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || name.len() > 32
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "Invalid profile name '{}', use up to 32 letters, digits, '-' or '_'",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Selects the profile for this process, `name` wins over `CLIPPY_PROFILE`.
+/// Has to run before any path is used.
+pub fn init_profile(name: Option<&str>) -> Result<(), String> {
+    let name = match name {
+        Some(name) => Some(name.to_string()),
+        None => env::var(PROFILE_ENV).ok().filter(|val| !val.is_empty()),
+    };
+    if let Some(name) = &name {
+        check_profile(name)?;
+    }
+    PROFILE
+        .set(name)
+        .map_err(|_| "The profile is already selected".to_string())
+}
+
+/// The selected profile, `None` for the default one.
+pub fn profile() -> Option<&'static str> {
+    PROFILE
+        .get_or_init(|| {
+            env::var(PROFILE_ENV)
+                .ok()
+                .filter(|val| check_profile(val).is_ok())
+        })
+        .as_deref()
+}
+
+fn base_dir() -> PathBuf {
+    BASE.get_or_init(resolve_base).clone()
+}
+
+fn resolve_base() -> PathBuf {
+    if let Some(home) = env::var_os(HOME_ENV).filter(|val| !val.is_empty()) {
+        return PathBuf::from(home);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
+        let legacy: PathBuf = [home.as_str(), ".local/share/clippy"].iter().collect();
+        match env::var("XDG_DATA_HOME").ok().filter(|val| !val.is_empty()) {
+            Some(data) => migrate(&legacy, PathBuf::from(data).join("clippy")),
+            None => legacy,
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let home = env::var("APPDATA").unwrap_or_else(|_| "C:\\Users\\Public".to_string());
+        [home.as_str(), "clippy"].iter().collect()
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        compile_error!("Unsupported operating system");
+    }
+}
+
+// moves the directory of an older version to `path`, it stays in use if it can not be moved
+#[cfg_attr(target_os = "windows", allow(dead_code))]
+fn migrate(legacy: &Path, path: PathBuf) -> PathBuf {
+    if legacy == path || path.exists() || !legacy.is_dir() {
+        return path;
+    }
+    let moved = match path.parent() {
+        Some(parent) => fs::create_dir_all(parent).and_then(|_| fs::rename(legacy, &path)),
+        None => fs::rename(legacy, &path),
+    };
+    match moved {
+        Ok(_) => {
+            info!(
+                "Moved the clippy data from {} to {}",
+                legacy.display(),
+                path.display()
+            );
+            path
+        }
+        Err(e) => {
+            warn!(
+                "Unable to move the clippy data from {} to {}, it is used where it is: {}",
+                legacy.display(),
+                path.display(),
+                e
+            );
+            legacy.to_path_buf()
+        }
+    }
+}
+
+fn with_profile(path: PathBuf) -> PathBuf {
+    match profile() {
+        Some(name) => path.join(PROFILES_DIR).join(name),
+        None => path,
+    }
+}
+
+/// The data directory of the selected profile, created when missing.
+pub fn data_dir() -> PathBuf {
+    let path = with_profile(base_dir());
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// The cache directory of the selected profile, created when missing.
+pub fn cache_dir() -> PathBuf {
+    let base: PathBuf = if env::var_os(HOME_ENV).is_some_and(|val| !val.is_empty()) {
+        base_dir().join("cache")
+    } else {
+        #[cfg(target_os = "linux")]
+        {
+            env::var("XDG_CACHE_HOME")
+                .ok()
+                .filter(|val| !val.is_empty())
+                .map(PathBuf::from)
+                .unwrap_or_else(|| {
+                    let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
+                    [home.as_str(), ".cache"].iter().collect()
+                })
+                .join("clippy")
+        }
+
+        #[cfg(target_os = "windows")]
+        {
+            PathBuf::from(env::var("LOCALAPPDATA").expect("LOCALAPPDATA not set")).join("clippy")
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
+            [home.as_str(), "Library/Caches/clippy"].iter().collect()
+        }
+
+        #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+        {
+            compile_error!("Unsupported operating system");
+        }
+    };
+
+    let path = with_profile(base);
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// The socket the daemon listens on, one per profile.
+#[cfg(target_family = "unix")]
+pub fn lock_socket() -> PathBuf {
+    data_dir().join(".LOCK")
+}
+
+/// The pipe the daemon listens on, one per profile and data directory.
+#[cfg(target_family = "windows")]
+pub fn lock_socket() -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut name = String::from(r"\\.\pipe\clippy");
+    if let Some(home) = env::var_os(HOME_ENV).filter(|val| !val.is_empty()) {
+        // pipes are global, tell instances with other data directories apart
+        let hash = Sha256::digest(home.to_string_lossy().as_bytes());
+        name.push('-');
+        for byte in &hash[..4] {
+            name.push_str(&format!("{:02x}", byte));
+        }
+    }
+    if let Some(profile) = profile() {
+        name.push('-');
+        name.push_str(profile);
+    }
+    name
+}
+
+/// Names of the profiles that have a data directory.
+pub fn profiles() -> Vec<String> {
+    let Ok(dir) = fs::read_dir(base_dir().join(PROFILES_DIR)) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = dir
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| check_profile(name).is_ok())
+        .collect();
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let path = env::temp_dir().join(format!("clippy-paths-{}", rand::random::<u64>()));
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn old_data_is_moved() {
+        let root = temp_dir();
+        let legacy = root.join("home/.local/share/clippy");
+        fs::create_dir_all(legacy.join("user")).unwrap();
+        fs::write(legacy.join("user/.settings"), "{}").unwrap();
+
+        let path = migrate(&legacy, root.join("data/clippy"));
+        assert_eq!(path, root.join("data/clippy"));
+        assert!(!legacy.exists());
+        assert_eq!(
+            fs::read_to_string(path.join("user/.settings")).unwrap(),
+            "{}"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn existing_data_is_kept() {
+        let root = temp_dir();
+        let legacy = root.join("old");
+        let path = root.join("new");
+        fs::create_dir_all(&legacy).unwrap();
+        fs::create_dir_all(&path).unwrap();
+        assert_eq!(migrate(&legacy, path.clone()), path);
+        assert!(legacy.exists());
+
+        // nothing to move
+        let fresh = root.join("fresh");
+        assert_eq!(migrate(&root.join("missing"), fresh.clone()), fresh);
+        assert!(!fresh.exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
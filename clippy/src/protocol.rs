@@ -0,0 +1,342 @@
+//! Framing used on every ipc connection (gui, command line and second instances).
+//!
+//! Each frame is a big endian `u32` length followed by that many bytes of json.
+//! The client opens with a `Hello` carrying its protocol version, then sends
+//! `Request`s and reads one `Response` per request (`watch` keeps answering the same id).
+
+use crate::cli::EntryInfo;
+use crate::search::SearchResult;
+use crate::{MessageIPC, SessionInfo};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+// the largest entry is a ~15MB image, base64 makes it ~21MB
+const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Hello {
+    pub version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Request {
+    pub id: u64,
+    pub body: MessageIPC,
+}
+
+// lets the server answer a request it could not parse with the right id
+#[derive(Deserialize)]
+struct RequestId {
+    id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    pub id: u64,
+    pub body: Result<Reply, IpcError>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Reply {
+    Done,
+    Id(i64),
+    Entry(EntryInfo),
+    Entries(Vec<EntryInfo>),
+    Sessions(Vec<SessionInfo>),
+    Search(SearchResult),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum IpcError {
+    Version { supported: u32, requested: u32 },
+    Malformed(String),
+    Unsupported,
+    Failed(String),
+}
+
+impl fmt::Display for IpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpcError::Version {
+                supported,
+                requested,
+            } => write!(
+                f,
+                "clippy speaks ipc version {} but version {} was requested, restart clippy after updating",
+                supported, requested
+            ),
+            IpcError::Malformed(e) => write!(f, "malformed request: {}", e),
+            IpcError::Unsupported => write!(f, "request not supported on this connection"),
+            IpcError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for IpcError {}
+
+impl From<io::Error> for IpcError {
+    fn from(e: io::Error) -> Self {
+        IpcError::Failed(e.to_string())
+    }
+}
+
+pub fn write_frame<W: Write, T: Serialize>(stream: &mut W, value: &T) -> io::Result<()> {
+    let data = serde_json::to_vec(value)?;
+    let len = u32::try_from(data.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_SIZE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "ipc frame too large"))?;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(&data)?;
+    stream.flush()
+}
+
+/// Reads one frame, `None` means the other side closed the connection.
+pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("ipc frame of {} bytes is too large", len),
+        ));
+    }
+    let mut data = vec![0u8; len as usize];
+    stream.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+fn decode<T: DeserializeOwned>(frame: Option<Vec<u8>>) -> Result<T, Box<dyn Error>> {
+    let frame = frame.ok_or("clippy closed the connection")?;
+    Ok(serde_json::from_slice(&frame)?)
+}
+
+/// Daemon side of a connection.
+pub struct Server<S> {
+    stream: S,
+}
+
+impl<S: Read + Write> Server<S> {
+    /// Waits for the client hello, clients with another version get an error and are dropped.
+    pub fn accept(mut stream: S) -> io::Result<Self> {
+        let hello = read_frame(&mut stream)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no hello"))?;
+        let result = match serde_json::from_slice::<Hello>(&hello) {
+            Ok(hello) if hello.version == PROTOCOL_VERSION => Ok(PROTOCOL_VERSION),
+            Ok(hello) => Err(IpcError::Version {
+                supported: PROTOCOL_VERSION,
+                requested: hello.version,
+            }),
+            Err(e) => Err(IpcError::Malformed(e.to_string())),
+        };
+        write_frame(&mut stream, &result)?;
+        match result {
+            Ok(_) => Ok(Self { stream }),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        }
+    }
+
+    /// Returns the next request, requests that can not be parsed are answered
+    /// with an error and skipped.
+    pub fn next_request(&mut self) -> io::Result<Option<Request>> {
+        loop {
+            let frame = match read_frame(&mut self.stream) {
+                Ok(Some(val)) => val,
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    // the stream can not be resynced after a bad length
+                    if e.kind() == io::ErrorKind::InvalidData {
+                        let _ = self.reply(0, Err(IpcError::Malformed(e.to_string())));
+                    }
+                    return Err(e);
+                }
+            };
+            match serde_json::from_slice::<Request>(&frame) {
+                Ok(request) => return Ok(Some(request)),
+                Err(e) => {
+                    let id = serde_json::from_slice::<RequestId>(&frame)
+                        .map(|val| val.id)
+                        .unwrap_or(0);
+                    self.reply(id, Err(IpcError::Malformed(e.to_string())))?;
+                }
+            }
+        }
+    }
+
+    pub fn reply(&mut self, id: u64, body: Result<Reply, IpcError>) -> io::Result<()> {
+        write_frame(&mut self.stream, &Response { id, body })
+    }
+}
+
+/// Gui and command line side of a connection.
+pub struct Client<S> {
+    stream: S,
+    next_id: u64,
+}
+
+impl<S: Read + Write> Client<S> {
+    pub fn connect(mut stream: S) -> Result<Self, Box<dyn Error>> {
+        write_frame(
+            &mut stream,
+            &Hello {
+                version: PROTOCOL_VERSION,
+            },
+        )?;
+        decode::<Result<u32, IpcError>>(read_frame(&mut stream)?)??;
+        Ok(Self { stream, next_id: 1 })
+    }
+
+    pub fn request(&mut self, body: MessageIPC) -> Result<Reply, Box<dyn Error>> {
+        let id = self.send(body)?;
+        self.recv(id)
+    }
+
+    pub fn send(&mut self, body: MessageIPC) -> io::Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        write_frame(&mut self.stream, &Request { id, body })?;
+        Ok(id)
+    }
+
+    /// Waits for the response to `id`, an error for id 0 means the daemon could not read the request.
+    pub fn recv(&mut self, id: u64) -> Result<Reply, Box<dyn Error>> {
+        loop {
+            let response: Response = decode(read_frame(&mut self.stream)?)?;
+            if response.id == id || response.id == 0 {
+                return Ok(response.body?);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn frame_round_trip() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &Hello { version: 7 }).unwrap();
+        // a length prefix and the json of `{"version":7}`
+        assert_eq!(&buf[..4], &13u32.to_be_bytes());
+        write_frame(&mut buf, &"second").unwrap();
+
+        let mut stream = Cursor::new(buf);
+        let hello: Hello =
+            serde_json::from_slice(&read_frame(&mut stream).unwrap().unwrap()).unwrap();
+        assert_eq!(hello.version, 7);
+        let second: String =
+            serde_json::from_slice(&read_frame(&mut stream).unwrap().unwrap()).unwrap();
+        assert_eq!(second, "second");
+        assert!(read_frame(&mut stream).unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        let mut stream = Cursor::new((MAX_FRAME_SIZE + 1).to_be_bytes().to_vec());
+        let err = read_frame(&mut stream).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_frame_is_an_error() {
+        let mut buf = 10u32.to_be_bytes().to_vec();
+        buf.extend_from_slice(b"{}");
+        let err = read_frame(&mut Cursor::new(buf)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn request_and_reply() {
+        use std::os::unix::net::UnixStream;
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let daemon = std::thread::spawn(move || {
+            let mut server = Server::accept(server).unwrap();
+            let mut ids = Vec::new();
+            while let Some(request) = server.next_request().unwrap() {
+                ids.push(request.id);
+                server
+                    .reply(request.id, Ok(Reply::Id(request.id as i64)))
+                    .unwrap();
+            }
+            ids
+        });
+
+        let mut client = Client::connect(client).unwrap();
+        assert!(matches!(
+            client.request(MessageIPC::Updated).unwrap(),
+            Reply::Id(1)
+        ));
+        assert!(matches!(
+            client.request(MessageIPC::Delete(3)).unwrap(),
+            Reply::Id(2)
+        ));
+        drop(client);
+        assert_eq!(daemon.join().unwrap(), vec![1, 2]);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn malformed_request_is_answered() {
+        use std::os::unix::net::UnixStream;
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let daemon = std::thread::spawn(move || {
+            let mut server = Server::accept(server).unwrap();
+            server.next_request().unwrap().is_none()
+        });
+
+        write_frame(
+            &mut client,
+            &Hello {
+                version: PROTOCOL_VERSION,
+            },
+        )
+        .unwrap();
+        let hello: Result<u32, IpcError> = decode(read_frame(&mut client).unwrap()).unwrap();
+        assert_eq!(hello.unwrap(), PROTOCOL_VERSION);
+        write_frame(
+            &mut client,
+            &serde_json::json!({ "id": 5, "body": "Bogus" }),
+        )
+        .unwrap();
+        let response: Response = decode(read_frame(&mut client).unwrap()).unwrap();
+        assert_eq!(response.id, 5);
+        assert!(matches!(response.body, Err(IpcError::Malformed(_))));
+        drop(client);
+        assert!(daemon.join().unwrap());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn other_version_is_refused() {
+        use std::os::unix::net::UnixStream;
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let daemon = std::thread::spawn(move || Server::accept(server).is_err());
+
+        write_frame(
+            &mut client,
+            &Hello {
+                version: PROTOCOL_VERSION + 1,
+            },
+        )
+        .unwrap();
+        let hello: Result<u32, IpcError> = decode(read_frame(&mut client).unwrap()).unwrap();
+        assert!(
+            matches!(hello, Err(IpcError::Version { requested, .. }) if requested == PROTOCOL_VERSION + 1)
+        );
+        assert!(daemon.join().unwrap());
+    }
+}
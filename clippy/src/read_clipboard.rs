@@ -1,15 +1,84 @@
-use crate::{Data, get_global_bool, set_global_bool};
+use crate::storage::storage;
+use crate::{
+    ContentFilters, Data, FilterAction, Flavor, SourceApp, device_name, get_global_bool,
+    make_thumbnail, set_global_bool,
+};
 use crate::{MessageChannel, UserSettings};
 use base64::{Engine, engine::general_purpose};
-use chrono::Utc;
 use clipboard_rs::common::RustImage;
-use clipboard_rs::{Clipboard, ClipboardContext, ClipboardHandler};
+use clipboard_rs::{Clipboard, ClipboardContext, ClipboardHandler, ContentFormat};
 use image::{ImageFormat, ImageReader, imageops};
-use log::{debug, error};
+use log::{debug, error, info};
+use regex::Regex;
 use std::error;
-use std::io::Cursor;
+use std::io::{self, Cursor};
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc::Sender;
 
+// representations stored next to the main one, see `Flavor`
+#[cfg(target_os = "linux")]
+const FLAVORS: [&str; 5] = [
+    "text/html",
+    "text/rtf",
+    "application/rtf",
+    "text/uri-list",
+    "x-special/gnome-copied-files",
+];
+const MAX_FLAVOR_SIZE: usize = 15_700_268;
+// password managers offer one of these next to a copied secret
+const PASSWORD_HINTS: [&str; 3] = [
+    "x-kde-passwordManagerHint",
+    "ExcludeClipboardContentFromMonitorProcessing",
+    "org.nspasteboard.ConcealedType",
+];
+
+static CAPTURE: Mutex<Option<Arc<Capture>>> = Mutex::new(None);
+
+// the settings copies are screened with, read once and again after they change
+struct Capture {
+    filters: ContentFilters,
+    rules: Vec<(Regex, String, FilterAction)>,
+    store_image: bool,
+}
+
+impl Capture {
+    fn load() -> Self {
+        let (filters, store_image) = match UserSettings::build_user() {
+            Ok(settings) => (settings.filters, settings.store_image),
+            Err(_) => (ContentFilters::default(), true),
+        };
+        let rules = filters
+            .rules
+            .iter()
+            .filter_map(|rule| match Regex::new(&rule.pattern) {
+                Ok(re) => Some((re, rule.name.clone(), rule.action)),
+                Err(e) => {
+                    error!("Invalid filter rule {}", rule.name);
+                    debug!("{e}");
+                    None
+                }
+            })
+            .collect();
+        Self {
+            filters,
+            rules,
+            store_image,
+        }
+    }
+}
+
+fn capture() -> Arc<Capture> {
+    let mut capture = CAPTURE.lock().unwrap_or_else(|e| e.into_inner());
+    capture
+        .get_or_insert_with(|| Arc::new(Capture::load()))
+        .clone()
+}
+
+/// Forgets the filters read from the settings, the next copy reads them again.
+pub fn reload_settings() {
+    *CAPTURE.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
 #[cfg(target_os = "linux")]
 pub fn read_wayland_clipboard(tx: &Sender<MessageChannel>) -> Result<(), std::io::Error> {
     use wayland_clipboard_listener::{WlClipboardPasteStream, WlListenType};
@@ -34,7 +103,7 @@ pub fn read_wayland_clipboard(tx: &Sender<MessageChannel>) -> Result<(), std::io
     stream.set_priority(preferred_formats);
     for i in stream.paste_stream().flatten().flatten() {
         if get_global_bool() {
-            if let Err(e) = parse_wayland_clipboard(i.context, tx) {
+            if let Err(e) = parse_wayland_clipboard(i, tx) {
                 error!("Unable read clipboard: {}", e);
             };
         } else {
@@ -63,20 +132,28 @@ impl<'a> ClipboardHandler for Manager<'a> {
             let types = ctx.available_formats().unwrap();
 
             debug!("Available types: {:?}", types);
+            let flavors = clipboard_flavors(ctx);
+            let source = clipboard_owner();
 
             if let Ok(val) = ctx.get_image() {
                 debug!("Type img");
                 write_to_json(
                     val.to_png().unwrap().get_bytes().to_vec(),
                     String::from("image/png"),
-                    String::from("os"),
+                    device_name(),
+                    flavors,
+                    source,
+                    &types,
                     &self.tx,
                 );
             } else if let Ok(val) = ctx.get_text() {
                 write_to_json(
                     val.into_bytes(),
                     String::from("String"),
-                    String::from("os"),
+                    device_name(),
+                    flavors,
+                    source,
+                    &types,
                     &self.tx,
                 );
             }
@@ -86,22 +163,58 @@ impl<'a> ClipboardHandler for Manager<'a> {
     }
 }
 
-pub fn write_to_json(data: Vec<u8>, typ: String, device: String, tx: &Sender<MessageChannel>) {
-    let time = Utc::now().format("%Y-%m-%d_%H-%M-%S").to_string();
-
-    let store_image = match UserSettings::build_user() {
-        Ok(settings) => settings.store_image,
-        Err(_) => true,
-    };
+#[cfg(target_os = "linux")]
+fn clipboard_owner() -> Option<SourceApp> {
+    match crate::x11::clipboard_owner() {
+        Ok(val) => val,
+        Err(e) => {
+            debug!("Unable to find the clipboard owner: {}", e);
+            None
+        }
+    }
+}
 
-    if typ.starts_with("image/") && store_image {
-        use crate::save_image;
+#[cfg(not(target_os = "linux"))]
+fn clipboard_owner() -> Option<SourceApp> {
+    None
+}
 
-        if let Err(e) = save_image(&time, &data) {
-            error!("Unable to write thumbnail");
-            debug!("{e}")
-        };
+// html, rtf and file lists offered next to the text or image
+fn clipboard_flavors(ctx: &ClipboardContext) -> Vec<Flavor> {
+    let mut contents = Vec::new();
+    if ctx.has(ContentFormat::Html)
+        && let Ok(val) = ctx.get_html()
+    {
+        contents.push(("text/html", val));
     }
+    if ctx.has(ContentFormat::Rtf)
+        && let Ok(val) = ctx.get_rich_text()
+    {
+        contents.push(("text/rtf", val));
+    }
+    if ctx.has(ContentFormat::Files)
+        && let Ok(val) = ctx.get_files()
+        && !val.is_empty()
+    {
+        contents.push(("text/uri-list", val.join("\r\n")));
+    }
+    contents
+        .into_iter()
+        .filter(|(_, data)| data.len() <= MAX_FLAVOR_SIZE)
+        .map(|(mime, data)| Flavor::new(mime.to_string(), data.as_bytes()))
+        .collect()
+}
+
+pub fn write_to_json(
+    data: Vec<u8>,
+    typ: String,
+    device: String,
+    flavors: Vec<Flavor>,
+    source: Option<SourceApp>,
+    formats: &[String],
+    tx: &Sender<MessageChannel>,
+) {
+    let thumbnail = thumbnail(&typ, &data);
 
     let data = if data.len() > 15700268 {
         if typ.starts_with("image/") {
@@ -118,38 +231,22 @@ pub fn write_to_json(data: Vec<u8>, typ: String, device: String, tx: &Sender<Mes
         }
     };
 
-    let data = Data::new(data, typ, device, false);
-    match data.write_to_json(tx, time) {
-        Ok(_) => (),
-        Err(err) => error!("Unable to write to json: {}", err),
-    }
+    let data = Data::new(data, typ, device, false)
+        .with_flavors(flavors)
+        .with_source(source);
+    store(data, formats, thumbnail, tx);
 }
 
 #[cfg(target_os = "linux")]
 pub fn parse_wayland_clipboard(
-    data: wayland_clipboard_listener::ClipBoardListenContext,
+    message: wayland_clipboard_listener::ClipBoardListenMessage,
     tx: &Sender<MessageChannel>,
 ) -> Result<(), Box<dyn error::Error>> {
-    use crate::UserSettings;
-    use chrono::Utc;
-
-    let (typ, data) = (data.mime_type, data.context);
+    let (typ, data) = (message.context.mime_type, message.context.context);
     log::info!("Clipboard data stored: {}", typ);
-    let time = Utc::now().format("%Y-%m-%d_%H-%M-%S").to_string();
-
-    let store_image = match UserSettings::build_user() {
-        Ok(settings) => settings.store_image,
-        Err(_) => true,
-    };
-
-    if typ.starts_with("image/") && store_image {
-        use crate::save_image;
-
-        if let Err(e) = save_image(&time, &data) {
-            error!("Unable to write thumbnail");
-            debug!("{e}")
-        };
-    }
+    let source = crate::wayland::focused_app();
+    let flavors = wayland_flavors(&message.mime_types, &typ);
+    let thumbnail = thumbnail(&typ, &data);
 
     let json_data = if data.len() > 15700268 {
         if !typ.starts_with("image/") {
@@ -166,14 +263,130 @@ pub fn parse_wayland_clipboard(
         }
     };
 
-    let result = Data::new(json_data, typ, "os".to_owned(), false);
-    match result.write_to_json(tx, time) {
-        Ok(_) => (),
-        Err(err) => error!("Unable to write to json: {}", err),
-    }
+    let result = Data::new(json_data, typ, device_name(), false)
+        .with_flavors(flavors)
+        .with_source(source);
+    store(result, &message.mime_types, thumbnail, tx);
     Ok(())
 }
 
+/// Runs the sensitive-content and application filters on a copy, returns the strictest
+/// matching action with the name of its rule, or `None` when the copy is stored and synced as usual.
+fn screen(formats: &[String], data: &Data) -> Option<(String, FilterAction)> {
+    let capture = capture();
+    let filters = &capture.filters;
+    let mut result: Option<(String, FilterAction)> = None;
+    let mut add = |name: &str, action: FilterAction| {
+        result = match result.take() {
+            Some((prev, val)) if val.strictest(action) == val => Some((prev, val)),
+            _ => Some((name.to_string(), action)),
+        };
+    };
+
+    let hinted = formats
+        .iter()
+        .any(|val| PASSWORD_HINTS.contains(&val.as_str()));
+    if hinted && let Some(action) = filters.password_hint {
+        add("password manager hint", action);
+    }
+
+    if let Some(source) = data.source() {
+        if !filters.allowed_apps.is_empty()
+            && !filters.allowed_apps.iter().any(|app| source.is(app))
+        {
+            add(
+                &format!("allowed apps ({})", source.name()),
+                FilterAction::Drop,
+            );
+        }
+        for rule in filters.apps.iter().filter(|rule| source.is(&rule.app)) {
+            add(&format!("app {}", rule.app), rule.action);
+        }
+    }
+
+    let texts: Vec<String> = data
+        .get_data()
+        .into_iter()
+        .chain(
+            data.flavors()
+                .iter()
+                .filter(|val| val.mime.starts_with("text/"))
+                .filter_map(|val| val.bytes())
+                .map(|val| String::from_utf8_lossy(&val).into_owned()),
+        )
+        .collect();
+    for (re, name, action) in &capture.rules {
+        if texts.iter().any(|text| re.is_match(text)) {
+            add(name, *action);
+        }
+    }
+    result
+}
+
+fn store(data: Data, formats: &[String], thumbnail: Option<Vec<u8>>, tx: &Sender<MessageChannel>) {
+    let result: io::Result<()> = match screen(formats, &data) {
+        None => data.write_pending(tx, thumbnail).map(|_| ()),
+        Some((name, FilterAction::Drop)) => {
+            info!("Copy skipped by the filter {}", name);
+            Ok(())
+        }
+        Some((name, FilterAction::LocalOnly)) => {
+            info!("Copy kept on this device by the filter {}", name);
+            data.write_local(tx, thumbnail).map(|_| ())
+        }
+        Some((name, FilterAction::Expire(secs))) => {
+            info!("Copy expires in {}s by the filter {}", secs, name);
+            data.write_pending(tx, thumbnail).and_then(|id| {
+                storage().set_expiry(id, chrono::Utc::now().timestamp() + secs as i64)
+            })
+        }
+    };
+    if let Err(err) = result {
+        error!("Unable to write to json: {}", err);
+    }
+}
+
+// the listener only reads the preferred type, the others are read from the selection again
+#[cfg(target_os = "linux")]
+fn wayland_flavors(offered: &[String], typ: &str) -> Vec<Flavor> {
+    let wanted: Vec<String> = FLAVORS
+        .iter()
+        .filter(|val| **val != typ && offered.iter().any(|mime| mime == *val))
+        .map(|val| val.to_string())
+        .collect();
+    if wanted.is_empty() {
+        return Vec::new();
+    }
+
+    match crate::wayland::read_selection(&wanted) {
+        Ok(contents) => contents
+            .into_iter()
+            .filter(|(_, data)| data.len() <= MAX_FLAVOR_SIZE)
+            .map(|(mime, data)| Flavor::new(mime, &data))
+            .collect(),
+        Err(e) => {
+            error!("Unable to read other clipboard formats");
+            debug!("{e}");
+            Vec::new()
+        }
+    }
+}
+
+fn thumbnail(typ: &str, data: &[u8]) -> Option<Vec<u8>> {
+    if !typ.starts_with("image/") || !capture().store_image {
+        return None;
+    }
+
+    match make_thumbnail(data) {
+        Ok(val) => Some(val),
+        Err(e) => {
+            error!("Unable to write thumbnail");
+            debug!("{e}");
+            None
+        }
+    }
+}
+
 fn compress_str(data: Vec<u8>) -> Result<String, Box<dyn error::Error>> {
     let data = general_purpose::STANDARD.encode(data);
     Ok(data)
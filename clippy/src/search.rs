@@ -0,0 +1,216 @@
+use crate::protocol::{IpcError, Reply};
+use crate::storage::{Entry, Location, storage};
+use log::error;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+static INDEX: OnceLock<Mutex<SearchIndex>> = OnceLock::new();
+
+/// The index of the daemon, built from the storage on first use.
+pub fn search_index() -> MutexGuard<'static, SearchIndex> {
+    let index = INDEX.get_or_init(|| Mutex::new(SearchIndex::build()));
+    index.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum SearchMode {
+    #[default]
+    Substring,
+    CaseInsensitive,
+    Regex,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SearchQuery {
+    pub text: String,
+    pub mode: SearchMode,
+    // matched as a prefix so "image/" selects every image type
+    pub typ: Option<String>,
+    pub device: Option<String>,
+    // name of the application the copy came from
+    #[serde(default)]
+    pub app: Option<String>,
+    pub pined: Option<bool>,
+}
+
+impl SearchQuery {
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+            && self.typ.is_none()
+            && self.device.is_none()
+            && self.app.is_none()
+            && self.pined.is_none()
+    }
+}
+
+/// The answer of the daemon to a search, with the values the filters can take.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SearchResult {
+    // the id of every match and whether it is still pending, newest first
+    pub ids: Vec<(i64, bool)>,
+    pub types: Vec<String>,
+    pub devices: Vec<String>,
+    pub apps: Vec<String>,
+}
+
+#[derive(Debug)]
+struct IndexEntry {
+    location: Location,
+    text: Option<String>,
+    typ: String,
+    device: String,
+    app: Option<String>,
+    pined: bool,
+}
+
+enum Matcher {
+    All,
+    Substring(String),
+    CaseInsensitive(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn new(query: &SearchQuery) -> Result<Self, regex::Error> {
+        if query.text.is_empty() {
+            return Ok(Self::All);
+        }
+        Ok(match query.mode {
+            SearchMode::Substring => Self::Substring(query.text.clone()),
+            SearchMode::CaseInsensitive => Self::CaseInsensitive(query.text.to_lowercase()),
+            SearchMode::Regex => {
+                Self::Regex(RegexBuilder::new(&query.text).size_limit(1 << 20).build()?)
+            }
+        })
+    }
+
+    fn is_match(&self, text: Option<&str>) -> bool {
+        match (self, text) {
+            (Self::All, _) => true,
+            (_, None) => false,
+            (Self::Substring(val), Some(text)) => text.contains(val.as_str()),
+            (Self::CaseInsensitive(val), Some(text)) => text.to_lowercase().contains(val.as_str()),
+            (Self::Regex(re), Some(text)) => re.is_match(text),
+        }
+    }
+}
+
+/// In memory index over the clipboard history, used to search entries
+/// without reading every file on each query.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    entries: BTreeMap<i64, IndexEntry>,
+}
+
+impl SearchIndex {
+    pub fn build() -> Self {
+        let mut index = Self::default();
+        match storage().entries() {
+            Ok(entries) => entries.into_iter().for_each(|entry| index.add(entry)),
+            Err(e) => error!("Unable to build search index: {}", e),
+        }
+        index
+    }
+
+    fn add(&mut self, entry: Entry) {
+        self.entries.insert(
+            entry.id,
+            IndexEntry {
+                text: entry.data.get_data(),
+                app: entry.data.source().map(|val| val.name().to_string()),
+                location: entry.location,
+                typ: entry.data.typ,
+                device: entry.data.device,
+                pined: entry.data.pined,
+            },
+        );
+    }
+
+    /// Adds or refreshes an entry from the storage.
+    pub fn insert(&mut self, id: i64) {
+        match storage().get(id) {
+            Ok(Some(entry)) => self.add(entry),
+            _ => {
+                self.entries.remove(&id);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: i64) {
+        self.entries.remove(&id);
+    }
+
+    pub fn types(&self) -> Vec<String> {
+        let mut types: Vec<String> = self.entries.values().map(|x| x.typ.clone()).collect();
+        types.sort();
+        types.dedup();
+        types
+    }
+
+    pub fn devices(&self) -> Vec<String> {
+        let mut devices: Vec<String> = self.entries.values().map(|x| x.device.clone()).collect();
+        devices.sort();
+        devices.dedup();
+        devices
+    }
+
+    pub fn apps(&self) -> Vec<String> {
+        let mut apps: Vec<String> = self
+            .entries
+            .values()
+            .filter_map(|x| x.app.clone())
+            .collect();
+        apps.sort();
+        apps.dedup();
+        apps
+    }
+
+    /// Returns the id of every matching entry and whether it is still pending,
+    /// newest first.
+    pub fn search(&self, query: &SearchQuery) -> Result<Vec<(i64, bool)>, regex::Error> {
+        let matcher = Matcher::new(query)?;
+        let mut result: Vec<(&i64, &IndexEntry)> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                query
+                    .typ
+                    .as_ref()
+                    .is_none_or(|typ| entry.typ.starts_with(typ))
+                    && query.device.as_ref().is_none_or(|dev| &entry.device == dev)
+                    && query
+                        .app
+                        .as_ref()
+                        .is_none_or(|app| entry.app.as_ref() == Some(app))
+                    && query.pined.is_none_or(|pined| entry.pined == pined)
+                    && matcher.is_match(entry.text.as_deref())
+            })
+            .collect();
+
+        result.sort_by(|(a_id, a), (b_id, b)| b.location.cmp(&a.location).then(b_id.cmp(a_id)));
+        Ok(result
+            .into_iter()
+            .map(|(id, entry)| (*id, entry.location == Location::Pending))
+            .collect())
+    }
+
+    /// Runs `query` and adds the filter values of the whole history.
+    pub fn query(&self, query: &SearchQuery) -> Result<SearchResult, regex::Error> {
+        Ok(SearchResult {
+            ids: self.search(query)?,
+            types: self.types(),
+            devices: self.devices(),
+            apps: self.apps(),
+        })
+    }
+}
+
+/// Answers a search of the gui from the index of the daemon.
+pub fn handle_search(query: &SearchQuery) -> Result<Reply, IpcError> {
+    search_index()
+        .query(query)
+        .map(Reply::Search)
+        .map_err(|e| IpcError::Failed(e.to_string()))
+}
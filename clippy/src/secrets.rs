@@ -0,0 +1,343 @@
+//! Storage for sync credentials and the encryption passphrase.
+//!
+//! Secrets go to the Secret Service of the desktop session (GNOME Keyring, KWallet)
+//! when it is running. Otherwise they are kept in files encrypted with a random key
+//! created for this install.
+//!
+//! A secret is read once and kept in memory, the daemon is the only process that
+//! changes them while it runs.
+
+use crate::encryption_decryption::{decrypt_file, encrept_file};
+use crate::paths;
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+const KEY_FILE: &str = ".key";
+
+trait Backend: Send + Sync {
+    fn get(&self, name: &str) -> io::Result<Option<Vec<u8>>>;
+    fn set(&self, name: &str, value: &[u8]) -> io::Result<()>;
+    fn delete(&self, name: &str) -> io::Result<()>;
+}
+
+fn user_dir() -> io::Result<PathBuf> {
+    let path = paths::data_dir().join("user");
+    fs::create_dir_all(&path)?;
+    Ok(path)
+}
+
+#[cfg(target_family = "unix")]
+fn private(options: &mut OpenOptions) -> &mut OpenOptions {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600)
+}
+
+#[cfg(not(target_family = "unix"))]
+fn private(options: &mut OpenOptions) -> &mut OpenOptions {
+    options
+}
+
+/// The random key of this install, created on first use. Files that hold secrets
+/// are encrypted with it.
+pub fn install_key() -> io::Result<[u8; 32]> {
+    let path = user_dir()?.join(KEY_FILE);
+    let mut key = [0u8; 32];
+    match private(OpenOptions::new().write(true).create_new(true)).open(&path) {
+        Ok(mut file) => {
+            OsRng.fill_bytes(&mut key);
+            file.write_all(&key)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            fs::File::open(&path)?.read_exact(&mut key)?;
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(key)
+}
+
+struct FileStore;
+
+impl FileStore {
+    fn path(name: &str) -> io::Result<PathBuf> {
+        Ok(user_dir()?.join(format!(".{}", name)))
+    }
+}
+
+impl Backend for FileStore {
+    fn get(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let data = match fs::read(Self::path(name)?) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        decrypt_file(&install_key()?, &data)
+            .map(Some)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Unable to decrypt secret"))
+    }
+
+    fn set(&self, name: &str, value: &[u8]) -> io::Result<()> {
+        let data = encrept_file(&install_key()?, value)
+            .map_err(|_| io::Error::other("Unable to encrypt secret"))?;
+        private(OpenOptions::new().write(true).create(true).truncate(true))
+            .open(Self::path(name)?)?
+            .write_all(&data)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        match fs::remove_file(Self::path(name)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod secret_service {
+    use super::Backend;
+    use crate::paths;
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+    use std::io;
+    use zbus::blocking::{Connection, Proxy};
+    use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Type, Value};
+
+    const DEST: &str = "org.freedesktop.secrets";
+    const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+    const DEFAULT_COLLECTION: &str = "/org/freedesktop/secrets/aliases/default";
+    const SERVICE: &str = "org.freedesktop.Secret.Service";
+    const COLLECTION: &str = "org.freedesktop.Secret.Collection";
+    const ITEM: &str = "org.freedesktop.Secret.Item";
+    const PROMPT: &str = "org.freedesktop.Secret.Prompt";
+
+    #[derive(Serialize, Deserialize, Type)]
+    struct Secret {
+        session: OwnedObjectPath,
+        parameters: Vec<u8>,
+        value: Vec<u8>,
+        content_type: String,
+    }
+
+    fn error(e: zbus::Error) -> io::Error {
+        io::Error::other(format!("Secret Service: {}", e))
+    }
+
+    // profiles and data directories keep their secrets apart
+    fn attributes(name: &str) -> HashMap<&'static str, String> {
+        HashMap::from([
+            ("application", String::from("clippy")),
+            ("data", paths::data_dir().display().to_string()),
+            ("secret", name.to_string()),
+        ])
+    }
+
+    pub struct SecretService {
+        conn: Connection,
+        // the plain algorithm, the bus of the session is not shared with other users
+        session: OwnedObjectPath,
+    }
+
+    impl SecretService {
+        pub fn connect() -> zbus::Result<Self> {
+            let conn = Connection::session()?;
+            let service = Proxy::new(&conn, DEST, SERVICE_PATH, SERVICE)?;
+            let (_, session): (OwnedValue, OwnedObjectPath) =
+                service.call("OpenSession", &("plain", Value::from("")))?;
+            Ok(Self { conn, session })
+        }
+
+        fn proxy<'a>(&self, path: ObjectPath<'a>, interface: &'a str) -> zbus::Result<Proxy<'a>> {
+            Proxy::new(&self.conn, DEST, path, interface)
+        }
+
+        // waits for the user to answer the prompt, "/" means none is needed
+        fn prompt(&self, prompt: OwnedObjectPath) -> zbus::Result<()> {
+            if prompt.as_str() == "/" {
+                return Ok(());
+            }
+            let proxy = self.proxy(prompt.into(), PROMPT)?;
+            let mut completed = proxy.receive_signal("Completed")?;
+            proxy.call_method("Prompt", &("",))?;
+            let msg = completed
+                .next()
+                .ok_or_else(|| zbus::Error::Failure("Prompt was closed".into()))?;
+            let (dismissed, _): (bool, OwnedValue) = msg.body().deserialize()?;
+            if dismissed {
+                return Err(zbus::Error::Failure("The keyring was not unlocked".into()));
+            }
+            Ok(())
+        }
+
+        fn unlock(&self, path: ObjectPath) -> zbus::Result<()> {
+            let service =
+                self.proxy(ObjectPath::from_static_str_unchecked(SERVICE_PATH), SERVICE)?;
+            let (_, prompt): (Vec<OwnedObjectPath>, OwnedObjectPath) =
+                service.call("Unlock", &(vec![path],))?;
+            self.prompt(prompt)
+        }
+
+        fn find(&self, name: &str) -> zbus::Result<Option<OwnedObjectPath>> {
+            let service =
+                self.proxy(ObjectPath::from_static_str_unchecked(SERVICE_PATH), SERVICE)?;
+            let (unlocked, locked): (Vec<OwnedObjectPath>, Vec<OwnedObjectPath>) =
+                service.call("SearchItems", &(attributes(name),))?;
+            if let Some(item) = unlocked.into_iter().next() {
+                return Ok(Some(item));
+            }
+            let Some(item) = locked.into_iter().next() else {
+                return Ok(None);
+            };
+            self.unlock(item.as_ref())?;
+            Ok(Some(item))
+        }
+    }
+
+    impl Backend for SecretService {
+        fn get(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+            let Some(item) = self.find(name).map_err(error)? else {
+                return Ok(None);
+            };
+            let secret: Secret = self
+                .proxy(item.into(), ITEM)
+                .and_then(|item| item.call("GetSecret", &(&self.session,)))
+                .map_err(error)?;
+            Ok(Some(secret.value))
+        }
+
+        fn set(&self, name: &str, value: &[u8]) -> io::Result<()> {
+            let collection = ObjectPath::from_static_str_unchecked(DEFAULT_COLLECTION);
+            self.unlock(collection.clone()).map_err(error)?;
+            let properties: HashMap<&str, Value> = HashMap::from([
+                (
+                    "org.freedesktop.Secret.Item.Label",
+                    Value::from(format!("clippy {}", name)),
+                ),
+                (
+                    "org.freedesktop.Secret.Item.Attributes",
+                    Value::from(attributes(name)),
+                ),
+            ]);
+            let secret = Secret {
+                session: self.session.clone(),
+                parameters: Vec::new(),
+                value: value.to_vec(),
+                content_type: String::from("application/octet-stream"),
+            };
+            let (_, prompt): (OwnedObjectPath, OwnedObjectPath) = self
+                .proxy(collection, COLLECTION)
+                .and_then(|collection| collection.call("CreateItem", &(properties, secret, true)))
+                .map_err(error)?;
+            self.prompt(prompt).map_err(error)
+        }
+
+        fn delete(&self, name: &str) -> io::Result<()> {
+            let Some(item) = self.find(name).map_err(error)? else {
+                return Ok(());
+            };
+            let prompt: OwnedObjectPath = self
+                .proxy(item.into(), ITEM)
+                .and_then(|item| item.call("Delete", &()))
+                .map_err(error)?;
+            self.prompt(prompt).map_err(error)
+        }
+    }
+}
+
+enum Store {
+    Files,
+    #[cfg(target_os = "linux")]
+    Service(secret_service::SecretService),
+}
+
+static STORE: OnceLock<Store> = OnceLock::new();
+// `None` for a secret that is not stored
+static CACHE: OnceLock<Mutex<HashMap<String, Option<Vec<u8>>>>> = OnceLock::new();
+
+fn store() -> &'static Store {
+    STORE.get_or_init(|| {
+        #[cfg(target_os = "linux")]
+        match secret_service::SecretService::connect() {
+            Ok(service) => return Store::Service(service),
+            Err(e) => {
+                info!("Secret Service is not available, secrets are stored in files");
+                debug!("{}", e);
+            }
+        }
+        Store::Files
+    })
+}
+
+fn cache(name: &str, value: Option<Vec<u8>>) {
+    CACHE
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(name.to_string(), value);
+}
+
+/// Reads the secret `name`, the store is only asked the first time.
+pub fn get(name: &str) -> io::Result<Option<Vec<u8>>> {
+    let cached = CACHE
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(name)
+        .cloned();
+    if let Some(value) = cached {
+        return Ok(value);
+    }
+    let value = read(name)?;
+    cache(name, value.clone());
+    Ok(value)
+}
+
+fn read(name: &str) -> io::Result<Option<Vec<u8>>> {
+    match store() {
+        Store::Files => FileStore.get(name),
+        #[cfg(target_os = "linux")]
+        Store::Service(service) => {
+            if let Some(value) = service.get(name)? {
+                return Ok(Some(value));
+            }
+            // stored while the service was not running
+            let Some(value) = FileStore.get(name)? else {
+                return Ok(None);
+            };
+            if let Err(e) = service
+                .set(name, &value)
+                .and_then(|_| FileStore.delete(name))
+            {
+                warn!("Unable to move secret to the Secret Service");
+                debug!("{}", e);
+            }
+            Ok(Some(value))
+        }
+    }
+}
+
+/// Stores `value` as the secret `name`.
+pub fn set(name: &str, value: &[u8]) -> io::Result<()> {
+    match store() {
+        Store::Files => FileStore.set(name, value)?,
+        #[cfg(target_os = "linux")]
+        Store::Service(service) => service.set(name, value)?,
+    }
+    cache(name, Some(value.to_vec()));
+    Ok(())
+}
+
+/// Removes the secret `name`.
+pub fn delete(name: &str) -> io::Result<()> {
+    FileStore.delete(name)?;
+    match store() {
+        Store::Files => (),
+        #[cfg(target_os = "linux")]
+        Store::Service(service) => service.delete(name)?,
+    }
+    cache(name, None);
+    Ok(())
+}
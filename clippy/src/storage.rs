@@ -0,0 +1,726 @@
+use crate::{Data, SyncCursor, get_path_local};
+use log::{error, info, warn};
+use rusqlite::{Connection, OptionalExtension, Row, Transaction, TransactionBehavior, params};
+use std::{
+    fs, io,
+    path::Path,
+    process,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+const DATABASE_FILE: &str = "clippy.db";
+const SCHEMA_VERSION: i32 = 5;
+
+// ids come from AUTOINCREMENT so they are never reused, even after a delete
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS entries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    remote_id TEXT UNIQUE,
+    location INTEGER NOT NULL,
+    typ TEXT NOT NULL,
+    pined INTEGER NOT NULL,
+    data TEXT NOT NULL,
+    thumbnail BLOB
+);
+CREATE INDEX IF NOT EXISTS entries_location ON entries (location, id);
+";
+
+// last server change applied, per account
+const SCHEMA_V2: &str = "
+CREATE TABLE IF NOT EXISTS sync_cursor (
+    account TEXT PRIMARY KEY,
+    epoch TEXT NOT NULL,
+    seq INTEGER NOT NULL
+);
+";
+
+// unix time after which an entry is removed, set by the sensitive-content filters
+const SCHEMA_V3: &str = "
+ALTER TABLE entries ADD COLUMN expires_at INTEGER;
+CREATE INDEX IF NOT EXISTS entries_expires_at ON entries (expires_at);
+";
+
+// unix time the entry was stored, entries of older versions count from the upgrade
+const SCHEMA_V4: &str = "
+ALTER TABLE entries ADD COLUMN created_at INTEGER;
+UPDATE entries SET created_at = CAST(strftime('%s', 'now') AS INTEGER);
+";
+
+// remote ids of removed entries, so devices that missed a removal do not bring them back
+const SCHEMA_V5: &str = "
+CREATE TABLE IF NOT EXISTS removed (
+    remote_id TEXT PRIMARY KEY,
+    removed_at INTEGER NOT NULL
+);
+";
+
+// removals remembered for devices that were offline
+const MAX_REMOVED: i64 = 1000;
+
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+
+/// Where an entry lives, in the order the gui lists them (highest first).
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum Location {
+    // pined entries that were trimmed out of the synced history
+    Pined,
+    Data,
+    // local entries the server has not accepted yet
+    Pending,
+    // entries that are never synced
+    Local,
+}
+
+impl Location {
+    fn from_i64(val: i64) -> Self {
+        match val {
+            0 => Location::Pined,
+            1 => Location::Data,
+            3 => Location::Local,
+            _ => Location::Pending,
+        }
+    }
+
+    fn as_i64(self) -> i64 {
+        match self {
+            Location::Pined => 0,
+            Location::Data => 1,
+            Location::Pending => 2,
+            Location::Local => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub id: i64,
+    pub remote_id: Option<String>,
+    pub location: Location,
+    pub data: Data,
+}
+
+/// What the retention policy needs to know about an entry, without its content.
+#[derive(Debug, Clone)]
+pub struct EntryStats {
+    pub id: i64,
+    pub typ: String,
+    pub pined: bool,
+    pub created_at: i64,
+    // bytes of the entry and its thumbnail
+    pub size: u64,
+}
+
+/// Clipboard history store shared by the daemon and the gui.
+/// Every method that changes more than one row runs as a single transaction.
+pub trait Storage: Send + Sync {
+    /// Adds a local entry that still has to be synced and returns its id.
+    fn add_pending(&self, data: &Data, thumbnail: Option<&[u8]>) -> io::Result<i64>;
+
+    /// Adds an entry that stays on this device and returns its id.
+    fn add_local(&self, data: &Data, thumbnail: Option<&[u8]>) -> io::Result<i64>;
+
+    /// Adds an entry received from the server, replacing the local copy with the same remote id.
+    fn add_synced(&self, remote_id: &str, data: &Data, thumbnail: Option<&[u8]>)
+    -> io::Result<i64>;
+
+    /// Replaces an entry with an edited copy that has to be synced again, local entries stay local.
+    /// Returns the new id and the remote id of the replaced entry, `NotFound` if there is none.
+    fn replace(&self, id: i64, data: &Data) -> io::Result<(i64, Option<String>)>;
+
+    /// Moves a pending entry into the synced history once the server accepted it.
+    fn mark_synced(&self, id: i64, remote_id: &str) -> io::Result<()>;
+
+    /// Keeps a pending entry on this device only, for entries the server refused.
+    fn keep_local(&self, id: i64) -> io::Result<()>;
+
+    /// Removes an entry and returns its remote id, if it had one.
+    fn remove(&self, id: i64) -> io::Result<Option<String>>;
+
+    fn remove_remote(&self, remote_id: &str) -> io::Result<Option<i64>>;
+
+    fn find_remote(&self, remote_id: &str) -> io::Result<Option<i64>>;
+
+    /// Keeps the newest `max` synced entries, older ones are deleted or moved to
+    /// `Location::Pined` when pined. Returns the ids that changed.
+    fn trim(&self, max: u32) -> io::Result<Vec<i64>>;
+
+    fn get(&self, id: i64) -> io::Result<Option<Entry>>;
+
+    fn thumbnail(&self, id: i64) -> io::Result<Option<Vec<u8>>>;
+
+    /// Every entry id with its location, newest first within each location.
+    fn list(&self) -> io::Result<Vec<(i64, Location)>>;
+
+    fn entries(&self) -> io::Result<Vec<Entry>>;
+
+    fn pending(&self) -> io::Result<Vec<i64>>;
+
+    /// Removes the entry at unix time `at`.
+    fn set_expiry(&self, id: i64, at: i64) -> io::Result<()>;
+
+    /// Entries whose expiry is at or before `now`.
+    fn expired(&self, now: i64) -> io::Result<Vec<i64>>;
+
+    /// Every entry, newest first.
+    fn stats(&self) -> io::Result<Vec<EntryStats>>;
+
+    fn sync_cursor(&self, account: &str) -> io::Result<Option<SyncCursor>>;
+
+    /// Stores the cursor of `account`, within the same epoch it never moves back.
+    fn set_sync_cursor(&self, account: &str, cursor: &SyncCursor) -> io::Result<()>;
+
+    /// Synced entries stored after unix time `since`, oldest first.
+    fn synced_since(&self, since: i64) -> io::Result<Vec<Entry>>;
+
+    /// Remembers that the entry with `remote_id` was removed, only the newest removals are kept.
+    fn add_removed(&self, remote_id: &str) -> io::Result<()>;
+
+    fn is_removed(&self, remote_id: &str) -> io::Result<bool>;
+
+    /// Remote ids removed after unix time `since`.
+    fn removed_since(&self, since: i64) -> io::Result<Vec<String>>;
+}
+
+/// Returns the storage of the current user, opening it on first use.
+pub fn storage() -> &'static dyn Storage {
+    STORAGE
+        .get_or_init(|| {
+            let root = get_path_local();
+            match SqliteStorage::open(&root.join(DATABASE_FILE), &root) {
+                Ok(val) => Box::new(val),
+                Err(e) => {
+                    error!("Unable to open clipboard database: {}", e);
+                    process::exit(1);
+                }
+            }
+        })
+        .as_ref()
+}
+
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+fn to_io(e: rusqlite::Error) -> io::Error {
+    match e {
+        rusqlite::Error::QueryReturnedNoRows => io::Error::new(io::ErrorKind::NotFound, e),
+        e => io::Error::other(e),
+    }
+}
+
+fn read_entry(row: &Row) -> rusqlite::Result<(i64, Option<String>, i64, String)> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+}
+
+fn build_entry(
+    (id, remote_id, location, data): (i64, Option<String>, i64, String),
+) -> io::Result<Entry> {
+    Ok(Entry {
+        id,
+        remote_id,
+        location: Location::from_i64(location),
+        data: serde_json::from_str(&data)?,
+    })
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, the first time it also imports the entries
+    /// stored as json files under `legacy_root` by older versions.
+    pub fn open(path: &Path, legacy_root: &Path) -> Result<Self, rusqlite::Error> {
+        let mut conn = Connection::open(path)?;
+        // the gui reads while the daemon writes
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut conn, legacy_root)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut Connection) -> rusqlite::Result<T>) -> io::Result<T> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|_| io::Error::other("storage lock poisoned"))?;
+        f(&mut conn).map_err(to_io)
+    }
+}
+
+fn migrate(conn: &mut Connection, legacy_root: &Path) -> rusqlite::Result<()> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version: i32 = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version < 1 {
+        tx.execute_batch(SCHEMA)?;
+        let count = import_legacy(&tx, legacy_root)?;
+        if count > 0 {
+            info!(
+                "Imported {} clipboard entries into the database, the old files in {} can be removed",
+                count,
+                legacy_root.display()
+            );
+        }
+    }
+    if version < 2 {
+        tx.execute_batch(SCHEMA_V2)?;
+    }
+    if version < 3 {
+        tx.execute_batch(SCHEMA_V3)?;
+    }
+    if version < 4 {
+        tx.execute_batch(SCHEMA_V4)?;
+    }
+    if version < 5 {
+        tx.execute_batch(SCHEMA_V5)?;
+    }
+    tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    tx.commit()
+}
+
+fn import_legacy(tx: &Transaction, root: &Path) -> rusqlite::Result<usize> {
+    let mut count = 0;
+    // oldest location first so the new ids keep the old order
+    for (dir, location) in [
+        ("pined", Location::Pined),
+        ("data", Location::Data),
+        ("local_data", Location::Pending),
+    ] {
+        let Ok(entries) = fs::read_dir(root.join(dir)) else {
+            continue;
+        };
+        let mut names: Vec<String> = entries
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+            .collect();
+        names.sort();
+
+        for name in names {
+            let data = match fs::read(root.join(dir).join(&name))
+                .map_err(|e| e.to_string())
+                .and_then(|val| serde_json::from_slice::<Data>(&val).map_err(|e| e.to_string()))
+            {
+                Ok(val) => val,
+                Err(e) => {
+                    warn!("Skipping unreadable entry {}/{}: {}", dir, name, e);
+                    continue;
+                }
+            };
+            let thumbnail = fs::read(root.join("image").join(format!("{}.png", name))).ok();
+            let remote_id = (location != Location::Pending).then_some(name);
+
+            count += tx.execute(
+                "INSERT OR IGNORE INTO entries (remote_id, location, typ, pined, data, thumbnail)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    remote_id,
+                    location.as_i64(),
+                    data.typ,
+                    data.pined,
+                    serde_json::to_string(&data).unwrap_or_default(),
+                    thumbnail
+                ],
+            )?;
+        }
+    }
+    Ok(count)
+}
+
+fn insert(
+    tx: &Transaction,
+    remote_id: Option<&str>,
+    location: Location,
+    data: &Data,
+    thumbnail: Option<&[u8]>,
+) -> rusqlite::Result<i64> {
+    let json = serde_json::to_string(data)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    tx.execute(
+        "INSERT INTO entries (remote_id, location, typ, pined, data, thumbnail, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            remote_id,
+            location.as_i64(),
+            data.typ,
+            data.pined,
+            json,
+            thumbnail,
+            chrono::Utc::now().timestamp()
+        ],
+    )?;
+    Ok(tx.last_insert_rowid())
+}
+
+impl Storage for SqliteStorage {
+    fn add_pending(&self, data: &Data, thumbnail: Option<&[u8]>) -> io::Result<i64> {
+        self.with(|conn| {
+            let tx = conn.transaction()?;
+            let id = insert(&tx, None, Location::Pending, data, thumbnail)?;
+            tx.commit()?;
+            Ok(id)
+        })
+    }
+
+    fn add_local(&self, data: &Data, thumbnail: Option<&[u8]>) -> io::Result<i64> {
+        self.with(|conn| {
+            let tx = conn.transaction()?;
+            let id = insert(&tx, None, Location::Local, data, thumbnail)?;
+            tx.commit()?;
+            Ok(id)
+        })
+    }
+
+    fn add_synced(
+        &self,
+        remote_id: &str,
+        data: &Data,
+        thumbnail: Option<&[u8]>,
+    ) -> io::Result<i64> {
+        self.with(|conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM entries WHERE remote_id = ?1", [remote_id])?;
+            let id = insert(&tx, Some(remote_id), Location::Data, data, thumbnail)?;
+            tx.commit()?;
+            Ok(id)
+        })
+    }
+
+    fn replace(&self, id: i64, data: &Data) -> io::Result<(i64, Option<String>)> {
+        self.with(|conn| {
+            let tx = conn.transaction()?;
+            let (remote_id, location, thumbnail): (Option<String>, i64, Option<Vec<u8>>) = tx
+                .query_row(
+                    "SELECT remote_id, location, thumbnail FROM entries WHERE id = ?1",
+                    [id],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .optional()?
+                .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            let location = match Location::from_i64(location) {
+                Location::Local => Location::Local,
+                _ => Location::Pending,
+            };
+            let new_id = insert(&tx, None, location, data, thumbnail.as_deref())?;
+            // an edit does not extend the life of an entry
+            tx.execute(
+                "UPDATE entries SET expires_at = old.expires_at,
+                    created_at = coalesce(old.created_at, entries.created_at)
+                 FROM (SELECT expires_at, created_at FROM entries WHERE id = ?1) AS old
+                 WHERE id = ?2",
+                params![id, new_id],
+            )?;
+            tx.execute("DELETE FROM entries WHERE id = ?1", [id])?;
+            tx.commit()?;
+            Ok((new_id, remote_id))
+        })
+    }
+
+    fn mark_synced(&self, id: i64, remote_id: &str) -> io::Result<()> {
+        self.with(|conn| {
+            let tx = conn.transaction()?;
+            // the server may send the same entry back before the ack arrives
+            tx.execute(
+                "DELETE FROM entries WHERE remote_id = ?1 AND id != ?2",
+                params![remote_id, id],
+            )?;
+            tx.execute(
+                "UPDATE entries SET remote_id = ?1, location = ?2 WHERE id = ?3",
+                params![remote_id, Location::Data.as_i64(), id],
+            )?;
+            tx.commit()
+        })
+    }
+
+    fn keep_local(&self, id: i64) -> io::Result<()> {
+        self.with(|conn| {
+            conn.execute(
+                "UPDATE entries SET location = ?1 WHERE id = ?2",
+                params![Location::Local.as_i64(), id],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn remove(&self, id: i64) -> io::Result<Option<String>> {
+        self.with(|conn| {
+            let tx = conn.transaction()?;
+            let remote_id: Option<String> = tx
+                .query_row("SELECT remote_id FROM entries WHERE id = ?1", [id], |row| {
+                    row.get(0)
+                })
+                .optional()?
+                .flatten();
+            tx.execute("DELETE FROM entries WHERE id = ?1", [id])?;
+            tx.commit()?;
+            Ok(remote_id)
+        })
+    }
+
+    fn remove_remote(&self, remote_id: &str) -> io::Result<Option<i64>> {
+        self.with(|conn| {
+            let tx = conn.transaction()?;
+            let id = tx
+                .query_row(
+                    "SELECT id FROM entries WHERE remote_id = ?1",
+                    [remote_id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            tx.execute("DELETE FROM entries WHERE remote_id = ?1", [remote_id])?;
+            tx.commit()?;
+            Ok(id)
+        })
+    }
+
+    fn find_remote(&self, remote_id: &str) -> io::Result<Option<i64>> {
+        self.with(|conn| {
+            conn.query_row(
+                "SELECT id FROM entries WHERE remote_id = ?1",
+                [remote_id],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+    }
+
+    fn trim(&self, max: u32) -> io::Result<Vec<i64>> {
+        self.with(|conn| {
+            let tx = conn.transaction()?;
+            let old: Vec<(i64, bool)> = {
+                let mut stmt = tx.prepare(
+                    "SELECT id, pined FROM entries WHERE location = ?1
+                     ORDER BY id DESC LIMIT -1 OFFSET ?2",
+                )?;
+                stmt.query_map(params![Location::Data.as_i64(), max], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?
+                .collect::<Result<_, _>>()?
+            };
+            for (id, pined) in &old {
+                if *pined {
+                    tx.execute(
+                        "UPDATE entries SET location = ?1 WHERE id = ?2",
+                        params![Location::Pined.as_i64(), id],
+                    )?;
+                } else {
+                    tx.execute("DELETE FROM entries WHERE id = ?1", [id])?;
+                }
+            }
+            tx.commit()?;
+            Ok(old.into_iter().map(|(id, _)| id).collect())
+        })
+    }
+
+    fn get(&self, id: i64) -> io::Result<Option<Entry>> {
+        let row = self.with(|conn| {
+            conn.query_row(
+                "SELECT id, remote_id, location, data FROM entries WHERE id = ?1",
+                [id],
+                read_entry,
+            )
+            .optional()
+        })?;
+        row.map(build_entry).transpose()
+    }
+
+    fn thumbnail(&self, id: i64) -> io::Result<Option<Vec<u8>>> {
+        self.with(|conn| {
+            conn.query_row("SELECT thumbnail FROM entries WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map(Option::flatten)
+        })
+    }
+
+    fn list(&self) -> io::Result<Vec<(i64, Location)>> {
+        self.with(|conn| {
+            let mut stmt =
+                conn.prepare("SELECT id, location FROM entries ORDER BY location DESC, id DESC")?;
+            stmt.query_map([], |row| Ok((row.get(0)?, Location::from_i64(row.get(1)?))))?
+                .collect()
+        })
+    }
+
+    fn entries(&self) -> io::Result<Vec<Entry>> {
+        let rows = self.with(|conn| {
+            let mut stmt = conn.prepare("SELECT id, remote_id, location, data FROM entries")?;
+            stmt.query_map([], read_entry)?
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| match build_entry(row) {
+                Ok(val) => Some(val),
+                Err(e) => {
+                    warn!("Skipping unreadable entry: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    fn pending(&self) -> io::Result<Vec<i64>> {
+        self.with(|conn| {
+            let mut stmt =
+                conn.prepare("SELECT id FROM entries WHERE location = ?1 ORDER BY id")?;
+            stmt.query_map([Location::Pending.as_i64()], |row| row.get(0))?
+                .collect()
+        })
+    }
+
+    fn set_expiry(&self, id: i64, at: i64) -> io::Result<()> {
+        self.with(|conn| {
+            conn.execute(
+                "UPDATE entries SET expires_at = ?1 WHERE id = ?2",
+                params![at, id],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn expired(&self, now: i64) -> io::Result<Vec<i64>> {
+        self.with(|conn| {
+            let mut stmt = conn.prepare("SELECT id FROM entries WHERE expires_at <= ?1")?;
+            stmt.query_map([now], |row| row.get(0))?.collect()
+        })
+    }
+
+    fn stats(&self) -> io::Result<Vec<EntryStats>> {
+        self.with(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, typ, pined, coalesce(created_at, 0),
+                    length(CAST(data AS BLOB)) + coalesce(length(thumbnail), 0)
+                 FROM entries ORDER BY coalesce(created_at, 0) DESC, id DESC",
+            )?;
+            stmt.query_map([], |row| {
+                Ok(EntryStats {
+                    id: row.get(0)?,
+                    typ: row.get(1)?,
+                    pined: row.get(2)?,
+                    created_at: row.get(3)?,
+                    size: row.get::<_, i64>(4)? as u64,
+                })
+            })?
+            .collect()
+        })
+    }
+
+    fn sync_cursor(&self, account: &str) -> io::Result<Option<SyncCursor>> {
+        self.with(|conn| {
+            conn.query_row(
+                "SELECT epoch, seq FROM sync_cursor WHERE account = ?1",
+                [account],
+                |row| {
+                    Ok(SyncCursor {
+                        epoch: row.get(0)?,
+                        seq: row.get::<_, i64>(1)? as u64,
+                    })
+                },
+            )
+            .optional()
+        })
+    }
+
+    fn set_sync_cursor(&self, account: &str, cursor: &SyncCursor) -> io::Result<()> {
+        self.with(|conn| {
+            conn.execute(
+                "INSERT INTO sync_cursor (account, epoch, seq) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (account) DO UPDATE SET
+                    seq = CASE WHEN epoch = excluded.epoch THEN max(seq, excluded.seq)
+                          ELSE excluded.seq END,
+                    epoch = excluded.epoch",
+                params![account, cursor.epoch, cursor.seq as i64],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn synced_since(&self, since: i64) -> io::Result<Vec<Entry>> {
+        let rows = self.with(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, remote_id, location, data FROM entries
+                 WHERE remote_id IS NOT NULL AND coalesce(created_at, 0) > ?1
+                 ORDER BY coalesce(created_at, 0), id",
+            )?;
+            stmt.query_map([since], read_entry)?
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+        rows.into_iter().map(build_entry).collect()
+    }
+
+    fn add_removed(&self, remote_id: &str) -> io::Result<()> {
+        self.with(|conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR REPLACE INTO removed (remote_id, removed_at) VALUES (?1, ?2)",
+                params![remote_id, chrono::Utc::now().timestamp()],
+            )?;
+            tx.execute(
+                "DELETE FROM removed WHERE remote_id NOT IN
+                    (SELECT remote_id FROM removed ORDER BY removed_at DESC LIMIT ?1)",
+                [MAX_REMOVED],
+            )?;
+            tx.commit()
+        })
+    }
+
+    fn is_removed(&self, remote_id: &str) -> io::Result<bool> {
+        self.with(|conn| {
+            conn.query_row(
+                "SELECT 1 FROM removed WHERE remote_id = ?1",
+                [remote_id],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|val| val.is_some())
+        })
+    }
+
+    fn removed_since(&self, since: i64) -> io::Result<Vec<String>> {
+        self.with(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT remote_id FROM removed WHERE removed_at > ?1 ORDER BY removed_at",
+            )?;
+            stmt.query_map([since], |row| row.get(0))?.collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_storage() -> (SqliteStorage, std::path::PathBuf) {
+        let root = env::temp_dir().join(format!("clippy-storage-{}", rand::random::<u64>()));
+        fs::create_dir_all(&root).unwrap();
+        let storage = SqliteStorage::open(&root.join(DATABASE_FILE), &root).unwrap();
+        (storage, root)
+    }
+
+    fn data(text: &str) -> Data {
+        Data::new(text.into(), "text/plain".into(), "device".into(), false)
+    }
+
+    #[test]
+    fn trim_drops_the_oldest_entries() {
+        let (storage, root) = temp_storage();
+        // `100-100` sorts before `100-99` as text but came later
+        let older = storage.add_synced("100-99", &data("older"), None).unwrap();
+        storage.add_synced("100-100", &data("newer"), None).unwrap();
+
+        assert_eq!(storage.trim(1).unwrap(), vec![older]);
+        assert!(storage.find_remote("100-99").unwrap().is_none());
+        assert!(storage.find_remote("100-100").unwrap().is_some());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn replacing_a_missing_entry_fails() {
+        let (storage, root) = temp_storage();
+        let err = storage.replace(42, &data("edit")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(storage.entries().unwrap().is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
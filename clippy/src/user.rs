@@ -1,10 +1,13 @@
+use crate::encryption_decryption::SyncKey;
+use crate::local::start_local;
+use crate::storage::{Entry, Location, storage};
 use crate::{
-    Data, Edit, MessageChannel, ResopnseServerToClient, ToByteString, log_error,
-    rewrite_pending_to_data,
+    Data, DeviceHello, Edit, MessageChannel, ResopnseServerToClient, ToByteString, log_error,
+    remote_time, set_sync_error,
 };
 use crate::{
     MessageType, ResopnseClientToServer, UserData, UserSettings,
-    http::{SERVER_WS, get_token, get_token_serv, health},
+    http::{LoggedOut, get_key_check, get_token, get_token_serv, health, set_key_check},
     set_global_update_bool,
 };
 use actix_codec::Framed;
@@ -19,42 +22,81 @@ use bytes::{Bytes, BytesMut};
 use futures_util::{SinkExt, StreamExt};
 use log::{debug, error, info};
 use reqwest::{self, Client};
+use std::collections::HashSet;
 use std::io;
 use std::{error::Error, sync::Arc, time::Duration};
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
 use tokio::{
     select,
     sync::mpsc::Receiver,
     time::{Instant, sleep},
 };
 
+enum SyncKeyErr {
+    Passphrase(String),
+    Connection(Box<dyn Error>),
+}
+
 pub fn start_cloud(rx: &mut Receiver<MessageChannel>, mut usersettings: UserSettings) {
     let user_data = UserData::build();
     let client = Arc::new(Client::new());
+    let mut locked = false;
 
     actix_rt::System::new().block_on(async {
         loop {
-            let Some(usercred) = usersettings.get_sync() else {
+            let Some(account) = usersettings.sync_account() else {
                 break;
             };
+            let server = usersettings.server().to_string();
             if usersettings.disable_sync {
                 break;
             }
             log::debug!("starting WebSocket client");
-            health(&client, rx, &user_data).await;
-            if let Err(e) = get_token_serv(&usercred, &client).await {
+            if health(&client, rx, &user_data, &mut usersettings).await {
+                continue;
+            }
+            if let Err(e) = get_token_serv(&server, &client).await {
+                if e.is::<LoggedOut>() {
+                    error!("Unable to verify credentials, logging out");
+                    usersettings.remove_user();
+                    if let Err(e) = usersettings.write_local() {
+                        error!("Unable to save settings");
+                        debug!("{}", e);
+                    }
+                    break;
+                }
                 error!("unable to get secure key from server");
                 debug!("{}", e);
-                health(&client, rx, &user_data).await;
+                health(&client, rx, &user_data, &mut usersettings).await;
                 continue;
             };
+            let sync_key = match get_sync_key(&usersettings, &client).await {
+                Ok((key, enabled)) => {
+                    // the history synced before was stored as plain text
+                    if enabled {
+                        reseal_history(&user_data).await;
+                    }
+                    key
+                }
+                Err(SyncKeyErr::Passphrase(e)) => {
+                    error!("Sync disabled: {}", e);
+                    set_sync_error(Some(&e));
+                    locked = true;
+                    break;
+                }
+                Err(SyncKeyErr::Connection(e)) => {
+                    error!("unable to verify encryption key");
+                    debug!("{}", e);
+                    health(&client, rx, &user_data, &mut usersettings).await;
+                    continue;
+                }
+            };
+            set_sync_error(None);
             let token = get_token();
             let config_ws = awc::Client::builder()
                 .max_http_version(awc::http::Version::HTTP_11)
                 .finish();
             let result = config_ws
-                .ws(SERVER_WS)
+                .ws(connect_url(&usersettings))
                 .set_header(header::AUTHORIZATION, format!("Bearer {}", token))
                 .max_frame_size(30 * 1024 * 1024)
                 .connect()
@@ -64,31 +106,157 @@ pub fn start_cloud(rx: &mut Receiver<MessageChannel>, mut usersettings: UserSett
                 Ok((resp, conn)) => (resp, conn),
                 Err(e) => {
                     error!("Client connect error: {e:?}");
-                    health(&client, rx, &user_data).await;
+                    health(&client, rx, &user_data, &mut usersettings).await;
                     continue;
                 }
             };
 
-            if let Err(e) = check_uptodate_state(&mut ws, &user_data).await {
+            if let Err(e) = check_uptodate_state(&mut ws, &account).await {
                 error!("Unable to check client state");
                 debug!("{}", e);
             };
-            if let Err(e) = handle_connection(&mut ws, &user_data, &mut usersettings, rx).await {
+            if let Err(e) = handle_connection(
+                &mut ws,
+                &user_data,
+                &mut usersettings,
+                rx,
+                sync_key.as_ref(),
+            )
+            .await
+            {
                 error!("Unable to maintain connection");
                 debug!("{}", e);
             };
 
-            health(&client, rx, &user_data).await;
+            health(&client, rx, &user_data, &mut usersettings).await;
         }
     });
+
+    // the clipboard keeps working locally until the passphrase is fixed in settings
+    if locked {
+        start_local(rx, usersettings);
+    }
 }
 
+// the server keeps a registry of the devices of the account
+fn connect_url(usersettings: &UserSettings) -> String {
+    let hello = DeviceHello::this_device();
+    match reqwest::Url::parse_with_params(
+        &usersettings.server_ws(),
+        &[
+            ("device", hello.device),
+            ("name", hello.name),
+            ("version", hello.version),
+        ],
+    ) {
+        Ok(url) => url.to_string(),
+        Err(e) => {
+            debug!("{}", e);
+            usersettings.server_ws()
+        }
+    }
+}
+
+/// The key the clipboard is sealed with, and whether encryption was turned on just now.
+async fn get_sync_key(
+    usersettings: &UserSettings,
+    client: &Client,
+) -> Result<(Option<SyncKey>, bool), SyncKeyErr> {
+    let server = usersettings.server();
+    let record = get_key_check(server, client)
+        .await
+        .map_err(SyncKeyErr::Connection)?;
+
+    match (usersettings.get_encrept(), record) {
+        (None, None) => Ok((None, false)),
+        (None, Some(_)) => Err(SyncKeyErr::Passphrase(
+            "Clipboard sync is end-to-end encrypted, enter the encryption passphrase in settings"
+                .to_string(),
+        )),
+        (Some(passphrase), None) => {
+            let (key, record) = SyncKey::generate(passphrase).map_err(SyncKeyErr::Passphrase)?;
+            set_key_check(server, client, &record)
+                .await
+                .map_err(SyncKeyErr::Connection)?;
+            info!("End-to-end encryption enabled for sync");
+            Ok((Some(key), true))
+        }
+        (Some(passphrase), Some(record)) => SyncKey::verify(passphrase, &record)
+            .map(|key| (Some(key), false))
+            .map_err(SyncKeyErr::Passphrase),
+    }
+}
+
+/// Sends the synced history again so the server only keeps sealed copies.
+async fn reseal_history(user_data: &UserData) {
+    let entries = match storage().entries() {
+        Ok(val) => val,
+        Err(e) => {
+            error!("Unable to read the clipboard history");
+            debug!("{}", e);
+            return;
+        }
+    };
+    for (remote_id, id) in synced(&entries) {
+        user_data.add_pending(remote_id, Edit::Edit { id }).await;
+    }
+}
+
+// the entries the server holds a copy of, by remote id
+fn synced(entries: &[Entry]) -> Vec<(String, i64)> {
+    entries
+        .iter()
+        .filter(|entry| entry.location == Location::Data)
+        .filter_map(|entry| Some((entry.remote_id.clone()?, entry.id)))
+        .collect()
+}
+
+fn seal_data(data: String, sync_key: Option<&SyncKey>) -> Result<String, String> {
+    match sync_key {
+        Some(key) => key.seal(&data),
+        None => Ok(data),
+    }
+}
+
+// the key of a connection and how many of the entries it received could not be opened
+struct Opener<'a> {
+    key: Option<&'a SyncKey>,
+    unopened: usize,
+}
+
+fn open_data(data: &str, sync_key: Option<&SyncKey>) -> Result<Data, Box<dyn Error>> {
+    let data = match sync_key {
+        Some(key) => key.open(data)?,
+        None => data.to_string(),
+    };
+    Ok(serde_json::from_str(&data)?)
+}
+
+// removes the synced entries that were removed while this device was away, the
+// entries the server dropped to make room are older than `removed_after` and stay
+fn resync(user_data: &UserData, live: &[String], removed_after: i64) -> Result<(), io::Error> {
+    let live: HashSet<&str> = live.iter().map(String::as_str).collect();
+    for entry in storage().entries()? {
+        let Some(remote_id) = entry.remote_id else {
+            continue;
+        };
+        if entry.location != Location::Pending
+            && !live.contains(remote_id.as_str())
+            && remote_time(&remote_id).is_some_and(|at| at > removed_after)
+        {
+            debug!("entry {} was removed while offline", remote_id);
+            user_data.remove_remote(&remote_id)?;
+        }
+    }
+    Ok(())
+}
+
+/// Asks the server for every change since the last one this device applied.
 async fn check_uptodate_state<T: AsyncRead + AsyncWrite + Unpin + 'static>(
     ws: &mut Framed<T, Codec>,
-    user_data: &UserData,
+    account: &str,
 ) -> Result<(), Box<dyn Error>> {
-    let state = user_data.get_sync_30();
-    let data = ResopnseClientToServer::CheckVersionArr(state);
+    let data = ResopnseClientToServer::Sync(storage().sync_cursor(account)?);
     Ok(ws
         .send(ws::Message::Text(
             serde_json::to_string(&data).unwrap().into(),
@@ -101,10 +269,14 @@ async fn handle_connection<T: AsyncRead + AsyncWrite + Unpin + 'static>(
     user_data: &UserData,
     usersettings: &mut UserSettings,
     rx: &mut Receiver<MessageChannel>,
+    sync_key: Option<&SyncKey>,
 ) -> Result<(), Box<dyn Error>> {
-    let mut buffer: Option<BytesMut> = None;
-    let mut current_type: Option<MessageType> = None;
+    let mut buffer: Option<(BytesMut, MessageType)> = None;
     let mut last_pong = Instant::now();
+    let mut opener = Opener {
+        key: sync_key,
+        unopened: 0,
+    };
     loop {
         select! {
             _ = sleep(Duration::from_secs(1)) => {
@@ -126,29 +298,25 @@ async fn handle_connection<T: AsyncRead + AsyncWrite + Unpin + 'static>(
                         continue;
                     }
                 };
-                if let Err(e) = handle_mag(msg, usersettings, user_data, ws, &mut last_pong, &mut buffer, &mut current_type).await{
+                // a device that was logged out on the server is closed
+                if let ws::Frame::Close(reason) = &msg {
+                    info!("Server closed the connection: {:?}", reason);
+                    return Ok(());
+                }
+                if let Err(e) = handle_mag(msg, usersettings, user_data, ws, &mut last_pong, &mut buffer, &mut opener).await{
                     error!("Unable to process message: {}",e);
                 };
             }
 
             Some(va) = rx.recv() => {
-            match va {
-                MessageChannel::New { path, time, typ } => {
-                    user_data.add_pending(time, Edit::New { path: path.into(), typ, }).await;
-                }
-                MessageChannel::Edit { path, old_id, new_id, typ } => {
-                    debug!("path {} old_id {} new_time {} typ {}",path,old_id,new_id,typ);
-                    user_data.add_pending(old_id, Edit::Edit { path: path.into(), typ: typ, new_id, }).await;
-                }
-                MessageChannel::SettingsChanged => {
-                    *usersettings = UserSettings::build_user()?;
-                    debug!("change settings");
-                    break Ok(());
-                },
-                MessageChannel::Remove(id)  => {
-                    user_data.add_pending(id.clone(), Edit::Remove).await;
+                match va {
+                    MessageChannel::SettingsChanged => {
+                        *usersettings = UserSettings::build_user()?.with_secrets()?;
+                        debug!("change settings");
+                        break Ok(());
+                    },
+                    va => user_data.queue(va).await,
                 }
-        }
             }
             Some((last, id, edit)) = user_data.next() => {
                 match edit {
@@ -163,70 +331,51 @@ async fn handle_connection<T: AsyncRead + AsyncWrite + Unpin + 'static>(
                         }
                         user_data.change_state(&id);
                     }
-                    Edit::New { path, typ } => match File::open(&path).await {
-                        Ok(mut file) => {
-                            let mut file_data = String::new();
-                            match file.read_to_string(&mut file_data).await {
-                                Ok(_) => {
-                                    let buffer = ResopnseClientToServer::Data {
-                                        data: file_data,
-                                        id: id.clone(),
-                                        last,
-                                        is_it_edit: None,
-                                    };
-                                    if ws
-                                        .send(ws::Message::Text(buffer.to_bytestring().unwrap()))
-                                        .await
-                                        .is_err()
-                                    {
-                                        return Err("Unable to send data to server".into());
-                                    }
-                                    user_data.change_state(&id);
-                                    last_pong = Instant::now();
-                                }
-                                Err(e) => {
-                                    error!("Failed to read file data: {}", e);
-                                    user_data.pop_pending(&id);
-                                }
+                    Edit::New { id: entry_id } | Edit::Edit { id: entry_id } => {
+                        let entry = match storage().get(entry_id) {
+                            Ok(Some(val)) => val,
+                            Ok(None) => {
+                                debug!("entry {} was removed before sync", entry_id);
+                                user_data.pop_pending(&id);
+                                continue;
                             }
-                        }
-                        Err(e) => {
-                            error!("Failed to open file {:?}: {}", path, e);
-                            user_data.pop_pending(&id);
-                        }
-                    },
-                    Edit::Edit { path, typ, new_id } => match File::open(&path).await {
-                        Ok(mut file) => {
-                            let mut file_data = String::new();
-                            match file.read_to_string(&mut file_data).await {
-                                Ok(_) => {
-                                    let buffer = ResopnseClientToServer::Data {
-                                        data: file_data,
-                                        id: id.clone(),
-                                        last,
-                                        is_it_edit: Some(new_id.clone()),
-                                    };
-                                    if ws
-                                        .send(ws::Message::Text(buffer.to_bytestring().unwrap()))
-                                        .await
-                                        .is_err()
-                                    {
-                                        return Err("Unable to send data to server".into());
-                                    }
-                                    user_data.change_state(&id);
-                                    last_pong = Instant::now();
-                                }
-                                Err(e) => {
-                                    error!("Failed to read file data: {:?} {}", path, e);
-                                    user_data.pop_pending(&id);
-                                }
+                            Err(e) => {
+                                error!("Failed to read entry {}: {}", entry_id, e);
+                                user_data.pop_pending(&id);
+                                continue;
                             }
+                        };
+                        let file_data = match serde_json::to_string(&entry.data)
+                            .map_err(|e| e.to_string())
+                            .and_then(|val| seal_data(val, sync_key))
+                        {
+                            Ok(val) => val,
+                            Err(e) => {
+                                error!("Unable to encrypt data: {}", e);
+                                user_data.pop_pending(&id);
+                                continue;
+                            }
+                        };
+                        let is_it_edit = match edit {
+                            Edit::Edit { .. } => Some(entry_id.to_string()),
+                            _ => None,
+                        };
+                        let buffer = ResopnseClientToServer::Data {
+                            data: file_data,
+                            id: id.clone(),
+                            last,
+                            is_it_edit,
+                        };
+                        if ws
+                            .send(ws::Message::Text(buffer.to_bytestring().unwrap()))
+                            .await
+                            .is_err()
+                        {
+                            return Err("Unable to send data to server".into());
                         }
-                        Err(e) => {
-                            error!("Failed to open file {:?}: {}", path, e);
-                            user_data.pop_pending(&id);
-                        }
-                    },
+                        user_data.change_state(&id);
+                        last_pong = Instant::now();
+                    }
                 }
             }
 
@@ -234,12 +383,12 @@ async fn handle_connection<T: AsyncRead + AsyncWrite + Unpin + 'static>(
     }
 }
 
-async fn process_text<T: AsyncRead + AsyncWrite + Unpin + 'static>(
+async fn process_text(
     bin: Bytes,
     usersettings: &UserSettings,
     user_data: &UserData,
-    ws: &mut Framed<T, Codec>,
     last_pong: &mut Instant,
+    opener: &mut Opener<'_>,
 ) -> Result<(), io::Error> {
     let state: ResopnseServerToClient = serde_json::from_slice(&bin)?;
     match state {
@@ -257,71 +406,100 @@ async fn process_text<T: AsyncRead + AsyncWrite + Unpin + 'static>(
             };
 
             match edit {
-                Edit::New { path, typ } => {
-                    rewrite_pending_to_data(
-                        path,
-                        typ,
-                        &new.clone().unwrap(),
-                        usersettings.store_image,
-                    );
-                    user_data.add_data(new.unwrap(), usersettings.max_clipboard);
-                }
-                Edit::Edit { path, typ, new_id } => {
-                    debug!("old edit data id: {:?}| new item id: {}", &old_id, new_id);
-                    rewrite_pending_to_data(path, typ, &new.unwrap(), usersettings.store_image);
-                    user_data.add_data(new_id, usersettings.max_clipboard);
+                Edit::New { id } | Edit::Edit { id } => {
+                    let Some(new) = new else {
+                        return Err(io::Error::other("Server did not return an id"));
+                    };
+                    debug!("sent id: {:?}| entry: {}| new id: {}", &old_id, id, new);
+                    storage().mark_synced(id, &new)?;
+                    user_data.add_data(id, usersettings.max_clipboard);
                 }
                 Edit::Remove => {
-                    user_data.remove_and_remove_file(&old_id)?;
+                    user_data.remove_remote(&old_id)?;
                 }
             }
             info!("Surcess sending new data");
             set_global_update_bool(true);
         }
-        ResopnseServerToClient::Outdated => {
-            let state = user_data.get_30_data();
-            let data = ResopnseClientToServer::CheckVersionArr(state);
-            if let Err(e) = ws
-                .send(ws::Message::Text(serde_json::to_string(&data)?.into()))
-                .await
-            {
-                error!("unable to send initial state {}", e);
-            };
+        ResopnseServerToClient::Rejected { old, error } => {
+            error!("The server refused an entry: {}", error);
+            set_sync_error(Some(&format!("Not synced, {}", error)));
+            // resending it would be refused again
+            if let Some((Edit::New { id } | Edit::Edit { id }, _)) = user_data.pop_pending(&old) {
+                storage().keep_local(id)?;
+                user_data.add_data(id, usersettings.max_clipboard);
+            }
+        }
+        ResopnseServerToClient::Failed { old, error } => {
+            error!("The server could not store an entry: {}", error);
+            user_data.retry_later(old);
+        }
+        ResopnseServerToClient::Resync {
+            live,
+            removed_after,
+        } => {
+            resync(user_data, &live, removed_after)?;
+            set_global_update_bool(true);
+        }
+        // the entries that could not be opened are sent again on the next connection
+        ResopnseServerToClient::Cursor(_) if opener.unopened > 0 => {
+            set_sync_error(Some(&format!(
+                "{} synced entries could not be decrypted, check the encryption passphrase",
+                opener.unopened
+            )));
+        }
+        ResopnseServerToClient::Cursor(cursor) => {
+            if let Some(account) = usersettings.sync_account() {
+                storage().set_sync_cursor(&account, &cursor)?;
+            }
+        }
+        // already applied, the server resends changes that raced with the cursor
+        ResopnseServerToClient::Data { new_id, .. }
+            if storage().find_remote(&new_id)?.is_some() =>
+        {
+            debug!("entry {} is already synced", new_id);
         }
         ResopnseServerToClient::Data {
             data,
             is_it_last,
             new_id,
-        } => match serde_json::from_str::<Data>(&data) {
+        } => match open_data(&data, opener.key) {
             Ok(data) => {
-                log_error!(data.just_write_paste(&new_id, is_it_last, false));
-                user_data.add_data(new_id, usersettings.max_clipboard);
+                let id = data.just_write_paste(&new_id, is_it_last, false)?;
+                user_data.add_data(id, usersettings.max_clipboard);
             }
             Err(e) => {
-                error!("Unable to process the data");
-                debug!("{}", e)
+                error!("Unable to open entry {}", new_id);
+                debug!("{}", e);
+                opener.unopened += 1;
             }
         },
         ResopnseServerToClient::Remove(id) => {
             for id in id.iter().rev() {
-                log_error!(user_data.remove_and_remove_file(&id));
+                log_error!(user_data.remove_remote(id));
             }
             set_global_update_bool(true)
         }
+        ResopnseServerToClient::EditReplace { old_id, new_id, .. }
+            if storage().find_remote(&new_id)?.is_some() =>
+        {
+            log_error!(user_data.remove_remote(&old_id));
+        }
         ResopnseServerToClient::EditReplace {
             data,
             is_it_last,
             old_id,
             new_id,
-        } => match serde_json::from_str::<Data>(&data) {
+        } => match open_data(&data, opener.key) {
             Ok(data) => {
-                log_error!(data.just_write_paste(&new_id, is_it_last, false));
-                user_data.add_data(new_id, usersettings.max_clipboard);
-                log_error!(user_data.remove_and_remove_file(&old_id));
+                let id = data.just_write_paste(&new_id, is_it_last, false)?;
+                user_data.add_data(id, usersettings.max_clipboard);
+                log_error!(user_data.remove_remote(&old_id));
             }
             Err(e) => {
-                error!("Unable to process the data");
-                debug!("{}", e)
+                error!("Unable to open entry {}", new_id);
+                debug!("{}", e);
+                opener.unopened += 1;
             }
         },
         _ => {}
@@ -336,12 +514,12 @@ async fn handle_mag<T: AsyncRead + AsyncWrite + Unpin + 'static>(
     user_data: &UserData,
     ws: &mut Framed<T, Codec>,
     last_pong: &mut Instant,
-    buffer: &mut Option<BytesMut>,
-    current_type: &mut Option<MessageType>,
+    buffer: &mut Option<(BytesMut, MessageType)>,
+    opener: &mut Opener<'_>,
 ) -> Result<(), String> {
     match msg {
         ws::Frame::Text(txt) => {
-            if let Err(e) = process_text(txt, usersettings, user_data, ws, last_pong).await {
+            if let Err(e) = process_text(txt, usersettings, user_data, last_pong, opener).await {
                 error!("Error saving data!");
                 debug!("{e}")
             }
@@ -356,33 +534,31 @@ async fn handle_mag<T: AsyncRead + AsyncWrite + Unpin + 'static>(
 
         ws::Frame::Continuation(bin) => match bin {
             Item::FirstText(data) => {
-                *buffer = Some(BytesMut::from(&data[..]));
-                *current_type = Some(MessageType::Text);
+                *buffer = Some((BytesMut::from(&data[..]), MessageType::Text));
             }
 
             Item::FirstBinary(data) => {
-                *buffer = Some(BytesMut::from(&data[..]));
-                *current_type = Some(MessageType::Binary);
+                *buffer = Some((BytesMut::from(&data[..]), MessageType::Binary));
             }
 
             Item::Continue(data) => {
-                if let Some(buf) = buffer {
+                if let Some((buf, _)) = buffer {
                     buf.extend_from_slice(&data);
                 } else {
                     error!("Received CONTINUE without FIRST. Dropping.");
                     *buffer = None;
-                    *current_type = None;
                 }
             }
 
             Item::Last(data) => {
-                if let (Some(mut buf), Some(msg_type)) = (buffer.take(), current_type.take()) {
+                if let Some((mut buf, msg_type)) = buffer.take() {
                     buf.extend_from_slice(&data);
                     let complete = buf.freeze();
                     match msg_type {
                         MessageType::Text => {
                             if let Err(e) =
-                                process_text(complete, usersettings, user_data, ws, last_pong).await
+                                process_text(complete, usersettings, user_data, last_pong, opener)
+                                    .await
                             {
                                 error!("Error saving data!");
                                 debug!("{e}")
@@ -399,3 +575,41 @@ async fn handle_mag<T: AsyncRead + AsyncWrite + Unpin + 'static>(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: i64, remote_id: Option<&str>, location: Location) -> Entry {
+        Entry {
+            id,
+            remote_id: remote_id.map(str::to_string),
+            location,
+            data: Data::new("text".into(), "text/plain".into(), "device".into(), false),
+        }
+    }
+
+    #[test]
+    fn encryption_enabled_with_plaintext_history() {
+        let (key, _) = SyncKey::generate("correct horse").unwrap();
+        let plain = serde_json::to_string(&entry(1, None, Location::Data).data).unwrap();
+
+        // entries uploaded before the passphrase was set can not be opened with the key
+        assert!(open_data(&plain, Some(&key)).is_err());
+        let sealed = seal_data(plain, Some(&key)).unwrap();
+        assert_eq!(open_data(&sealed, Some(&key)).unwrap().data, "text");
+
+        // so every entry the server holds is sent again sealed
+        let entries = [
+            entry(1, Some("100-10"), Location::Data),
+            entry(2, Some("100-11"), Location::Pined),
+            entry(3, None, Location::Pending),
+            entry(4, None, Location::Local),
+            entry(5, Some("100-12"), Location::Data),
+        ];
+        assert_eq!(
+            synced(&entries),
+            vec![("100-10".to_string(), 1), ("100-12".to_string(), 5)]
+        );
+    }
+}
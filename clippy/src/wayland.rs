@@ -0,0 +1,249 @@
+//! Minimal wlr-data-control client for entries with several representations.
+//! `wayland_clipboard_listener` reads one mime type per copy and only serves text and png.
+
+use crate::SourceApp;
+use serde_json::Value;
+use std::{
+    env,
+    error::Error,
+    io::{self, Read, Write},
+    os::{fd::AsFd, unix::net::UnixStream},
+    path::PathBuf,
+};
+use wayland_client::{
+    Connection, Dispatch, EventQueue, QueueHandle, delegate_noop, event_created_child,
+    globals::{GlobalListContents, registry_queue_init},
+    protocol::{wl_registry, wl_seat},
+};
+use wayland_protocols_wlr::data_control::v1::client::{
+    zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+    zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    zwlr_data_control_offer_v1::{self, ZwlrDataControlOfferV1},
+    zwlr_data_control_source_v1::{self, ZwlrDataControlSourceV1},
+};
+
+/// `(mime type, data)` pairs of one copy.
+pub type Contents = Vec<(String, Vec<u8>)>;
+
+#[derive(Default)]
+struct State {
+    // current selection and the mime types it offers
+    selection: Option<ZwlrDataControlOfferV1>,
+    offers: Vec<(ZwlrDataControlOfferV1, Vec<String>)>,
+    // what this client serves while it owns the selection
+    contents: Contents,
+    cancelled: bool,
+}
+
+struct DataControl {
+    conn: Connection,
+    queue: EventQueue<State>,
+    state: State,
+    manager: ZwlrDataControlManagerV1,
+    device: ZwlrDataControlDeviceV1,
+}
+
+impl DataControl {
+    fn connect() -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::connect_to_env()?;
+        let (globals, mut queue) = registry_queue_init::<State>(&conn)?;
+        let qh = queue.handle();
+        let seat: wl_seat::WlSeat = globals.bind(&qh, 1..=9, ())?;
+        let manager: ZwlrDataControlManagerV1 = globals.bind(&qh, 1..=2, ())?;
+        let device = manager.get_data_device(&seat, &qh, ());
+        let mut state = State::default();
+        // the device sends the current selection right away
+        queue.roundtrip(&mut state)?;
+        Ok(Self {
+            conn,
+            queue,
+            state,
+            manager,
+            device,
+        })
+    }
+}
+
+/// Reads the given mime types from the current selection, types it does not offer are skipped.
+pub fn read_selection(mime_types: &[String]) -> Result<Contents, Box<dyn Error>> {
+    let data_control = DataControl::connect()?;
+    let Some(offer) = data_control.state.selection.clone() else {
+        return Ok(Vec::new());
+    };
+    let offered = data_control
+        .state
+        .offers
+        .iter()
+        .find(|(val, _)| *val == offer)
+        .map(|(_, types)| types.clone())
+        .unwrap_or_default();
+
+    let mut contents = Vec::new();
+    for mime in mime_types.iter().filter(|val| offered.contains(val)) {
+        let (mut reader, writer) = io::pipe()?;
+        offer.receive(mime.clone(), writer.as_fd());
+        drop(writer);
+        data_control.conn.flush()?;
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        contents.push((mime.clone(), buf));
+    }
+    Ok(contents)
+}
+
+/// Takes the selection and serves every `(mime type, data)` pair until another client copies.
+pub fn serve_selection(contents: Contents) -> Result<(), Box<dyn Error>> {
+    let mut data_control = DataControl::connect()?;
+    let qh = data_control.queue.handle();
+    let source = data_control.manager.create_data_source(&qh, ());
+    for (mime, _) in &contents {
+        source.offer(mime.clone());
+    }
+    data_control.device.set_selection(Some(&source));
+    data_control.state.contents = contents;
+    while !data_control.state.cancelled {
+        data_control
+            .queue
+            .blocking_dispatch(&mut data_control.state)?;
+    }
+    source.destroy();
+    Ok(())
+}
+
+/// The focused window, asked to the compositor over its IPC socket.
+///
+/// Data-control does not tell which client owns the selection, the focused window is
+/// almost always the one that copied. Only Hyprland and sway expose it.
+pub fn focused_app() -> Option<SourceApp> {
+    if let Ok(signature) = env::var("HYPRLAND_INSTANCE_SIGNATURE") {
+        let runtime = env::var("XDG_RUNTIME_DIR").ok()?;
+        let socket = PathBuf::from(runtime)
+            .join("hypr")
+            .join(signature)
+            .join(".socket.sock");
+        let mut stream = UnixStream::connect(socket).ok()?;
+        stream.write_all(b"j/activewindow").ok()?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).ok()?;
+        let window: Value = serde_json::from_slice(&reply).ok()?;
+        return SourceApp::new(
+            window["class"].as_str().map(str::to_string),
+            window["pid"].as_u64().map(|val| val as u32),
+        );
+    }
+
+    if let Ok(socket) = env::var("SWAYSOCK") {
+        // i3 ipc: magic, payload length, message type, 4 is GET_TREE
+        let mut stream = UnixStream::connect(socket).ok()?;
+        let mut request = b"i3-ipc".to_vec();
+        request.extend(0u32.to_ne_bytes());
+        request.extend(4u32.to_ne_bytes());
+        stream.write_all(&request).ok()?;
+        let mut header = [0u8; 14];
+        stream.read_exact(&mut header).ok()?;
+        let len = u32::from_ne_bytes(header[6..10].try_into().ok()?) as usize;
+        let mut reply = vec![0u8; len];
+        stream.read_exact(&mut reply).ok()?;
+        let tree: Value = serde_json::from_slice(&reply).ok()?;
+        let window = focused_node(&tree)?;
+        // xwayland windows have no app id
+        let class = window["app_id"]
+            .as_str()
+            .or(window["window_properties"]["class"].as_str())
+            .map(str::to_string);
+        return SourceApp::new(class, window["pid"].as_u64().map(|val| val as u32));
+    }
+    None
+}
+
+fn focused_node(node: &Value) -> Option<&Value> {
+    if node["focused"].as_bool() == Some(true) {
+        return Some(node);
+    }
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|key| node[key].as_array())
+        .flatten()
+        .find_map(focused_node)
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &wl_registry::WlRegistry,
+        _event: wl_registry::Event,
+        _data: &GlobalListContents,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+delegate_noop!(State: ignore wl_seat::WlSeat);
+delegate_noop!(State: ZwlrDataControlManagerV1);
+
+impl Dispatch<ZwlrDataControlDeviceV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrDataControlDeviceV1,
+        event: zwlr_data_control_device_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_data_control_device_v1::Event::DataOffer { id } => {
+                state.offers.push((id, Vec::new()));
+            }
+            zwlr_data_control_device_v1::Event::Selection { id } => {
+                state.selection = id;
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(State, ZwlrDataControlDeviceV1, [
+        zwlr_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ZwlrDataControlOfferV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrDataControlOfferV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrDataControlOfferV1,
+        event: zwlr_data_control_offer_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let zwlr_data_control_offer_v1::Event::Offer { mime_type } = event
+            && let Some((_, types)) = state.offers.iter_mut().find(|(val, _)| val == proxy)
+        {
+            types.push(mime_type);
+        }
+    }
+}
+
+impl Dispatch<ZwlrDataControlSourceV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrDataControlSourceV1,
+        event: zwlr_data_control_source_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_data_control_source_v1::Event::Send { mime_type, fd } => {
+                if let Some((_, data)) = state.contents.iter().find(|(val, _)| *val == mime_type) {
+                    let mut file = std::fs::File::from(fd);
+                    if let Err(e) = file.write_all(data) {
+                        log::debug!("Unable to send {}: {}", mime_type, e);
+                    }
+                }
+            }
+            zwlr_data_control_source_v1::Event::Cancelled => state.cancelled = true,
+            _ => {}
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use crate::{Data, set_global_bool};
 use base64::{Engine, engine::general_purpose};
-use clipboard_rs::{Clipboard, ClipboardContext, RustImageData, common::RustImage};
+use clipboard_rs::{
+    Clipboard, ClipboardContent, ClipboardContext, RustImageData, common::RustImage,
+};
 use std::{error::Error, thread, time::Duration};
 
 #[cfg(target_family = "unix")]
@@ -19,18 +21,28 @@ pub fn copy_to_unix(data: Data, paste_on_click: bool) -> Result<(), String> {
 pub fn copy_to_clipboard_wl(data: Data, paste_on_click: bool) -> Result<(), String> {
     use wayland_clipboard_listener::WlClipboardCopyStream;
     set_global_bool(false);
-    thread::spawn(move || {
-        let context = if data.typ.starts_with("text") {
-            data.data.into_bytes()
-        } else {
-            string_to_vecu8(data.data)
-        };
-
-        let mut stream = WlClipboardCopyStream::init().unwrap();
-        stream
-            .copy_to_clipboard(context, vec![&data.typ], false)
-            .unwrap();
-    });
+    if !data.flavors().is_empty() {
+        // the copy stream serves a single text or png payload
+        let contents = wayland_contents(data);
+        thread::spawn(move || {
+            if let Err(e) = crate::wayland::serve_selection(contents) {
+                log::error!("Unable to copy to clipboard: {}", e);
+            }
+        });
+    } else {
+        thread::spawn(move || {
+            let context = if data.typ.starts_with("text") {
+                data.data.into_bytes()
+            } else {
+                string_to_vecu8(data.data)
+            };
+
+            let mut stream = WlClipboardCopyStream::init().unwrap();
+            stream
+                .copy_to_clipboard(context, vec![&data.typ], false)
+                .unwrap();
+        });
+    }
     #[cfg(feature = "default")]
     if paste_on_click {
         ctrl_v();
@@ -39,6 +51,35 @@ pub fn copy_to_clipboard_wl(data: Data, paste_on_click: bool) -> Result<(), Stri
     Ok(())
 }
 
+// every representation with its own mime type, text is also offered under the usual aliases
+#[cfg(target_os = "linux")]
+fn wayland_contents(data: Data) -> crate::wayland::Contents {
+    let mut contents = Vec::new();
+    if let Some(bytes) = data.bytes() {
+        if data.typ.starts_with("image/") {
+            contents.push((data.typ.clone(), bytes));
+        } else {
+            for mime in [
+                "text/plain;charset=utf-8",
+                "text/plain",
+                "UTF8_STRING",
+                "STRING",
+                "TEXT",
+            ] {
+                contents.push((mime.to_string(), bytes.clone()));
+            }
+        }
+    }
+    for flavor in data.flavors() {
+        if contents.iter().all(|(mime, _)| *mime != flavor.mime)
+            && let Some(bytes) = flavor.bytes()
+        {
+            contents.push((flavor.mime.clone(), bytes));
+        }
+    }
+    contents
+}
+
 #[cfg(target_os = "linux")]
 pub fn push_to_clipboard_wl_command(data: Data) -> Result<(), String> {
     use base64::{Engine, engine::general_purpose};
@@ -74,11 +115,28 @@ pub fn copy_to_clipboard(
     set_global_bool(false);
     let ctx = ClipboardContext::new()?;
 
+    let mut contents = Vec::new();
+    for flavor in data.flavors() {
+        let Some(bytes) = flavor.bytes() else {
+            continue;
+        };
+        let text = String::from_utf8_lossy(&bytes).into_owned();
+        contents.push(match flavor.mime.as_str() {
+            "text/html" => ClipboardContent::Html(text),
+            "text/rtf" | "application/rtf" => ClipboardContent::Rtf(text),
+            "text/uri-list" => ClipboardContent::Files(text.lines().map(String::from).collect()),
+            _ => ClipboardContent::Other(flavor.mime.clone(), bytes),
+        });
+    }
     if data.typ.starts_with("image/") {
-        ctx.set_image(RustImageData::from_bytes(&string_to_vecu8(data.data))?)?;
+        contents.insert(
+            0,
+            ClipboardContent::Image(RustImageData::from_bytes(&string_to_vecu8(data.data))?),
+        );
     } else {
-        ctx.set_text(data.data)?;
+        contents.insert(0, ClipboardContent::Text(data.data));
     }
+    ctx.set(contents)?;
     #[cfg(feature = "default")]
     if paste_on_click {
         ctrl_v();
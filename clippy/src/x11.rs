@@ -0,0 +1,76 @@
+//! Finds the application owning the X11 clipboard.
+
+use crate::SourceApp;
+use std::error::Error;
+use x11rb::{
+    protocol::{
+        res::{ClientIdMask, ClientIdSpec, ConnectionExt as _},
+        xproto::{AtomEnum, ConnectionExt as _, Window},
+    },
+    rust_connection::RustConnection,
+};
+
+/// The application owning the clipboard selection, `None` if nobody owns it or the
+/// owner window does not tell.
+pub fn clipboard_owner() -> Result<Option<SourceApp>, Box<dyn Error>> {
+    let (conn, _) = x11rb::connect(None)?;
+    let clipboard = conn.intern_atom(false, b"CLIPBOARD")?.reply()?.atom;
+    let owner = conn.get_selection_owner(clipboard)?.reply()?.owner;
+    if owner == x11rb::NONE {
+        return Ok(None);
+    }
+
+    // toolkits often own the selection with a child or a hidden window, the class
+    // is then looked up on its parents
+    let mut window = owner;
+    let class = loop {
+        let class = wm_class(&conn, window)?;
+        let tree = conn.query_tree(window)?.reply()?;
+        if class.is_some() || tree.parent == tree.root || tree.parent == x11rb::NONE {
+            break class;
+        }
+        window = tree.parent;
+    };
+
+    let pid = match net_wm_pid(&conn, window)? {
+        Some(pid) => Some(pid),
+        None => client_pid(&conn, owner),
+    };
+    Ok(SourceApp::new(class, pid))
+}
+
+// the second string of WM_CLASS, the first one is the instance name
+fn wm_class(conn: &RustConnection, window: Window) -> Result<Option<String>, Box<dyn Error>> {
+    let reply = conn
+        .get_property(false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 1024)?
+        .reply()?;
+    let class = reply
+        .value
+        .split(|val| *val == 0)
+        .nth(1)
+        .filter(|val| !val.is_empty())
+        .map(|val| String::from_utf8_lossy(val).into_owned());
+    Ok(class)
+}
+
+fn net_wm_pid(conn: &RustConnection, window: Window) -> Result<Option<u32>, Box<dyn Error>> {
+    let atom = conn.intern_atom(false, b"_NET_WM_PID")?.reply()?.atom;
+    let reply = conn
+        .get_property(false, window, atom, AtomEnum::CARDINAL, 0, 1)?
+        .reply()?;
+    Ok(reply.value32().and_then(|mut val| val.next()))
+}
+
+// asks the server which process created the window, needs the X-Resource extension
+fn client_pid(conn: &RustConnection, window: Window) -> Option<u32> {
+    let spec = ClientIdSpec {
+        client: window,
+        mask: ClientIdMask::LOCAL_CLIENT_PID,
+    };
+    let reply = conn.res_query_client_ids(&[spec]).ok()?.reply().ok()?;
+    reply
+        .ids
+        .into_iter()
+        .find(|val| val.spec.mask == ClientIdMask::LOCAL_CLIENT_PID)
+        .and_then(|val| val.value.first().copied())
+}